@@ -0,0 +1,70 @@
+// Times a few small programs through the library, for comparing interpreter
+// changes. Run with `cargo bench --bench interpreter`.
+
+use crafting_interpreters::{Lox, Scanner};
+use std::io;
+use std::time::{Duration, Instant};
+
+const PROGRAMS: [(&str, &str); 2] = [
+    (
+        "fib(25)",
+        "fun fib(n) {
+           if (n < 2) return n;
+           return fib(n - 1) + fib(n - 2);
+         }
+         fib(25);",
+    ),
+    // In a block so that `i` is a local rather than a global.
+    ("local loop", "{ var i = 0; while (i < 100000) i = i + 1; }"),
+];
+
+const RUNS: usize = 5;
+
+// Prints the best and median of `RUNS` timings of `f`.
+fn time(name: &str, mut f: impl FnMut()) {
+    let mut times: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    println!(
+        "{}: best {:.1} ms, median {:.1} ms over {} runs",
+        name,
+        times[0].as_secs_f64() * 1000.0,
+        times[RUNS / 2].as_secs_f64() * 1000.0,
+        RUNS
+    );
+}
+
+// Scanning on its own: one large source, and many one-line sources the way
+// the REPL sees them.
+fn scanning() {
+    let line =
+        "fun f(a, b) { if (a and b) return a; else while (true) print \"s\"; var x = nil; }\n";
+    let large = line.repeat(5000);
+    time("scan large file", || {
+        Scanner::new(large.as_bytes().to_vec())
+            .scan_tokens()
+            .unwrap();
+    });
+    time("scan 20000 lines", || {
+        for _ in 0..20000 {
+            Scanner::new(line.as_bytes().to_vec())
+                .scan_tokens()
+                .unwrap();
+        }
+    });
+}
+
+fn main() {
+    for (name, source) in PROGRAMS {
+        time(name, || {
+            let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::stderr()));
+            lox.run_source(source).expect("benchmark program failed");
+        });
+    }
+    scanning();
+}
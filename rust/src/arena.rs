@@ -0,0 +1,50 @@
+use crate::expr::{Expr, ExprId};
+use crate::stmt::{Stmt, StmtId};
+use std::ops::Index;
+
+// Owns every node of the programs parsed so far. Nodes refer to their
+// children by id rather than by pointer, so a tree of any depth is freed
+// without recursing, and an id is a cheap key for what a pass learns about
+// a node. Nothing is ever removed: functions defined by one run are called
+// by later ones.
+#[derive(Clone, Debug, Default)]
+pub struct AstArena {
+    exprs: Vec<Expr>,
+    stmts: Vec<Stmt>,
+}
+
+impl AstArena {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn add_expr(&mut self, expr: Expr) -> ExprId {
+        let id = u32::try_from(self.exprs.len()).expect("Too many expressions.");
+        self.exprs.push(expr);
+        ExprId(id)
+    }
+    pub fn add_stmt(&mut self, stmt: Stmt) -> StmtId {
+        let id = u32::try_from(self.stmts.len()).expect("Too many statements.");
+        self.stmts.push(stmt);
+        StmtId(id)
+    }
+    pub fn expr_count(&self) -> usize {
+        self.exprs.len()
+    }
+    pub fn stmt_count(&self) -> usize {
+        self.stmts.len()
+    }
+}
+
+impl Index<ExprId> for AstArena {
+    type Output = Expr;
+    fn index(&self, id: ExprId) -> &Expr {
+        &self.exprs[id.0 as usize]
+    }
+}
+
+impl Index<StmtId> for AstArena {
+    type Output = Stmt;
+    fn index(&self, id: StmtId) -> &Stmt {
+        &self.stmts[id.0 as usize]
+    }
+}
@@ -0,0 +1,601 @@
+// A corpus with at least one instance of every Expr and Stmt variant, for
+// tests that need to exercise each consumer of the AST. All tokens sit on
+// line 1 so that trees built here compare equal to trees parsed back from
+// `formatter` output.
+
+use crate::arena::AstArena;
+use crate::ast_printer::{ast_to_string, stmt_to_string};
+use crate::expr::{Expr, ExprId};
+use crate::formatter::stmt_to_source;
+use crate::interpreter::Interpreter;
+use crate::lox::Lox;
+use crate::parser::Parser;
+use crate::resolver::Resolver;
+use crate::scanner::Scanner;
+use crate::span::Span;
+use crate::stmt::{Stmt, StmtId};
+use crate::token::{Literal, RcToken, Token};
+use crate::token_type::TokenType;
+use crate::token_type::TokenType::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub fn token(type_: TokenType, lexeme: &str) -> RcToken {
+    Rc::new(Token::new(
+        type_,
+        lexeme.as_bytes().to_vec(),
+        Literal::NIL,
+        1,
+        0,
+    ))
+}
+
+pub fn identifier(name: &str) -> RcToken {
+    token(IDENTIFIER, name)
+}
+
+pub fn variable(ast: &mut AstArena, name: &str) -> ExprId {
+    ast.add_expr(Expr::Variable {
+        name: identifier(name),
+        span: Span::default(),
+    })
+}
+
+pub fn literal(ast: &mut AstArena, value: Literal) -> ExprId {
+    ast.add_expr(Expr::Literal {
+        value,
+        span: Span::default(),
+    })
+}
+
+pub fn number(ast: &mut AstArena, n: f64) -> ExprId {
+    literal(ast, Literal::NUMBER(n))
+}
+
+pub fn string(ast: &mut AstArena, s: &str) -> ExprId {
+    literal(ast, Literal::STRING(String::from(s)))
+}
+
+pub fn expression(ast: &mut AstArena, expr: ExprId) -> StmtId {
+    ast.add_stmt(Stmt::Expression {
+        expr,
+        span: Span::default(),
+    })
+}
+
+pub fn block(ast: &mut AstArena, statements: Vec<StmtId>) -> StmtId {
+    ast.add_stmt(Stmt::Block {
+        statements,
+        span: Span::default(),
+    })
+}
+
+// Every variable in the corpus refers to `a` or `f`, which `program()` binds
+// as parameters so the resolver has to resolve all of them as locals.
+pub fn exprs(ast: &mut AstArena) -> Vec<ExprId> {
+    let mut exprs = Vec::new();
+
+    let value = number(ast, 1.0);
+    exprs.push(ast.add_expr(Expr::Assign {
+        name: identifier("a"),
+        value,
+        span: Span::default(),
+    }));
+
+    let left = variable(ast, "a");
+    let right = number(ast, 2.5);
+    exprs.push(ast.add_expr(Expr::Binary {
+        left,
+        operator: token(PLUS, "+"),
+        right,
+        span: Span::default(),
+    }));
+
+    let callee = variable(ast, "f");
+    let arguments = vec![variable(ast, "a"), string(ast, "s")];
+    exprs.push(ast.add_expr(Expr::Call {
+        callee,
+        paren: token(RIGHT_PAREN, ")"),
+        arguments,
+        span: Span::default(),
+    }));
+
+    let object = variable(ast, "a");
+    exprs.push(ast.add_expr(Expr::Get {
+        object,
+        name: identifier("length"),
+        span: Span::default(),
+    }));
+
+    let left = number(ast, 1.0);
+    let right = variable(ast, "a");
+    let expression = ast.add_expr(Expr::Binary {
+        left,
+        operator: token(STAR, "*"),
+        right,
+        span: Span::default(),
+    });
+    exprs.push(ast.add_expr(Expr::Grouping {
+        expression,
+        span: Span::default(),
+    }));
+
+    let object = variable(ast, "a");
+    let index = number(ast, 0.0);
+    exprs.push(ast.add_expr(Expr::Index {
+        object,
+        bracket: token(RIGHT_BRACKET, "]"),
+        index,
+        span: Span::default(),
+    }));
+
+    let object = variable(ast, "a");
+    let index = variable(ast, "a");
+    let value = string(ast, "v");
+    exprs.push(ast.add_expr(Expr::IndexAssign {
+        object,
+        bracket: token(RIGHT_BRACKET, "]"),
+        index,
+        value,
+        span: Span::default(),
+    }));
+
+    let elements = vec![number(ast, 1.0), variable(ast, "a")];
+    exprs.push(ast.add_expr(Expr::ListLiteral {
+        elements,
+        span: Span::default(),
+    }));
+
+    let parts = vec![string(ast, "a is "), variable(ast, "a"), string(ast, "")];
+    exprs.push(ast.add_expr(Expr::Interpolation {
+        parts,
+        span: Span::default(),
+    }));
+
+    exprs.push(literal(ast, Literal::BOOL(true)));
+    exprs.push(literal(ast, Literal::NIL));
+    exprs.push(string(ast, "text"));
+
+    let left = variable(ast, "a");
+    let right = literal(ast, Literal::BOOL(false));
+    exprs.push(ast.add_expr(Expr::Logical {
+        left,
+        operator: token(OR, "or"),
+        right,
+        span: Span::default(),
+    }));
+
+    let target = variable(ast, "a");
+    let left = variable(ast, "a");
+    let right = number(ast, 1.0);
+    let value = ast.add_expr(Expr::Binary {
+        left,
+        operator: token(PLUS, "+"),
+        right,
+        span: Span::default(),
+    });
+    let update = ast.add_expr(Expr::Assign {
+        name: identifier("a"),
+        value,
+        span: Span::default(),
+    });
+    exprs.push(ast.add_expr(Expr::Postfix {
+        operator: token(PLUS_PLUS, "++"),
+        target,
+        update,
+        span: Span::default(),
+    }));
+
+    let right = variable(ast, "a");
+    let right = ast.add_expr(Expr::Unary {
+        operator: token(MINUS, "-"),
+        right,
+        span: Span::default(),
+    });
+    exprs.push(ast.add_expr(Expr::Unary {
+        operator: token(MINUS, "-"),
+        right,
+        span: Span::default(),
+    }));
+
+    exprs.push(variable(ast, "a"));
+    exprs
+}
+
+pub fn stmts(ast: &mut AstArena) -> Vec<StmtId> {
+    let mut stmts = Vec::new();
+
+    let a = variable(ast, "a");
+    let inner = expression(ast, a);
+    stmts.push(block(ast, vec![inner]));
+
+    stmts.push(ast.add_stmt(Stmt::Break {
+        keyword: token(BREAK, "break"),
+        span: Span::default(),
+    }));
+    stmts.push(ast.add_stmt(Stmt::Continue {
+        keyword: token(CONTINUE, "continue"),
+        span: Span::default(),
+    }));
+
+    let f = variable(ast, "f");
+    stmts.push(expression(ast, f));
+
+    let default = variable(ast, "a");
+    let value = variable(ast, "p");
+    let body = vec![ast.add_stmt(Stmt::Return {
+        keyword: token(RETURN, "return"),
+        value,
+        span: Span::default(),
+    })];
+    stmts.push(ast.add_stmt(Stmt::Function {
+        name: identifier("g"),
+        params: vec![(identifier("p"), None), (identifier("q"), Some(default))],
+        body,
+        span: Span::default(),
+    }));
+
+    let iterable = variable(ast, "a");
+    let e = variable(ast, "e");
+    let inner = expression(ast, e);
+    let body = block(ast, vec![inner]);
+    stmts.push(ast.add_stmt(Stmt::ForEach {
+        variable: identifier("e"),
+        keyword: identifier("in"),
+        iterable,
+        body,
+        span: Span::default(),
+    }));
+
+    let condition = variable(ast, "a");
+    let a = variable(ast, "a");
+    let then_branch = expression(ast, a);
+    let else_branch = Some(block(ast, vec![]));
+    stmts.push(ast.add_stmt(Stmt::If {
+        condition,
+        then_branch,
+        else_branch,
+        span: Span::default(),
+    }));
+
+    let expr = variable(ast, "a");
+    stmts.push(ast.add_stmt(Stmt::Print {
+        expr,
+        span: Span::default(),
+    }));
+
+    stmts.push(ast.add_stmt(Stmt::Import {
+        keyword: token(IMPORT, "import"),
+        path: token(STRING, "\"m.lox\""),
+        span: Span::default(),
+    }));
+
+    let value = literal(ast, Literal::NIL);
+    stmts.push(ast.add_stmt(Stmt::Return {
+        keyword: token(RETURN, "return"),
+        value,
+        span: Span::default(),
+    }));
+
+    let value = variable(ast, "a");
+    stmts.push(ast.add_stmt(Stmt::Throw {
+        keyword: token(THROW, "throw"),
+        value,
+        span: Span::default(),
+    }));
+
+    let a = variable(ast, "a");
+    let body = vec![expression(ast, a)];
+    let err = variable(ast, "err");
+    let handler = vec![expression(ast, err)];
+    stmts.push(ast.add_stmt(Stmt::Try {
+        body,
+        binding: identifier("err"),
+        handler,
+        span: Span::default(),
+    }));
+
+    let initializer = Some(variable(ast, "a"));
+    stmts.push(ast.add_stmt(Stmt::Var {
+        name: identifier("x"),
+        initializer,
+        constant: false,
+        span: Span::default(),
+    }));
+    stmts.push(ast.add_stmt(Stmt::Var {
+        name: identifier("y"),
+        initializer: None,
+        constant: false,
+        span: Span::default(),
+    }));
+    let initializer = Some(variable(ast, "a"));
+    stmts.push(ast.add_stmt(Stmt::Var {
+        name: identifier("z"),
+        initializer,
+        constant: true,
+        span: Span::default(),
+    }));
+
+    let condition = variable(ast, "a");
+    let a = variable(ast, "a");
+    let body = expression(ast, a);
+    stmts.push(ast.add_stmt(Stmt::While {
+        condition,
+        body,
+        span: Span::default(),
+    }));
+
+    let initializer = Some(variable(ast, "a"));
+    let initializer = Some(ast.add_stmt(Stmt::Var {
+        name: identifier("i"),
+        initializer,
+        constant: false,
+        span: Span::default(),
+    }));
+    let condition = Some(variable(ast, "i"));
+    let increment = Some(variable(ast, "a"));
+    let body = block(ast, vec![]);
+    stmts.push(ast.add_stmt(Stmt::For {
+        initializer,
+        condition,
+        increment,
+        body,
+        span: Span::default(),
+    }));
+
+    let a = variable(ast, "a");
+    let initializer = Some(expression(ast, a));
+    let body = block(ast, vec![]);
+    stmts.push(ast.add_stmt(Stmt::For {
+        initializer,
+        condition: None,
+        increment: None,
+        body,
+        span: Span::default(),
+    }));
+
+    stmts
+}
+
+// Wraps the whole corpus in a function and loop so that every statement is
+// legal for the resolver. Imports are only legal at the top level, so they
+// follow the wrapper instead.
+pub fn program(ast: &mut AstArena) -> Vec<StmtId> {
+    let mut body: Vec<StmtId> = exprs(ast)
+        .into_iter()
+        .map(|expr| expression(ast, expr))
+        .collect();
+    let (imports, stmts): (Vec<StmtId>, Vec<StmtId>) = stmts(ast)
+        .into_iter()
+        .partition(|stmt| matches!(ast[*stmt], Stmt::Import { .. }));
+    body.extend(stmts);
+    let condition = literal(ast, Literal::BOOL(true));
+    let body = block(ast, body);
+    let body = vec![ast.add_stmt(Stmt::While {
+        condition,
+        body,
+        span: Span::default(),
+    })];
+    let mut program = vec![ast.add_stmt(Stmt::Function {
+        name: identifier("wrapper"),
+        params: vec![(identifier("a"), None), (identifier("f"), None)],
+        body,
+        span: Span::default(),
+    })];
+    program.extend(imports);
+    program
+}
+
+// Adding a variant makes these matches non-exhaustive, which fails the build
+// until the corpus above (and the counts below) cover it.
+pub const EXPR_VARIANTS: usize = 14;
+pub const STMT_VARIANTS: usize = 15;
+
+pub fn expr_variant(expr: &Expr) -> usize {
+    match expr {
+        Expr::Assign { .. } => 0,
+        Expr::Binary { .. } => 1,
+        Expr::Call { .. } => 2,
+        Expr::Get { .. } => 3,
+        Expr::Grouping { .. } => 4,
+        Expr::Index { .. } => 5,
+        Expr::IndexAssign { .. } => 6,
+        Expr::Interpolation { .. } => 7,
+        Expr::ListLiteral { .. } => 8,
+        Expr::Literal { .. } => 9,
+        Expr::Logical { .. } => 10,
+        Expr::Postfix { .. } => 11,
+        Expr::Unary { .. } => 12,
+        Expr::Variable { .. } => 13,
+    }
+}
+
+pub fn stmt_variant(stmt: &Stmt) -> usize {
+    match stmt {
+        Stmt::Block { .. } => 0,
+        Stmt::Break { .. } => 1,
+        Stmt::Continue { .. } => 2,
+        Stmt::Expression { .. } => 3,
+        Stmt::For { .. } => 4,
+        Stmt::ForEach { .. } => 5,
+        Stmt::Function { .. } => 6,
+        Stmt::If { .. } => 7,
+        Stmt::Import { .. } => 8,
+        Stmt::Print { .. } => 9,
+        Stmt::Return { .. } => 10,
+        Stmt::Throw { .. } => 11,
+        Stmt::Try { .. } => 12,
+        Stmt::Var { .. } => 13,
+        Stmt::While { .. } => 14,
+    }
+}
+
+// Collects every expression that names a variable, in statement order.
+fn variable_uses(ast: &AstArena, stmt: StmtId, uses: &mut Vec<ExprId>) {
+    fn visit(ast: &AstArena, expr: ExprId, uses: &mut Vec<ExprId>) {
+        if matches!(&ast[expr], Expr::Variable { .. } | Expr::Assign { .. }) {
+            uses.push(expr);
+        }
+        for child in ast[expr].children() {
+            visit(ast, child, uses);
+        }
+    }
+    match &ast[stmt] {
+        Stmt::Block { statements, .. } => {
+            statements.iter().for_each(|s| variable_uses(ast, *s, uses))
+        }
+        Stmt::Break { .. } | Stmt::Continue { .. } | Stmt::Import { .. } => {}
+        Stmt::Expression { expr, .. } | Stmt::Print { expr, .. } => visit(ast, *expr, uses),
+        Stmt::For {
+            initializer,
+            condition,
+            increment,
+            body,
+            ..
+        } => {
+            if let Some(initializer) = initializer {
+                variable_uses(ast, *initializer, uses);
+            }
+            for expr in condition.iter().chain(increment) {
+                visit(ast, *expr, uses);
+            }
+            variable_uses(ast, *body, uses);
+        }
+        Stmt::ForEach { iterable, body, .. } => {
+            visit(ast, *iterable, uses);
+            variable_uses(ast, *body, uses);
+        }
+        Stmt::Function { params, body, .. } => {
+            for default in params.iter().filter_map(|(_, default)| *default) {
+                visit(ast, default, uses);
+            }
+            body.iter().for_each(|s| variable_uses(ast, *s, uses))
+        }
+        Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            visit(ast, *condition, uses);
+            variable_uses(ast, *then_branch, uses);
+            if let Some(else_branch) = else_branch {
+                variable_uses(ast, *else_branch, uses);
+            }
+        }
+        Stmt::Return { value, .. } | Stmt::Throw { value, .. } => visit(ast, *value, uses),
+        Stmt::Try { body, handler, .. } => body
+            .iter()
+            .chain(handler.iter())
+            .for_each(|s| variable_uses(ast, *s, uses)),
+        Stmt::Var {
+            name: _,
+            initializer,
+            constant: _,
+            ..
+        } => {
+            if let Some(initializer) = initializer {
+                visit(ast, *initializer, uses);
+            }
+        }
+        Stmt::While {
+            condition, body, ..
+        } => {
+            visit(ast, *condition, uses);
+            variable_uses(ast, *body, uses);
+        }
+    }
+}
+
+fn parse(source: &str) -> (AstArena, Vec<StmtId>) {
+    let mut scanner = Scanner::new(source.as_bytes().to_vec());
+    scanner.scan_tokens().expect(source);
+    let mut lox = Lox::new();
+    let mut ast = AstArena::new();
+    let statements = Parser::new(&mut lox, scanner.tokens, &mut ast).parse();
+    assert!(!lox.had_error, "{}", source);
+    (ast, statements)
+}
+
+#[test]
+fn corpus_covers_every_variant() {
+    let mut ast = AstArena::new();
+    let mut exprs_seen = [false; EXPR_VARIANTS];
+    for expr in exprs(&mut ast) {
+        exprs_seen[expr_variant(&ast[expr])] = true;
+    }
+    assert!(exprs_seen.iter().all(|&seen| seen), "{:?}", exprs_seen);
+
+    let mut stmts_seen = [false; STMT_VARIANTS];
+    for stmt in stmts(&mut ast) {
+        stmts_seen[stmt_variant(&ast[stmt])] = true;
+    }
+    assert!(stmts_seen.iter().all(|&seen| seen), "{:?}", stmts_seen);
+}
+
+// Parsed tokens carry real columns, so trees are compared through the
+// printer, which shows their whole structure but no positions.
+fn printed(ast: &AstArena, stmts: &[StmtId]) -> Vec<String> {
+    stmts.iter().map(|s| stmt_to_string(ast, *s)).collect()
+}
+
+#[test]
+fn formatter_and_parser_round_trip() {
+    let mut ast = AstArena::new();
+    let mut corpus: Vec<StmtId> = exprs(&mut ast)
+        .into_iter()
+        .map(|expr| expression(&mut ast, expr))
+        .collect();
+    corpus.extend(stmts(&mut ast));
+    corpus.extend(program(&mut ast));
+    for stmt in corpus {
+        let source = stmt_to_source(&ast, stmt);
+        let (parsed_ast, parsed) = parse(&source);
+        assert_eq!(
+            printed(&parsed_ast, &parsed),
+            printed(&ast, &[stmt]),
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn printer_keeps_every_child() {
+    let mut ast = AstArena::new();
+    for expr in exprs(&mut ast) {
+        let printed = ast_to_string(&ast, expr);
+        for child in ast[expr].children() {
+            let child = ast_to_string(&ast, child);
+            assert!(printed.contains(&child), "{} missing {}", printed, child);
+        }
+    }
+    let program = program(&mut ast);
+    let printed = printed(&ast, &program).join(" ");
+    for stmt in stmts(&mut ast) {
+        let stmt = stmt_to_string(&ast, stmt);
+        assert!(printed.contains(&stmt), "{} missing {}", printed, stmt);
+    }
+}
+
+#[test]
+fn resolver_visits_every_variable() {
+    let interpreter = Rc::new(RefCell::new(Interpreter::new()));
+    let mut ast = AstArena::new();
+    let program = program(&mut ast);
+    interpreter.borrow_mut().set_ast(ast);
+    Resolver::new(&interpreter)
+        .resolve_program(&program)
+        .expect("corpus should resolve");
+    let ast = Rc::clone(interpreter.borrow().ast());
+    let mut uses = Vec::new();
+    variable_uses(&ast, program[0], &mut uses);
+    assert!(!uses.is_empty());
+    for expr in uses {
+        assert!(
+            interpreter.borrow().resolved_depth(expr).is_some(),
+            "unresolved {}",
+            ast_to_string(&ast, expr)
+        );
+    }
+}
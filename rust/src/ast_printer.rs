@@ -1,74 +1,372 @@
+use crate::arena::AstArena;
 use crate::expr::*;
+use crate::interpreter::Interpreter;
+use crate::stmt::{Parameter, Stmt, StmtId};
 use crate::token::*;
-use crate::token_type::TokenType;
-use std::rc::Rc;
+use crate::visitor::{walk_expr, walk_stmt, ExprVisitor, StmtVisitor};
 
-#[allow(dead_code)]
-pub fn main() {
-    let expression = Rc::from(Expr::Binary {
-        left: Rc::from(Expr::Unary {
-            operator: Rc::new(Token::new(
-                TokenType::MINUS,
-                "-".as_bytes().to_vec(),
-                Literal::NIL,
-                1,
-            )),
-            right: Rc::from(Expr::Literal(Literal::NUMBER(123.0))),
-        }),
-        operator: Rc::new(Token::new(
-            TokenType::STAR,
-            "*".as_bytes().to_vec(),
-            Literal::NIL,
-            1,
-        )),
-        right: Rc::from(Expr::Grouping(Rc::from(Expr::Literal(Literal::NUMBER(
-            45.67,
-        ))))),
-    });
-    print!("{}", ast_to_string(expression));
+pub fn ast_to_string(ast: &AstArena, expr: ExprId) -> String {
+    AstPrinter {
+        ast,
+        resolved: None,
+    }
+    .expr(expr)
 }
 
-pub fn ast_to_string(expr: RcExpr) -> String {
-    match &*expr {
-        Expr::Assign { name, value: _ } => name.lexeme.clone(),
-        Expr::Binary {
-            left,
-            operator,
-            right,
-        } => parenthesize(
-            operator.lexeme.clone(),
-            vec![Rc::clone(left), Rc::clone(right)],
-        ),
-        Expr::Call {
-            callee: _,
-            paren: _,
-            arguments,
-        } => parenthesize(String::from("call"), arguments.clone()),
-        Expr::Grouping(expr) => parenthesize(String::from("group"), vec![Rc::clone(expr)]),
-        Expr::Literal(literal) => literal.to_string(),
-        Expr::Logical {
-            left,
-            operator,
-            right,
-        } => parenthesize(
-            operator.lexeme.clone(),
-            vec![Rc::clone(left), Rc::clone(right)],
-        ),
-        Expr::Unary { operator, right } => {
-            parenthesize(operator.lexeme.clone(), vec![Rc::clone(right)])
+#[cfg(test)]
+pub fn stmt_to_string(ast: &AstArena, stmt: StmtId) -> String {
+    AstPrinter {
+        ast,
+        resolved: None,
+    }
+    .stmt(stmt)
+}
+
+// Prints a resolved statement with each variable marked with the depth the
+// resolver gave it, `a@1`, or `a@global` where it found none.
+pub fn resolved_stmt_to_string(interpreter: &Interpreter, stmt: StmtId) -> String {
+    let ast = interpreter.ast();
+    AstPrinter {
+        ast,
+        resolved: Some(interpreter),
+    }
+    .stmt(stmt)
+}
+
+// One line for a statement about to run, as a trace shows it. Statements
+// that contain others show only their own part, with `...` for the rest.
+pub fn stmt_summary(ast: &AstArena, stmt: StmtId) -> String {
+    let mut printer = AstPrinter {
+        ast,
+        resolved: None,
+    };
+    match &ast[stmt] {
+        Stmt::Block { statements, .. } if statements.is_empty() => String::from("(block)"),
+        Stmt::Block { .. } => String::from("(block ...)"),
+        Stmt::For {
+            initializer,
+            condition,
+            increment,
+            ..
+        } => {
+            let mut parts = printer.for_clauses(*initializer, *condition, *increment);
+            parts.push(String::from("..."));
+            parenthesize_strings("for", parts)
+        }
+        Stmt::ForEach {
+            variable, iterable, ..
+        } => {
+            let iterable = printer.expr(*iterable);
+            format!("(for {} in {} ...)", variable.lexeme, iterable)
+        }
+        Stmt::Function { name, params, .. } => {
+            let names: Vec<&str> = params.iter().map(|(name, _)| &*name.lexeme).collect();
+            format!("(fun {} ({}) ...)", name.lexeme, names.join(" "))
         }
-        Expr::Variable { name } => name.lexeme.clone(),
+        Stmt::If { condition, .. } => format!("(if {} ...)", printer.expr(*condition)),
+        Stmt::Try { binding, .. } => format!("(try ... (catch {} ...))", binding.lexeme),
+        Stmt::While { condition, .. } => format!("(while {} ...)", printer.expr(*condition)),
+        _ => printer.stmt(stmt),
     }
 }
 
-fn parenthesize(name: String, exprs: Vec<RcExpr>) -> String {
-    let mut builder: String = String::with_capacity(2 + exprs.len() * 2);
-    builder.push('(');
-    builder.push_str(&name);
-    for expr in exprs {
+// Prints trees as S-expressions, e.g. `(* (- 123) (group 45.67))`.
+struct AstPrinter<'a> {
+    ast: &'a AstArena,
+    // Where to look up resolved depths, if variables should show them.
+    resolved: Option<&'a Interpreter>,
+}
+
+impl AstPrinter<'_> {
+    fn expr(&mut self, expr: ExprId) -> String {
+        let ast = self.ast;
+        walk_expr(self, ast, expr)
+    }
+    fn stmt(&mut self, stmt: StmtId) -> String {
+        let ast = self.ast;
+        walk_stmt(self, ast, stmt)
+    }
+    fn parenthesize(&mut self, name: &str, exprs: &[ExprId]) -> String {
+        let parts: Vec<String> = exprs.iter().map(|&e| self.expr(e)).collect();
+        parenthesize_strings(name, parts)
+    }
+    fn all(&mut self, exprs: &[ExprId]) -> Vec<String> {
+        exprs.iter().map(|&e| self.expr(e)).collect()
+    }
+    fn variable(&self, expr: ExprId, name: &RcToken) -> String {
+        match self
+            .resolved
+            .map(|interpreter| interpreter.resolved_depth(expr))
+        {
+            None => name.lexeme.to_string(),
+            Some(Some(depth)) => format!("{}@{}", name.lexeme, depth),
+            Some(None) => format!("{}@global", name.lexeme),
+        }
+    }
+    // The clauses a `for` loop has, leaving out those it omits.
+    fn for_clauses(
+        &mut self,
+        initializer: Option<StmtId>,
+        condition: Option<ExprId>,
+        increment: Option<ExprId>,
+    ) -> Vec<String> {
+        let mut parts: Vec<String> = initializer.map(|s| self.stmt(s)).into_iter().collect();
+        parts.extend(condition.map(|e| self.expr(e)));
+        parts.extend(increment.map(|e| self.expr(e)));
+        parts
+    }
+}
+
+impl ExprVisitor<String> for AstPrinter<'_> {
+    fn visit_assign(&mut self, expr: ExprId, name: &RcToken, value: ExprId) -> String {
+        let name = self.variable(expr, name);
+        self.parenthesize(&format!("= {}", name), &[value])
+    }
+    fn visit_binary(&mut self, left: ExprId, operator: &RcToken, right: ExprId) -> String {
+        self.parenthesize(&operator.lexeme, &[left, right])
+    }
+    fn visit_call(&mut self, callee: ExprId, _paren: &RcToken, arguments: &[ExprId]) -> String {
+        let mut parts = vec![self.expr(callee)];
+        parts.extend(self.all(arguments));
+        parenthesize_strings("call", parts)
+    }
+    fn visit_get(&mut self, object: ExprId, name: &RcToken) -> String {
+        self.parenthesize(&format!(". {}", name.lexeme), &[object])
+    }
+    fn visit_grouping(&mut self, expression: ExprId) -> String {
+        self.parenthesize("group", &[expression])
+    }
+    fn visit_index(&mut self, object: ExprId, _bracket: &RcToken, index: ExprId) -> String {
+        self.parenthesize("index", &[object, index])
+    }
+    fn visit_index_assign(
+        &mut self,
+        object: ExprId,
+        _bracket: &RcToken,
+        index: ExprId,
+        value: ExprId,
+    ) -> String {
+        self.parenthesize("index=", &[object, index, value])
+    }
+    fn visit_interpolation(&mut self, parts: &[ExprId]) -> String {
+        let parts = self.all(parts);
+        parenthesize_strings("interpolate", parts)
+    }
+    fn visit_list_literal(&mut self, elements: &[ExprId]) -> String {
+        let elements = self.all(elements);
+        parenthesize_strings("list", elements)
+    }
+    fn visit_literal(&mut self, value: &Literal) -> String {
+        value.to_string()
+    }
+    fn visit_logical(&mut self, left: ExprId, operator: &RcToken, right: ExprId) -> String {
+        self.parenthesize(&operator.lexeme, &[left, right])
+    }
+    fn visit_postfix(&mut self, operator: &RcToken, target: ExprId, update: ExprId) -> String {
+        self.parenthesize(&format!("post{}", operator.lexeme), &[target, update])
+    }
+    fn visit_unary(&mut self, operator: &RcToken, right: ExprId) -> String {
+        self.parenthesize(&operator.lexeme, &[right])
+    }
+    fn visit_variable(&mut self, expr: ExprId, name: &RcToken) -> String {
+        self.variable(expr, name)
+    }
+}
+
+impl StmtVisitor<String> for AstPrinter<'_> {
+    fn visit_block(&mut self, statements: &[StmtId]) -> String {
+        let statements = statements.iter().map(|&s| self.stmt(s)).collect();
+        parenthesize_strings("block", statements)
+    }
+    fn visit_break(&mut self, _keyword: &RcToken) -> String {
+        String::from("(break)")
+    }
+    fn visit_continue(&mut self, _keyword: &RcToken) -> String {
+        String::from("(continue)")
+    }
+    fn visit_expression(&mut self, expr: ExprId) -> String {
+        self.expr(expr)
+    }
+    fn visit_for(
+        &mut self,
+        initializer: Option<StmtId>,
+        condition: Option<ExprId>,
+        increment: Option<ExprId>,
+        body: StmtId,
+    ) -> String {
+        let mut parts = self.for_clauses(initializer, condition, increment);
+        parts.push(self.stmt(body));
+        parenthesize_strings("for", parts)
+    }
+    fn visit_for_each(
+        &mut self,
+        variable: &RcToken,
+        _keyword: &RcToken,
+        iterable: ExprId,
+        body: StmtId,
+    ) -> String {
+        let parts = vec![self.expr(iterable), self.stmt(body)];
+        parenthesize_strings(&format!("for {} in", variable.lexeme), parts)
+    }
+    fn visit_function(&mut self, name: &RcToken, params: &[Parameter], body: &[StmtId]) -> String {
+        let params: Vec<String> = params
+            .iter()
+            .map(|(name, default)| match default {
+                Some(default) => self.parenthesize(&format!("= {}", name.lexeme), &[*default]),
+                None => name.lexeme.to_string(),
+            })
+            .collect();
+        format!(
+            "(fun {} ({}) {})",
+            name.lexeme,
+            params.join(" "),
+            self.visit_block(body)
+        )
+    }
+    fn visit_if(
+        &mut self,
+        condition: ExprId,
+        then_branch: StmtId,
+        else_branch: Option<StmtId>,
+    ) -> String {
+        let mut parts = vec![self.expr(condition), self.stmt(then_branch)];
+        if let Some(else_branch) = else_branch {
+            parts.push(self.stmt(else_branch));
+        }
+        parenthesize_strings("if", parts)
+    }
+    fn visit_import(&mut self, _keyword: &RcToken, path: &RcToken) -> String {
+        format!("(import {})", path.lexeme)
+    }
+    fn visit_print(&mut self, expr: ExprId) -> String {
+        self.parenthesize("print", &[expr])
+    }
+    fn visit_return(&mut self, _keyword: &RcToken, value: ExprId) -> String {
+        self.parenthesize("return", &[value])
+    }
+    fn visit_throw(&mut self, _keyword: &RcToken, value: ExprId) -> String {
+        self.parenthesize("throw", &[value])
+    }
+    fn visit_try(&mut self, body: &[StmtId], binding: &RcToken, handler: &[StmtId]) -> String {
+        format!(
+            "(try {} (catch {} {}))",
+            self.visit_block(body),
+            binding.lexeme,
+            self.visit_block(handler)
+        )
+    }
+    fn visit_var(&mut self, name: &RcToken, initializer: Option<ExprId>, constant: bool) -> String {
+        let keyword = if constant { "const" } else { "var" };
+        match initializer {
+            Some(initializer) => {
+                self.parenthesize(&format!("{} {}", keyword, name.lexeme), &[initializer])
+            }
+            None => format!("({} {})", keyword, name.lexeme),
+        }
+    }
+    fn visit_while(&mut self, condition: ExprId, body: StmtId) -> String {
+        let parts = vec![self.expr(condition), self.stmt(body)];
+        parenthesize_strings("while", parts)
+    }
+}
+
+fn parenthesize_strings(name: &str, parts: Vec<String>) -> String {
+    let mut builder = String::from("(");
+    builder.push_str(name);
+    for part in parts {
         builder.push(' ');
-        builder.push_str(&ast_to_string(expr));
+        builder.push_str(&part);
     }
     builder.push(')');
     builder
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::Lox;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn print_program(source: &str) -> Vec<String> {
+        let mut scanner = Scanner::new(source.as_bytes().to_vec());
+        scanner.scan_tokens().unwrap();
+        let mut ast = AstArena::new();
+        let statements = Parser::new(&mut Lox::new(), scanner.tokens, &mut ast).parse();
+        statements
+            .into_iter()
+            .map(|stmt| stmt_to_string(&ast, stmt))
+            .collect()
+    }
+
+    #[test]
+    fn prints_declarations() {
+        assert_eq!(
+            print_program(
+                "var x = 1; var y; const z = 2; fun foo(a, b) { return a + b; } fun bar() {}"
+            ),
+            vec![
+                "(var x 1)",
+                "(var y)",
+                "(const z 2)",
+                "(fun foo (a b) (block (return (+ a b))))",
+                "(fun bar () (block))",
+            ]
+        );
+    }
+
+    #[test]
+    fn prints_control_flow() {
+        assert_eq!(
+            print_program(
+                "if (x) print 1; else { print 2; }
+                while (x < 3) { x = x + 1; if (x) break; }
+                for (var i = 0; i < 3; i = i + 1) { continue; }
+                { f(1)(2); }"
+            ),
+            vec![
+                "(if x (print 1) (block (print 2)))",
+                "(while (< x 3) (block (= x (+ x 1)) (if x (break))))",
+                "(for (var i 0) (< i 3) (= i (+ i 1)) (block (continue)))",
+                "(block (call (call f 1) 2))",
+            ]
+        );
+    }
+
+    #[test]
+    fn summarizes_statements() {
+        let source = "if (x) print 1;
+            while (x < 3) x = x + 1;
+            for (var i = 0; i < 3; i = i + 1) print i;
+            for (; i < 3; i++) {}
+            { var i = 0; while (i < 3) i = i + 1; }
+            for (var v in [1]) {}
+            fun f(a, b = 2) { return a; }
+            try { throw 1; } catch (e) {}
+            {}
+            print 1 + 2;";
+        let mut scanner = Scanner::new(source.as_bytes().to_vec());
+        scanner.scan_tokens().unwrap();
+        let mut ast = AstArena::new();
+        let statements = Parser::new(&mut Lox::new(), scanner.tokens, &mut ast).parse();
+        let summaries: Vec<String> = statements
+            .into_iter()
+            .map(|stmt| stmt_summary(&ast, stmt))
+            .collect();
+        assert_eq!(
+            summaries,
+            vec![
+                "(if x ...)",
+                "(while (< x 3) ...)",
+                "(for (var i 0) (< i 3) (= i (+ i 1)) ...)",
+                "(for (< i 3) (post++ i (= i (+ i 1))) ...)",
+                "(block ...)",
+                "(for v in (list 1) ...)",
+                "(fun f (a b) ...)",
+                "(try ... (catch e ...))",
+                "(block)",
+                "(print (+ 1 2))",
+            ]
+        );
+    }
+}
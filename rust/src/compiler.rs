@@ -0,0 +1,931 @@
+// Compiles resolved statements to bytecode for the VM in vm.rs, as clox
+// does, for `--vm`. The resolver has already checked the program, so the
+// only errors here are for what the VM can't run yet: closures over an
+// enclosing function's locals, default parameters, `for-in`, `try`, `throw`
+// and `import`.
+//
+// Values, globals, natives and the operators themselves are the tree-walk
+// interpreter's, so the two engines print the same results and errors.
+
+use crate::arena::AstArena;
+use crate::expr::ExprId;
+use crate::formatter::expr_to_source;
+use crate::interpreter::{Arity, ExprValue, ExprValueResult, Interpreter, LoxCallable};
+use crate::lox::LoxError;
+use crate::span::Span;
+use crate::stmt::{Parameter, StmtId};
+use crate::token::{Literal, RcToken};
+use crate::token_type::TokenType;
+use crate::value;
+use crate::visitor::{walk_expr, walk_stmt, ExprVisitor, StmtVisitor};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+// One byte each, followed by their operands: a byte for local slots and
+// counts of arguments, a big-endian u16 for constant indexes, jump
+// distances and counts of list elements or string parts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpCode {
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal,
+    SetLocal,
+    GetGlobal,
+    DefineGlobal,
+    DefineConstant,
+    SetGlobal,
+    GetProperty,
+    Index,
+    IndexSet,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+    Not,
+    Negate,
+    List,
+    Interpolate,
+    Print,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Return,
+}
+
+const OPCODES: [OpCode; 40] = [
+    OpCode::Constant,
+    OpCode::Nil,
+    OpCode::True,
+    OpCode::False,
+    OpCode::Pop,
+    OpCode::GetLocal,
+    OpCode::SetLocal,
+    OpCode::GetGlobal,
+    OpCode::DefineGlobal,
+    OpCode::DefineConstant,
+    OpCode::SetGlobal,
+    OpCode::GetProperty,
+    OpCode::Index,
+    OpCode::IndexSet,
+    OpCode::Equal,
+    OpCode::NotEqual,
+    OpCode::Greater,
+    OpCode::GreaterEqual,
+    OpCode::Less,
+    OpCode::LessEqual,
+    OpCode::Add,
+    OpCode::Subtract,
+    OpCode::Multiply,
+    OpCode::Divide,
+    OpCode::Modulo,
+    OpCode::BitAnd,
+    OpCode::BitOr,
+    OpCode::BitXor,
+    OpCode::ShiftLeft,
+    OpCode::ShiftRight,
+    OpCode::Not,
+    OpCode::Negate,
+    OpCode::List,
+    OpCode::Interpolate,
+    OpCode::Print,
+    OpCode::Jump,
+    OpCode::JumpIfFalse,
+    OpCode::Loop,
+    OpCode::Call,
+    OpCode::Return,
+];
+
+// How an instruction's operand is laid out and shown by `disassemble`.
+enum Operand {
+    None,
+    Byte,
+    Short,
+    Constant,
+    Jump(i64),
+}
+
+impl OpCode {
+    pub fn from_byte(byte: u8) -> OpCode {
+        OPCODES[byte as usize]
+    }
+    fn name(self) -> &'static str {
+        match self {
+            OpCode::Constant => "OP_CONSTANT",
+            OpCode::Nil => "OP_NIL",
+            OpCode::True => "OP_TRUE",
+            OpCode::False => "OP_FALSE",
+            OpCode::Pop => "OP_POP",
+            OpCode::GetLocal => "OP_GET_LOCAL",
+            OpCode::SetLocal => "OP_SET_LOCAL",
+            OpCode::GetGlobal => "OP_GET_GLOBAL",
+            OpCode::DefineGlobal => "OP_DEFINE_GLOBAL",
+            OpCode::DefineConstant => "OP_DEFINE_CONSTANT",
+            OpCode::SetGlobal => "OP_SET_GLOBAL",
+            OpCode::GetProperty => "OP_GET_PROPERTY",
+            OpCode::Index => "OP_INDEX",
+            OpCode::IndexSet => "OP_INDEX_SET",
+            OpCode::Equal => "OP_EQUAL",
+            OpCode::NotEqual => "OP_NOT_EQUAL",
+            OpCode::Greater => "OP_GREATER",
+            OpCode::GreaterEqual => "OP_GREATER_EQUAL",
+            OpCode::Less => "OP_LESS",
+            OpCode::LessEqual => "OP_LESS_EQUAL",
+            OpCode::Add => "OP_ADD",
+            OpCode::Subtract => "OP_SUBTRACT",
+            OpCode::Multiply => "OP_MULTIPLY",
+            OpCode::Divide => "OP_DIVIDE",
+            OpCode::Modulo => "OP_MODULO",
+            OpCode::BitAnd => "OP_BIT_AND",
+            OpCode::BitOr => "OP_BIT_OR",
+            OpCode::BitXor => "OP_BIT_XOR",
+            OpCode::ShiftLeft => "OP_SHIFT_LEFT",
+            OpCode::ShiftRight => "OP_SHIFT_RIGHT",
+            OpCode::Not => "OP_NOT",
+            OpCode::Negate => "OP_NEGATE",
+            OpCode::List => "OP_LIST",
+            OpCode::Interpolate => "OP_INTERPOLATE",
+            OpCode::Print => "OP_PRINT",
+            OpCode::Jump => "OP_JUMP",
+            OpCode::JumpIfFalse => "OP_JUMP_IF_FALSE",
+            OpCode::Loop => "OP_LOOP",
+            OpCode::Call => "OP_CALL",
+            OpCode::Return => "OP_RETURN",
+        }
+    }
+    fn operand(self) -> Operand {
+        match self {
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::DefineConstant
+            | OpCode::SetGlobal
+            | OpCode::GetProperty => Operand::Constant,
+            OpCode::GetLocal | OpCode::SetLocal | OpCode::Call => Operand::Byte,
+            OpCode::List | OpCode::Interpolate => Operand::Short,
+            OpCode::Jump | OpCode::JumpIfFalse => Operand::Jump(1),
+            OpCode::Loop => Operand::Jump(-1),
+            _ => Operand::None,
+        }
+    }
+    // The instruction for a binary operator.
+    fn binary(operator: &TokenType) -> OpCode {
+        match operator {
+            TokenType::EQUAL_EQUAL => OpCode::Equal,
+            TokenType::BANG_EQUAL => OpCode::NotEqual,
+            TokenType::GREATER => OpCode::Greater,
+            TokenType::GREATER_EQUAL => OpCode::GreaterEqual,
+            TokenType::LESS => OpCode::Less,
+            TokenType::LESS_EQUAL => OpCode::LessEqual,
+            TokenType::PLUS => OpCode::Add,
+            TokenType::MINUS => OpCode::Subtract,
+            TokenType::STAR => OpCode::Multiply,
+            TokenType::SLASH => OpCode::Divide,
+            TokenType::PERCENT => OpCode::Modulo,
+            TokenType::AMPERSAND => OpCode::BitAnd,
+            TokenType::PIPE => OpCode::BitOr,
+            TokenType::CARET => OpCode::BitXor,
+            TokenType::LESS_LESS => OpCode::ShiftLeft,
+            TokenType::GREATER_GREATER => OpCode::ShiftRight,
+            _ => unreachable!("invalid binary operator"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Rc<ExprValue>>,
+    // The source line of each byte in `code`.
+    pub lines: Vec<usize>,
+    // The token and span of each instruction that can fail, by offset, so
+    // its runtime errors point where the tree-walk interpreter's would.
+    pub origins: HashMap<usize, (RcToken, Span)>,
+    // The callee of each call, as source for the error if it isn't
+    // callable.
+    pub callees: HashMap<usize, String>,
+}
+
+impl Chunk {
+    pub fn read_byte(&self, offset: usize) -> u8 {
+        self.code[offset]
+    }
+    pub fn read_short(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+    // The name a global instruction's operand refers to.
+    pub fn name(&self, index: u16) -> Rc<str> {
+        match &*self.constants[index as usize] {
+            ExprValue::Literal(Literal::STRING(name)) => Rc::from(name.as_str()),
+            _ => unreachable!("global names are strings"),
+        }
+    }
+}
+
+// A function compiled for the VM. Calls to it are made by the VM itself.
+#[derive(Debug)]
+pub struct VmFunction {
+    pub name: Rc<str>,
+    pub arity: usize,
+    pub chunk: Rc<Chunk>,
+}
+
+impl LoxCallable for VmFunction {
+    fn arity(&self) -> Arity {
+        Arity::Exact(self.arity)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        _arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        Err(LoxError::RuntimeError {
+            token: Rc::clone(paren),
+            message: String::from("Natives can't call back into the VM yet."),
+            span: None,
+        })
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn to_string(&self) -> String {
+        format!("<fn {}({})>", self.name, self.arity)
+    }
+    fn as_compiled(&self) -> Option<&VmFunction> {
+        Some(self)
+    }
+}
+
+// The bytecode of `function` and of every function it declares, one
+// instruction per line, for `--vm --dump`.
+pub fn disassemble(function: &VmFunction) -> String {
+    let chunk = &function.chunk;
+    let mut out = format!("== {} ==\n", function.name);
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = OpCode::from_byte(chunk.code[offset]);
+        let line = match offset {
+            0 => chunk.lines[offset].to_string(),
+            _ if chunk.lines[offset] == chunk.lines[offset - 1] => String::from("|"),
+            _ => chunk.lines[offset].to_string(),
+        };
+        write!(out, "{:04} {:>4} ", offset, line).unwrap();
+        offset += 1;
+        match op.operand() {
+            Operand::None => out.push_str(op.name()),
+            Operand::Byte => {
+                write!(out, "{:<18} {:4}", op.name(), chunk.read_byte(offset)).unwrap();
+                offset += 1;
+            }
+            Operand::Short => {
+                write!(out, "{:<18} {:4}", op.name(), chunk.read_short(offset)).unwrap();
+                offset += 2;
+            }
+            Operand::Constant => {
+                let index = chunk.read_short(offset);
+                let constant = value::display(&chunk.constants[index as usize], false);
+                write!(out, "{:<18} {:4} '{}'", op.name(), index, constant).unwrap();
+                offset += 2;
+            }
+            Operand::Jump(sign) => {
+                let jump = chunk.read_short(offset);
+                offset += 2;
+                let target = offset as i64 + sign * jump as i64;
+                write!(out, "{:<18} {:4} -> {}", op.name(), offset - 3, target).unwrap();
+            }
+        }
+        out.push('\n');
+    }
+    for constant in &chunk.constants {
+        if let ExprValue::LoxCallable(callable) = &**constant {
+            if let Some(function) = callable.as_compiled() {
+                out.push_str(&disassemble(function));
+            }
+        }
+    }
+    out
+}
+
+// Compiles a program's top-level statements into the function the VM runs
+// first. `file` names the source for errors that have no token to do it.
+pub fn compile(
+    ast: &AstArena,
+    statements: &[StmtId],
+    file: Option<Rc<str>>,
+) -> Result<Rc<VmFunction>, LoxError> {
+    let mut compiler = Compiler {
+        ast,
+        file,
+        functions: vec![FunctionState::new()],
+        span: Span::default(),
+    };
+    for &stmt in statements {
+        compiler.statement(stmt)?;
+    }
+    let chunk = compiler.end_function();
+    Ok(Rc::new(VmFunction {
+        name: Rc::from("<script>"),
+        arity: 0,
+        chunk: Rc::new(chunk),
+    }))
+}
+
+type CompileResult = Result<(), LoxError>;
+
+struct Local {
+    name: Rc<str>,
+    depth: usize,
+}
+
+// Where a loop's `break` and `continue` jumps go once it's been compiled.
+struct Loop {
+    // The locals in scope outside it, which jumping out of its body keeps.
+    locals: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+// A function being compiled: its chunk and the locals on its part of the
+// VM's stack. Slot 0 holds the function itself, as in clox.
+struct FunctionState {
+    chunk: Chunk,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+}
+
+impl FunctionState {
+    fn new() -> Self {
+        FunctionState {
+            chunk: Chunk::default(),
+            locals: vec![Local {
+                name: Rc::from(""),
+                depth: 0,
+            }],
+            scope_depth: 0,
+            loops: Vec::new(),
+        }
+    }
+}
+
+struct Compiler<'a> {
+    ast: &'a AstArena,
+    // The function being compiled last, after those it's declared in.
+    functions: Vec<FunctionState>,
+    file: Option<Rc<str>>,
+    // The node being compiled, for the lines and origins of its code.
+    span: Span,
+}
+
+fn unsupported(token: &RcToken, what: &str) -> LoxError {
+    LoxError::ParseError {
+        token: Rc::clone(token),
+        message: format!("The VM doesn't support {} yet.", what),
+    }
+}
+
+impl Compiler<'_> {
+    fn statement(&mut self, stmt: StmtId) -> CompileResult {
+        let ast = self.ast;
+        let span = std::mem::replace(&mut self.span, ast[stmt].span());
+        let result = walk_stmt(self, ast, stmt);
+        self.span = span;
+        result
+    }
+    fn expression(&mut self, expr: ExprId) -> CompileResult {
+        let ast = self.ast;
+        let span = std::mem::replace(&mut self.span, ast[expr].span());
+        let result = walk_expr(self, ast, expr);
+        self.span = span;
+        result
+    }
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().unwrap()
+    }
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.current().chunk
+    }
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.span.start_line;
+        let chunk = self.chunk();
+        // Code the parser made up, like an implicit return, goes with the
+        // line before.
+        let line = match line {
+            0 => chunk.lines.last().copied().unwrap_or(0),
+            line => line,
+        };
+        chunk.code.push(byte);
+        chunk.lines.push(line);
+    }
+    fn emit(&mut self, op: OpCode) {
+        self.emit_byte(op as u8);
+    }
+    fn emit_short(&mut self, short: u16) {
+        for byte in short.to_be_bytes() {
+            self.emit_byte(byte);
+        }
+    }
+    // An instruction that can fail, blaming `token` and the current node.
+    fn emit_at(&mut self, op: OpCode, token: &RcToken) {
+        self.emit_at_span(op, token, self.span);
+    }
+    fn emit_at_span(&mut self, op: OpCode, token: &RcToken, span: Span) {
+        let offset = self.chunk().code.len();
+        self.chunk()
+            .origins
+            .insert(offset, (Rc::clone(token), span));
+        self.emit(op);
+    }
+    // A limit of the chunk format reached by the current node.
+    fn error(&self, message: &str) -> LoxError {
+        LoxError::ScanError {
+            line: self.span.start_line,
+            column: self.span.start_col,
+            file: self.file.clone(),
+            message: String::from(message),
+        }
+    }
+    fn make_constant(&mut self, value: Rc<ExprValue>) -> Result<u16, LoxError> {
+        let constants = &mut self.chunk().constants;
+        constants.push(value);
+        let index = constants.len() - 1;
+        u16::try_from(index).map_err(|_| self.error("Too many constants in one chunk."))
+    }
+    fn emit_constant(&mut self, value: Rc<ExprValue>) -> CompileResult {
+        let index = self.make_constant(value)?;
+        self.emit(OpCode::Constant);
+        self.emit_short(index);
+        Ok(())
+    }
+    fn name_constant(&mut self, name: &RcToken) -> Result<u16, LoxError> {
+        let value = Rc::from(ExprValue::Literal(Literal::STRING(name.lexeme.to_string())));
+        self.make_constant(value)
+    }
+    // Emits a jump to be patched once its target is known, returning where
+    // its operand is.
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit(op);
+        self.emit_short(u16::MAX);
+        self.chunk().code.len() - 2
+    }
+    fn patch_jump(&mut self, operand: usize) -> CompileResult {
+        let jump = self.chunk().code.len() - operand - 2;
+        let jump = u16::try_from(jump).map_err(|_| self.error("Too much code to jump over."))?;
+        self.chunk().code[operand..operand + 2].copy_from_slice(&jump.to_be_bytes());
+        Ok(())
+    }
+    fn emit_loop(&mut self, start: usize) -> CompileResult {
+        self.emit(OpCode::Loop);
+        let jump = self.chunk().code.len() - start + 2;
+        let jump = u16::try_from(jump).map_err(|_| self.error("Loop body too large."))?;
+        self.emit_short(jump);
+        Ok(())
+    }
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+    fn end_scope(&mut self) {
+        let function = self.current();
+        function.scope_depth -= 1;
+        let depth = function.scope_depth;
+        let kept = function
+            .locals
+            .iter()
+            .position(|local| local.depth > depth)
+            .unwrap_or(function.locals.len());
+        let popped = function.locals.len() - kept;
+        function.locals.truncate(kept);
+        for _ in 0..popped {
+            self.emit(OpCode::Pop);
+        }
+    }
+    // Whether a declaration here makes a global rather than a local.
+    fn at_top_level(&self) -> bool {
+        self.functions.len() == 1 && self.functions[0].scope_depth == 0
+    }
+    fn add_local(&mut self, name: &RcToken) -> CompileResult {
+        let function = self.current();
+        if function.locals.len() > u8::MAX as usize {
+            return Err(LoxError::ParseError {
+                token: Rc::clone(name),
+                message: String::from("Too many local variables in function."),
+            });
+        }
+        let depth = function.scope_depth;
+        function.locals.push(Local {
+            name: Rc::clone(&name.lexeme),
+            depth,
+        });
+        Ok(())
+    }
+    // The slot of the local `name` names in the current function, or None
+    // for a global.
+    fn resolve_local(&self, name: &RcToken) -> Result<Option<u8>, LoxError> {
+        let (current, enclosing) = self.functions.split_last().unwrap();
+        if let Some(slot) = current
+            .locals
+            .iter()
+            .rposition(|local| local.name == name.lexeme)
+        {
+            return Ok(Some(slot as u8));
+        }
+        let captured = enclosing.iter().any(|function| {
+            function
+                .locals
+                .iter()
+                .any(|local| local.name == name.lexeme)
+        });
+        if captured {
+            return Err(unsupported(name, "closures over enclosing locals"));
+        }
+        Ok(None)
+    }
+    // Finishes the current function with an implicit `return nil;`.
+    // The parameters and body of the function whose state was just pushed.
+    fn function_body(&mut self, params: &[Parameter], body: &[StmtId]) -> CompileResult {
+        self.begin_scope();
+        for (param, _) in params {
+            self.add_local(param)?;
+        }
+        for &stmt in body {
+            self.statement(stmt)?;
+        }
+        Ok(())
+    }
+    fn end_function(&mut self) -> Chunk {
+        self.emit(OpCode::Nil);
+        self.emit(OpCode::Return);
+        self.functions.pop().unwrap().chunk
+    }
+    // Jumps out of the innermost loop's body, dropping its locals first.
+    // The resolver has made sure there is a loop.
+    fn loop_jump(&mut self) -> usize {
+        let function = self.current();
+        let popped = function.locals.len() - function.loops.last().unwrap().locals;
+        for _ in 0..popped {
+            self.emit(OpCode::Pop);
+        }
+        self.emit_jump(OpCode::Jump)
+    }
+    // The loop of a `while` or `for`. Without a condition only a `break`
+    // leaves it.
+    fn loop_(
+        &mut self,
+        condition: Option<ExprId>,
+        increment: Option<ExprId>,
+        body: StmtId,
+    ) -> CompileResult {
+        let start = self.chunk().code.len();
+        let mut exit_jump = None;
+        if let Some(condition) = condition {
+            self.expression(condition)?;
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit(OpCode::Pop);
+        }
+        let locals = self.current().locals.len();
+        self.current().loops.push(Loop {
+            locals,
+            breaks: Vec::new(),
+            continues: Vec::new(),
+        });
+        let body_result = self.statement(body);
+        let this = self.current().loops.pop().unwrap();
+        body_result?;
+        for jump in this.continues {
+            self.patch_jump(jump)?;
+        }
+        if let Some(increment) = increment {
+            self.expression(increment)?;
+            self.emit(OpCode::Pop);
+        }
+        self.emit_loop(start)?;
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump)?;
+            self.emit(OpCode::Pop);
+        }
+        for jump in this.breaks {
+            self.patch_jump(jump)?;
+        }
+        Ok(())
+    }
+}
+
+impl ExprVisitor<CompileResult> for Compiler<'_> {
+    fn visit_assign(&mut self, _expr: ExprId, name: &RcToken, value: ExprId) -> CompileResult {
+        self.expression(value)?;
+        match self.resolve_local(name)? {
+            Some(slot) => {
+                self.emit(OpCode::SetLocal);
+                self.emit_byte(slot);
+            }
+            None => {
+                let index = self.name_constant(name)?;
+                // An undefined target is the name's fault, not the whole
+                // assignment's.
+                self.emit_at_span(OpCode::SetGlobal, name, Span::of(name));
+                self.emit_short(index);
+            }
+        }
+        Ok(())
+    }
+    fn visit_binary(&mut self, left: ExprId, operator: &RcToken, right: ExprId) -> CompileResult {
+        self.expression(left)?;
+        if operator.type_ == TokenType::COMMA {
+            self.emit(OpCode::Pop);
+            return self.expression(right);
+        }
+        self.expression(right)?;
+        self.emit_at(OpCode::binary(&operator.type_), operator);
+        Ok(())
+    }
+    fn visit_call(
+        &mut self,
+        callee: ExprId,
+        paren: &RcToken,
+        arguments: &[ExprId],
+    ) -> CompileResult {
+        self.expression(callee)?;
+        for &argument in arguments {
+            self.expression(argument)?;
+        }
+        let offset = self.chunk().code.len();
+        let printed = expr_to_source(self.ast, callee);
+        self.chunk().callees.insert(offset, printed);
+        self.emit_at(OpCode::Call, paren);
+        // The parser allows at most 255 arguments.
+        self.emit_byte(arguments.len() as u8);
+        Ok(())
+    }
+    fn visit_get(&mut self, object: ExprId, name: &RcToken) -> CompileResult {
+        self.expression(object)?;
+        let index = self.name_constant(name)?;
+        self.emit_at(OpCode::GetProperty, name);
+        self.emit_short(index);
+        Ok(())
+    }
+    fn visit_grouping(&mut self, expression: ExprId) -> CompileResult {
+        self.expression(expression)
+    }
+    fn visit_index(&mut self, object: ExprId, bracket: &RcToken, index: ExprId) -> CompileResult {
+        self.expression(object)?;
+        self.expression(index)?;
+        self.emit_at(OpCode::Index, bracket);
+        Ok(())
+    }
+    fn visit_index_assign(
+        &mut self,
+        object: ExprId,
+        bracket: &RcToken,
+        index: ExprId,
+        value: ExprId,
+    ) -> CompileResult {
+        self.expression(object)?;
+        self.expression(index)?;
+        self.expression(value)?;
+        self.emit_at(OpCode::IndexSet, bracket);
+        Ok(())
+    }
+    fn visit_interpolation(&mut self, parts: &[ExprId]) -> CompileResult {
+        for &part in parts {
+            self.expression(part)?;
+        }
+        self.emit(OpCode::Interpolate);
+        self.emit_short(parts.len() as u16);
+        Ok(())
+    }
+    fn visit_list_literal(&mut self, elements: &[ExprId]) -> CompileResult {
+        for &element in elements {
+            self.expression(element)?;
+        }
+        self.emit(OpCode::List);
+        self.emit_short(elements.len() as u16);
+        Ok(())
+    }
+    fn visit_literal(&mut self, value: &Literal) -> CompileResult {
+        match value {
+            Literal::NIL => self.emit(OpCode::Nil),
+            Literal::BOOL(true) => self.emit(OpCode::True),
+            Literal::BOOL(false) => self.emit(OpCode::False),
+            _ => return self.emit_constant(Rc::from(ExprValue::Literal(value.clone()))),
+        }
+        Ok(())
+    }
+    fn visit_logical(&mut self, left: ExprId, operator: &RcToken, right: ExprId) -> CompileResult {
+        self.expression(left)?;
+        if operator.type_ == TokenType::OR {
+            let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+            let end_jump = self.emit_jump(OpCode::Jump);
+            self.patch_jump(else_jump)?;
+            self.emit(OpCode::Pop);
+            self.expression(right)?;
+            self.patch_jump(end_jump)
+        } else {
+            let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+            self.emit(OpCode::Pop);
+            self.expression(right)?;
+            self.patch_jump(end_jump)
+        }
+    }
+    fn visit_postfix(
+        &mut self,
+        _operator: &RcToken,
+        target: ExprId,
+        update: ExprId,
+    ) -> CompileResult {
+        self.expression(target)?;
+        self.expression(update)?;
+        self.emit(OpCode::Pop);
+        Ok(())
+    }
+    fn visit_unary(&mut self, operator: &RcToken, right: ExprId) -> CompileResult {
+        self.expression(right)?;
+        match operator.type_ {
+            TokenType::BANG => self.emit(OpCode::Not),
+            _ => self.emit_at(OpCode::Negate, operator),
+        }
+        Ok(())
+    }
+    fn visit_variable(&mut self, _expr: ExprId, name: &RcToken) -> CompileResult {
+        match self.resolve_local(name)? {
+            Some(slot) => {
+                self.emit(OpCode::GetLocal);
+                self.emit_byte(slot);
+            }
+            None => {
+                let index = self.name_constant(name)?;
+                self.emit_at(OpCode::GetGlobal, name);
+                self.emit_short(index);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StmtVisitor<CompileResult> for Compiler<'_> {
+    fn visit_block(&mut self, statements: &[StmtId]) -> CompileResult {
+        self.begin_scope();
+        for &stmt in statements {
+            self.statement(stmt)?;
+        }
+        self.end_scope();
+        Ok(())
+    }
+    fn visit_break(&mut self, _keyword: &RcToken) -> CompileResult {
+        let jump = self.loop_jump();
+        self.current().loops.last_mut().unwrap().breaks.push(jump);
+        Ok(())
+    }
+    fn visit_continue(&mut self, _keyword: &RcToken) -> CompileResult {
+        let jump = self.loop_jump();
+        self.current()
+            .loops
+            .last_mut()
+            .unwrap()
+            .continues
+            .push(jump);
+        Ok(())
+    }
+    fn visit_expression(&mut self, expr: ExprId) -> CompileResult {
+        self.expression(expr)?;
+        self.emit(OpCode::Pop);
+        Ok(())
+    }
+    fn visit_for(
+        &mut self,
+        initializer: Option<StmtId>,
+        condition: Option<ExprId>,
+        increment: Option<ExprId>,
+        body: StmtId,
+    ) -> CompileResult {
+        self.begin_scope();
+        if let Some(initializer) = initializer {
+            self.statement(initializer)?;
+        }
+        self.loop_(condition, increment, body)?;
+        self.end_scope();
+        Ok(())
+    }
+    fn visit_for_each(
+        &mut self,
+        _variable: &RcToken,
+        keyword: &RcToken,
+        _iterable: ExprId,
+        _body: StmtId,
+    ) -> CompileResult {
+        Err(unsupported(keyword, "'for-in' loops"))
+    }
+    fn visit_function(
+        &mut self,
+        name: &RcToken,
+        params: &[Parameter],
+        body: &[StmtId],
+    ) -> CompileResult {
+        if let Some((param, _)) = params.iter().find(|(_, default)| default.is_some()) {
+            return Err(unsupported(param, "default parameters"));
+        }
+        let global = self.at_top_level();
+        if !global {
+            self.add_local(name)?;
+        }
+        self.functions.push(FunctionState::new());
+        let body_result = self.function_body(params, body);
+        let chunk = self.end_function();
+        body_result?;
+        let function = VmFunction {
+            name: Rc::clone(&name.lexeme),
+            arity: params.len(),
+            chunk: Rc::new(chunk),
+        };
+        self.emit_constant(Rc::from(ExprValue::LoxCallable(Rc::new(function))))?;
+        if global {
+            let index = self.name_constant(name)?;
+            self.emit(OpCode::DefineGlobal);
+            self.emit_short(index);
+        }
+        Ok(())
+    }
+    fn visit_if(
+        &mut self,
+        condition: ExprId,
+        then_branch: StmtId,
+        else_branch: Option<StmtId>,
+    ) -> CompileResult {
+        self.expression(condition)?;
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit(OpCode::Pop);
+        self.statement(then_branch)?;
+        let end_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(else_jump)?;
+        self.emit(OpCode::Pop);
+        if let Some(else_branch) = else_branch {
+            self.statement(else_branch)?;
+        }
+        self.patch_jump(end_jump)
+    }
+    fn visit_import(&mut self, keyword: &RcToken, _path: &RcToken) -> CompileResult {
+        Err(unsupported(keyword, "imports"))
+    }
+    fn visit_print(&mut self, expr: ExprId) -> CompileResult {
+        self.expression(expr)?;
+        self.emit(OpCode::Print);
+        Ok(())
+    }
+    fn visit_return(&mut self, _keyword: &RcToken, value: ExprId) -> CompileResult {
+        self.expression(value)?;
+        self.emit(OpCode::Return);
+        Ok(())
+    }
+    fn visit_throw(&mut self, keyword: &RcToken, _value: ExprId) -> CompileResult {
+        Err(unsupported(keyword, "'throw'"))
+    }
+    fn visit_try(
+        &mut self,
+        _body: &[StmtId],
+        binding: &RcToken,
+        _handler: &[StmtId],
+    ) -> CompileResult {
+        Err(unsupported(binding, "'try'"))
+    }
+    fn visit_var(
+        &mut self,
+        name: &RcToken,
+        initializer: Option<ExprId>,
+        constant: bool,
+    ) -> CompileResult {
+        match initializer {
+            Some(initializer) => self.expression(initializer)?,
+            None => self.emit(OpCode::Nil),
+        }
+        if !self.at_top_level() {
+            return self.add_local(name);
+        }
+        let index = self.name_constant(name)?;
+        self.emit(if constant {
+            OpCode::DefineConstant
+        } else {
+            OpCode::DefineGlobal
+        });
+        self.emit_short(index);
+        Ok(())
+    }
+    fn visit_while(&mut self, condition: ExprId, body: StmtId) -> CompileResult {
+        self.loop_(Some(condition), None, body)
+    }
+}
@@ -0,0 +1,58 @@
+use crate::interpreter::Interpreter;
+use crate::value;
+use std::io::{BufRead, Write};
+
+const HELP: &str = "Commands: p <expr>, env, step, c.";
+
+// What `--debug` attaches to the interpreter. Without one, `breakpoint()`
+// does nothing and statements run unchecked.
+pub struct DebugHook {
+    // Where commands are read from and answered, usually the terminal.
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    // Set by `step`, so the next statement stops too.
+    pub(crate) stepping: bool,
+}
+
+impl DebugHook {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        DebugHook {
+            input,
+            output,
+            stepping: false,
+        }
+    }
+}
+
+// Reads commands until one resumes the program. The end of the input
+// resumes it too, for good.
+pub(crate) fn prompt(interpreter: &mut Interpreter, hook: &mut DebugHook, stop: &str) {
+    hook.stepping = false;
+    writeln!(hook.output, "{}", stop).expect("Couldn't write output");
+    loop {
+        write!(hook.output, "(debug) ").expect("Couldn't write output");
+        hook.output.flush().expect("Couldn't write output");
+        let mut line = String::new();
+        if hook.input.read_line(&mut line).unwrap_or(0) == 0 {
+            writeln!(hook.output).expect("Couldn't write output");
+            return;
+        }
+        let reply = match line.trim() {
+            "" => continue,
+            "c" | "continue" => return,
+            "s" | "step" => {
+                hook.stepping = true;
+                return;
+            }
+            "env" => interpreter.dump_environment(),
+            command => match command.strip_prefix("p ") {
+                Some(source) => match interpreter.evaluate_source(source) {
+                    Ok(value) => format!("{}\n", value::display(&value, true)),
+                    Err(message) => format!("{}\n", message),
+                },
+                None => format!("Unknown command '{}'. {}\n", command, HELP),
+            },
+        };
+        write!(hook.output, "{}", reply).expect("Couldn't write output");
+    }
+}
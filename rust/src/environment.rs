@@ -1,14 +1,23 @@
-use crate::interpreter::ExprValue;
+use crate::interpreter::{ExprValue, Interpreter};
 use crate::lox::LoxError;
 use crate::token::{Literal, RcToken};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
 #[derive(Debug)]
 pub struct Environment {
     enclosing: Option<Rc<RefCell<Environment>>>,
-    values: HashMap<String, Rc<ExprValue>>,
+    // Globals live in a map, since a REPL line or a call made before the
+    // definition can refer to them by name.
+    values: HashMap<Rc<str>, Rc<ExprValue>>,
+    // Locals are numbered by the resolver in declaration order and read
+    // through `get_slot`. The names are kept only for lookups by name.
+    slots: Vec<Rc<ExprValue>>,
+    slot_names: Vec<Rc<str>>,
+    // Names bound with `const`. The resolver rejects assignments it can see;
+    // this catches the rest, e.g. globals from an earlier REPL line.
+    constants: HashSet<Rc<str>>,
 }
 type OptionExprValue = Option<Rc<ExprValue>>;
 
@@ -23,16 +32,53 @@ impl Environment {
                 }
             },
             values: HashMap::new(),
+            slots: Vec::new(),
+            slot_names: Vec::new(),
+            constants: HashSet::new(),
         }
     }
-    pub fn define(&mut self, name: String, value: OptionExprValue) {
-        if let Some(v) = value {
-            self.values.insert(name, v);
+    // A local scope whose first slots are already filled, as for a call's
+    // parameters.
+    pub fn with_slots(
+        enclosing: &Rc<RefCell<Environment>>,
+        names: Vec<Rc<str>>,
+        slots: Vec<Rc<ExprValue>>,
+    ) -> Self {
+        Environment {
+            slots,
+            slot_names: names,
+            ..Environment::new(Some(enclosing))
+        }
+    }
+    // A sibling of this environment holding the same bindings, which from
+    // then on are assigned separately.
+    pub fn copy_locals(&self) -> Self {
+        Environment {
+            slots: self.slots.clone(),
+            slot_names: self.slot_names.clone(),
+            constants: self.constants.clone(),
+            ..Environment::new(self.enclosing.as_ref())
+        }
+    }
+    pub fn define_constant(&mut self, name: Rc<str>, value: OptionExprValue) {
+        self.define(Rc::clone(&name), value);
+        self.constants.insert(name);
+    }
+    pub fn define(&mut self, name: Rc<str>, value: OptionExprValue) {
+        self.constants.remove(&name);
+        let value = value.unwrap_or_else(|| Rc::from(ExprValue::Literal(Literal::NIL)));
+        if self.enclosing.is_some() {
+            self.slot_names.push(name);
+            self.slots.push(value);
         } else {
-            self.values
-                .insert(name, Rc::from(ExprValue::Literal(Literal::NIL)));
+            self.values.insert(name, value);
         }
     }
+    // Only for globals; locals keep their slots for good.
+    pub fn undefine(&mut self, name: &str) {
+        self.values.remove(name);
+        self.constants.remove(name);
+    }
     pub fn ancestor(self_: &Rc<RefCell<Environment>>, distance: usize) -> Rc<RefCell<Environment>> {
         let mut env = Rc::clone(self_);
         for _ in 0..distance {
@@ -44,12 +90,121 @@ impl Environment {
         self_: &Rc<RefCell<Environment>>,
         distance: usize,
         name: &RcToken,
-    ) -> Result<Rc<ExprValue>, LoxError<String>> {
+    ) -> Result<Rc<ExprValue>, LoxError> {
         Environment::ancestor(self_, distance).borrow().get(name)
     }
-    pub fn get(&self, name: &RcToken) -> Result<Rc<ExprValue>, LoxError<String>> {
-        if self.values.contains_key(&name.lexeme) {
-            return Ok(Rc::clone(self.values.get(&name.lexeme).unwrap()));
+    pub fn assign_at(
+        self_: &Rc<RefCell<Environment>>,
+        distance: usize,
+        name: &RcToken,
+        value: OptionExprValue,
+    ) -> Result<(), LoxError> {
+        Environment::ancestor(self_, distance)
+            .borrow_mut()
+            .assign(name, value)
+    }
+    pub fn get_slot(
+        self_: &Rc<RefCell<Environment>>,
+        distance: usize,
+        slot: usize,
+    ) -> Rc<ExprValue> {
+        if distance == 0 {
+            return Rc::clone(&self_.borrow().slots[slot]);
+        }
+        let ancestor = Environment::ancestor(self_, distance);
+        let value = Rc::clone(&ancestor.borrow().slots[slot]);
+        value
+    }
+    pub fn assign_slot(
+        self_: &Rc<RefCell<Environment>>,
+        distance: usize,
+        slot: usize,
+        value: Rc<ExprValue>,
+    ) {
+        if distance == 0 {
+            self_.borrow_mut().slots[slot] = value;
+        } else {
+            Environment::ancestor(self_, distance).borrow_mut().slots[slot] = value;
+        }
+    }
+    pub fn enclosing(&self) -> Option<&Rc<RefCell<Environment>>> {
+        self.enclosing.as_ref()
+    }
+    // Every value bound here, globals and locals alike.
+    pub fn values(&self) -> impl Iterator<Item = &Rc<ExprValue>> {
+        self.values.values().chain(self.slots.iter())
+    }
+    // Moves everything out, leaving this environment empty.
+    pub fn take(&mut self) -> Environment {
+        std::mem::replace(self, Environment::new(None))
+    }
+    fn local(&self, name: &str) -> Option<usize> {
+        self.slot_names.iter().rposition(|n| &**n == name)
+    }
+    // Token-free lookup through the enclosing chain, for natives and tests.
+    #[allow(dead_code)]
+    pub fn get_by_name(&self, name: &str) -> OptionExprValue {
+        if let Some(value) = self.values.get(name) {
+            return Some(Rc::clone(value));
+        }
+        if let Some(slot) = self.local(name) {
+            return Some(Rc::clone(&self.slots[slot]));
+        }
+        match &self.enclosing {
+            Some(enclosing) => enclosing.borrow().get_by_name(name),
+            None => None,
+        }
+    }
+    // The (depth, slot) at which `name` is bound in a local scope, as the
+    // resolver would have found it. None means it's a global, if anything.
+    pub fn find_local(self_: &Rc<RefCell<Environment>>, name: &str) -> Option<(usize, usize)> {
+        let mut env = Rc::clone(self_);
+        let mut distance = 0;
+        loop {
+            let enclosing = {
+                let scope = env.borrow();
+                if let Some(slot) = scope.local(name) {
+                    return Some((distance, slot));
+                }
+                Rc::clone(scope.enclosing.as_ref()?)
+            };
+            env = enclosing;
+            distance += 1;
+        }
+    }
+    // Names defined directly in this environment, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .values
+            .keys()
+            .chain(self.slot_names.iter())
+            .map(|name| name.to_string())
+            .collect();
+        names.sort();
+        names
+    }
+    // Each name defined directly here with its value as `print` would show
+    // it, sorted by name.
+    pub fn snapshot(&self) -> Vec<(String, String)> {
+        let mut names = self.names();
+        names.dedup();
+        names
+            .into_iter()
+            .map(|name| {
+                let value = match self.values.get(name.as_str()) {
+                    Some(value) => Rc::clone(value),
+                    None => Rc::clone(&self.slots[self.local(&name).unwrap()]),
+                };
+                (name, Interpreter::stringify(value))
+            })
+            .collect()
+    }
+    pub fn get(&self, name: &RcToken) -> Result<Rc<ExprValue>, LoxError> {
+        if let Some(value) = self.values.get(&name.lexeme) {
+            return Ok(Rc::clone(value));
+        }
+        if let Some(slot) = self.local(&name.lexeme) {
+            return Ok(Rc::clone(&self.slots[slot]));
         }
 
         if let Some(enclosing) = &self.enclosing {
@@ -59,20 +214,26 @@ impl Environment {
         Err(LoxError::RuntimeError {
             token: Rc::clone(name),
             message: format!("Undefined variable '{}'.", name.lexeme),
+            span: None,
         })
     }
-    pub fn assign(
-        &mut self,
-        name: &RcToken,
-        value: OptionExprValue,
-    ) -> Result<(), LoxError<String>> {
-        if self.values.contains_key(&name.lexeme) {
-            let val = self.values.get_mut(&name.lexeme).unwrap();
-            *val = if let Some(v) = value {
-                v
-            } else {
-                Rc::from(ExprValue::Literal(Literal::NIL))
-            };
+    pub fn assign(&mut self, name: &RcToken, value: OptionExprValue) -> Result<(), LoxError> {
+        let local = self.local(&name.lexeme);
+        if local.is_some() || self.values.contains_key(&name.lexeme) {
+            if self.constants.contains(&name.lexeme) {
+                return Err(LoxError::RuntimeError {
+                    token: Rc::clone(name),
+                    message: format!("Can't assign to constant '{}'.", name.lexeme),
+                    span: None,
+                });
+            }
+            let value = value.unwrap_or_else(|| Rc::from(ExprValue::Literal(Literal::NIL)));
+            match local {
+                Some(slot) => self.slots[slot] = value,
+                None => {
+                    self.values.insert(Rc::clone(&name.lexeme), value);
+                }
+            }
             return Ok(());
         }
 
@@ -84,6 +245,121 @@ impl Environment {
         Err(LoxError::RuntimeError {
             token: Rc::clone(name),
             message: format!("Undefined variable '{}'.", name.lexeme),
+            span: None,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::Token;
+    use crate::token_type::TokenType;
+
+    fn nested(depth: usize) -> Vec<Rc<RefCell<Environment>>> {
+        let mut chain = vec![Rc::new(RefCell::new(Environment::new(None)))];
+        for _ in 0..depth {
+            let child = Environment::new(chain.last());
+            chain.push(Rc::new(RefCell::new(child)));
+        }
+        chain
+    }
+
+    fn name(lexeme: &str) -> RcToken {
+        Rc::new(Token::new(
+            TokenType::IDENTIFIER,
+            lexeme.as_bytes().to_vec(),
+            Literal::NIL,
+            1,
+            0,
+        ))
+    }
+
+    fn number(value: f64) -> OptionExprValue {
+        Some(Rc::new(ExprValue::Literal(Literal::NUMBER(value))))
+    }
+
+    fn as_number(value: Rc<ExprValue>) -> f64 {
+        match &*value {
+            ExprValue::Literal(Literal::NUMBER(n)) => *n,
+            _ => panic!("not a number"),
+        }
+    }
+
+    #[test]
+    fn ancestor_follows_enclosing_links() {
+        let chain = nested(2);
+        let innermost = chain.last().unwrap();
+        for distance in 0..3 {
+            let ancestor = Environment::ancestor(innermost, distance);
+            assert!(Rc::ptr_eq(&ancestor, &chain[2 - distance]));
+        }
+    }
+
+    #[test]
+    fn get_at_and_assign_at_skip_shadowing() {
+        let chain = nested(2);
+        chain[0].borrow_mut().define(Rc::from("x"), number(1.0));
+        chain[2].borrow_mut().define(Rc::from("x"), number(2.0));
+        let innermost = chain.last().unwrap();
+        let x = name("x");
+
+        assert_eq!(
+            as_number(Environment::get_at(innermost, 2, &x).unwrap()),
+            1.0
+        );
+        Environment::assign_at(innermost, 2, &x, number(3.0)).unwrap();
+        assert_eq!(as_number(chain[0].borrow().get(&x).unwrap()), 3.0);
+        assert_eq!(as_number(innermost.borrow().get(&x).unwrap()), 2.0);
+    }
+
+    #[test]
+    fn get_by_name_and_names() {
+        let chain = nested(1);
+        chain[0].borrow_mut().define(Rc::from("b"), number(1.0));
+        chain[0].borrow_mut().define(Rc::from("a"), None);
+        chain[1].borrow_mut().define(Rc::from("c"), number(2.0));
+        let inner = chain[1].borrow();
+
+        assert_eq!(as_number(inner.get_by_name("b").unwrap()), 1.0);
+        assert_eq!(as_number(inner.get_by_name("c").unwrap()), 2.0);
+        assert!(inner.get_by_name("d").is_none());
+        assert_eq!(inner.names(), vec!["c"]);
+        assert_eq!(chain[0].borrow().names(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn locals_are_addressed_by_slot() {
+        let chain = nested(2);
+        chain[1].borrow_mut().define(Rc::from("a"), number(1.0));
+        chain[1].borrow_mut().define(Rc::from("b"), number(2.0));
+        chain[2].borrow_mut().define(Rc::from("a"), number(3.0));
+        let innermost = chain.last().unwrap();
+
+        assert_eq!(as_number(Environment::get_slot(innermost, 0, 0)), 3.0);
+        assert_eq!(as_number(Environment::get_slot(innermost, 1, 1)), 2.0);
+        Environment::assign_slot(innermost, 1, 0, number(4.0).unwrap());
+        assert_eq!(as_number(chain[1].borrow().get(&name("a")).unwrap()), 4.0);
+        assert_eq!(as_number(innermost.borrow().get(&name("a")).unwrap()), 3.0);
+        assert!(chain[0].borrow().names().is_empty());
+    }
+
+    #[test]
+    fn snapshot_is_sorted_by_name() {
+        let chain = nested(1);
+        chain[0].borrow_mut().define(Rc::from("b"), number(1.5));
+        chain[0].borrow_mut().define(Rc::from("a"), None);
+        chain[1].borrow_mut().define(Rc::from("z"), number(2.0));
+        chain[1].borrow_mut().define(Rc::from("y"), number(3.0));
+
+        let pair = |name: &str, value: &str| (String::from(name), String::from(value));
+        assert_eq!(
+            chain[0].borrow().snapshot(),
+            vec![pair("a", "nil"), pair("b", "1.5")]
+        );
+        assert_eq!(
+            chain[1].borrow().snapshot(),
+            vec![pair("y", "3"), pair("z", "2")]
+        );
+    }
+}
@@ -1,36 +1,143 @@
+use crate::span::Span;
 use crate::token::*;
-use std::rc::Rc;
 
-#[derive(PartialEq, Hash, Clone, Debug, Eq)]
+#[derive(PartialEq, Clone, Debug)]
 pub enum Expr {
     Assign {
         name: RcToken,
-        value: RcExpr,
+        value: ExprId,
+        span: Span,
     },
     Binary {
-        left: RcExpr,
+        left: ExprId,
         operator: RcToken,
-        right: RcExpr,
+        right: ExprId,
+        span: Span,
     },
     Call {
-        callee: RcExpr,
+        callee: ExprId,
         paren: RcToken,
-        arguments: Vec<RcExpr>,
+        arguments: Vec<ExprId>,
+        span: Span,
+    },
+    // `object.name`, a property read.
+    Get {
+        object: ExprId,
+        name: RcToken,
+        span: Span,
+    },
+    Grouping {
+        expression: ExprId,
+        span: Span,
+    },
+    Index {
+        object: ExprId,
+        bracket: RcToken,
+        index: ExprId,
+        span: Span,
+    },
+    IndexAssign {
+        object: ExprId,
+        bracket: RcToken,
+        index: ExprId,
+        value: ExprId,
+        span: Span,
+    },
+    // Alternating string literal and embedded expression parts, always
+    // starting and ending with a literal.
+    Interpolation {
+        parts: Vec<ExprId>,
+        span: Span,
+    },
+    ListLiteral {
+        elements: Vec<ExprId>,
+        span: Span,
+    },
+    Literal {
+        value: Literal,
+        span: Span,
     },
-    Grouping(RcExpr),
-    Literal(Literal),
     Logical {
-        left: RcExpr,
+        left: ExprId,
+        operator: RcToken,
+        right: ExprId,
+        span: Span,
+    },
+    // `a++` / `a--`: `update` is the desugared `a = a + 1`, and the
+    // expression yields `target`'s value from before the update.
+    Postfix {
         operator: RcToken,
-        right: RcExpr,
+        target: ExprId,
+        update: ExprId,
+        span: Span,
     },
     Unary {
         operator: RcToken,
-        right: RcExpr,
+        right: ExprId,
+        span: Span,
     },
     Variable {
         name: RcToken,
+        span: Span,
     },
 }
 
-pub type RcExpr = Rc<Expr>;
+impl Expr {
+    // The expressions directly inside this one, in source order.
+    pub fn children(&self) -> Vec<ExprId> {
+        match self {
+            Expr::Assign { value, .. } => vec![*value],
+            Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+                vec![*left, *right]
+            }
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                let mut children = vec![*callee];
+                children.extend(arguments.iter().copied());
+                children
+            }
+            Expr::Get { object, .. } => vec![*object],
+            Expr::Grouping {
+                expression: expr, ..
+            } => vec![*expr],
+            Expr::Index { object, index, .. } => vec![*object, *index],
+            Expr::IndexAssign {
+                object,
+                index,
+                value,
+                ..
+            } => vec![*object, *index, *value],
+            Expr::Interpolation { parts, .. }
+            | Expr::ListLiteral {
+                elements: parts, ..
+            } => parts.clone(),
+            Expr::Literal { .. } | Expr::Variable { .. } => vec![],
+            Expr::Postfix { target, update, .. } => vec![*target, *update],
+            Expr::Unary { right, .. } => vec![*right],
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Assign { span, .. }
+            | Expr::Binary { span, .. }
+            | Expr::Call { span, .. }
+            | Expr::Get { span, .. }
+            | Expr::Grouping { span, .. }
+            | Expr::Index { span, .. }
+            | Expr::IndexAssign { span, .. }
+            | Expr::Interpolation { span, .. }
+            | Expr::ListLiteral { span, .. }
+            | Expr::Literal { span, .. }
+            | Expr::Logical { span, .. }
+            | Expr::Postfix { span, .. }
+            | Expr::Unary { span, .. }
+            | Expr::Variable { span, .. } => *span,
+        }
+    }
+}
+
+// Where an expression lives in its `AstArena`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ExprId(pub(crate) u32);
@@ -0,0 +1,218 @@
+use crate::interpreter::ExprValue;
+use crate::token::Literal;
+use crate::value;
+use std::rc::Rc;
+
+// The most digits `{n:.D}` will print after the point.
+const MAX_PRECISION: usize = 100;
+
+// Fills in a `format()` template. `{n}` is replaced by argument n as `print`
+// would show it, `{n:.D}` by a number rounded to D decimal places, and `{{`
+// and `}}` stand for literal braces. The error is the message for the call.
+pub fn format(template: &str, arguments: &[Rc<ExprValue>]) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let end = rest
+                    .find('}')
+                    .ok_or_else(|| String::from("format() has an unclosed '{'."))?;
+                out.push_str(&placeholder(&rest[..end], arguments)?);
+                chars = rest[end + 1..].chars();
+            }
+            '}' => return Err(String::from("format() has an unmatched '}'; use '}}'.")),
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+// Renders the placeholder whose text between the braces is `spec`.
+fn placeholder(spec: &str, arguments: &[Rc<ExprValue>]) -> Result<String, String> {
+    let malformed = || format!("format() spec '{{{}}}' is malformed.", spec);
+    let (index, precision) = match spec.split_once(':') {
+        Some((index, precision)) => (index, Some(precision)),
+        None => (spec, None),
+    };
+    let index = digits(index).ok_or_else(malformed)?;
+    let argument = arguments.get(index).ok_or_else(|| {
+        format!(
+            "format() index {} out of range for {} {}.",
+            index,
+            arguments.len(),
+            if arguments.len() == 1 {
+                "argument"
+            } else {
+                "arguments"
+            }
+        )
+    })?;
+    let precision = match precision {
+        None => return Ok(value::display(argument, false)),
+        Some(precision) => precision
+            .strip_prefix('.')
+            .and_then(digits)
+            .ok_or_else(malformed)?,
+    };
+    if precision > MAX_PRECISION {
+        return Err(format!(
+            "format() precision can be at most {}.",
+            MAX_PRECISION
+        ));
+    }
+    match &**argument {
+        ExprValue::Literal(Literal::NUMBER(n)) => Ok(fixed(*n, precision)),
+        _ => Err(format!("format() precision '{{{}}}' needs a number.", spec)),
+    }
+}
+
+// A run of ASCII digits as a number.
+fn digits(text: &str) -> Option<usize> {
+    if text.is_empty() || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+// `n` with exactly `places` decimals. Rounding works on the shortest decimal
+// text for `n`, half away from zero, so 1.005 rounds to 1.01 as written
+// rather than to 1.00 as its binary value would.
+fn fixed(n: f64, places: usize) -> String {
+    if !n.is_finite() {
+        return Literal::NUMBER(n).to_string();
+    }
+    let text = format!("{}", n.abs());
+    let (whole, fraction) = text.split_once('.').unwrap_or((&text, ""));
+    let mut digits: Vec<u8> = whole.bytes().collect();
+    digits.extend(fraction.bytes().chain(std::iter::repeat(b'0')).take(places));
+    if fraction.as_bytes().get(places).is_some_and(|&d| d >= b'5') {
+        // Carry the rounding up through the nines.
+        let mut i = digits.len();
+        loop {
+            if i == 0 {
+                digits.insert(0, b'1');
+                break;
+            }
+            i -= 1;
+            if digits[i] == b'9' {
+                digits[i] = b'0';
+            } else {
+                digits[i] += 1;
+                break;
+            }
+        }
+    }
+    let point = digits.len() - places;
+    let mut out = String::new();
+    if n < 0.0 && digits.iter().any(|&d| d != b'0') {
+        out.push('-');
+    }
+    out.push_str(std::str::from_utf8(&digits[..point]).unwrap());
+    if places > 0 {
+        out.push('.');
+        out.push_str(std::str::from_utf8(&digits[point..]).unwrap());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[Literal]) -> Vec<Rc<ExprValue>> {
+        values
+            .iter()
+            .map(|v| Rc::new(ExprValue::Literal(v.clone())))
+            .collect()
+    }
+
+    #[test]
+    fn placeholders() {
+        let arguments = args(&[
+            Literal::NUMBER(1.005),
+            Literal::STRING(String::from("x")),
+            Literal::NIL,
+        ]);
+        let cases = [
+            ("{0:.2} + {1}", "1.01 + x"),
+            ("{1}{1}{0}", "xx1.005"),
+            ("{2} {{0}} }}{{", "nil {0} }{"),
+            ("no placeholders", "no placeholders"),
+            ("{00}", "1.005"),
+            ("é{1}é", "éxé"),
+        ];
+        for (template, expected) in cases {
+            assert_eq!(
+                format(template, &arguments).unwrap(),
+                expected,
+                "{}",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn precision() {
+        let cases = [
+            (1.005, 2, "1.01"),
+            (2.5, 0, "3"),
+            (-2.5, 0, "-3"),
+            (0.125, 2, "0.13"),
+            (9.995, 2, "10.00"),
+            (99.5, 0, "100"),
+            (3.0, 3, "3.000"),
+            (-0.001, 2, "0.00"),
+            (-1.25, 1, "-1.3"),
+            (1e21, 1, "1000000000000000000000.0"),
+            (1e-7, 8, "0.00000010"),
+            (f64::INFINITY, 2, "inf"),
+        ];
+        for (n, places, expected) in cases {
+            assert_eq!(fixed(n, places), expected, "{} to {}", n, places);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let arguments = args(&[Literal::NUMBER(1.0), Literal::STRING(String::from("s"))]);
+        let cases = [
+            ("{2}", "format() index 2 out of range for 2 arguments."),
+            ("{}", "format() spec '{}' is malformed."),
+            ("{a}", "format() spec '{a}' is malformed."),
+            ("{0:2}", "format() spec '{0:2}' is malformed."),
+            ("{0:.}", "format() spec '{0:.}' is malformed."),
+            ("{0:.-1}", "format() spec '{0:.-1}' is malformed."),
+            ("{ 0}", "format() spec '{ 0}' is malformed."),
+            ("{0", "format() has an unclosed '{'."),
+            ("0}", "format() has an unmatched '}'; use '}}'."),
+            ("{1:.2}", "format() precision '{1:.2}' needs a number."),
+            ("{0:.101}", "format() precision can be at most 100."),
+        ];
+        for (template, expected) in cases {
+            assert_eq!(
+                format(template, &arguments).unwrap_err(),
+                expected,
+                "{}",
+                template
+            );
+        }
+        assert_eq!(
+            format("{0}", &[]).unwrap_err(),
+            "format() index 0 out of range for 0 arguments."
+        );
+        assert_eq!(
+            format("{1}", &arguments[..1]).unwrap_err(),
+            "format() index 1 out of range for 1 argument."
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use crate::arena::AstArena;
+use crate::expr::{Expr, ExprId};
+use crate::stmt::{Stmt, StmtId};
+use crate::token::Literal;
+
+// Renders the AST back into Lox source. Everything is kept on one line so
+// that scanning the output again reproduces the original tokens exactly.
+
+pub fn expr_to_source(ast: &AstArena, expr: ExprId) -> String {
+    let source = |expr: ExprId| expr_to_source(ast, expr);
+    match &ast[expr] {
+        Expr::Assign { name, value, .. } => format!("{} = {}", name.lexeme, source(*value)),
+        Expr::Binary {
+            left,
+            operator,
+            right,
+            ..
+        }
+        | Expr::Logical {
+            left,
+            operator,
+            right,
+            ..
+        } => format!("{} {} {}", source(*left), operator.lexeme, source(*right)),
+        Expr::Call {
+            callee,
+            paren: _,
+            arguments,
+            ..
+        } => {
+            let arguments: Vec<String> = arguments.iter().map(|&a| source(a)).collect();
+            format!("{}({})", source(*callee), arguments.join(", "))
+        }
+        Expr::Get { object, name, .. } => format!("{}.{}", source(*object), name.lexeme),
+        Expr::Grouping {
+            expression: expr, ..
+        } => format!("({})", source(*expr)),
+        Expr::Index {
+            object,
+            bracket: _,
+            index,
+            ..
+        } => format!("{}[{}]", source(*object), source(*index)),
+        Expr::IndexAssign {
+            object,
+            bracket: _,
+            index,
+            value,
+            ..
+        } => format!(
+            "{}[{}] = {}",
+            source(*object),
+            source(*index),
+            source(*value)
+        ),
+        Expr::ListLiteral { elements, .. } => {
+            let elements: Vec<String> = elements.iter().map(|&e| source(e)).collect();
+            format!("[{}]", elements.join(", "))
+        }
+        Expr::Interpolation { parts, .. } => {
+            let mut builder = String::from("\"");
+            for (i, part) in parts.iter().enumerate() {
+                match &ast[*part] {
+                    Expr::Literal {
+                        value: Literal::STRING(s),
+                        ..
+                    } if i % 2 == 0 => builder.push_str(s),
+                    _ => {
+                        builder.push_str("${");
+                        builder.push_str(&source(*part));
+                        builder.push('}');
+                    }
+                }
+            }
+            builder.push('"');
+            builder
+        }
+        Expr::Literal {
+            value: Literal::STRING(s),
+            ..
+        } => format!("\"{}\"", s),
+        Expr::Literal { value: literal, .. } => literal.to_string(),
+        Expr::Postfix {
+            operator, target, ..
+        } => format!("{}{}", source(*target), operator.lexeme),
+        Expr::Unary {
+            operator, right, ..
+        } => {
+            // A desugared `++a` under a unary operator needs its own parens.
+            let right = match &ast[*right] {
+                Expr::Assign { .. } => format!("({})", source(*right)),
+                _ => source(*right),
+            };
+            // Keep `- -x` from running together into a different token.
+            if right.starts_with(&*operator.lexeme) {
+                format!("{} {}", operator.lexeme, right)
+            } else {
+                format!("{}{}", operator.lexeme, right)
+            }
+        }
+        Expr::Variable { name, .. } => name.lexeme.to_string(),
+    }
+}
+
+fn block_to_source(ast: &AstArena, statements: &[StmtId]) -> String {
+    let mut builder = String::from("{");
+    for &statement in statements {
+        builder.push(' ');
+        builder.push_str(&stmt_to_source(ast, statement));
+    }
+    builder.push_str(" }");
+    builder
+}
+
+pub fn stmt_to_source(ast: &AstArena, stmt: StmtId) -> String {
+    let source = |expr: ExprId| expr_to_source(ast, expr);
+    let stmt_source = |stmt: StmtId| stmt_to_source(ast, stmt);
+    match &ast[stmt] {
+        Stmt::Block { statements, .. } => block_to_source(ast, statements),
+        Stmt::Break { .. } => String::from("break;"),
+        Stmt::Continue { .. } => String::from("continue;"),
+        Stmt::Expression { expr, .. } => format!("{};", source(*expr)),
+        Stmt::Function {
+            name, params, body, ..
+        } => {
+            let params: Vec<String> = params
+                .iter()
+                .map(|(name, default)| match default {
+                    Some(default) => format!("{} = {}", name.lexeme, source(*default)),
+                    None => name.lexeme.to_string(),
+                })
+                .collect();
+            format!(
+                "fun {}({}) {}",
+                name.lexeme,
+                params.join(", "),
+                block_to_source(ast, body)
+            )
+        }
+        Stmt::For {
+            initializer,
+            condition,
+            increment,
+            body,
+            ..
+        } => {
+            // The initializer brings its own semicolon.
+            let initializer = initializer.map_or(String::from(";"), stmt_source);
+            format!(
+                "for ({} {}; {}) {}",
+                initializer,
+                condition.map_or(String::new(), source),
+                increment.map_or(String::new(), source),
+                stmt_source(*body)
+            )
+        }
+        Stmt::ForEach {
+            variable,
+            iterable,
+            body,
+            ..
+        } => format!(
+            "for (var {} in {}) {}",
+            variable.lexeme,
+            source(*iterable),
+            stmt_source(*body)
+        ),
+        Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            let mut builder = format!("if ({}) {}", source(*condition), stmt_source(*then_branch));
+            if let Some(else_branch) = else_branch {
+                builder.push_str(" else ");
+                builder.push_str(&stmt_source(*else_branch));
+            }
+            builder
+        }
+        Stmt::Import { path, .. } => format!("import {};", path.lexeme),
+        Stmt::Print { expr, .. } => format!("print {};", source(*expr)),
+        Stmt::Return { value, .. } => match &ast[*value] {
+            Expr::Literal {
+                value: Literal::NIL,
+                ..
+            } => String::from("return;"),
+            _ => format!("return {};", source(*value)),
+        },
+        Stmt::Throw { value, .. } => format!("throw {};", source(*value)),
+        Stmt::Try {
+            body,
+            binding,
+            handler,
+            ..
+        } => {
+            format!(
+                "try {} catch ({}) {}",
+                block_to_source(ast, body),
+                binding.lexeme,
+                block_to_source(ast, handler)
+            )
+        }
+        Stmt::Var {
+            name,
+            initializer,
+            constant,
+            ..
+        } => {
+            let keyword = if *constant { "const" } else { "var" };
+            match initializer {
+                Some(initializer) => {
+                    format!("{} {} = {};", keyword, name.lexeme, source(*initializer))
+                }
+                None => format!("{} {};", keyword, name.lexeme),
+            }
+        }
+        Stmt::While {
+            condition, body, ..
+        } => format!("while ({}) {}", source(*condition), stmt_source(*body)),
+    }
+}
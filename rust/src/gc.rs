@@ -0,0 +1,211 @@
+use crate::environment::Environment;
+use crate::interpreter::{ExprValue, LoxCallable};
+use crate::token::Literal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+// Closures make reference cycles: a function is stored in the environment it
+// closes over, so neither count ever reaches zero. Every environment the
+// interpreter creates is registered here, and `collect` empties the ones only
+// such cycles keep alive, after which `Rc` frees them.
+//
+// It works by trial deletion. The references an object gets from other
+// objects in the graph are subtracted from its strong count; anything with
+// references left over is held from outside, by the interpreter or by a value
+// on the Rust stack, and it survives along with everything it reaches.
+pub struct Environments {
+    tracked: Vec<Weak<RefCell<Environment>>>,
+    // Registering this many environments prompts a collection.
+    threshold: usize,
+}
+
+const MIN_THRESHOLD: usize = 1024;
+
+// Something that can hold environments alive. Plain literals can't, so they
+// never become nodes.
+enum Node {
+    Environment(Rc<RefCell<Environment>>),
+    Value(Rc<ExprValue>),
+    Callable(Rc<dyn LoxCallable>),
+    List(Rc<RefCell<Vec<Rc<ExprValue>>>>),
+    Map(Rc<RefCell<HashMap<Literal, Rc<ExprValue>>>>),
+}
+
+impl Node {
+    fn value(value: &Rc<ExprValue>) -> Option<Node> {
+        match &**value {
+            ExprValue::Literal(_) => None,
+            _ => Some(Node::Value(Rc::clone(value))),
+        }
+    }
+    fn address(&self) -> *const () {
+        match self {
+            Node::Environment(e) => Rc::as_ptr(e) as *const (),
+            Node::Value(v) => Rc::as_ptr(v) as *const (),
+            Node::Callable(c) => Rc::as_ptr(c) as *const (),
+            Node::List(l) => Rc::as_ptr(l) as *const (),
+            Node::Map(m) => Rc::as_ptr(m) as *const (),
+        }
+    }
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Environment(e) => Rc::strong_count(e),
+            Node::Value(v) => Rc::strong_count(v),
+            Node::Callable(c) => Rc::strong_count(c),
+            Node::List(l) => Rc::strong_count(l),
+            Node::Map(m) => Rc::strong_count(m),
+        }
+    }
+    // The nodes this one references, or None if one of them is borrowed
+    // right now and the graph can't be read safely.
+    fn children(&self) -> Option<Vec<Node>> {
+        let mut children = Vec::new();
+        match self {
+            Node::Environment(e) => {
+                let environment = e.try_borrow().ok()?;
+                if let Some(enclosing) = environment.enclosing() {
+                    children.push(Node::Environment(Rc::clone(enclosing)));
+                }
+                children.extend(environment.values().filter_map(Node::value));
+            }
+            Node::Value(v) => match &**v {
+                ExprValue::Literal(_) => {}
+                ExprValue::LoxCallable(c) => children.push(Node::Callable(Rc::clone(c))),
+                ExprValue::List(l) => children.push(Node::List(Rc::clone(l))),
+                ExprValue::Map(m) => children.push(Node::Map(Rc::clone(m))),
+            },
+            Node::Callable(c) => {
+                if let Some(closure) = c.closure() {
+                    children.push(Node::Environment(Rc::clone(closure)));
+                }
+            }
+            Node::List(l) => children.extend(l.try_borrow().ok()?.iter().filter_map(Node::value)),
+            Node::Map(m) => children.extend(m.try_borrow().ok()?.values().filter_map(Node::value)),
+        }
+        Some(children)
+    }
+}
+
+impl Default for Environments {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Environments {
+    pub fn new() -> Self {
+        Environments {
+            tracked: Vec::new(),
+            threshold: MIN_THRESHOLD,
+        }
+    }
+    pub fn track(&mut self, environment: Environment) -> Rc<RefCell<Environment>> {
+        let environment = Rc::new(RefCell::new(environment));
+        self.tracked.push(Rc::downgrade(&environment));
+        environment
+    }
+    // Whether enough environments have piled up to be worth a collection.
+    // Most of them die young without help, so those are dropped first.
+    pub fn due(&mut self) -> bool {
+        if self.tracked.len() < self.threshold {
+            return false;
+        }
+        self.prune();
+        self.tracked.len() >= self.threshold / 2
+    }
+    pub fn live(&self) -> usize {
+        self.tracked.iter().filter(|e| e.strong_count() > 0).count()
+    }
+    fn prune(&mut self) {
+        self.tracked.retain(|e| e.strong_count() > 0);
+    }
+    // Frees the environments only reachable through cycles and returns how
+    // many there were.
+    pub fn collect(&mut self) -> usize {
+        self.prune();
+        let garbage = self.garbage().unwrap_or_default();
+        let count = garbage.len();
+        // Emptied first and dropped once no environment is borrowed, since
+        // dropping the contents can free further environments.
+        let contents: Vec<Environment> = garbage
+            .iter()
+            .filter_map(|e| e.try_borrow_mut().ok().map(|mut e| e.take()))
+            .collect();
+        drop(garbage);
+        drop(contents);
+        self.prune();
+        self.threshold = MIN_THRESHOLD.max(self.tracked.len() * 2);
+        count
+    }
+    // Empties every environment, for when the interpreter goes away.
+    pub fn clear(&mut self) {
+        let contents: Vec<Environment> = self
+            .tracked
+            .iter()
+            .filter_map(|e| e.upgrade())
+            .filter_map(|e| e.try_borrow_mut().ok().map(|mut e| e.take()))
+            .collect();
+        drop(contents);
+        self.tracked.clear();
+    }
+    fn garbage(&self) -> Option<Vec<Rc<RefCell<Environment>>>> {
+        // Every node reachable from a tracked environment, each held once.
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut index: HashMap<*const (), usize> = HashMap::new();
+        let mut edges: Vec<Vec<usize>> = Vec::new();
+        for environment in self.tracked.iter().filter_map(|e| e.upgrade()) {
+            let node = Node::Environment(environment);
+            index.insert(node.address(), nodes.len());
+            nodes.push(node);
+            edges.push(Vec::new());
+        }
+        let mut next = 0;
+        while next < nodes.len() {
+            for child in nodes[next].children()? {
+                let id = match index.get(&child.address()) {
+                    Some(&id) => id,
+                    None => {
+                        index.insert(child.address(), nodes.len());
+                        nodes.push(child);
+                        edges.push(Vec::new());
+                        nodes.len() - 1
+                    }
+                };
+                edges[next].push(id);
+            }
+            next += 1;
+        }
+
+        let mut internal = vec![0; nodes.len()];
+        for &to in edges.iter().flatten() {
+            internal[to] += 1;
+        }
+        // Our own handle on each node accounts for one strong reference.
+        let mut alive: Vec<bool> = nodes
+            .iter()
+            .zip(&internal)
+            .map(|(node, &internal)| node.strong_count() - 1 > internal)
+            .collect();
+        let mut pending: Vec<usize> = (0..nodes.len()).filter(|&id| alive[id]).collect();
+        while let Some(id) = pending.pop() {
+            for &to in &edges[id] {
+                if !alive[to] {
+                    alive[to] = true;
+                    pending.push(to);
+                }
+            }
+        }
+
+        Some(
+            nodes
+                .into_iter()
+                .zip(alive)
+                .filter_map(|(node, alive)| match node {
+                    Node::Environment(e) if !alive => Some(e),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+}
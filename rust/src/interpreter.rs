@@ -1,22 +1,45 @@
+use crate::arena::AstArena;
+use crate::ast_printer::{ast_to_string, stmt_summary};
+use crate::compiler::VmFunction;
+use crate::debugger::{self, DebugHook};
 use crate::environment::Environment;
-use crate::expr::{Expr, RcExpr};
-use crate::lox::LoxError;
-use crate::lox_function::LoxFunction;
-use crate::stmt::{RcStmt, Stmt};
+use crate::expr::{Expr, ExprId};
+use crate::format_string;
+use crate::formatter::expr_to_source;
+use crate::gc::Environments;
+use crate::lox::{Lox, LoxError};
+use crate::lox_function::{parameter_arity, LoxFunction};
+use crate::parser::Parser;
+use crate::profiler::{ProfileKey, Profiler};
+use crate::random::Random;
+use crate::scanner::Scanner;
+use crate::span::Span;
+use crate::stmt::{Stmt, StmtId};
 use crate::token::*;
 use crate::token_type::TokenType;
+use crate::value;
 use std::borrow::Borrow;
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::fmt::Debug;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt::{self, Debug, Display};
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[allow(non_camel_case_types)]
 #[derive(Clone, Debug)]
 pub enum ExprValue {
     Literal(Literal),
     LoxCallable(Rc<dyn LoxCallable>),
+    // Shared so that mutation through one alias is seen by all of them.
+    List(Rc<RefCell<Vec<Rc<ExprValue>>>>),
+    Map(Rc<RefCell<HashMap<Literal, Rc<ExprValue>>>>),
 }
 
 impl ExprValue {
@@ -36,29 +59,87 @@ impl ExprValue {
 
 impl PartialEq for ExprValue {
     fn eq(&self, other: &Self) -> bool {
+        value::equal(self, other)
+    }
+}
+
+// How many arguments a callable takes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+    // Inclusive on both ends.
+    Range(usize, usize),
+    // At least this many.
+    Variadic(usize),
+}
+
+// `2`, `1..2` or `1+`.
+impl Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ExprValue::Literal(l1) => match other {
-                ExprValue::Literal(l2) => l1 == l2,
-                _ => false,
-            },
-            ExprValue::LoxCallable(c1) => match other {
-                ExprValue::LoxCallable(c2) => std::ptr::eq(c1, c2),
-                _ => false,
-            },
+            Arity::Exact(n) => write!(f, "{}", n),
+            Arity::Range(min, max) => write!(f, "{}..{}", min, max),
+            Arity::Variadic(min) => write!(f, "{}+", min),
         }
     }
 }
 
+impl Arity {
+    // The runtime error message for a call with `got` arguments, if any.
+    pub fn check(self, got: usize) -> Result<(), String> {
+        let (min, max) = match self {
+            Arity::Exact(n) => (n, Some(n)),
+            Arity::Range(min, max) => (min, Some(max)),
+            Arity::Variadic(min) => (min, None),
+        };
+        let (expected, bound) = match (self, max) {
+            (Arity::Exact(n), _) if got != n => (String::new(), n),
+            _ if got < min => (String::from("at least "), min),
+            (_, Some(max)) if got > max => (String::from("at most "), max),
+            _ => return Ok(()),
+        };
+        let noun = if bound == 1 { "argument" } else { "arguments" };
+        Err(format!(
+            "Expected {}{} {} but got {}.",
+            expected, bound, noun, got
+        ))
+    }
+}
+
 pub trait LoxCallable: Debug {
-    fn arity(&self) -> usize;
-    fn call(&self, interpreter: &mut Interpreter, arguments: Vec<Rc<ExprValue>>)
-        -> ExprValueResult;
-    fn to_string(&self) -> String;
+    fn arity(&self) -> Arity;
+    // `paren` is the call's closing parenthesis, for runtime errors.
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult;
+    fn name(&self) -> &str;
+    // How the function prints, e.g. `<native fn clock(0)>`.
+    fn to_string(&self) -> String {
+        format!("<native fn {}({})>", self.name(), self.arity())
+    }
+    // The environment a user-defined function closes over, for the
+    // collector. Natives don't have one.
+    fn closure(&self) -> Option<&Rc<RefCell<Environment>>> {
+        None
+    }
+    // The function itself when it's written in Lox, so a tail call to it can
+    // run in the caller's place.
+    fn as_function(&self) -> Option<&LoxFunction> {
+        None
+    }
+    // The function itself when it was compiled for the VM, which calls it
+    // with a frame of its own.
+    fn as_compiled(&self) -> Option<&VmFunction> {
+        None
+    }
 }
 
 impl PartialEq for dyn LoxCallable {
     fn eq(&self, other: &Self) -> bool {
-        std::ptr::eq(&self, &other)
+        std::ptr::addr_eq(self, other)
     }
 }
 
@@ -72,90 +153,2387 @@ impl PartialEq for dyn LoxCallable {
 //     }
 // }
 
-pub type ExprValueResult = Result<Rc<ExprValue>, LoxError<String>>;
-pub type VoidResult = Result<(), LoxError<String>>;
+pub type ExprValueResult = Result<Rc<ExprValue>, LoxError>;
+pub type VoidResult = Result<(), LoxError>;
+pub type InterpretResult = Result<Option<Rc<ExprValue>>, LoxError>;
+// Executing a statement can also jump out of it.
+type ExecResult = Result<ControlFlow, LoxError>;
+// A function and the arguments to call it with.
+type CallResult = Result<(Rc<dyn LoxCallable>, Vec<Rc<ExprValue>>), LoxError>;
+
+// How a statement finished: by running to completion, or with a jump that
+// the enclosing function or loop catches.
+pub(crate) enum ControlFlow {
+    Normal,
+    Return {
+        keyword: RcToken,
+        value: Rc<ExprValue>,
+    },
+    // `return f(...)`: the caller makes the call once this function's frame
+    // is gone, so tail recursion doesn't grow the host stack.
+    TailCall {
+        keyword: RcToken,
+        function: Rc<dyn LoxCallable>,
+        paren: RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+        span: Span,
+    },
+    Break {
+        keyword: RcToken,
+    },
+    Continue {
+        keyword: RcToken,
+    },
+}
+
+impl ControlFlow {
+    // The error for a jump with nothing to catch it. The resolver rejects
+    // these, but `interpret` can be handed unresolved statements.
+    pub(crate) fn stray(self) -> LoxError {
+        let (keyword, message) = match self {
+            ControlFlow::Normal => unreachable!("Only jumps can stray"),
+            ControlFlow::Return { keyword, .. } | ControlFlow::TailCall { keyword, .. } => {
+                (keyword, "Can't return from top-level code.")
+            }
+            ControlFlow::Break { keyword } => (keyword, "Can't use 'break' outside of a loop."),
+            ControlFlow::Continue { keyword } => {
+                (keyword, "Can't use 'continue' outside of a loop.")
+            }
+        };
+        LoxError::RuntimeError {
+            token: keyword,
+            message: String::from(message),
+            span: None,
+        }
+    }
+}
 
 macro_rules! operand_err {
     ($operator:tt) => {
         Err(LoxError::RuntimeError {
             token: Rc::clone(&$operator),
             message: format!("{:?} operands must be a number(s)", $operator.type_),
+            span: None,
         })
     };
 }
 
 // BUILTINS
 
+// Where the time natives get the time and do their waiting, so that an
+// embedder or a test can substitute its own clock.
+pub trait TimeSource {
+    // The time since the Unix epoch.
+    fn now(&self) -> Duration;
+    fn sleep(&mut self, duration: Duration);
+}
+
+// The real clock, which every interpreter starts with.
+struct SystemClock;
+impl TimeSource for SystemClock {
+    fn now(&self) -> Duration {
+        // A clock set before 1970 reads as the epoch.
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
 #[derive(Clone, Debug)]
 struct Clock();
 impl LoxCallable for Clock {
-    fn arity(&self) -> usize {
-        0
+    fn arity(&self) -> Arity {
+        Arity::Exact(0)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        _paren: &RcToken,
+        _arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let now = interpreter.time.now();
+        Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(
+            now.as_secs_f64(),
+        ))))
+    }
+    fn name(&self) -> &str {
+        "clock"
+    }
+}
+
+// `args()`: the command-line arguments after the script's path.
+#[derive(Clone, Debug)]
+struct Args();
+impl LoxCallable for Args {
+    fn arity(&self) -> Arity {
+        Arity::Exact(0)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        _paren: &RcToken,
+        _arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let args = interpreter
+            .args
+            .iter()
+            .map(|arg| Rc::from(ExprValue::Literal(Literal::STRING(arg.clone()))))
+            .collect();
+        Ok(Rc::from(ExprValue::List(Rc::new(RefCell::new(args)))))
+    }
+    fn name(&self) -> &str {
+        "args"
+    }
+}
+
+// `getenv(name)`: an environment variable's value, or nil if it isn't set
+// or isn't valid UTF-8.
+#[derive(Clone, Debug)]
+struct Getenv();
+impl LoxCallable for Getenv {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let name = string_arg(paren, "getenv", &arguments[0])?;
+        // `env::var` panics on these rather than saying it's unset.
+        if name.is_empty() || name.contains(['=', '\0']) {
+            return Ok(interpreter.nil());
+        }
+        Ok(match env::var(name) {
+            Ok(value) => Rc::from(ExprValue::Literal(Literal::STRING(value))),
+            Err(_) => interpreter.nil(),
+        })
+    }
+    fn name(&self) -> &str {
+        "getenv"
+    }
+}
+
+// `exit(code)` ends the program with that status, by unwinding it rather
+// than the process, so an embedder gets control back.
+#[derive(Clone, Debug)]
+struct Exit();
+impl LoxCallable for Exit {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
     }
     fn call(
         &self,
         _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        match arguments[0].get_number() {
+            Some(code) if code.fract() == 0.0 && (0.0..=255.0).contains(&code) => {
+                Err(LoxError::Exit { code: code as u8 })
+            }
+            _ => Err(native_error(
+                paren,
+                String::from("exit() code must be an integer from 0 to 255."),
+            )),
+        }
+    }
+    fn name(&self) -> &str {
+        "exit"
+    }
+}
+
+// Whole milliseconds since the epoch, for timing without float noise.
+#[derive(Clone, Debug)]
+struct ClockMillis();
+impl LoxCallable for ClockMillis {
+    fn arity(&self) -> Arity {
+        Arity::Exact(0)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        _paren: &RcToken,
         _arguments: Vec<Rc<ExprValue>>,
     ) -> ExprValueResult {
-        let duration = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time is broken");
+        let millis = interpreter.time.now().as_millis();
+        Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(millis as f64))))
+    }
+    fn name(&self) -> &str {
+        "clockMillis"
+    }
+}
+
+// The current time in UTC, e.g. "2024-02-29T13:05:09.250Z".
+#[derive(Clone, Debug)]
+struct Date();
+impl LoxCallable for Date {
+    fn arity(&self) -> Arity {
+        Arity::Exact(0)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        _paren: &RcToken,
+        _arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let date = iso_8601(interpreter.time.now());
+        Ok(Rc::from(ExprValue::Literal(Literal::STRING(date))))
+    }
+    fn name(&self) -> &str {
+        "date"
+    }
+}
+
+// Formats a time since the epoch as ISO 8601 to the millisecond. The date
+// is worked out with Howard Hinnant's days-to-civil algorithm.
+fn iso_8601(since_epoch: Duration) -> String {
+    let seconds = since_epoch.as_secs();
+    let days = seconds / 86_400;
+    let time = seconds % 86_400;
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// `sleep(seconds)` blocks for that long; fractions of a second are fine.
+#[derive(Clone, Debug)]
+struct Sleep();
+impl LoxCallable for Sleep {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let seconds = arguments[0]
+            .get_number()
+            .ok_or_else(|| native_error(paren, String::from("sleep() expects a number.")))?;
+        if seconds < 0.0 {
+            return Err(native_error(
+                paren,
+                String::from("sleep() can't wait a negative time."),
+            ));
+        }
+        let duration = Duration::try_from_secs_f64(seconds)
+            .map_err(|_| native_error(paren, format!("sleep() can't wait {} seconds.", seconds)))?;
+        interpreter.time.sleep(duration);
+        Ok(interpreter.nil())
+    }
+    fn name(&self) -> &str {
+        "sleep"
+    }
+}
+
+fn native_error(paren: &RcToken, message: String) -> LoxError {
+    LoxError::RuntimeError {
+        token: Rc::clone(paren),
+        message,
+        span: None,
+    }
+}
+
+fn string_arg<'a>(
+    paren: &RcToken,
+    function: &str,
+    value: &'a ExprValue,
+) -> Result<&'a String, LoxError> {
+    value
+        .get_string()
+        .ok_or_else(|| native_error(paren, format!("{}() expects a string.", function)))
+}
+
+// The message for calling `callee`, which `expression` evaluated to, when it
+// isn't a function. The expression is as it would be written.
+pub(crate) fn not_callable(callee: &ExprValue, expression: &str) -> String {
+    format!(
+        "Can only call functions and classes, got {} from expression '{}'.",
+        value::type_name(callee),
+        expression
+    )
+}
+
+// Converts a Lox number into a character index, which must be integral and
+// lie within 0..=len.
+fn index_arg(
+    paren: &RcToken,
+    function: &str,
+    value: &ExprValue,
+    len: usize,
+) -> Result<usize, LoxError> {
+    let index = value
+        .get_number()
+        .ok_or_else(|| native_error(paren, format!("{}() index must be a number.", function)))?;
+    if index.fract() != 0.0 {
+        return Err(native_error(
+            paren,
+            format!("{}() index must be an integer.", function),
+        ));
+    }
+    if index < 0.0 || index > len as f64 {
+        return Err(native_error(
+            paren,
+            format!("{}() index {} out of range.", function, index),
+        ));
+    }
+    Ok(index as usize)
+}
+
+#[derive(Clone, Debug)]
+struct Len();
+impl LoxCallable for Len {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        if let ExprValue::List(list) = &*arguments[0] {
+            return Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(
+                (**list).borrow().len() as f64,
+            ))));
+        }
+        if let ExprValue::Map(map) = &*arguments[0] {
+            return Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(
+                (**map).borrow().len() as f64,
+            ))));
+        }
+        let s = string_arg(paren, "len", &arguments[0])?;
         Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(
-            (duration.as_secs() as f64) + (duration.subsec_nanos() as f64) * 1e-9,
+            s.chars().count() as f64,
         ))))
     }
-    fn to_string(&self) -> String {
-        String::from("<native fn>")
+    fn name(&self) -> &str {
+        "len"
     }
 }
 
-pub type SharedInterpreter = Rc<RefCell<Interpreter>>;
+#[derive(Clone, Debug)]
+struct Substr();
+impl LoxCallable for Substr {
+    fn arity(&self) -> Arity {
+        Arity::Exact(3)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let s = string_arg(paren, "substr", &arguments[0])?;
+        let len = s.chars().count();
+        let start = index_arg(paren, "substr", &arguments[1], len)?;
+        let end = index_arg(paren, "substr", &arguments[2], len)?;
+        if start > end {
+            return Err(native_error(
+                paren,
+                String::from("substr() start must not be after end."),
+            ));
+        }
+        Ok(Rc::from(ExprValue::Literal(Literal::STRING(
+            s.chars().skip(start).take(end - start).collect(),
+        ))))
+    }
+    fn name(&self) -> &str {
+        "substr"
+    }
+}
 
-pub struct Interpreter {
-    environment: Rc<RefCell<Environment>>,
-    pub globals: Rc<RefCell<Environment>>,
-    locals: HashMap<RcExpr, usize>,
+#[derive(Clone, Debug)]
+struct CharAt();
+impl LoxCallable for CharAt {
+    fn arity(&self) -> Arity {
+        Arity::Exact(2)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let s = string_arg(paren, "char_at", &arguments[0])?;
+        let len = s.chars().count();
+        let index = index_arg(paren, "char_at", &arguments[1], len)?;
+        match s.chars().nth(index) {
+            Some(c) => Ok(Rc::from(ExprValue::Literal(Literal::STRING(c.to_string())))),
+            None => Err(native_error(
+                paren,
+                format!("char_at() index {} out of range.", index),
+            )),
+        }
+    }
+    fn name(&self) -> &str {
+        "char_at"
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Str();
+impl LoxCallable for Str {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        _paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        Ok(Rc::from(ExprValue::Literal(Literal::STRING(
+            Interpreter::stringify(Rc::clone(&arguments[0])),
+        ))))
+    }
+    fn name(&self) -> &str {
+        "str"
+    }
+}
+
+// `format(template, values...)`: see `format_string::format`.
+#[derive(Clone, Debug)]
+struct Format();
+impl LoxCallable for Format {
+    fn arity(&self) -> Arity {
+        Arity::Variadic(1)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let template = string_arg(paren, "format", &arguments[0])?;
+        let text = format_string::format(template, &arguments[1..])
+            .map_err(|message| native_error(paren, message))?;
+        Ok(Rc::from(ExprValue::Literal(Literal::STRING(text))))
+    }
+    fn name(&self) -> &str {
+        "format"
+    }
+}
+
+// `repr(value)`: the value as it prints inside a list, with strings quoted.
+#[derive(Clone, Debug)]
+struct Repr();
+impl LoxCallable for Repr {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        _paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        Ok(Rc::from(ExprValue::Literal(Literal::STRING(
+            value::display(&arguments[0], true),
+        ))))
+    }
+    fn name(&self) -> &str {
+        "repr"
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Num();
+impl LoxCallable for Num {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let s = string_arg(paren, "num", &arguments[0])?;
+        Ok(Rc::from(ExprValue::Literal(match s.trim().parse() {
+            Ok(n) => Literal::NUMBER(n),
+            Err(_) => Literal::NIL,
+        })))
+    }
+    fn name(&self) -> &str {
+        "num"
+    }
+}
+
+// Whether a value counts as true in a condition.
+#[derive(Clone, Debug)]
+struct Bool();
+impl LoxCallable for Bool {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        _paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        Ok(interpreter.boolean(Interpreter::is_truthy(&arguments[0])))
+    }
+    fn name(&self) -> &str {
+        "bool"
+    }
+}
+
+fn callable_arg<'a>(
+    paren: &RcToken,
+    native: &str,
+    value: &'a ExprValue,
+) -> Result<&'a Rc<dyn LoxCallable>, LoxError> {
+    match value {
+        ExprValue::LoxCallable(function) => Ok(function),
+        _ => Err(native_error(
+            paren,
+            format!("{}() expects a function.", native),
+        )),
+    }
+}
+
+// The number of arguments a function requires.
+#[derive(Clone, Debug)]
+struct ArityFn();
+impl LoxCallable for ArityFn {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let required = match callable_arg(paren, "arity", &arguments[0])?.arity() {
+            Arity::Exact(n) | Arity::Range(n, _) | Arity::Variadic(n) => n,
+        };
+        Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(
+            required as f64,
+        ))))
+    }
+    fn name(&self) -> &str {
+        "arity"
+    }
+}
+
+#[derive(Clone, Debug)]
+struct NameFn();
+impl LoxCallable for NameFn {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let function = callable_arg(paren, "name", &arguments[0])?;
+        Ok(Rc::from(ExprValue::Literal(Literal::STRING(
+            function.name().to_string(),
+        ))))
+    }
+    fn name(&self) -> &str {
+        "name"
+    }
+}
+
+// floor, ceil, abs and sqrt only differ in the f64 method they apply.
+#[derive(Clone, Debug)]
+struct NumberFn(&'static str, fn(f64) -> f64);
+impl LoxCallable for NumberFn {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let n = arguments[0]
+            .get_number()
+            .ok_or_else(|| native_error(paren, format!("{}() expects a number.", self.0)))?;
+        Ok(Rc::from(ExprValue::Literal(Literal::NUMBER((self.1)(n)))))
+    }
+    fn name(&self) -> &str {
+        self.0
+    }
+}
+
+// `random()`: a number in [0, 1) from the interpreter's generator.
+#[derive(Clone, Debug)]
+struct RandomFn();
+impl LoxCallable for RandomFn {
+    fn arity(&self) -> Arity {
+        Arity::Exact(0)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        _paren: &RcToken,
+        _arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let x = interpreter.random.float();
+        Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(x))))
+    }
+    fn name(&self) -> &str {
+        "random"
+    }
+}
+
+// Integers past this aren't all representable, so they can't be drawn
+// evenly.
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_991.0;
+
+// The longest string, in bytes, that `*` will build by repetition.
+const MAX_STRING_LENGTH: usize = 1 << 28;
+
+// `randomInt(min, max)`: an integer from `min` to `max` inclusive.
+#[derive(Clone, Debug)]
+struct RandomInt();
+impl LoxCallable for RandomInt {
+    fn arity(&self) -> Arity {
+        Arity::Exact(2)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let mut bounds = [0.0; 2];
+        for (bound, argument) in bounds.iter_mut().zip(&arguments) {
+            *bound = argument
+                .get_number()
+                .filter(|n| n.fract() == 0.0 && n.abs() <= MAX_SAFE_INTEGER)
+                .ok_or_else(|| {
+                    native_error(paren, String::from("randomInt() expects integers."))
+                })?;
+        }
+        let [min, max] = bounds;
+        if min > max {
+            return Err(native_error(
+                paren,
+                format!("randomInt() min {} is greater than max {}.", min, max),
+            ));
+        }
+        let offset = interpreter.random.below((max - min) as u64 + 1);
+        Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(
+            min + offset as f64,
+        ))))
+    }
+    fn name(&self) -> &str {
+        "randomInt"
+    }
+}
+
+// `seed(n)` restarts the generator, so that what follows repeats from run
+// to run.
+#[derive(Clone, Debug)]
+struct Seed();
+impl LoxCallable for Seed {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let n = arguments[0]
+            .get_number()
+            .ok_or_else(|| native_error(paren, String::from("seed() expects a number.")))?;
+        interpreter.random = Random::new(n.to_bits());
+        Ok(interpreter.nil())
+    }
+    fn name(&self) -> &str {
+        "seed"
+    }
+}
+
+// The most elements `range` will make, since the list is built up front.
+const MAX_RANGE: f64 = 10_000_000.0;
+
+// `range(stop)`, `range(start, stop)` or `range(start, stop, step)`: the
+// numbers from `start` (default 0) up to but not including `stop`, as a
+// list. Each is worked out from its index rather than by adding up steps,
+// so fractional steps don't drift.
+#[derive(Clone, Debug)]
+struct RangeFn();
+impl LoxCallable for RangeFn {
+    fn arity(&self) -> Arity {
+        Arity::Range(1, 3)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let mut numbers = Vec::with_capacity(arguments.len());
+        for argument in &arguments {
+            numbers.push(
+                argument
+                    .get_number()
+                    .ok_or_else(|| native_error(paren, String::from("range() expects numbers.")))?,
+            );
+        }
+        let (start, stop, step) = match numbers[..] {
+            [stop] => (0.0, stop, 1.0),
+            [start, stop] => (start, stop, 1.0),
+            [start, stop, step] => (start, stop, step),
+            _ => unreachable!("arity was checked"),
+        };
+        if step == 0.0 {
+            return Err(native_error(
+                paren,
+                String::from("range() step can't be 0."),
+            ));
+        }
+        // Also 0 when the count is NaN, as for an infinite start and stop.
+        let count = ((stop - start) / step).ceil();
+        let count = if count > 0.0 { count } else { 0.0 };
+        if count > MAX_RANGE {
+            return Err(native_error(
+                paren,
+                format!("range() can't make more than {} numbers.", MAX_RANGE),
+            ));
+        }
+        let elements = (0..count as usize)
+            .map(|i| Rc::from(ExprValue::Literal(Literal::NUMBER(start + i as f64 * step))))
+            .collect();
+        Ok(Rc::from(ExprValue::List(Rc::new(RefCell::new(elements)))))
+    }
+    fn name(&self) -> &str {
+        "range"
+    }
+}
+
+// `dict()`: a new, empty map.
+#[derive(Clone, Debug)]
+struct Dict();
+impl LoxCallable for Dict {
+    fn arity(&self) -> Arity {
+        Arity::Exact(0)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        _paren: &RcToken,
+        _arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        Ok(Rc::from(ExprValue::Map(Rc::new(RefCell::new(
+            HashMap::new(),
+        )))))
+    }
+    fn name(&self) -> &str {
+        "dict"
+    }
+}
+
+// `map(f, list)`: a list of `f` applied to each element.
+#[derive(Clone, Debug)]
+struct Map();
+impl LoxCallable for Map {
+    fn arity(&self) -> Arity {
+        Arity::Exact(2)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let function = function_arg(paren, "map", &arguments[0])?;
+        let mut mapped = Vec::new();
+        for element in list_arg(paren, "map", &arguments[1])? {
+            mapped.push(call_back(
+                interpreter,
+                paren,
+                "map",
+                &function,
+                vec![element],
+            )?);
+        }
+        Ok(Rc::from(ExprValue::List(Rc::new(RefCell::new(mapped)))))
+    }
+    fn name(&self) -> &str {
+        "map"
+    }
+}
+
+// `filter(f, list)`: the elements for which `f` returns a truthy value.
+#[derive(Clone, Debug)]
+struct Filter();
+impl LoxCallable for Filter {
+    fn arity(&self) -> Arity {
+        Arity::Exact(2)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let function = function_arg(paren, "filter", &arguments[0])?;
+        let mut kept = Vec::new();
+        for element in list_arg(paren, "filter", &arguments[1])? {
+            let keep = call_back(
+                interpreter,
+                paren,
+                "filter",
+                &function,
+                vec![Rc::clone(&element)],
+            )?;
+            if Interpreter::is_truthy(&keep) {
+                kept.push(element);
+            }
+        }
+        Ok(Rc::from(ExprValue::List(Rc::new(RefCell::new(kept)))))
+    }
+    fn name(&self) -> &str {
+        "filter"
+    }
+}
+
+// `reduce(f, init, list)`: folds the list from the left, starting from
+// `init`, with `f(accumulated, element)`.
+#[derive(Clone, Debug)]
+struct Reduce();
+impl LoxCallable for Reduce {
+    fn arity(&self) -> Arity {
+        Arity::Exact(3)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let function = function_arg(paren, "reduce", &arguments[0])?;
+        let mut accumulated = Rc::clone(&arguments[1]);
+        for element in list_arg(paren, "reduce", &arguments[2])? {
+            accumulated = call_back(
+                interpreter,
+                paren,
+                "reduce",
+                &function,
+                vec![accumulated, element],
+            )?;
+        }
+        Ok(accumulated)
+    }
+    fn name(&self) -> &str {
+        "reduce"
+    }
+}
+
+// `sort(list)` or `sort(list, cmp)`: sorts the list in place, stably, and
+// returns it. Without `cmp`, numbers and strings sort in their natural
+// order; `cmp(a, b)` returns a negative number, zero or a positive number.
+#[derive(Clone, Debug)]
+struct Sort();
+impl LoxCallable for Sort {
+    fn arity(&self) -> Arity {
+        Arity::Range(1, 2)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let list = match &*arguments[0] {
+            ExprValue::List(list) => list,
+            _ => return Err(native_error(paren, String::from("sort() expects a list."))),
+        };
+        let mut items = (**list).borrow().clone();
+        match arguments.get(1) {
+            Some(function) => {
+                let function = function_arg(paren, "sort", function)?;
+                merge_sort(&mut items, &mut |a, b| {
+                    let order = call_back(
+                        interpreter,
+                        paren,
+                        "sort",
+                        &function,
+                        vec![Rc::clone(a), Rc::clone(b)],
+                    )?;
+                    let order = order.get_number().ok_or_else(|| {
+                        native_error(
+                            paren,
+                            String::from("sort() comparator must return a number."),
+                        )
+                    })?;
+                    Ok(order.partial_cmp(&0.0).unwrap_or(Ordering::Equal))
+                })?
+            }
+            None => merge_sort(&mut items, &mut |a, b| match (&**a, &**b) {
+                (
+                    ExprValue::Literal(Literal::NUMBER(a)),
+                    ExprValue::Literal(Literal::NUMBER(b)),
+                ) => Ok(a.total_cmp(b)),
+                (
+                    ExprValue::Literal(Literal::STRING(a)),
+                    ExprValue::Literal(Literal::STRING(b)),
+                ) => Ok(a.cmp(b)),
+                _ => Err(native_error(
+                    paren,
+                    String::from("sort() can only compare two numbers or two strings."),
+                )),
+            })?,
+        }
+        // The list is only replaced once the sort has succeeded, so a failed
+        // comparison leaves it as it was.
+        *list.borrow_mut() = items;
+        Ok(Rc::clone(&arguments[0]))
+    }
+    fn name(&self) -> &str {
+        "sort"
+    }
+}
+
+type Comparison<'a> = dyn FnMut(&Rc<ExprValue>, &Rc<ExprValue>) -> Result<Ordering, LoxError> + 'a;
+
+// A stable merge sort that stops at the first failed comparison, which
+// `sort_by` has no way to do.
+fn merge_sort(items: &mut Vec<Rc<ExprValue>>, compare: &mut Comparison) -> VoidResult {
+    if items.len() < 2 {
+        return Ok(());
+    }
+    let mut right = items.split_off(items.len() / 2);
+    let mut left = std::mem::take(items);
+    merge_sort(&mut left, compare)?;
+    merge_sort(&mut right, compare)?;
+    items.reserve(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        // Ties go to the left half, which keeps equal elements in order.
+        if compare(a, b)? == Ordering::Greater {
+            items.extend(right.next());
+        } else {
+            items.extend(left.next());
+        }
+    }
+    items.extend(left);
+    items.extend(right);
+    Ok(())
+}
+
+fn function_arg(
+    paren: &RcToken,
+    function: &str,
+    value: &ExprValue,
+) -> Result<Rc<dyn LoxCallable>, LoxError> {
+    match value {
+        ExprValue::LoxCallable(callable) => Ok(Rc::clone(callable)),
+        _ => Err(native_error(
+            paren,
+            format!("{}() expects a function.", function),
+        )),
+    }
+}
+
+// The list's elements as they are now, so a callback that changes the list
+// doesn't affect the iteration.
+fn list_arg(
+    paren: &RcToken,
+    function: &str,
+    value: &ExprValue,
+) -> Result<Vec<Rc<ExprValue>>, LoxError> {
+    match value {
+        ExprValue::List(list) => Ok((**list).borrow().clone()),
+        _ => Err(native_error(
+            paren,
+            format!("{}() expects a list.", function),
+        )),
+    }
+}
+
+// Calls the function passed to a higher-order native. An arity mismatch is
+// the callback's, so the message names it rather than the native.
+fn call_back(
+    interpreter: &mut Interpreter,
+    paren: &RcToken,
+    native: &str,
+    function: &Rc<dyn LoxCallable>,
+    arguments: Vec<Rc<ExprValue>>,
+) -> ExprValueResult {
+    if let Err(message) = function.arity().check(arguments.len()) {
+        return Err(native_error(
+            paren,
+            format!("{}() callback {}(): {}", native, function.name(), message),
+        ));
+    }
+    interpreter.call(function, paren, arguments)
+}
+
+// Converts a Lox number into the character with that code point.
+fn char_code_arg(paren: &RcToken, function: &str, value: &ExprValue) -> Result<char, LoxError> {
+    value
+        .get_number()
+        .filter(|n| n.fract() == 0.0 && *n >= 0.0 && *n <= u32::MAX as f64)
+        .and_then(|n| char::from_u32(n as u32))
+        .ok_or_else(|| native_error(paren, format!("{}() expects a character code.", function)))
+}
+
+// Reads one UTF-8 encoded character, or None at the end of input. Bytes that
+// don't decode come back as U+FFFD.
+fn read_char(input: &mut dyn Read) -> io::Result<Option<char>> {
+    let mut buf = [0u8; 4];
+    match input.read_exact(&mut buf[..1]) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let len = match buf[0] {
+        0x00..=0x7F => 1,
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => return Ok(Some(char::REPLACEMENT_CHARACTER)),
+    };
+    match input.read_exact(&mut buf[1..len]) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Ok(Some(char::REPLACEMENT_CHARACTER))
+        }
+        result => result?,
+    }
+    Ok(Some(
+        std::str::from_utf8(&buf[..len])
+            .ok()
+            .and_then(|s| s.chars().next())
+            .unwrap_or(char::REPLACEMENT_CHARACTER),
+    ))
+}
+
+#[derive(Clone, Debug)]
+struct Getc();
+impl LoxCallable for Getc {
+    fn arity(&self) -> Arity {
+        Arity::Exact(0)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        _arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let c = read_char(&mut *interpreter.input)
+            .map_err(|e| native_error(paren, format!("getc() couldn't read input: {}", e)))?;
+        Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(
+            c.map_or(-1.0, |c| c as u32 as f64),
+        ))))
+    }
+    fn name(&self) -> &str {
+        "getc"
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Putc();
+impl LoxCallable for Putc {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let c = char_code_arg(paren, "putc", &arguments[0])?;
+        write!(interpreter.output, "{}", c).expect("Couldn't write output");
+        Ok(interpreter.nil())
+    }
+    fn name(&self) -> &str {
+        "putc"
+    }
+}
+
+// `print(value)`: the print statement as a value that can be passed around,
+// so it ends the line as the statement does.
+#[derive(Clone, Debug)]
+struct Print();
+impl LoxCallable for Print {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        _paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let text = Interpreter::stringify(Rc::clone(&arguments[0]));
+        writeln!(interpreter.output, "{}", text).expect("Couldn't write output");
+        Ok(interpreter.nil())
+    }
+    fn name(&self) -> &str {
+        "print"
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Chr();
+impl LoxCallable for Chr {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let c = char_code_arg(paren, "chr", &arguments[0])?;
+        Ok(Rc::from(ExprValue::Literal(Literal::STRING(c.to_string()))))
+    }
+    fn name(&self) -> &str {
+        "chr"
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Ord();
+impl LoxCallable for Ord {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let s = string_arg(paren, "ord", &arguments[0])?;
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(
+                c as u32 as f64,
+            )))),
+            _ => Err(native_error(
+                paren,
+                String::from("ord() expects a single character."),
+            )),
+        }
+    }
+    fn name(&self) -> &str {
+        "ord"
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ReadLine();
+impl LoxCallable for ReadLine {
+    fn arity(&self) -> Arity {
+        Arity::Exact(0)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        _arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        // A byte at a time, so nothing past the line is taken from `getc`.
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        loop {
+            match interpreter.input.read(&mut byte) {
+                Ok(0) if line.is_empty() => return Ok(interpreter.nil()),
+                Ok(0) => break,
+                Ok(_) if byte[0] == b'\n' => break,
+                Ok(_) => line.push(byte[0]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(native_error(
+                        paren,
+                        format!("readLine() couldn't read input: {}", e),
+                    ))
+                }
+            }
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(Rc::from(ExprValue::Literal(Literal::STRING(
+            String::from_utf8_lossy(&line).into_owned(),
+        ))))
+    }
+    fn name(&self) -> &str {
+        "readLine"
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ReadFile();
+impl LoxCallable for ReadFile {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let path = string_arg(paren, "readFile", &arguments[0])?;
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(Rc::from(ExprValue::Literal(Literal::STRING(contents)))),
+            Err(e) => Err(native_error(
+                paren,
+                format!("Couldn't read file '{}': {}", path, e),
+            )),
+        }
+    }
+    fn name(&self) -> &str {
+        "readFile"
+    }
+}
+
+#[derive(Clone, Debug)]
+struct WriteFile();
+impl LoxCallable for WriteFile {
+    fn arity(&self) -> Arity {
+        Arity::Exact(2)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let path = string_arg(paren, "writeFile", &arguments[0])?;
+        let contents = string_arg(paren, "writeFile", &arguments[1])?;
+        match fs::write(path, contents) {
+            Ok(()) => Ok(interpreter.boolean(true)),
+            Err(e) => Err(native_error(
+                paren,
+                format!("Couldn't write file '{}': {}", path, e),
+            )),
+        }
+    }
+    fn name(&self) -> &str {
+        "writeFile"
+    }
+}
+
+// The natives that touch the file system, left out under `disable_fs`.
+const FS_NATIVES: [&str; 2] = ["readFile", "writeFile"];
+
+// assert(condition, message?) fails with `message` when `condition` is
+// falsy.
+#[derive(Clone, Debug)]
+struct Assert();
+impl LoxCallable for Assert {
+    fn arity(&self) -> Arity {
+        Arity::Range(1, 2)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        if Interpreter::is_truthy(&arguments[0]) {
+            return Ok(interpreter.nil());
+        }
+        let message = match arguments.get(1) {
+            Some(message) => Interpreter::stringify(Rc::clone(message)),
+            None => String::from("Assertion failed."),
+        };
+        Err(native_error(paren, message))
+    }
+    fn name(&self) -> &str {
+        "assert"
+    }
+}
+
+// Raises a runtime error with the given message, to fail a script on purpose.
+#[derive(Clone, Debug)]
+struct Error();
+impl LoxCallable for Error {
+    fn arity(&self) -> Arity {
+        Arity::Exact(1)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        Err(native_error(
+            paren,
+            Interpreter::stringify(Rc::clone(&arguments[0])),
+        ))
+    }
+    fn name(&self) -> &str {
+        "error"
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Gc();
+impl LoxCallable for Gc {
+    fn arity(&self) -> Arity {
+        Arity::Exact(0)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        _paren: &RcToken,
+        _arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let reclaimed = interpreter.collect_garbage();
+        Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(
+            reclaimed as f64,
+        ))))
+    }
+    fn name(&self) -> &str {
+        "gc"
+    }
+}
+
+// Stops for the debugger, or does nothing without one.
+#[derive(Clone, Debug)]
+struct Breakpoint();
+impl LoxCallable for Breakpoint {
+    fn arity(&self) -> Arity {
+        Arity::Exact(0)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &RcToken,
+        _arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        interpreter.pause(&format!("Breakpoint at line {}.", paren.line));
+        Ok(interpreter.nil())
+    }
+    fn name(&self) -> &str {
+        "breakpoint"
+    }
+}
+
+// Starts profiling calls, forgetting whatever was counted before, so a
+// script can measure just the part it's interested in.
+#[derive(Clone, Debug)]
+struct ProfileStart();
+impl LoxCallable for ProfileStart {
+    fn arity(&self) -> Arity {
+        Arity::Exact(0)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        _paren: &RcToken,
+        _arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        interpreter.profiler = Some(Profiler::default());
+        Ok(interpreter.nil())
+    }
+    fn name(&self) -> &str {
+        "profileStart"
+    }
+}
+
+// The profile so far as a table, or nil when nothing is being profiled.
+#[derive(Clone, Debug)]
+struct ProfileReport();
+impl LoxCallable for ProfileReport {
+    fn arity(&self) -> Arity {
+        Arity::Exact(0)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        _paren: &RcToken,
+        _arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        match interpreter.profile_report() {
+            Some(report) => Ok(Rc::from(ExprValue::Literal(Literal::STRING(report)))),
+            None => Ok(interpreter.nil()),
+        }
+    }
+    fn name(&self) -> &str {
+        "profileReport"
+    }
+}
+
+// Prints every variable in scope, for debugging closures.
+#[derive(Clone, Debug)]
+struct Env();
+impl LoxCallable for Env {
+    fn arity(&self) -> Arity {
+        Arity::Exact(0)
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        _paren: &RcToken,
+        _arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let dump = interpreter.dump_environment();
+        write!(interpreter.output, "{}", dump).expect("Couldn't write output");
+        Ok(interpreter.nil())
+    }
+    fn name(&self) -> &str {
+        "env"
+    }
+}
+
+type MethodFn = fn(&ExprValue, &RcToken, &[Rc<ExprValue>]) -> ExprValueResult;
+
+// A built-in method of a string or number, bound to the value it was read
+// from: `"a,b".split` is a function of one argument.
+#[derive(Clone, Debug)]
+pub(crate) struct Method {
+    name: &'static str,
+    arity: usize,
+    receiver: Rc<ExprValue>,
+    function: MethodFn,
+}
+impl Method {
+    pub(crate) fn get(receiver: Rc<ExprValue>, name: &RcToken) -> ExprValueResult {
+        let (method, arity, function): (_, _, MethodFn) = match (&*receiver, &*name.lexeme) {
+            (ExprValue::Literal(Literal::STRING(_)), "length") => ("length", 0, string_length),
+            (ExprValue::Literal(Literal::STRING(_)), "upper") => ("upper", 0, string_upper),
+            (ExprValue::Literal(Literal::STRING(_)), "lower") => ("lower", 0, string_lower),
+            (ExprValue::Literal(Literal::STRING(_)), "split") => ("split", 1, string_split),
+            (ExprValue::Literal(Literal::NUMBER(_)), "floor") => ("floor", 0, number_floor),
+            _ => {
+                return Err(LoxError::RuntimeError {
+                    token: Rc::clone(name),
+                    message: format!("Undefined property '{}'.", name.lexeme),
+                    span: None,
+                })
+            }
+        };
+        Ok(Rc::from(ExprValue::LoxCallable(Rc::new(Method {
+            name: method,
+            arity,
+            receiver,
+            function,
+        }))))
+    }
+}
+impl LoxCallable for Method {
+    fn arity(&self) -> Arity {
+        Arity::Exact(self.arity)
+    }
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        (self.function)(&self.receiver, paren, &arguments)
+    }
+    fn name(&self) -> &str {
+        self.name
+    }
+}
+
+fn receiver_string(receiver: &ExprValue) -> &str {
+    receiver.get_string().expect("string method on a string")
+}
+
+fn string_length(receiver: &ExprValue, _: &RcToken, _: &[Rc<ExprValue>]) -> ExprValueResult {
+    let length = receiver_string(receiver).chars().count();
+    Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(length as f64))))
+}
+
+fn string_upper(receiver: &ExprValue, _: &RcToken, _: &[Rc<ExprValue>]) -> ExprValueResult {
+    let upper = receiver_string(receiver).to_uppercase();
+    Ok(Rc::from(ExprValue::Literal(Literal::STRING(upper))))
+}
+
+fn string_lower(receiver: &ExprValue, _: &RcToken, _: &[Rc<ExprValue>]) -> ExprValueResult {
+    let lower = receiver_string(receiver).to_lowercase();
+    Ok(Rc::from(ExprValue::Literal(Literal::STRING(lower))))
+}
+
+// An empty separator splits the string into its characters.
+fn string_split(
+    receiver: &ExprValue,
+    paren: &RcToken,
+    arguments: &[Rc<ExprValue>],
+) -> ExprValueResult {
+    let text = receiver_string(receiver);
+    let separator = string_arg(paren, "split", &arguments[0])?;
+    let parts: Vec<String> = if separator.is_empty() {
+        text.chars().map(String::from).collect()
+    } else {
+        text.split(separator.as_str()).map(String::from).collect()
+    };
+    let parts = parts
+        .into_iter()
+        .map(|part| Rc::from(ExprValue::Literal(Literal::STRING(part))))
+        .collect();
+    Ok(Rc::from(ExprValue::List(Rc::new(RefCell::new(parts)))))
+}
+
+fn number_floor(receiver: &ExprValue, _: &RcToken, _: &[Rc<ExprValue>]) -> ExprValueResult {
+    let n = receiver.get_number().expect("number method on a number");
+    Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(n.floor()))))
+}
+
+// What a catch clause binds: a thrown value as it was thrown, or for a
+// runtime error, a map of its message and line.
+fn caught(error: LoxError) -> Rc<ExprValue> {
+    match error {
+        LoxError::Thrown { value, .. } => value,
+        LoxError::RuntimeError { token, message, .. } => {
+            let mut fields = HashMap::new();
+            fields.insert(
+                Literal::STRING(String::from("message")),
+                Rc::from(ExprValue::Literal(Literal::STRING(message))),
+            );
+            fields.insert(
+                Literal::STRING(String::from("line")),
+                Rc::from(ExprValue::Literal(Literal::NUMBER(token.line as f64))),
+            );
+            Rc::from(ExprValue::Map(Rc::new(RefCell::new(fields))))
+        }
+        other => Rc::from(ExprValue::Literal(Literal::STRING(String::from(
+            other.message(),
+        )))),
+    }
+}
+
+pub type SharedInterpreter = Rc<RefCell<Interpreter>>;
+
+// Pending work for `Interpreter::run`.
+enum Frame {
+    Stmt(StmtId),
+    // Leaving a block: put back the environment it replaced.
+    Restore(Rc<RefCell<Environment>>),
+    // The next test of a while loop's condition; `iterated` once the body
+    // has run, so the increment is due first.
+    Loop {
+        stmt: StmtId,
+        iterated: bool,
+    },
+    // The end of a try statement's body. Errors unwinding past it run its
+    // handler instead.
+    Try {
+        stmt: StmtId,
+    },
+    // The rest of a for-each loop: `items` from `next` on.
+    ForEach {
+        stmt: StmtId,
+        items: Rc<Vec<Rc<ExprValue>>>,
+        next: usize,
+    },
+}
+
+// A file brought in by `import`. `Lox` parses and resolves it when the
+// importing program is loaded; it runs the first time an import of it is
+// reached.
+pub(crate) struct Module {
+    statements: Vec<StmtId>,
+    // Every global it defines, those of the files it imports included.
+    pub(crate) globals: Vec<Rc<str>>,
+    executed: bool,
+}
+
+// How much `--trace` reports: nothing, each statement as it runs, or each
+// expression evaluated as well.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceLevel {
+    Off,
+    Statements,
+    Expressions,
+}
+
+pub struct Interpreter {
+    environment: Rc<RefCell<Environment>>,
+    pub globals: Rc<RefCell<Environment>>,
+    // Every environment created, so cycles through closures can be freed.
+    environments: Environments,
+    // Every program run so far. Each run's parse adds to it, and functions
+    // defined by earlier runs still point into it.
+    ast: Rc<AstArena>,
+    // The (depth, slot) each resolved local is found at.
+    locals: HashMap<ExprId, (usize, usize)>,
+    // Under --strict, arithmetic that overflows to inf/NaN is a runtime error.
+    // The resolver reads it too, for its own stricter checks.
+    pub strict: bool,
+    // Under --fresh-loop-bindings, each iteration of a `for` loop gets its
+    // own copy of the initializer's variables, as `let` does in JavaScript,
+    // so a closure made in the body keeps the value from its iteration.
+    // Otherwise, as in the book, every iteration shares one binding.
+    pub fresh_loop_bindings: bool,
+    // Lox calls recurse natively, so deep recursion is cut off here before
+    // it can overflow the host stack.
+    pub max_call_depth: usize,
+    call_depth: usize,
+    // An embedder's bound on how long a program may run: a number of steps,
+    // one per statement executed and expression evaluated, or a point in
+    // time. `steps` counts from the start of each `interpret`.
+    step_limit: Option<u64>,
+    deadline: Option<Instant>,
+    steps: u64,
+    // Where `print` writes; stdout unless an embedder supplies a sink.
+    output: Box<dyn Write>,
+    // What `getc` and `readLine` read; stdin unless replaced with `set_input`.
+    input: Box<dyn Read>,
+    fs_access: bool,
+    // Set under --debug; checked before every statement, so it costs
+    // nothing much when it's absent.
+    debugger: Option<DebugHook>,
+    // Counts and times calls under --profile or after `profileStart()`.
+    // Calls check for it once each.
+    profiler: Option<Profiler>,
+    pub trace: TraceLevel,
+    // What `clock`, `date` and `sleep` use; the system clock unless replaced
+    // with `set_time_source`.
+    time: Box<dyn TimeSource>,
+    // Loaded modules by canonical path, and the module each import
+    // statement names.
+    modules: HashMap<PathBuf, Module>,
+    imports: HashMap<StmtId, PathBuf>,
+    // What `args()` returns: the command line after the script's path.
+    args: Vec<String>,
+    // Behind `random()`; seeded from the clock until a program calls `seed`.
+    random: Random,
+    // Where trace lines go; stderr unless replaced with `set_trace_output`.
+    trace_output: Box<dyn Write>,
+    // The initializers of `Stmt::For` loops, which the loop's own trace line
+    // shows along with the condition and increment.
+    trace_hidden: HashSet<StmtId>,
+    // Shared instances of the values that carry no data, so evaluating
+    // `nil`, booleans and comparisons doesn't allocate.
+    nil: Rc<ExprValue>,
+    true_value: Rc<ExprValue>,
+    false_value: Rc<ExprValue>,
+}
+
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+// Reading the clock every step would be slow, so the deadline is checked
+// this often.
+const DEADLINE_INTERVAL: u64 = 1024;
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Closure cycles would otherwise outlive the interpreter.
+impl Drop for Interpreter {
+    fn drop(&mut self) {
+        self.environments.clear();
+    }
 }
 
 impl Interpreter {
     pub fn new() -> Self {
-        let mut globals = Environment::new(None);
-        globals.define(
-            String::from("clock"),
-            Some(Rc::from(ExprValue::LoxCallable(Rc::from(Clock())))),
-        );
-        let global_env = Rc::from(RefCell::new(globals));
+        Interpreter::with_output(Box::new(io::stdout()))
+    }
+    pub fn with_output(output: Box<dyn Write>) -> Self {
+        let mut environments = Environments::new();
+        let global_env = environments.track(Interpreter::native_globals());
         Interpreter {
             environment: Rc::clone(&global_env),
             globals: global_env,
+            environments,
+            ast: Rc::new(AstArena::new()),
             locals: HashMap::new(),
+            strict: false,
+            fresh_loop_bindings: false,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            call_depth: 0,
+            step_limit: None,
+            deadline: None,
+            steps: 0,
+            output,
+            input: Box::new(io::stdin()),
+            fs_access: true,
+            debugger: None,
+            profiler: None,
+            trace: TraceLevel::Off,
+            trace_output: Box::new(io::stderr()),
+            trace_hidden: HashSet::new(),
+            time: Box::new(SystemClock),
+            modules: HashMap::new(),
+            imports: HashMap::new(),
+            args: Vec::new(),
+            random: Random::new(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |time| time.as_nanos() as u64),
+            ),
+            nil: Rc::from(ExprValue::Literal(Literal::NIL)),
+            true_value: Rc::from(ExprValue::Literal(Literal::BOOL(true))),
+            false_value: Rc::from(ExprValue::Literal(Literal::BOOL(false))),
         }
     }
-    pub fn interpret(&mut self, statements: Vec<RcStmt>) -> VoidResult {
-        for statement in statements {
-            self.execute(statement)?;
+    // Forgets every user definition, keeping only the natives. Options such
+    // as `strict` are left alone.
+    pub fn reset(&mut self) {
+        self.globals = self.environments.track(Interpreter::native_globals());
+        if !self.fs_access {
+            self.disable_fs();
+        }
+        self.environment = Rc::clone(&self.globals);
+        self.locals.clear();
+        self.modules.clear();
+        self.imports.clear();
+        self.call_depth = 0;
+        self.environments.collect();
+    }
+    pub fn ast(&self) -> &Rc<AstArena> {
+        &self.ast
+    }
+    // Hands the arena over for a parse to add to; `set_ast` gives it back.
+    pub fn take_ast(&mut self) -> AstArena {
+        let ast = std::mem::take(&mut self.ast);
+        Rc::try_unwrap(ast).unwrap_or_else(|ast| (*ast).clone())
+    }
+    pub fn set_ast(&mut self, ast: AstArena) {
+        self.ast = Rc::new(ast);
+    }
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.step_limit = limit;
+    }
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+    // Counts a step against the budget, failing once it's used up.
+    fn step(&mut self, span: Span) -> VoidResult {
+        self.steps += 1;
+        if self.over_budget(self.steps.is_multiple_of(DEADLINE_INTERVAL)) {
+            let token = Token::new(TokenType::EOF, Vec::new(), Literal::NIL, span.start_line, 0);
+            return Err(LoxError::RuntimeError {
+                token: Rc::new(token),
+                message: String::from("Execution budget exceeded."),
+                span: None,
+            }
+            .with_span(span));
         }
         Ok(())
     }
-    fn execute(&mut self, stmt: RcStmt) -> VoidResult {
-        match &*stmt {
-            Stmt::Block { statements } => {
-                self.execute_block(
-                    Rc::clone(statements),
-                    Rc::from(RefCell::new(Environment::new(Some(&self.environment)))),
-                )?;
-            }
-            Stmt::Expression { expr } => {
-                self.evaluate(Rc::clone(expr))?;
-            }
-            Stmt::Function {
-                ref name,
-                params: _,
-                body: _,
+    // Every variable in scope, frame by frame from the innermost out to the
+    // globals. A name also bound in an inner frame is marked as shadowed.
+    // Natives are left out of the globals, which would otherwise drown out
+    // the program's own.
+    pub fn dump_environment(&self) -> String {
+        let mut dump = String::new();
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut frame = Some(Rc::clone(&self.environment));
+        let mut depth = 0;
+        while let Some(environment) = frame {
+            let environment = (*environment).borrow();
+            let globals = environment.enclosing().is_none();
+            if globals {
+                dump.push_str("globals:\n");
+            } else {
+                dump.push_str(&format!("frame {}:\n", depth));
+            }
+            let mut names = Vec::new();
+            for (name, value) in environment.snapshot() {
+                if globals && Interpreter::is_native(&environment.get_by_name(&name)) {
+                    continue;
+                }
+                let shadowed = if seen.contains(&name) {
+                    " (shadowed)"
+                } else {
+                    ""
+                };
+                dump.push_str(&format!("  {} = {}{}\n", name, value, shadowed));
+                names.push(name);
+            }
+            seen.extend(names);
+            frame = environment.enclosing().map(Rc::clone);
+            depth += 1;
+        }
+        dump
+    }
+    fn is_native(value: &Option<Rc<ExprValue>>) -> bool {
+        matches!(value.as_deref(), Some(ExprValue::LoxCallable(c)) if c.closure().is_none())
+    }
+    fn over_budget(&self, check_deadline: bool) -> bool {
+        self.step_limit.is_some_and(|limit| self.steps > limit)
+            || check_deadline
+                && self
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+    pub fn fs_access(&self) -> bool {
+        self.fs_access
+    }
+    pub fn set_input(&mut self, input: Box<dyn Read>) {
+        self.input = input;
+    }
+    pub fn set_debugger(&mut self, debugger: Option<DebugHook>) {
+        self.debugger = debugger;
+    }
+    pub fn start_profiling(&mut self) {
+        self.profiler = Some(Profiler::default());
+    }
+    // The table --profile prints, if profiling was started.
+    pub fn profile_report(&self) -> Option<String> {
+        self.profiler.as_ref().map(Profiler::report)
+    }
+    fn profile_key(function: &dyn LoxCallable) -> ProfileKey {
+        match function.as_function() {
+            Some(function) => (Rc::clone(&function.name.lexeme), function.name.line),
+            None => (Rc::from(function.name()), 0),
+        }
+    }
+    // Counts a call the trampoline makes in place of its caller.
+    pub(crate) fn profile_tail_call(&mut self, function: &LoxFunction) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.count(&Interpreter::profile_key(function));
+        }
+    }
+    pub(crate) fn module(&self, path: &Path) -> Option<&Module> {
+        self.modules.get(path)
+    }
+    pub(crate) fn add_module(
+        &mut self,
+        path: PathBuf,
+        statements: Vec<StmtId>,
+        globals: Vec<Rc<str>>,
+    ) {
+        let module = Module {
+            statements,
+            globals,
+            executed: false,
+        };
+        self.modules.insert(path, module);
+    }
+    // Records which loaded module an import statement brings in.
+    pub(crate) fn link_import(&mut self, stmt: StmtId, path: PathBuf) {
+        self.imports.insert(stmt, path);
+    }
+    // Runs an imported module's top level in the globals, unless an earlier
+    // import already has.
+    fn import(&mut self, stmt: StmtId) -> ExecResult {
+        let module = self
+            .imports
+            .get(&stmt)
+            .and_then(|path| self.modules.get_mut(path))
+            .expect("import wasn't loaded");
+        if module.executed {
+            return Ok(ControlFlow::Normal);
+        }
+        module.executed = true;
+        let statements = module.statements.clone();
+        match self.execute_block(&statements, Rc::clone(&self.globals))? {
+            ControlFlow::Normal => Ok(ControlFlow::Normal),
+            flow => Err(flow.stray()),
+        }
+    }
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+    pub fn set_time_source(&mut self, time: Box<dyn TimeSource>) {
+        self.time = time;
+    }
+    pub fn set_trace_output(&mut self, output: Box<dyn Write>) {
+        self.trace_output = output;
+    }
+    // The hooks consulted before each statement runs. Both are off unless
+    // asked for, and then this is all they cost.
+    fn before_statement(&mut self, stmt: StmtId) {
+        if self.trace != TraceLevel::Off {
+            self.trace_statement(stmt);
+        }
+        if self.debugger.is_some() {
+            self.on_statement(stmt);
+        }
+    }
+    fn trace_statement(&mut self, stmt: StmtId) {
+        if self.trace_hidden.contains(&stmt) {
+            return;
+        }
+        // A `for` loop's summary shows its initializer already.
+        if let Stmt::For {
+            initializer: Some(initializer),
+            ..
+        } = &self.ast[stmt]
+        {
+            self.trace_hidden.insert(*initializer);
+        }
+        let line = format!("exec: {}", stmt_summary(&self.ast, stmt));
+        self.trace_line(self.ast[stmt].span(), &line);
+    }
+    fn trace_expression(&mut self, expr: ExprId) {
+        let line = format!("eval: {}", ast_to_string(&self.ast, expr));
+        self.trace_line(self.ast[expr].span(), &line);
+    }
+    // Indented by call depth, so a call's trace nests under its caller.
+    fn trace_line(&mut self, span: Span, line: &str) {
+        writeln!(
+            self.trace_output,
+            "{}[line {}] {}",
+            "  ".repeat(self.call_depth),
+            span.start_line,
+            line
+        )
+        .expect("Couldn't write trace");
+    }
+    // Consulted before each statement runs, to stop there after a `step`.
+    fn on_statement(&mut self, stmt: StmtId) {
+        if self.debugger.as_ref().is_some_and(|hook| hook.stepping) {
+            let line = self.ast[stmt].span().start_line;
+            self.pause(&format!("Stopped at line {}.", line));
+        }
+    }
+    // Hands control to the debugger's prompt. The hook is taken out while
+    // the prompt runs, so code it evaluates doesn't stop again.
+    fn pause(&mut self, stop: &str) {
+        if let Some(mut hook) = self.debugger.take() {
+            debugger::prompt(self, &mut hook, stop);
+            self.debugger = Some(hook);
+        }
+    }
+    // Evaluates an expression typed at the debugger in the current scope.
+    // Its names are looked up in the environments as they are now, since
+    // the resolver never saw it. The error is the message to show.
+    pub(crate) fn evaluate_source(&mut self, source: &str) -> Result<Rc<ExprValue>, String> {
+        let mut scanner = Scanner::new(source.as_bytes().to_vec());
+        scanner
+            .scan_tokens()
+            .map_err(|e| format!("Error: {}", e.message()))?;
+        let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        let mut ast = self.take_ast();
+        let first = ast.expr_count();
+        let parsed = Parser::new(&mut lox, scanner.tokens, &mut ast).parse_expression();
+        let last = ast.expr_count();
+        self.set_ast(ast);
+        let expr = match (&parsed, lox.errors().first()) {
+            (Err(e), _) | (Ok(_), Some(e)) => return Err(format!("Error: {}", e.message())),
+            (Ok(expr), None) => *expr,
+        };
+        for id in first..last {
+            let id = ExprId(id as u32);
+            if let Expr::Variable { name, .. } | Expr::Assign { name, .. } = &self.ast[id] {
+                if let Some((depth, slot)) =
+                    Environment::find_local(&self.environment, &name.lexeme)
+                {
+                    self.locals.insert(id, (depth, slot));
+                }
+            }
+        }
+        self.evaluate(expr)
+            .map_err(|e| format!("Error: {}", e.message()))
+    }
+    // Removes readFile() and writeFile(), for running untrusted scripts.
+    pub fn disable_fs(&mut self) {
+        self.fs_access = false;
+        let mut globals = self.globals.borrow_mut();
+        for name in FS_NATIVES {
+            globals.undefine(name);
+        }
+    }
+    pub fn new_environment(&mut self, environment: Environment) -> Rc<RefCell<Environment>> {
+        if self.environments.due() {
+            self.environments.collect();
+        }
+        self.environments.track(environment)
+    }
+    // Frees environments kept alive only by closure cycles, returning how
+    // many. This also happens on its own as environments pile up.
+    pub fn collect_garbage(&mut self) -> usize {
+        self.environments.collect()
+    }
+    #[allow(dead_code)]
+    pub fn live_environments(&self) -> usize {
+        self.environments.live()
+    }
+    // What a `print` statement does with its value.
+    pub(crate) fn print(&mut self, value: Rc<ExprValue>) {
+        writeln!(self.output, "{}", Interpreter::stringify(value)).expect("Couldn't write output");
+    }
+    pub fn nil(&self) -> Rc<ExprValue> {
+        Rc::clone(&self.nil)
+    }
+    pub(crate) fn boolean(&self, value: bool) -> Rc<ExprValue> {
+        Rc::clone(if value {
+            &self.true_value
+        } else {
+            &self.false_value
+        })
+    }
+    fn native_globals() -> Environment {
+        let mut globals = Environment::new(None);
+        let natives: Vec<Rc<dyn LoxCallable>> = vec![
+            Rc::from(Clock()),
+            Rc::from(ClockMillis()),
+            Rc::from(Date()),
+            Rc::from(Sleep()),
+            Rc::from(Exit()),
+            Rc::from(Args()),
+            Rc::from(Getenv()),
+            Rc::from(Len()),
+            Rc::from(Substr()),
+            Rc::from(CharAt()),
+            Rc::from(Str()),
+            Rc::from(Repr()),
+            Rc::from(Format()),
+            Rc::from(Num()),
+            Rc::from(Bool()),
+            Rc::from(ArityFn()),
+            Rc::from(NameFn()),
+            Rc::from(NumberFn("floor", f64::floor)),
+            Rc::from(NumberFn("ceil", f64::ceil)),
+            Rc::from(NumberFn("abs", f64::abs)),
+            Rc::from(NumberFn("sqrt", f64::sqrt)),
+            Rc::from(Dict()),
+            Rc::from(Map()),
+            Rc::from(Filter()),
+            Rc::from(Reduce()),
+            Rc::from(Sort()),
+            Rc::from(RangeFn()),
+            Rc::from(RandomFn()),
+            Rc::from(RandomInt()),
+            Rc::from(Seed()),
+            Rc::from(Getc()),
+            Rc::from(Putc()),
+            Rc::from(Print()),
+            Rc::from(Chr()),
+            Rc::from(Ord()),
+            Rc::from(ReadLine()),
+            Rc::from(ReadFile()),
+            Rc::from(WriteFile()),
+            Rc::from(Assert()),
+            Rc::from(Error()),
+            Rc::from(Gc()),
+            Rc::from(Env()),
+            Rc::from(Breakpoint()),
+            Rc::from(ProfileStart()),
+            Rc::from(ProfileReport()),
+        ];
+        for function in natives {
+            globals.define(
+                Rc::from(function.name()),
+                Some(Rc::from(ExprValue::LoxCallable(function))),
+            );
+        }
+        globals
+    }
+    // Yields the value of the final statement when it is an expression
+    // statement, so embedders and the REPL can get a result out.
+    pub fn interpret(&mut self, statements: Vec<StmtId>) -> InterpretResult {
+        self.steps = 0;
+        let ast = Rc::clone(&self.ast);
+        let mut last = None;
+        for statement in statements {
+            last = match &ast[statement] {
+                Stmt::Expression { expr, .. } => {
+                    self.before_statement(statement);
+                    Some(self.evaluate(*expr)?)
+                }
+                _ => match self.execute(statement)? {
+                    ControlFlow::Normal => None,
+                    flow => return Err(flow.stray()),
+                },
+            };
+        }
+        Ok(last)
+    }
+    fn execute(&mut self, stmt: StmtId) -> ExecResult {
+        self.run(vec![Frame::Stmt(stmt)])
+    }
+    pub(crate) fn execute_block(
+        &mut self,
+        statements: &[StmtId],
+        environment: Rc<RefCell<Environment>>,
+    ) -> ExecResult {
+        let mut work = Vec::with_capacity(statements.len() + 1);
+        self.enter_block(statements, environment, &mut work);
+        self.run(work)
+    }
+    // Runs frames off the top of `work` until it's empty. Blocks and loops
+    // push their parts instead of recursing, so only expressions (and the
+    // calls inside them) grow the host stack.
+    fn run(&mut self, mut work: Vec<Frame>) -> ExecResult {
+        while let Some(frame) = work.pop() {
+            let result = match frame {
+                Frame::Stmt(stmt) => {
+                    self.before_statement(stmt);
+                    match self.step(self.ast[stmt].span()) {
+                        Ok(()) => self.execute_one(stmt, &mut work),
+                        Err(e) => Err(e),
+                    }
+                }
+                Frame::Restore(environment) => {
+                    self.environment = environment;
+                    Ok(ControlFlow::Normal)
+                }
+                Frame::Loop { stmt, iterated } => self.loop_step(stmt, iterated, &mut work),
+                Frame::ForEach { stmt, items, next } => {
+                    self.for_each_step(stmt, items, next, &mut work)
+                }
+                Frame::Try { .. } => Ok(ControlFlow::Normal),
+            };
+            match result {
+                Ok(ControlFlow::Normal) => {}
+                Ok(flow) => {
+                    if !self.unwind(&mut work, Some(&flow)) {
+                        return Ok(flow);
+                    }
+                }
+                Err(e) => self.catch(&mut work, e)?,
+            }
+        }
+        Ok(ControlFlow::Normal)
+    }
+    // Drops frames until a loop catches `flow` as a break or continue,
+    // restoring the environment of every block left on the way, and says
+    // whether one did. Anything else, like a return or an error (`None`),
+    // goes back to the caller.
+    fn unwind(&mut self, work: &mut Vec<Frame>, flow: Option<&ControlFlow>) -> bool {
+        while let Some(frame) = work.pop() {
+            match (frame, flow) {
+                (Frame::Restore(environment), _) => self.environment = environment,
+                (Frame::Loop { .. } | Frame::ForEach { .. }, Some(ControlFlow::Break { .. })) => {
+                    return true
+                }
+                (Frame::Loop { stmt, .. }, Some(ControlFlow::Continue { .. })) => {
+                    work.push(Frame::Loop {
+                        stmt,
+                        iterated: true,
+                    });
+                    return true;
+                }
+                (frame @ Frame::ForEach { .. }, Some(ControlFlow::Continue { .. })) => {
+                    work.push(frame);
+                    return true;
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+    // Drops frames until a try statement catches `error`, restoring the
+    // environment of every block left on the way, and starts its handler.
+    // Running out of budget can't be caught, or a script could carry on
+    // regardless, and neither can `exit()`.
+    fn catch(&mut self, work: &mut Vec<Frame>, error: LoxError) -> VoidResult {
+        let catchable = !matches!(error, LoxError::Exit { .. }) && !self.over_budget(true);
+        while let Some(frame) = work.pop() {
+            match frame {
+                Frame::Restore(environment) => self.environment = environment,
+                Frame::Try { stmt } if catchable => {
+                    let ast = Rc::clone(&self.ast);
+                    if let Stmt::Try {
+                        binding, handler, ..
+                    } = &ast[stmt]
+                    {
+                        let mut environment = Environment::new(Some(&self.environment));
+                        environment.define(Rc::clone(&binding.lexeme), Some(caught(error)));
+                        let environment = self.new_environment(environment);
+                        self.enter_block(handler, environment, work);
+                    }
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        Err(error)
+    }
+    fn enter_block(
+        &mut self,
+        statements: &[StmtId],
+        environment: Rc<RefCell<Environment>>,
+        work: &mut Vec<Frame>,
+    ) {
+        let previous = std::mem::replace(&mut self.environment, environment);
+        work.push(Frame::Restore(previous));
+        work.extend(statements.iter().rev().map(|&st| Frame::Stmt(st)));
+    }
+    // One trip around a while or for loop: the increment of the previous
+    // iteration, if any, then the condition, then the body.
+    fn loop_step(&mut self, stmt: StmtId, iterated: bool, work: &mut Vec<Frame>) -> ExecResult {
+        let ast = Rc::clone(&self.ast);
+        let (condition, increment, body, fresh) = match &ast[stmt] {
+            Stmt::While {
+                condition, body, ..
+            } => (Some(*condition), None, *body, false),
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                ..
+            } => (
+                *condition,
+                *increment,
+                *body,
+                self.fresh_loop_bindings && initializer.is_some(),
+            ),
+            _ => return Ok(ControlFlow::Normal),
+        };
+        if iterated {
+            // The next iteration starts from a copy of the loop's variables,
+            // taken before the increment, so closures from the last one keep
+            // theirs. The depths the resolver gave are the same in the copy,
+            // and the Restore frame under this loop still leaves it behind.
+            if fresh {
+                let copy = (*self.environment).borrow().copy_locals();
+                self.environment = self.new_environment(copy);
+            }
+            if let Some(increment) = increment {
+                self.evaluate(increment)?;
+            }
+        }
+        // A `for` without a condition loops until something stops it.
+        let running = match condition {
+            Some(condition) => Interpreter::is_truthy(&self.evaluate(condition)?),
+            None => true,
+        };
+        if running {
+            work.push(Frame::Loop {
+                stmt,
+                iterated: true,
+            });
+            work.push(Frame::Stmt(body));
+        }
+        Ok(ControlFlow::Normal)
+    }
+    // The next trip around a for-each loop, if there are items left: the
+    // body runs in a fresh environment holding just the loop variable, so
+    // closures made in different iterations see different values.
+    fn for_each_step(
+        &mut self,
+        stmt: StmtId,
+        items: Rc<Vec<Rc<ExprValue>>>,
+        next: usize,
+        work: &mut Vec<Frame>,
+    ) -> ExecResult {
+        let ast = Rc::clone(&self.ast);
+        if let (Stmt::ForEach { variable, body, .. }, Some(item)) = (&ast[stmt], items.get(next)) {
+            let mut environment = Environment::new(Some(&self.environment));
+            environment.define(Rc::clone(&variable.lexeme), Some(Rc::clone(item)));
+            let environment = self.new_environment(environment);
+            work.push(Frame::ForEach {
+                stmt,
+                items,
+                next: next + 1,
+            });
+            self.enter_block(&[*body], environment, work);
+        }
+        Ok(ControlFlow::Normal)
+    }
+    // What a for-each loop goes through: a list's elements as they are now,
+    // or a string's characters.
+    fn for_each_items(
+        iterable: &ExprValue,
+        keyword: &RcToken,
+    ) -> Result<Vec<Rc<ExprValue>>, LoxError> {
+        match iterable {
+            ExprValue::List(list) => Ok((**list).borrow().clone()),
+            ExprValue::Literal(Literal::STRING(s)) => Ok(s
+                .chars()
+                .map(|c| Rc::from(ExprValue::Literal(Literal::STRING(c.to_string()))))
+                .collect()),
+            _ => Err(LoxError::RuntimeError {
+                token: Rc::clone(keyword),
+                message: String::from("Can only iterate over lists and strings."),
+                span: Some(Span::of(keyword)),
+            }),
+        }
+    }
+    fn execute_one(&mut self, stmt: StmtId, work: &mut Vec<Frame>) -> ExecResult {
+        let ast = Rc::clone(&self.ast);
+        match &ast[stmt] {
+            Stmt::Block { statements, .. } => {
+                let environment = self.new_environment(Environment::new(Some(&self.environment)));
+                self.enter_block(statements, environment, work);
+            }
+            Stmt::Break { keyword, .. } => {
+                return Ok(ControlFlow::Break {
+                    keyword: Rc::clone(keyword),
+                })
+            }
+            Stmt::Continue { keyword, .. } => {
+                return Ok(ControlFlow::Continue {
+                    keyword: Rc::clone(keyword),
+                })
+            }
+            Stmt::Expression { expr, .. } => {
+                self.evaluate(*expr)?;
+            }
+            // The initializer runs in an environment of the loop's own,
+            // which the loop frame sits above.
+            Stmt::For { initializer, .. } => {
+                let environment = self.new_environment(Environment::new(Some(&self.environment)));
+                let previous = std::mem::replace(&mut self.environment, environment);
+                work.push(Frame::Restore(previous));
+                work.push(Frame::Loop {
+                    stmt,
+                    iterated: false,
+                });
+                work.extend(initializer.map(Frame::Stmt));
+            }
+            Stmt::ForEach {
+                keyword, iterable, ..
             } => {
+                let iterable = self.evaluate(*iterable)?;
+                let items = Interpreter::for_each_items(&iterable, keyword)?;
+                work.push(Frame::ForEach {
+                    stmt,
+                    items: Rc::new(items),
+                    next: 0,
+                });
+            }
+            Stmt::Throw { keyword, value, .. } => {
+                let value = self.evaluate(*value)?;
+                return Err(LoxError::Thrown {
+                    token: Rc::clone(keyword),
+                    message: Interpreter::stringify(Rc::clone(&value)),
+                    value,
+                    span: Some(ast[stmt].span()),
+                });
+            }
+            Stmt::Try { body, .. } => {
+                work.push(Frame::Try { stmt });
+                let environment = self.new_environment(Environment::new(Some(&self.environment)));
+                self.enter_block(body, environment, work);
+            }
+            Stmt::Function { name, params, .. } => {
                 let name_copy = name.lexeme.to_owned();
                 let function = LoxFunction {
                     declaration: stmt,
+                    name: Rc::clone(name),
+                    arity: parameter_arity(params),
                     closure: Rc::clone(&self.environment),
                 };
                 self.environment.borrow_mut().define(
@@ -167,107 +2545,245 @@ impl Interpreter {
                 condition,
                 then_branch,
                 else_branch,
+                ..
             } => {
-                if Interpreter::is_truthy(&self.evaluate(Rc::clone(condition))?) {
-                    self.execute(Rc::clone(then_branch))?;
+                if Interpreter::is_truthy(&self.evaluate(*condition)?) {
+                    work.push(Frame::Stmt(*then_branch));
                 } else if let Some(els) = else_branch {
-                    self.execute(Rc::clone(els))?;
+                    work.push(Frame::Stmt(*els));
                 }
             }
-            Stmt::Print { expr } => {
-                let value = self.evaluate(Rc::clone(expr))?;
-                println!("{}", Interpreter::stringify(value));
+            Stmt::Import { .. } => return self.import(stmt),
+            Stmt::Print { expr, .. } => {
+                let value = self.evaluate(*expr)?;
+                self.print(value);
             }
-            Stmt::Return { keyword: _, value } => {
-                return Err(LoxError::ReturnValue {
-                    value: self.evaluate(Rc::clone(value))?,
+            Stmt::Return { keyword, value, .. } => {
+                if let Expr::Call {
+                    callee,
+                    paren,
+                    arguments,
+                    ..
+                } = &ast[*value]
+                {
+                    let span = ast[*value].span();
+                    let (function, arguments) = self
+                        .prepare_call(*callee, paren, arguments)
+                        .map_err(|e| e.with_span(span))?;
+                    return Ok(ControlFlow::TailCall {
+                        keyword: Rc::clone(keyword),
+                        function,
+                        paren: Rc::clone(paren),
+                        arguments,
+                        span,
+                    });
+                }
+                return Ok(ControlFlow::Return {
+                    keyword: Rc::clone(keyword),
+                    value: self.evaluate(*value)?,
                 });
             }
-            Stmt::Var { name, initializer } => {
+            Stmt::Var {
+                name,
+                initializer,
+                constant,
+                ..
+            } => {
                 let mut value = None;
                 if let Some(expr) = initializer {
-                    value = Some(self.evaluate(Rc::clone(expr))?);
+                    value = Some(self.evaluate(*expr)?);
                 }
-                (*self.environment)
-                    .borrow_mut()
-                    .define(name.lexeme.to_owned(), value);
-            }
-            Stmt::While { condition, body } => {
-                while Interpreter::is_truthy(&self.evaluate(Rc::clone(condition))?) {
-                    self.execute(Rc::clone(body))?;
+                let mut environment = (*self.environment).borrow_mut();
+                if *constant {
+                    environment.define_constant(name.lexeme.to_owned(), value);
+                } else {
+                    environment.define(name.lexeme.to_owned(), value);
                 }
             }
+            Stmt::While { .. } => work.push(Frame::Loop {
+                stmt,
+                iterated: false,
+            }),
         }
-        Ok(())
+        Ok(ControlFlow::Normal)
     }
-    pub fn execute_block(
+    pub fn evaluate_in(
         &mut self,
-        statements: Rc<Vec<RcStmt>>,
-        environment: Rc<RefCell<Environment>>,
-    ) -> VoidResult {
-        let previous = Rc::clone(&self.environment);
-        self.environment = environment;
-        for statement in statements.iter() {
-            if let Err(e) = self.execute(Rc::clone(statement)) {
-                self.environment = previous;
-                return Err(e);
+        expr: ExprId,
+        environment: &Rc<RefCell<Environment>>,
+    ) -> ExprValueResult {
+        let previous = std::mem::replace(&mut self.environment, Rc::clone(environment));
+        let result = self.evaluate(expr);
+        self.environment = previous;
+        result
+    }
+    // Evaluates the callee and arguments of a call and checks that the call
+    // can be made.
+    fn prepare_call(
+        &mut self,
+        callee_expr: ExprId,
+        paren: &RcToken,
+        arguments: &[ExprId],
+    ) -> CallResult {
+        let callee = self.evaluate(callee_expr)?;
+        let mut values = Vec::with_capacity(arguments.len());
+        for &argument in arguments {
+            values.push(self.evaluate(argument)?);
+        }
+        let function = match &*callee {
+            ExprValue::LoxCallable(function) => Rc::clone(function),
+            _ => {
+                let expression = expr_to_source(&self.ast, callee_expr);
+                return Err(LoxError::RuntimeError {
+                    token: Rc::clone(paren),
+                    message: not_callable(&callee, &expression),
+                    span: None,
+                });
             }
+        };
+        if let Err(message) = function.arity().check(values.len()) {
+            return Err(LoxError::RuntimeError {
+                token: Rc::clone(paren),
+                message,
+                span: None,
+            });
         }
-        self.environment = previous;
-        Ok(())
+        Ok((function, values))
+    }
+    // Makes a call whose arity has been checked, from a call expression or
+    // from a native such as `map`.
+    pub(crate) fn call(
+        &mut self,
+        function: &Rc<dyn LoxCallable>,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        if self.call_depth >= self.max_call_depth {
+            return Err(LoxError::RuntimeError {
+                token: Rc::clone(paren),
+                message: String::from("Stack overflow."),
+                span: None,
+            });
+        }
+        self.call_depth += 1;
+        let result = match self.profiler {
+            None => function.call(self, paren, arguments),
+            Some(_) => self.profiled_call(function, paren, arguments),
+        };
+        self.call_depth -= 1;
+        result
+    }
+    fn profiled_call(
+        &mut self,
+        function: &Rc<dyn LoxCallable>,
+        paren: &RcToken,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> ExprValueResult {
+        let key = Interpreter::profile_key(function.as_ref());
+        let start = self.profiler.as_mut().map(|profiler| profiler.enter(&key));
+        let result = function.call(self, paren, arguments);
+        // The call may have stopped profiling or started it afresh.
+        if let (Some(profiler), Some(start)) = (&mut self.profiler, start) {
+            profiler.exit(&key, start);
+        }
+        result
+    }
+    fn evaluate(&mut self, expr: ExprId) -> ExprValueResult {
+        let span = self.ast[expr].span();
+        self.step(span)?;
+        if self.trace == TraceLevel::Expressions {
+            self.trace_expression(expr);
+        }
+        self.evaluate_expr(expr).map_err(|e| e.with_span(span))
     }
-    fn evaluate(&mut self, expr: RcExpr) -> ExprValueResult {
-        match &*expr {
-            Expr::Assign { name, value } => {
-                let value = self.evaluate(Rc::clone(value))?;
-                self.environment
-                    .borrow_mut()
-                    .assign(&name, Some(Rc::clone(&value)))?;
+    fn evaluate_expr(&mut self, expr: ExprId) -> ExprValueResult {
+        let ast = Rc::clone(&self.ast);
+        match &ast[expr] {
+            Expr::Assign { name, value, .. } => {
+                let value = self.evaluate(*value)?;
+                if let Some(&(distance, slot)) = self.locals.get(&expr) {
+                    Environment::assign_slot(&self.environment, distance, slot, Rc::clone(&value));
+                } else {
+                    // An undefined target is the name's fault, not the whole
+                    // assignment's.
+                    (*self.globals)
+                        .borrow_mut()
+                        .assign(name, Some(Rc::clone(&value)))
+                        .map_err(|e| e.with_span(Span::of(name)))?;
+                }
                 Ok(value)
             }
             Expr::Binary {
                 left,
                 operator,
                 right,
-            } => self.interpret_expr_binary(Rc::clone(left), Rc::clone(operator), Rc::clone(right)),
+                ..
+            } => self.interpret_expr_binary(*left, Rc::clone(operator), *right),
             Expr::Call {
                 callee,
                 paren,
                 arguments,
+                ..
             } => {
-                let eval_callee = self.evaluate(Rc::clone(callee))?;
-
-                let mut eval_arguments: Vec<Rc<ExprValue>> = Vec::with_capacity(arguments.len());
-                let arg_len = arguments.len();
-                for argument in arguments.iter() {
-                    eval_arguments.push(self.evaluate(Rc::clone(argument))?);
+                let (function, arguments) = self.prepare_call(*callee, paren, arguments)?;
+                self.call(&function, paren, arguments)
+            }
+            Expr::Get { object, name, .. } => {
+                let object = self.evaluate(*object)?;
+                Method::get(object, name)
+            }
+            Expr::Grouping {
+                expression: expr, ..
+            } => self.evaluate(*expr),
+            Expr::Index {
+                object,
+                bracket,
+                index,
+                ..
+            } => {
+                let object = self.evaluate(*object)?;
+                let index = self.evaluate(*index)?;
+                Interpreter::get_index(&object, &index, bracket)
+            }
+            Expr::IndexAssign {
+                object,
+                bracket,
+                index,
+                value,
+                ..
+            } => {
+                let object = self.evaluate(*object)?;
+                let index = self.evaluate(*index)?;
+                let value = self.evaluate(*value)?;
+                Interpreter::set_index(&object, &index, Rc::clone(&value), bracket)?;
+                Ok(value)
+            }
+            Expr::ListLiteral { elements, .. } => {
+                let mut values = Vec::with_capacity(elements.len());
+                for &element in elements {
+                    values.push(self.evaluate(element)?);
                 }
-                let function = match &*eval_callee.borrow() {
-                    ExprValue::LoxCallable(function) => function,
-                    _ => {
-                        return Err(LoxError::RuntimeError {
-                            token: Rc::clone(paren),
-                            message: String::from("Can only call functions and classes."),
-                        });
-                    }
-                };
-                let arity = function.arity();
-                if arg_len != arity {
-                    return Err(LoxError::RuntimeError {
-                        token: Rc::clone(paren),
-                        message: format!("Expected {} arguments but got {}.", arity, arg_len),
-                    });
+                Ok(Rc::from(ExprValue::List(Rc::new(RefCell::new(values)))))
+            }
+            Expr::Interpolation { parts, .. } => {
+                let mut builder = String::new();
+                for &part in parts {
+                    builder.push_str(&Interpreter::stringify(self.evaluate(part)?));
                 }
-                Ok(function.call(self, eval_arguments)?)
+                Ok(Rc::from(ExprValue::Literal(Literal::STRING(builder))))
             }
-            Expr::Grouping(expr) => self.evaluate(Rc::clone(expr)),
-            Expr::Literal(literal) => Ok(Rc::from(ExprValue::Literal(literal.clone()))),
+            Expr::Literal { value: literal, .. } => Ok(match literal {
+                Literal::NIL => self.nil(),
+                Literal::BOOL(value) => self.boolean(*value),
+                _ => Rc::from(ExprValue::Literal(literal.clone())),
+            }),
             Expr::Logical {
                 left,
                 operator,
                 right,
+                ..
             } => {
-                let left = self.evaluate(Rc::clone(left))?;
+                let left = self.evaluate(*left)?;
                 if matches!(operator.type_, TokenType::OR) {
                     if Interpreter::is_truthy(&left) {
                         return Ok(Rc::clone(&left));
@@ -278,16 +2794,30 @@ impl Interpreter {
                         return Ok(Rc::clone(&left));
                     }
                 }
-                Ok(self.evaluate(Rc::clone(right))?)
+                Ok(self.evaluate(*right)?)
             }
-            Expr::Unary { operator, right } => {
-                self.interpret_expr_unary(Rc::clone(operator), Rc::clone(right))
+            Expr::Postfix {
+                operator: _,
+                target,
+                update,
+                ..
+            } => {
+                let old = self.evaluate(*target)?;
+                self.evaluate(*update)?;
+                Ok(old)
             }
-            Expr::Variable { name } => self.lookup_variable(name, &expr),
+            Expr::Unary {
+                operator, right, ..
+            } => self.interpret_expr_unary(Rc::clone(operator), *right),
+            Expr::Variable { name, .. } => self.lookup_variable(name, expr),
         }
     }
-    fn interpret_expr_unary(&mut self, operator: RcToken, right: RcExpr) -> ExprValueResult {
+    fn interpret_expr_unary(&mut self, operator: RcToken, right: ExprId) -> ExprValueResult {
         let res = self.evaluate(right)?;
+        self.unary(&operator, res)
+    }
+    // What a unary operator makes of its evaluated operand; the VM shares it.
+    pub(crate) fn unary(&self, operator: &RcToken, res: Rc<ExprValue>) -> ExprValueResult {
         return match operator.type_ {
             TokenType::MINUS => {
                 if let Some(num) = res.get_number() {
@@ -295,60 +2825,90 @@ impl Interpreter {
                 }
                 return operand_err!(operator);
             }
-            TokenType::BANG => Ok(Rc::from(ExprValue::Literal(Literal::BOOL(
-                !Interpreter::is_truthy(&res),
-            )))),
+            TokenType::BANG => Ok(self.boolean(!Interpreter::is_truthy(&res))),
             _ => unreachable!("Invalid unary operator"),
         };
     }
     fn interpret_expr_binary(
         &mut self,
-        left: RcExpr,
+        left: ExprId,
         operator: RcToken,
-        right: RcExpr,
+        right: ExprId,
     ) -> ExprValueResult {
         let res_left = self.evaluate(left)?;
         let res_right = self.evaluate(right)?;
-        macro_rules! binary_op_numeric_generic {
-            ($op:tt, $type_:tt) => {
+        self.binary(&operator, res_left, res_right)
+    }
+    // What a binary operator makes of its evaluated operands; the VM shares
+    // it.
+    pub(crate) fn binary(
+        &self,
+        operator: &RcToken,
+        res_left: Rc<ExprValue>,
+        res_right: Rc<ExprValue>,
+    ) -> ExprValueResult {
+        // The comma operator only evaluates its left operand for effects.
+        if matches!(operator.type_, TokenType::COMMA) {
+            return Ok(res_right);
+        }
+        macro_rules! binary_num_op {
+            ($op:tt) => {
                 if let (Some(num_left), Some(num_right)) = (res_left.get_number(), res_right.get_number()) {
-                    return Ok(Rc::from(ExprValue::Literal(Literal::$type_(num_left $op num_right))));
+                    let result = num_left $op num_right;
+                    if self.strict && !result.is_finite() && num_left.is_finite() && num_right.is_finite() {
+                        return Err(LoxError::RuntimeError {
+                            token: Rc::clone(&operator),
+                            message: String::from("Arithmetic overflow."),
+                            span: None,
+                        });
+                    }
+                    return Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(result))));
                 }
             };
         }
-        macro_rules! binary_num_op {
-            ($op:tt) => {
-                binary_op_numeric_generic!($op, NUMBER)
+        // Dividing by zero would quietly produce inf or NaN.
+        macro_rules! check_divisor {
+            () => {
+                if res_left.get_number().is_some() && res_right.get_number() == Some(0.0) {
+                    return Err(LoxError::RuntimeError {
+                        token: Rc::clone(&operator),
+                        message: String::from("Division by zero."),
+                        span: None,
+                    });
+                }
             };
         }
+        // Numbers compare numerically, strings lexicographically by scalar value.
         macro_rules! binary_bool_op {
             ($op:tt) => {
-                binary_op_numeric_generic!($op, BOOL)
+                if let (Some(num_left), Some(num_right)) = (res_left.get_number(), res_right.get_number()) {
+                    return Ok(self.boolean(num_left $op num_right));
+                }
+                if let (Some(str_left), Some(str_right)) = (res_left.get_string(), res_right.get_string()) {
+                    return Ok(self.boolean(str_left $op str_right));
+                }
+                return Err(LoxError::RuntimeError {
+                    token: Rc::clone(&operator),
+                    message: format!("{:?} operands must be two numbers or two strings", operator.type_),
+                    span: None,
+                });
             };
         }
         match operator.type_ {
             TokenType::GREATER => {
                 binary_bool_op!(>);
-                return operand_err!(operator);
             }
             TokenType::GREATER_EQUAL => {
                 binary_bool_op!(>=);
-                return operand_err!(operator);
             }
             TokenType::LESS => {
                 binary_bool_op!(<);
-                return operand_err!(operator);
             }
             TokenType::LESS_EQUAL => {
                 binary_bool_op!(<=);
-                return operand_err!(operator);
             }
-            TokenType::BANG_EQUAL => Ok(Rc::from(ExprValue::Literal(Literal::BOOL(
-                res_left != res_right,
-            )))),
-            TokenType::EQUAL_EQUAL => Ok(Rc::from(ExprValue::Literal(Literal::BOOL(
-                res_left == res_right,
-            )))),
+            TokenType::BANG_EQUAL => Ok(self.boolean(res_left != res_right)),
+            TokenType::EQUAL_EQUAL => Ok(self.boolean(res_left == res_right)),
             TokenType::MINUS => {
                 binary_num_op!(-);
                 return operand_err!(operator);
@@ -363,44 +2923,276 @@ impl Interpreter {
                     ))));
                 }
                 return Err(LoxError::RuntimeError {
-                    token: Rc::clone(&operator),
+                    token: Rc::clone(operator),
                     message: format!("{:?} operand must be numbers or strings", operator.type_),
+                    span: None,
                 });
             }
             TokenType::SLASH => {
+                check_divisor!();
                 binary_num_op!(/);
                 return operand_err!(operator);
             }
             TokenType::STAR => {
                 binary_num_op!(*);
+                let repeat = match (res_left.get_string(), res_right.get_string()) {
+                    (Some(s), None) => res_right.get_number().map(|n| (s, n)),
+                    (None, Some(s)) => res_left.get_number().map(|n| (s, n)),
+                    _ => None,
+                };
+                if let Some((s, count)) = repeat {
+                    if count < 0.0 || count.fract() != 0.0 {
+                        return Err(LoxError::RuntimeError {
+                            token: Rc::clone(operator),
+                            message: String::from(
+                                "String repeat count must be a non-negative integer.",
+                            ),
+                            span: None,
+                        });
+                    }
+                    // `as` saturates, so a huge count still fails the check.
+                    let count = count as usize;
+                    match s.len().checked_mul(count) {
+                        Some(length) if length <= MAX_STRING_LENGTH => {}
+                        _ => {
+                            return Err(LoxError::RuntimeError {
+                                token: Rc::clone(operator),
+                                message: String::from("String repeat result is too long."),
+                                span: None,
+                            })
+                        }
+                    }
+                    return Ok(Rc::from(ExprValue::Literal(Literal::STRING(
+                        s.repeat(count),
+                    ))));
+                }
+                return operand_err!(operator);
+            }
+            TokenType::PERCENT => {
+                check_divisor!();
+                binary_num_op!(%);
                 return operand_err!(operator);
             }
+            TokenType::AMPERSAND
+            | TokenType::PIPE
+            | TokenType::CARET
+            | TokenType::LESS_LESS
+            | TokenType::GREATER_GREATER => Interpreter::bitwise(operator, &res_left, &res_right),
             _ => unreachable!("invalid binary operator"),
         }
     }
-    fn is_truthy(expr_value: &Rc<ExprValue>) -> bool {
+    // Bitwise operators work on the i64 value of operands that are exact
+    // integers.
+    fn bitwise(operator: &RcToken, left: &ExprValue, right: &ExprValue) -> ExprValueResult {
+        let integer = |value: &ExprValue| {
+            value
+                .get_number()
+                .filter(|n| n.fract() == 0.0 && n.abs() < i64::MAX as f64)
+                .map(|n| n as i64)
+        };
+        let (left, right) = match (integer(left), integer(right)) {
+            (Some(left), Some(right)) => (left, right),
+            _ => {
+                return Err(LoxError::RuntimeError {
+                    token: Rc::clone(operator),
+                    message: format!("Operands of '{}' must be integers.", operator.lexeme),
+                    span: None,
+                })
+            }
+        };
+        let shift = || {
+            u32::try_from(right)
+                .ok()
+                .filter(|&bits| bits < i64::BITS)
+                .ok_or_else(|| LoxError::RuntimeError {
+                    token: Rc::clone(operator),
+                    message: String::from("Shift amount must be between 0 and 63."),
+                    span: None,
+                })
+        };
+        let result = match operator.type_ {
+            TokenType::AMPERSAND => left & right,
+            TokenType::PIPE => left | right,
+            TokenType::CARET => left ^ right,
+            TokenType::LESS_LESS => left << shift()?,
+            TokenType::GREATER_GREATER => left >> shift()?,
+            _ => unreachable!("invalid bitwise operator"),
+        };
+        Ok(Rc::from(ExprValue::Literal(Literal::NUMBER(result as f64))))
+    }
+    pub(crate) fn get_index(
+        object: &ExprValue,
+        index: &ExprValue,
+        bracket: &RcToken,
+    ) -> ExprValueResult {
+        match object {
+            ExprValue::List(list) => {
+                let list = (**list).borrow();
+                let i = Interpreter::list_index(index, list.len(), bracket)?;
+                Ok(Rc::clone(&list[i]))
+            }
+            // Missing keys read as nil.
+            ExprValue::Map(map) => Ok((**map)
+                .borrow()
+                .get(Interpreter::map_key(index, bracket)?)
+                .cloned()
+                .unwrap_or_else(|| Rc::from(ExprValue::Literal(Literal::NIL)))),
+            _ => Err(LoxError::RuntimeError {
+                token: Rc::clone(bracket),
+                message: String::from("Only lists and maps can be indexed."),
+                span: None,
+            }),
+        }
+    }
+    pub(crate) fn set_index(
+        object: &ExprValue,
+        index: &ExprValue,
+        value: Rc<ExprValue>,
+        bracket: &RcToken,
+    ) -> VoidResult {
+        match object {
+            ExprValue::List(list) => {
+                let mut list = list.borrow_mut();
+                let i = Interpreter::list_index(index, list.len(), bracket)?;
+                list[i] = value;
+            }
+            ExprValue::Map(map) => {
+                let key = Interpreter::map_key(index, bracket)?.clone();
+                map.borrow_mut().insert(key, value);
+            }
+            _ => {
+                return Err(LoxError::RuntimeError {
+                    token: Rc::clone(bracket),
+                    message: String::from("Only lists and maps can be indexed."),
+                    span: None,
+                })
+            }
+        }
+        Ok(())
+    }
+    fn map_key<'a>(index: &'a ExprValue, bracket: &RcToken) -> Result<&'a Literal, LoxError> {
+        match index {
+            // A NaN key could never be looked up again.
+            ExprValue::Literal(Literal::NUMBER(n)) if n.is_nan() => Err(LoxError::RuntimeError {
+                token: Rc::clone(bracket),
+                message: String::from("Map keys can't be NaN."),
+                span: None,
+            }),
+            ExprValue::Literal(literal) => Ok(literal),
+            _ => Err(LoxError::RuntimeError {
+                token: Rc::clone(bracket),
+                message: String::from("Map keys must be numbers, strings, booleans or nil."),
+                span: None,
+            }),
+        }
+    }
+    fn list_index(index: &ExprValue, len: usize, bracket: &RcToken) -> Result<usize, LoxError> {
+        let i = match index.get_number() {
+            Some(i) if i.fract() == 0.0 => i,
+            _ => {
+                return Err(LoxError::RuntimeError {
+                    token: Rc::clone(bracket),
+                    message: String::from("List index must be an integer."),
+                    span: None,
+                })
+            }
+        };
+        if i < 0.0 || i >= len as f64 {
+            return Err(LoxError::RuntimeError {
+                token: Rc::clone(bracket),
+                message: format!("List index {} out of range for length {}.", i, len),
+                span: None,
+            });
+        }
+        Ok(i as usize)
+    }
+    // As in Ruby and the book, only nil and false are falsey. 0, "", empty
+    // lists and maps are all true.
+    pub fn is_truthy(expr_value: &Rc<ExprValue>) -> bool {
         match expr_value.borrow() {
             ExprValue::Literal(Literal::NIL) => false,
             ExprValue::Literal(Literal::BOOL(b)) => b != &false,
             _ => true,
         }
     }
-    fn stringify(object: Rc<ExprValue>) -> String {
-        match object.borrow() {
-            ExprValue::Literal(l) => l.to_string(),
-            ExprValue::LoxCallable(c) => c.to_string(),
+    pub(crate) fn stringify(object: Rc<ExprValue>) -> String {
+        value::display(&object, false)
+    }
+    pub fn resolve(&mut self, expr: ExprId, depth: usize, slot: usize) {
+        self.locals.insert(expr, (depth, slot));
+    }
+    // Carries what the resolver found about `from` over to `to`, the same
+    // expression rebuilt by the optimizer.
+    pub(crate) fn moved(&mut self, from: ExprId, to: ExprId) {
+        if let Some(&local) = self.locals.get(&from) {
+            self.locals.insert(to, local);
         }
     }
-    pub fn resolve(&mut self, expr: &RcExpr, depth: usize) {
-        self.locals.insert(Rc::clone(expr), depth);
+    pub fn resolved_depth(&self, expr: ExprId) -> Option<usize> {
+        self.locals.get(&expr).map(|&(depth, _)| depth)
     }
-    fn lookup_variable(&mut self, name: &RcToken, expr: &RcExpr) -> ExprValueResult {
-        let distance = self.locals.get(expr);
-        println!("{:?}", distance);
-        if let Some(d) = distance {
-            Environment::get_at(&self.environment, *d, name)
+    fn lookup_variable(&mut self, name: &RcToken, expr: ExprId) -> ExprValueResult {
+        if let Some(&(distance, slot)) = self.locals.get(&expr) {
+            Ok(Environment::get_slot(&self.environment, distance, slot))
         } else {
             (*self.globals).borrow().get(name)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iso_8601_dates() {
+        let cases = [
+            (0, 0, "1970-01-01T00:00:00.000Z"),
+            (951_782_400, 5, "2000-02-29T00:00:00.005Z"),
+            (1_709_211_909, 250, "2024-02-29T13:05:09.250Z"),
+            (1_735_689_599, 999, "2024-12-31T23:59:59.999Z"),
+            (4_102_444_800, 0, "2100-01-01T00:00:00.000Z"),
+        ];
+        for (seconds, millis, expected) in cases {
+            let time = Duration::from_secs(seconds) + Duration::from_millis(millis);
+            assert_eq!(iso_8601(time), expected, "{}", seconds);
+        }
+    }
+
+    #[test]
+    fn arity_checks() {
+        let cases = [
+            (Arity::Exact(2), 2, None),
+            (Arity::Exact(2), 1, Some("Expected 2 arguments but got 1.")),
+            (Arity::Exact(1), 0, Some("Expected 1 argument but got 0.")),
+            (Arity::Range(1, 2), 1, None),
+            (Arity::Range(1, 2), 2, None),
+            (
+                Arity::Range(1, 2),
+                0,
+                Some("Expected at least 1 argument but got 0."),
+            ),
+            (
+                Arity::Range(1, 2),
+                3,
+                Some("Expected at most 2 arguments but got 3."),
+            ),
+            (Arity::Variadic(0), 0, None),
+            (Arity::Variadic(1), 7, None),
+            (
+                Arity::Variadic(2),
+                1,
+                Some("Expected at least 2 arguments but got 1."),
+            ),
+        ];
+        for (arity, got, expected) in cases {
+            assert_eq!(
+                arity.check(got).err().as_deref(),
+                expected,
+                "{:?} with {}",
+                arity,
+                got
+            );
+        }
+    }
+}
@@ -0,0 +1,43 @@
+mod arena;
+#[cfg(test)]
+mod ast_builder;
+mod ast_printer;
+mod compiler;
+mod debugger;
+mod environment;
+mod expr;
+mod format_string;
+mod formatter;
+mod gc;
+mod interpreter;
+mod lox;
+mod lox_function;
+mod optimizer;
+mod parser;
+mod profiler;
+mod random;
+mod reporter;
+mod resolver;
+mod scanner;
+mod span;
+mod stmt;
+mod token;
+mod token_type;
+mod value;
+mod visitor;
+mod vm;
+
+pub use crate::arena::AstArena;
+pub use crate::debugger::DebugHook;
+pub use crate::expr::{Expr, ExprId};
+pub use crate::formatter::{expr_to_source, stmt_to_source};
+pub use crate::interpreter::{Arity, ExprValue, Interpreter, TimeSource, TraceLevel};
+pub use crate::lox::{eval, ExitCode, Lox, LoxError, Mode};
+pub use crate::parser::Parser;
+pub use crate::reporter::Reporter;
+pub use crate::resolver::Resolver;
+pub use crate::scanner::Scanner;
+pub use crate::stmt::{Parameter, Stmt, StmtId};
+pub use crate::token::{Literal, RcToken, Token};
+pub use crate::token_type::TokenType;
+pub use crate::visitor::{walk_expr, walk_stmt, ExprVisitor, StmtVisitor};
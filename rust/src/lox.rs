@@ -1,84 +1,489 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Display;
 use std::fs;
 use std::io;
-use std::io::Write;
-use std::process;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
 // use crate::ast_printer::ast_to_string;
+use crate::arena::AstArena;
+use crate::ast_printer::resolved_stmt_to_string;
+use crate::compiler;
 // use crate::expr::Expr;
-use crate::interpreter::{ExprValue, SharedInterpreter};
+use crate::interpreter::{ExprValue, Interpreter, SharedInterpreter};
+use crate::optimizer;
 use crate::parser::Parser;
+use crate::reporter::{Phase, Reporter};
 use crate::resolver::Resolver;
 use crate::scanner::Scanner;
-use crate::token::RcToken;
+use crate::span::Span;
+use crate::stmt::{Stmt, StmtId};
+use crate::token::{Literal, RcToken};
 use crate::token_type::TokenType;
+use crate::vm::Vm;
 
-#[allow(dead_code)]
+/// An error from scanning, parsing, resolving or running a program.
 #[derive(Debug, Clone)]
-pub enum LoxError<T: Display> {
-    ScanError { line: usize, message: T },
-    ParseError { token: RcToken, message: T },
-    RuntimeError { token: RcToken, message: T },
-    ReturnValue { value: Rc<ExprValue> },
+pub enum LoxError {
+    ScanError {
+        line: usize,
+        column: usize,
+        file: Option<Rc<str>>,
+        message: String,
+    },
+    ParseError {
+        token: RcToken,
+        message: String,
+    },
+    RuntimeError {
+        token: RcToken,
+        message: String,
+        // The innermost expression being evaluated when the error happened.
+        span: Option<Span>,
+    },
+    // A `throw` no `catch` has caught (yet). `message` is the value as
+    // `print` would show it.
+    Thrown {
+        token: RcToken,
+        value: Rc<ExprValue>,
+        message: String,
+        span: Option<Span>,
+    },
+    // `exit(code)`: not a failure, but it unwinds the program the same way
+    // and no `catch` stops it.
+    Exit {
+        code: u8,
+    },
 }
 
-// for debugging only
-impl<T: Display> Display for LoxError<T> {
+impl LoxError {
+    pub fn message(&self) -> &str {
+        match self {
+            LoxError::ScanError { message, .. }
+            | LoxError::ParseError { message, .. }
+            | LoxError::RuntimeError { message, .. }
+            | LoxError::Thrown { message, .. } => message,
+            LoxError::Exit { .. } => "Exit requested.",
+        }
+    }
+    // The name of the source the error is in, if it has one.
+    pub fn file(&self) -> Option<&Rc<str>> {
+        match self {
+            LoxError::ScanError { file, .. } => file.as_ref(),
+            LoxError::ParseError { token, .. }
+            | LoxError::RuntimeError { token, .. }
+            | LoxError::Thrown { token, .. } => token.file.as_ref(),
+            LoxError::Exit { .. } => None,
+        }
+    }
+    // Formats the error for a user, quoting the offending line of `source`
+    // with a caret under the reported position. Runtime errors keep jlox's
+    // two-line format.
+    pub fn render(&self, source: &str) -> String {
+        match self {
+            LoxError::ScanError { line, column, .. } => {
+                format!("{}\n{}", self, snippet(source, *line, *column, 1))
+            }
+            LoxError::ParseError { token, .. } => {
+                if matches!(token.type_, TokenType::EOF) {
+                    let (line, column) = end_of_input(source);
+                    return format!(
+                        "{} Error at end: {}\n{}",
+                        heading(&token.file, line),
+                        self.message(),
+                        snippet(source, line, column, 1)
+                    );
+                }
+                // Multi-line strings carry the line they end on.
+                let first_line = token.lexeme.split('\n').next().unwrap_or("");
+                let line = token.line - token.lexeme.matches('\n').count();
+                format!(
+                    "{}\n{}",
+                    self,
+                    snippet(source, line, token.column, first_line.chars().count())
+                )
+            }
+            LoxError::RuntimeError { span, .. } | LoxError::Thrown { span, .. } => {
+                let mut rendered = format!("{}\n", self);
+                if let Some(span) = span {
+                    rendered.push_str(&span_snippet(source, span));
+                }
+                rendered
+            }
+            LoxError::Exit { .. } => format!("{}\n", self),
+        }
+    }
+    // The line and column the error points at in `source`, as `render`
+    // underlines it.
+    pub fn position(&self, source: &str) -> (usize, usize) {
+        match self {
+            LoxError::ScanError { line, column, .. } => (*line, *column),
+            LoxError::ParseError { token, .. } if matches!(token.type_, TokenType::EOF) => {
+                end_of_input(source)
+            }
+            // Multi-line strings carry the line they end on.
+            LoxError::ParseError { token, .. } => (
+                token.line - token.lexeme.matches('\n').count(),
+                token.column,
+            ),
+            LoxError::RuntimeError {
+                span: Some(span), ..
+            }
+            | LoxError::Thrown {
+                span: Some(span), ..
+            } => (span.start_line, span.start_col),
+            LoxError::RuntimeError { token, .. } | LoxError::Thrown { token, .. } => {
+                (token.line, token.column)
+            }
+            LoxError::Exit { .. } => (0, 0),
+        }
+    }
+    // Records where a runtime error happened, unless a more deeply nested
+    // expression already has.
+    pub fn with_span(self, span: Span) -> LoxError {
+        match self {
+            LoxError::RuntimeError {
+                token,
+                message,
+                span: None,
+            } if !span.is_synthetic() => LoxError::RuntimeError {
+                token,
+                message,
+                span: Some(span),
+            },
+            other => other,
+        }
+    }
+}
+
+// The quoted source line and a caret line, e.g.
+//   3 | print (1 + 2));
+//     |              ^
+// A `column` of 0 means the position is unknown and only the line is shown.
+fn snippet(source: &str, line: usize, column: usize, width: usize) -> String {
+    let text = match source.lines().nth(line.wrapping_sub(1)) {
+        Some(text) => text,
+        None => return String::new(),
+    };
+    let gutter = " ".repeat(line.to_string().len());
+    let mut rendered = format!("{} | {}\n", line, text);
+    if column == 0 {
+        return rendered;
+    }
+    let mut offset = (column - 1).min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let padding: String = text[..offset]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    rendered.push_str(&format!(
+        "{} | {}{}\n",
+        gutter,
+        padding,
+        "^".repeat(width.max(1))
+    ));
+    rendered
+}
+
+// Underlines `span` on its first line; the rest of a multi-line span is
+// left out.
+fn span_snippet(source: &str, span: &Span) -> String {
+    let width = span_text(source, span).chars().count();
+    snippet(source, span.start_line, span.start_col, width)
+}
+
+// The source `span` covers, up to the end of its first line.
+fn span_text<'a>(source: &'a str, span: &Span) -> &'a str {
+    if span.is_synthetic() {
+        return "";
+    }
+    let text = source.lines().nth(span.start_line - 1).unwrap_or("");
+    let end = if span.end_line == span.start_line {
+        span.end_col.min(text.len())
+    } else {
+        text.len()
+    };
+    text.get(span.start_col - 1..end).unwrap_or("")
+}
+
+// Line and column just past the last non-blank character of `source`, so
+// errors at EOF point at the end of the program rather than an empty line.
+fn end_of_input(source: &str) -> (usize, usize) {
+    let end = source.trim_end().len();
+    let line = source[..end].matches('\n').count() + 1;
+    let line_start = source[..end].rfind('\n').map_or(0, |newline| newline + 1);
+    (line, end - line_start + 1)
+}
+
+// Where a diagnostic points: "script.lox:12" when the source has a name,
+// jlox's "[line 12]" when it doesn't.
+fn location(file: &Option<Rc<str>>, line: usize) -> String {
+    match file {
+        Some(file) => format!("{}:{}", file, line),
+        None => format!("[line {}]", line),
+    }
+}
+
+// `location` as the start of a compile error or warning, which reads
+// "script.lox:12: Error ..." but "[line 12] Error ...".
+pub(crate) fn heading(file: &Option<Rc<str>>, line: usize) -> String {
+    match file {
+        Some(_) => format!("{}:", location(file, line)),
+        None => location(file, line),
+    }
+}
+
+// The first line of `render`, without the quoted source. Runtime errors
+// keep jlox's two-line format.
+impl Display for LoxError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            LoxError::ScanError { line, message } => {
-                let location = 0;
-                write!(f, "[line {}] Error {}: {}", line, location, message)
-            }
-            LoxError::RuntimeError { token, message } => {
-                let location = 0;
-                write!(f, "[line {}] Error {}: {}", token.line, location, message)
-            }
-            // LoxError::RuntimeError { expr, message } => match expr {
-            //     Expr::Binary { left, operator, .. } | Expr::Unary { operator, .. } => write!(
-            //         f,
-            //         "[line {} token {}] Error {}",
-            //         operator.line,
-            //         operator.literal.to_string(),
-            //         message
-            //     ),
-            //     _ => unreachable!(),
-            // },
+            LoxError::ScanError {
+                line,
+                file,
+                message,
+                ..
+            } => {
+                write!(f, "{} Error: {}", heading(file, *line), message)
+            }
             LoxError::ParseError { token, message } => {
-                write!(
-                    f,
-                    "[line {} token {:?}] Error {}",
-                    token.line, token, message
-                )
+                let heading = heading(&token.file, token.line);
+                if matches!(token.type_, TokenType::EOF) {
+                    write!(f, "{} Error at end: {}", heading, message)
+                } else {
+                    write!(f, "{} Error at '{}': {}", heading, token.lexeme, message)
+                }
             }
-            LoxError::ReturnValue { value } => write!(f, "Return {:?}", value),
+            LoxError::RuntimeError { token, message, .. }
+            | LoxError::Thrown { token, message, .. } => {
+                write!(f, "{}\n{}", message, location(&token.file, token.line))
+            }
+            LoxError::Exit { code } => write!(f, "exit requested (code {})", code),
         }
     }
 }
 
+impl std::error::Error for LoxError {}
+
+/// Process exit statuses, following the BSD sysexits.h values jlox uses.
+///
+/// When several results are combined (e.g. one per file) the most severe
+/// wins, in the order Usage > IoError > CompileError > RuntimeError > Exit
+/// > Ok.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Ok,
+    Usage,
+    CompileError,
+    RuntimeError,
+    IoError,
+    /// The status a script chose with `exit(code)`.
+    Exit(u8),
+}
+
+impl ExitCode {
+    fn severity(&self) -> u8 {
+        match self {
+            ExitCode::Ok => 0,
+            ExitCode::Exit(_) => 1,
+            ExitCode::RuntimeError => 2,
+            ExitCode::CompileError => 3,
+            ExitCode::IoError => 4,
+            ExitCode::Usage => 5,
+        }
+    }
+    pub fn combine(self, other: ExitCode) -> ExitCode {
+        if other.severity() > self.severity() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+impl From<ExitCode> for i32 {
+    fn from(code: ExitCode) -> i32 {
+        match code {
+            ExitCode::Ok => 0,
+            ExitCode::Usage => 64,
+            ExitCode::CompileError => 65,
+            ExitCode::RuntimeError => 70,
+            ExitCode::IoError => 74,
+            ExitCode::Exit(code) => code.into(),
+        }
+    }
+}
+
+// How far a source gets through the pipeline. The dump modes stop after
+// printing the scanner or parser output and never run the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Run,
+    Tokens,
+    // Print the resolved tree, each variable marked with the depth it was
+    // resolved to, e.g. `a@1` or `g@global`.
+    Ast,
+    // Compile for the VM and print the bytecode instead of running it.
+    Bytecode,
+}
+
+pub const DEFAULT_MAX_ERRORS: usize = 20;
+pub const DEFAULT_MAX_WARNINGS: usize = 100;
+
+// Runs `source` through a fresh interpreter and returns the value of its final
+// expression statement (nil if there is none). Diagnostics are reported as
+// usual; the error carries the exit code the failure maps to.
+pub fn eval(source: &str) -> Result<ExprValue, ExitCode> {
+    let mut lox = Lox::new();
+    let value = lox.run(source.as_bytes().to_vec());
+    match lox.exit_code() {
+        ExitCode::Ok => Ok(value.map_or(ExprValue::Literal(Literal::NIL), |v| (*v).clone())),
+        code => Err(code),
+    }
+}
+
 pub struct Lox {
     pub had_error: bool,
     pub had_runtime_error: bool,
     pub interpreter: SharedInterpreter,
+    pub mode: Mode,
+    // 0 means unlimited.
+    pub max_errors: usize,
+    error_count: usize,
+    suppressed_errors: usize,
+    pub max_warnings: usize,
+    // Dumps where the resolver found each variable to the diagnostics.
+    pub debug_resolver: bool,
+    // Folds constant expressions once resolved; off with --no-fold.
+    pub fold: bool,
+    // Drops branches that constant conditions keep from running when
+    // nothing is folded, with --optimize; folding drops them anyway.
+    // They're warned about either way.
+    pub optimize: bool,
+    // Runs programs on the bytecode VM instead of walking the tree, with
+    // --vm.
+    pub vm: bool,
+    // How diagnostics are formatted: for people, or as JSON lines with
+    // --json-errors.
+    pub reporter: Reporter,
+    warning_count: usize,
+    suppressed_warnings: usize,
+    // Every error reported since the last run, rendered or not.
+    errors: Vec<LoxError>,
+    // Where diagnostics go; stderr unless an embedder supplies a sink.
+    error_output: Box<dyn Write>,
+    // Source of the current run, quoted in diagnostics.
+    source: String,
+    // What the current source is called in diagnostics, e.g. "<stdin>" or
+    // a script's path. Without one they only give line numbers.
+    source_name: Option<Rc<str>>,
+    // The source of each imported file by its name, for quoting runtime
+    // errors raised in it.
+    module_sources: HashMap<Rc<str>, String>,
+    // The code passed to `exit()` since the last run, if it was called.
+    exit_requested: Option<u8>,
+    // The files being loaded, each imported by the one before, as canonical
+    // paths and the names diagnostics give them. A script run from a file
+    // is first.
+    import_chain: Vec<(PathBuf, Rc<str>)>,
+}
+
+impl Default for Lox {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Lox {
-    pub fn run_file(&mut self, path: &String) {
-        let contents = fs::read_to_string(path)
-            .expect("Couldn't read file.")
-            .into_bytes();
+    pub fn new() -> Self {
+        Lox::with_output(Box::new(io::stdout()), Box::new(io::stderr()))
+    }
+    // Sends program output to `output` and diagnostics to `error_output`.
+    pub fn with_output(output: Box<dyn Write>, error_output: Box<dyn Write>) -> Self {
+        Lox {
+            had_error: false,
+            had_runtime_error: false,
+            interpreter: Rc::from(RefCell::from(Interpreter::with_output(output))),
+            mode: Mode::Run,
+            max_errors: DEFAULT_MAX_ERRORS,
+            error_count: 0,
+            suppressed_errors: 0,
+            max_warnings: DEFAULT_MAX_WARNINGS,
+            debug_resolver: false,
+            fold: true,
+            optimize: false,
+            vm: false,
+            reporter: Reporter::Human,
+            warning_count: 0,
+            suppressed_warnings: 0,
+            errors: Vec::new(),
+            error_output,
+            source: String::new(),
+            source_name: None,
+            module_sources: HashMap::new(),
+            exit_requested: None,
+            import_chain: Vec::new(),
+        }
+    }
+    pub fn run_file(&mut self, path: &String) -> ExitCode {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                writeln!(self.error_output, "Couldn't read file '{}': {}", path, e)
+                    .expect("Couldn't write diagnostics");
+                return ExitCode::IoError;
+            }
+        };
+        // Imports are found relative to the script.
+        let script = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        let name: Rc<str> = Rc::from(path.as_str());
+        self.import_chain.push((script, Rc::clone(&name)));
+        let outer = self.source_name.replace(name);
+        let code = self.run_program(contents);
+        self.source_name = outer;
+        self.import_chain.pop();
+        code
+    }
+    // Names the source of later runs in diagnostics, e.g. "<stdin>", or
+    // stops naming it with None. `run_file` uses the script's path.
+    pub fn set_source_name(&mut self, name: Option<&str>) {
+        self.source_name = name.map(Rc::from);
+    }
+    // Runs everything `reader` yields as one program, e.g. a script piped
+    // to stdin.
+    pub fn run_reader(&mut self, mut reader: impl Read) -> ExitCode {
+        let mut contents = Vec::new();
+        if let Err(e) = reader.read_to_end(&mut contents) {
+            writeln!(self.error_output, "Couldn't read input: {}", e)
+                .expect("Couldn't write diagnostics");
+            return ExitCode::IoError;
+        }
+        self.run_program(contents)
+    }
+    fn run_program(&mut self, contents: Vec<u8>) -> ExitCode {
         self.run(contents);
+        self.report_suppressed();
+        self.exit_code()
+    }
+    pub fn exit_code(&self) -> ExitCode {
         if self.had_error {
-            process::exit(65);
-        }
-        if self.had_runtime_error {
-            process::exit(70);
+            ExitCode::CompileError
+        } else if self.had_runtime_error {
+            ExitCode::RuntimeError
+        } else if let Some(code) = self.exit_requested {
+            ExitCode::Exit(code)
+        } else {
+            ExitCode::Ok
         }
     }
-    pub fn run_prompt(&mut self) {
+    pub fn run_prompt(&mut self) -> ExitCode {
         println!("Lox tree-walk interpreter");
+        self.set_source_name(Some("<repl>"));
         loop {
             print!("> ");
             io::stdout().flush().expect("Couldn't flush print buffer");
@@ -89,58 +494,445 @@ impl Lox {
             // println!();
             if line.is_empty() {
                 println!("Exit");
-                break;
+                return ExitCode::Ok;
             }
-            self.run(line.into_bytes());
-            self.had_error = false;
+            self.run_repl_line(line);
         }
     }
-    fn run(&mut self, source: Vec<u8>) {
-        let mut scanner = Scanner::new(source);
-        if let Err(err) = scanner.scan_tokens() {
-            self.error(err);
+    // Runs one REPL line against the session's interpreter, so definitions
+    // persist until `:reset`.
+    pub fn run_repl_line(&mut self, line: String) {
+        if line.trim() == ":env" {
+            print!("{}", self.interpreter.borrow().dump_environment());
             return;
         }
-
-        let tokens = scanner.tokens;
-        let mut parser = Parser::new(self, tokens);
-        let res = parser.parse();
-        if let Err(e) = res {
-            self.error(e);
+        if line.trim() == ":reset" {
+            self.interpreter.borrow_mut().reset();
+            println!("Session reset.");
             return;
         }
-        let expr = res.unwrap();
-        let mut resolver = Resolver::new(&self.interpreter);
-        if let Err(e) = resolver.resolve_statements(&expr) {
-            self.error(e);
-            return;
+        self.run(line.into_bytes());
+        self.report_suppressed();
+        // The session outlives the script's request to end it.
+        if let Some(code) = self.exit_requested {
+            println!("{}", LoxError::Exit { code });
+        }
+        self.clear_errors();
+    }
+    // Runs a program for an embedder, handing back every error it reported.
+    pub fn run_source(&mut self, source: &str) -> Result<(), Vec<LoxError>> {
+        self.clear_errors();
+        self.run(source.as_bytes().to_vec());
+        self.report_suppressed();
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+    fn clear_errors(&mut self) {
+        self.had_error = false;
+        self.had_runtime_error = false;
+        self.errors.clear();
+        self.exit_requested = None;
+        self.error_count = 0;
+        self.suppressed_errors = 0;
+        self.warning_count = 0;
+        self.suppressed_warnings = 0;
+    }
+    // Makes `source` the one diagnostics quote and scans it.
+    fn scan(&mut self, source: Vec<u8>) -> Option<Vec<RcToken>> {
+        let text = String::from_utf8_lossy(&source);
+        self.source = String::from(text.strip_prefix('\u{feff}').unwrap_or(&text));
+        let mut scanner = Scanner::new(source);
+        scanner.file = self.source_name.clone();
+        // Scanning goes on past an error, so every one is reported.
+        let mut tokens = Vec::new();
+        let mut failed = false;
+        while let Some(token) = scanner.next_token() {
+            match token {
+                Ok(token) => tokens.push(token),
+                Err(err) => {
+                    self.error(err);
+                    failed = true;
+                }
+            }
         }
-        let res = self.interpreter.borrow_mut().interpret(expr);
+        if failed {
+            return None;
+        }
+        Some(tokens)
+    }
+    fn run(&mut self, source: Vec<u8>) -> Option<Rc<ExprValue>> {
+        let tokens = self.scan(source)?;
+        if self.mode == Mode::Tokens {
+            for token in tokens.iter() {
+                println!("{}", token.to_string());
+            }
+            return None;
+        }
+        let statements = self.parse(tokens);
+        if self.had_error {
+            return None;
+        }
+        let imported = self.load_imports(&statements);
+        if self.had_error {
+            return None;
+        }
+        let statements = self.resolve(statements, imported)?;
+        if self.mode == Mode::Ast {
+            let interpreter = self.interpreter.borrow();
+            for &stmt in &statements {
+                println!("{}", resolved_stmt_to_string(&interpreter, stmt));
+            }
+            return None;
+        }
+        if self.vm || self.mode == Mode::Bytecode {
+            self.run_vm(&statements);
+            return None;
+        }
+        let res = self.interpreter.borrow_mut().interpret(statements);
         // println!("{}", ast_to_string(Box::new(expr)))
-        if let Err(e) = res {
-            self.error(e)
+        match res {
+            Ok(value) => value,
+            Err(e) => {
+                self.error(e);
+                None
+            }
+        }
+    }
+    // Compiles resolved statements to bytecode and runs it, or prints it
+    // under --dump.
+    fn run_vm(&mut self, statements: &[StmtId]) {
+        let mut interpreter = self.interpreter.borrow_mut();
+        let compiled = compiler::compile(interpreter.ast(), statements, self.source_name.clone());
+        let result = match compiled {
+            Ok(script) if self.mode == Mode::Bytecode => {
+                print!("{}", compiler::disassemble(&script));
+                return;
+            }
+            Ok(script) => Vm::new(&mut interpreter).run(&script),
+            Err(e) => Err(e),
+        };
+        drop(interpreter);
+        if let Err(e) = result {
+            self.error(e);
+        }
+    }
+    // Parses into the interpreter's arena, where functions from earlier runs
+    // live on.
+    fn parse(&mut self, tokens: Vec<RcToken>) -> Vec<StmtId> {
+        let mut ast = self.interpreter.borrow_mut().take_ast();
+        let statements = Parser::new(self, tokens, &mut ast).parse();
+        self.interpreter.borrow_mut().set_ast(ast);
+        statements
+    }
+    // Runs an optimizer pass over the interpreter's arena, keeping the
+    // resolution of the expressions it rebuilds, and warns about the
+    // constant arithmetic it found overflowing.
+    fn rewrite(&mut self, pass: impl FnOnce(&mut AstArena) -> optimizer::Folded) -> Vec<StmtId> {
+        let folded = {
+            let mut interpreter = self.interpreter.borrow_mut();
+            let mut ast = interpreter.take_ast();
+            let folded = pass(&mut ast);
+            interpreter.set_ast(ast);
+            for &(from, to) in &folded.moved {
+                interpreter.moved(from, to);
+            }
+            folded
+        };
+        for operator in &folded.overflows {
+            self.warning_in(
+                Phase::Resolve,
+                operator,
+                &"Arithmetic overflow in constant expression.",
+            );
+        }
+        folded.statements
+    }
+    // Resolves a file's statements, knowing the globals its imports define,
+    // and returns them folded, or with dead code dropped under --optimize,
+    // unless that failed.
+    fn resolve(&mut self, statements: Vec<StmtId>, imported: Vec<Rc<str>>) -> Option<Vec<StmtId>> {
+        let mut resolver = Resolver::new(&self.interpreter);
+        resolver.debug = self.debug_resolver;
+        resolver.strict = self.interpreter.borrow().strict;
+        resolver.declare_globals(imported);
+        let resolved = resolver.resolve_program(&statements);
+        if self.debug_resolver {
+            write!(self.error_output, "{}", resolver.resolution_table())
+                .expect("Couldn't write diagnostics");
+        }
+        for (token, message) in resolver.take_warnings() {
+            self.warning_in(Phase::Resolve, &token, &message);
+        }
+        match resolved {
+            Ok(()) => {
+                let dead = self.warn_dead_code(&statements, &resolver);
+                Some(if self.fold {
+                    self.rewrite(|ast| optimizer::fold(ast, statements))
+                } else if self.optimize && !dead.is_empty() {
+                    self.rewrite(|ast| optimizer::eliminate(ast, statements, &dead))
+                } else {
+                    statements
+                })
+            }
+            Err(e) => {
+                self.error(e);
+                None
+            }
+        }
+    }
+    // Warns about each branch a constant condition rules out, and returns
+    // the statements they belong to. A name used after the branch that only
+    // the branch declares is pointed out, as a likely mistake.
+    fn warn_dead_code(&mut self, statements: &[StmtId], resolver: &Resolver) -> HashSet<StmtId> {
+        let found = optimizer::find_dead_code(self.interpreter.borrow().ast(), statements);
+        let mut stmts = HashSet::new();
+        for dead in found {
+            self.warning_at(dead.condition, &dead.message);
+            for name in &dead.declarations {
+                let uses: Vec<RcToken> = resolver
+                    .undeclared_uses()
+                    .filter(|used| {
+                        used.lexeme == name.lexeme && !dead.span.contains(Span::of(used))
+                    })
+                    .cloned()
+                    .collect();
+                for token in uses {
+                    let message = format!(
+                        "Undefined variable '{}'; the one declared in code that never runs is out of scope here.",
+                        token.lexeme
+                    );
+                    self.warning_in(Phase::Resolve, &token, &message);
+                }
+            }
+            stmts.insert(dead.stmt);
         }
+        stmts
     }
-    pub fn error<T: Display>(&mut self, err: LoxError<T>) {
+    // Loads the files `statements` import, and the files those import,
+    // unless they're loaded already. Returns every global they define.
+    fn load_imports(&mut self, statements: &[StmtId]) -> Vec<Rc<str>> {
+        let imports: Vec<(StmtId, RcToken)> = {
+            let interpreter = self.interpreter.borrow();
+            let ast = interpreter.ast();
+            statements
+                .iter()
+                .filter_map(|&stmt| match &ast[stmt] {
+                    Stmt::Import { path, .. } => Some((stmt, Rc::clone(path))),
+                    _ => None,
+                })
+                .collect()
+        };
+        let mut globals = Vec::new();
+        for (stmt, token) in imports {
+            if let Some(path) = self.load_module(&token) {
+                let mut interpreter = self.interpreter.borrow_mut();
+                if let Some(module) = interpreter.module(&path) {
+                    globals.extend(module.globals.iter().cloned());
+                }
+                interpreter.link_import(stmt, path);
+            }
+        }
+        globals
+    }
+    // Finds the file an import names, relative to the importing file, and
+    // compiles it if it's new. Returns its canonical path, or None once the
+    // problem has been reported.
+    fn load_module(&mut self, token: &RcToken) -> Option<PathBuf> {
+        let name = match &token.literal {
+            Literal::STRING(name) => name.clone(),
+            _ => unreachable!("import path is a string"),
+        };
+        let fail = |message: String| LoxError::ParseError {
+            token: Rc::clone(token),
+            message,
+        };
+        if !self.interpreter.borrow().fs_access() {
+            let message = String::from("Can't import with file access disabled.");
+            self.error(fail(message));
+            return None;
+        }
+        let (directory, shown) = match self.import_chain.last() {
+            Some((importer, importer_name)) => (
+                importer.parent().unwrap_or(Path::new(".")),
+                // Named as a path from the same place as the importer's.
+                Path::new(&**importer_name)
+                    .parent()
+                    .map_or(PathBuf::from(&name), |parent| parent.join(&name)),
+            ),
+            None => (Path::new("."), PathBuf::from(&name)),
+        };
+        let shown: Rc<str> = Rc::from(&*shown.to_string_lossy());
+        let path = match fs::canonicalize(directory.join(&name)) {
+            Ok(path) => path,
+            Err(e) => {
+                self.error(fail(format!("Couldn't import '{}': {}.", name, e)));
+                return None;
+            }
+        };
+        if self
+            .import_chain
+            .iter()
+            .any(|(importer, _)| *importer == path)
+        {
+            let mut cycle: Vec<&str> = self.import_chain.iter().map(|(_, name)| &**name).collect();
+            cycle.push(&shown);
+            let message = format!("Circular import detected: {}.", cycle.join(" -> "));
+            self.error(fail(message));
+            return None;
+        }
+        if self.interpreter.borrow().module(&path).is_some() {
+            return Some(path);
+        }
+        let source = match fs::read(&path) {
+            Ok(source) => source,
+            Err(e) => {
+                self.error(fail(format!("Couldn't import '{}': {}.", name, e)));
+                return None;
+            }
+        };
+        self.import_chain.push((path.clone(), Rc::clone(&shown)));
+        let importer = (
+            std::mem::take(&mut self.source),
+            self.source_name.replace(Rc::clone(&shown)),
+        );
+        let compiled = self.compile_module(source);
+        let (source, name) = importer;
+        self.module_sources
+            .insert(shown, std::mem::replace(&mut self.source, source));
+        self.source_name = name;
+        self.import_chain.pop();
+        let (statements, globals) = compiled?;
+        self.interpreter
+            .borrow_mut()
+            .add_module(path.clone(), statements, globals);
+        Some(path)
+    }
+    // Scans, parses and resolves an imported file, returning its statements
+    // and the globals it defines. They run later, when the import does.
+    fn compile_module(&mut self, source: Vec<u8>) -> Option<(Vec<StmtId>, Vec<Rc<str>>)> {
+        let tokens = self.scan(source)?;
+        let statements = self.parse(tokens);
+        if self.had_error {
+            return None;
+        }
+        let mut globals = self.load_imports(&statements);
+        if self.had_error {
+            return None;
+        }
+        let statements = self.resolve(statements, globals.clone())?;
+        let interpreter = self.interpreter.borrow();
+        let ast = interpreter.ast();
+        globals.extend(statements.iter().filter_map(|&stmt| match &ast[stmt] {
+            Stmt::Var { name, .. } | Stmt::Function { name, .. } => Some(Rc::clone(&name.lexeme)),
+            _ => None,
+        }));
+        drop(interpreter);
+        Some((statements, globals))
+    }
+    pub fn error(&mut self, err: LoxError) {
+        // Errors raised at runtime may be in any imported file.
+        let source = err
+            .file()
+            .and_then(|file| self.module_sources.get(file))
+            .unwrap_or(&self.source);
+        let diagnostic = self.reporter.error(&err, source);
         match err {
-            LoxError::ScanError { line, message } => self.report(line, &"", &message),
-            LoxError::RuntimeError { token, message } => self.error_token(token, &message),
-            // LoxError::RuntimeError { expr, message } => self.error_runtime(expr, &message),
-            LoxError::ParseError { token, message } => self.error_token(token, &message),
-            LoxError::ReturnValue { value: _ } => unreachable!("Return outside of function?"),
+            LoxError::ScanError { .. } | LoxError::ParseError { .. } => self.report(diagnostic),
+            LoxError::RuntimeError { .. } | LoxError::Thrown { .. } => {
+                self.runtime_error(diagnostic)
+            }
+            LoxError::Exit { code } => self.exit_requested = Some(code),
         }
+        self.errors.push(err);
     }
-    fn report<T: Display, U: Display>(&mut self, line: usize, location: &U, message: &T) {
-        eprintln!("[line {}] Error {}: {}", line, location, message);
+    fn report(&mut self, diagnostic: String) {
         self.had_error = true;
+        if self.error_limit_reached() {
+            self.suppressed_errors += 1;
+            return;
+        }
+        self.error_count += 1;
+        write!(self.error_output, "{}", diagnostic).expect("Couldn't write diagnostics");
     }
 
-    fn error_token<T: Display>(&mut self, token: RcToken, message: &T) {
-        if matches!(token.type_, TokenType::EOF) {
-            self.report(token.line, &"at end", message);
-        } else {
-            self.report(token.line, &format!("at '{}'", token.lexeme), message);
+    fn runtime_error(&mut self, diagnostic: String) {
+        write!(self.error_output, "{}", diagnostic).expect("Couldn't write diagnostics");
+        self.had_runtime_error = true;
+    }
+
+    // Every error reported since the last run.
+    pub(crate) fn errors(&self) -> &[LoxError] {
+        &self.errors
+    }
+
+    pub fn error_limit_reached(&self) -> bool {
+        self.max_errors != 0 && self.error_count >= self.max_errors
+    }
+
+    // Says how many diagnostics the limits held back. JSON consumers only
+    // get the diagnostics themselves.
+    fn report_suppressed(&mut self) {
+        if self.reporter == Reporter::Json {
+            return;
+        }
+        if self.suppressed_warnings > 0 {
+            writeln!(
+                self.error_output,
+                "... and {} more warnings.",
+                self.suppressed_warnings
+            )
+            .expect("Couldn't write diagnostics");
+        }
+        if self.suppressed_errors > 0 {
+            writeln!(
+                self.error_output,
+                "... and {} more errors (rerun with --max-errors 0 to see all).",
+                self.suppressed_errors
+            )
+            .expect("Couldn't write diagnostics");
+        }
+    }
+
+    // Warnings never set had_error and are capped separately from errors.
+    pub fn warning<T: Display>(&mut self, token: &RcToken, message: &T) {
+        self.warning_in(Phase::Parse, token, message);
+    }
+
+    fn warning_in<T: Display>(&mut self, phase: Phase, token: &RcToken, message: &T) {
+        let position = (token.line, token.column);
+        self.write_warning(phase, token.file.clone(), position, &token.lexeme, message);
+    }
+
+    // A warning from the resolver about the code `span` covers in the
+    // current source, which it quotes.
+    fn warning_at<T: Display>(&mut self, span: Span, message: &T) {
+        let text = span_text(&self.source, &span).to_string();
+        let position = (span.start_line, span.start_col);
+        let file = self.source_name.clone();
+        self.write_warning(Phase::Resolve, file, position, &text, message);
+    }
+
+    fn write_warning<T: Display>(
+        &mut self,
+        phase: Phase,
+        file: Option<Rc<str>>,
+        position: (usize, usize),
+        text: &str,
+        message: &T,
+    ) {
+        if self.max_warnings != 0 && self.warning_count >= self.max_warnings {
+            self.suppressed_warnings += 1;
+            return;
         }
+        self.warning_count += 1;
+        let message = message.to_string();
+        let diagnostic = self
+            .reporter
+            .warning(phase, file.as_ref(), position, text, &message);
+        write!(self.error_output, "{}", diagnostic).expect("Couldn't write diagnostics");
     }
 
     // fn error_runtime<T: Display>(&mut self, expr: Expr, message: &T) {
@@ -151,10 +943,2822 @@ impl Lox {
     //             operator,
     //             right,
     //         }
-    //         | Expr::Unary { operator, right } => {
+    //         | Expr::Unary { operator, right, .. } => {
     //             self.report(operator.line, &operator.literal.to_string(), message)
     //         }
     //         _ => unreachable!("Unknown operator encountered in runtimeerror"),
     //     }
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arena::AstArena;
+    use crate::debugger::DebugHook;
+    use crate::interpreter::{TimeSource, TraceLevel};
+    use crate::token::{Literal, Token};
+    use std::time::{Duration, Instant};
+
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn global(lox: &Lox, name: &str) -> Rc<ExprValue> {
+        let token = Rc::new(Token::new(
+            TokenType::IDENTIFIER,
+            name.as_bytes().to_vec(),
+            Literal::NIL,
+            0,
+            0,
+        ));
+        let value = lox.interpreter.borrow().globals.borrow().get(&token);
+        value.expect("undefined global")
+    }
+
+    fn number(value: Rc<ExprValue>) -> f64 {
+        match &*value {
+            ExprValue::Literal(Literal::NUMBER(n)) => *n,
+            other => panic!("expected a number, got {:?}", other),
+        }
+    }
+
+    fn string(value: Rc<ExprValue>) -> String {
+        match &*value {
+            ExprValue::Literal(Literal::STRING(s)) => s.clone(),
+            other => panic!("expected a string, got {:?}", other),
+        }
+    }
+
+    fn cascade(count: usize) -> Vec<u8> {
+        "1 = 2;\n".repeat(count).into_bytes()
+    }
+
+    // Runs `source` as a script would be with `--max-errors max`, giving
+    // the exit code and stderr.
+    fn run_capped(source: Vec<u8>, max: usize) -> (ExitCode, String) {
+        let err = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(io::sink()),
+            Box::new(SharedBuffer(Rc::clone(&err))),
+        );
+        lox.max_errors = max;
+        let code = lox.run_reader(&source[..]);
+        let err = String::from_utf8(err.borrow().clone()).unwrap();
+        (code, err)
+    }
+
+    fn diagnostics(err: &str) -> usize {
+        err.lines()
+            .filter(|line| line.starts_with("[line "))
+            .count()
+    }
+
+    #[test]
+    fn max_errors_caps_rendered_errors() {
+        let (code, err) = run_capped(cascade(50), DEFAULT_MAX_ERRORS);
+        assert_eq!(code, ExitCode::CompileError);
+        assert_eq!(diagnostics(&err), DEFAULT_MAX_ERRORS);
+        assert!(
+            err.ends_with(&format!(
+                "\n... and {} more errors (rerun with --max-errors 0 to see all).\n",
+                50 - DEFAULT_MAX_ERRORS
+            )),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parsing_stops_once_the_error_limit_is_reached() {
+        // Recovering after the first error would find the other two.
+        let source = b"var = 1;\nvar = 2;\n{ print (; }\n".to_vec();
+        assert_eq!(diagnostics(&run_capped(source.clone(), 0).1), 3);
+
+        let (code, err) = run_capped(source, 1);
+        assert_eq!(code, ExitCode::CompileError);
+        assert_eq!(diagnostics(&err), 1);
+        assert!(!err.contains("more errors"), "{}", err);
+    }
+
+    #[test]
+    fn max_errors_zero_is_unlimited() {
+        let (code, err) = run_capped(cascade(50), 0);
+        assert_eq!(code, ExitCode::CompileError);
+        assert_eq!(diagnostics(&err), 50);
+        assert!(!err.contains("more errors"), "{}", err);
+    }
+
+    #[test]
+    fn every_scan_error_is_reported() {
+        let source = b"var a = @;\nvar b = 1 # 2;\nprint \"open".to_vec();
+        let (code, err) = run_capped(source.clone(), 0);
+        assert_eq!(code, ExitCode::CompileError);
+        let errors: Vec<&str> = err
+            .lines()
+            .filter(|line| line.starts_with("[line "))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "[line 1] Error: Unexpected character '@'.",
+                "[line 2] Error: Unexpected character '#'.",
+                "[line 3] Error: Unterminated string starting with \"open\".",
+            ]
+        );
+
+        let (_, err) = run_capped(source, 1);
+        assert_eq!(diagnostics(&err), 1);
+        assert!(
+            err.ends_with("... and 2 more errors (rerun with --max-errors 0 to see all).\n"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn continue_runs_for_increment() {
+        let mut lox = Lox::new();
+        lox.run(
+            b"var sum = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                if (i == 5) continue;
+                sum = sum + i;
+            }"
+            .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "sum")), 40.0);
+    }
+
+    #[test]
+    fn continue_only_affects_innermost_loop() {
+        let mut lox = Lox::new();
+        lox.run(
+            b"var outer = 0;
+            var inner = 0;
+            for (var i = 0; i < 3; i = i + 1) {
+                var j = 0;
+                while (j < 4) {
+                    j = j + 1;
+                    if (j > 2) continue;
+                    inner = inner + 1;
+                }
+                outer = outer + 1;
+            }"
+            .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "outer")), 3.0);
+        assert_eq!(number(global(&lox, "inner")), 6.0);
+    }
+
+    #[test]
+    fn slotted_locals_keep_closures_and_shadowing() {
+        let mut lox = Lox::new();
+        lox.run(
+            b"fun counter(start) {
+                var count = start;
+                fun next() { count = count + 1; return count; }
+                return next;
+            }
+            var a = counter(10);
+            var b = counter(20);
+            a(); a(); b();
+            var first = a();
+            var second = b();
+            var x = 1;
+            var shadowed;
+            var outer;
+            {
+                var x = 2;
+                {
+                    var y = 0;
+                    var x = 3;
+                    shadowed = x;
+                }
+                outer = x;
+            }"
+            .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "first")), 13.0);
+        assert_eq!(number(global(&lox, "second")), 22.0);
+        assert_eq!(number(global(&lox, "shadowed")), 3.0);
+        assert_eq!(number(global(&lox, "outer")), 2.0);
+        assert_eq!(number(global(&lox, "x")), 1.0);
+    }
+
+    #[test]
+    fn closures_over_loop_variables() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(SharedBuffer(Rc::clone(&out))),
+            Box::new(io::sink()),
+        );
+        lox.run_source(
+            "fun makeCounter() {
+               var i = 0;
+               fun count() {
+                 i = i + 1;
+                 print i;
+               }
+               return count;
+             }
+             var counter = makeCounter();
+             counter();
+             counter();
+
+             var last;
+             for (var i = 0; i < 3; i = i + 1) {
+               var j = i;
+               fun show() { print j; i = i + 0; }
+               show();
+               last = show;
+               { { if (i == 1) continue; } }
+               print \"after\";
+             }
+             last();",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out.borrow().clone()).unwrap(),
+            "1\n2\n0\nafter\n1\n2\nafter\n2\n"
+        );
+    }
+
+    // `for` runs as the block and `while` the book desugars it to, except
+    // that `continue` still runs the increment.
+    #[test]
+    fn for_loops_match_their_desugaring() {
+        let output = |source: &str| {
+            let out = Rc::new(RefCell::new(Vec::new()));
+            let mut lox = Lox::with_output(
+                Box::new(SharedBuffer(Rc::clone(&out))),
+                Box::new(io::sink()),
+            );
+            lox.run_source(source).unwrap();
+            let printed = String::from_utf8(out.borrow().clone()).unwrap();
+            printed
+        };
+        let cases = [
+            (
+                "for (var i = 0; i < 3; i = i + 1) print i;",
+                "{ var i = 0; while (i < 3) { print i; i = i + 1; } }",
+            ),
+            // Every closure made in the loop shares its one variable.
+            (
+                "var first; var last;
+                 for (var i = 0; i < 3; i = i + 1) {
+                   fun get() { return i; }
+                   if (i == 0) first = get;
+                   last = get;
+                 }
+                 print first(); print last();",
+                "var first; var last;
+                 { var i = 0; while (i < 3) {
+                   { fun get() { return i; } if (i == 0) first = get; last = get; }
+                   i = i + 1;
+                 } }
+                 print first(); print last();",
+            ),
+            // The loop's variable shadows an outer one only inside it.
+            (
+                "var i = \"outer\"; for (var i = 0; i < 2; i = i + 1) print i; print i;",
+                "var i = \"outer\"; { var i = 0; while (i < 2) { print i; i = i + 1; } } print i;",
+            ),
+            (
+                "var n = 0; for (n = 5; n > 3;) n = n - 1; print n;",
+                "var n = 0; { n = 5; while (n > 3) n = n - 1; } print n;",
+            ),
+            (
+                "var n = 0; for (;;) { n = n + 1; if (n == 4) break; } print n;",
+                "var n = 0; while (true) { n = n + 1; if (n == 4) break; } print n;",
+            ),
+            (
+                "fun f() { for (var i = 0;; i = i + 1) if (i * i > 10) return i; } print f();",
+                "fun f() { { var i = 0; while (true) { if (i * i > 10) return i; i = i + 1; } } } print f();",
+            ),
+        ];
+        for (for_loop, desugared) in cases {
+            assert_eq!(output(for_loop), output(desugared), "{}", for_loop);
+        }
+        assert_eq!(
+            output("for (var i = 0; i < 5; i = i + 1) { if (i % 2 == 0) continue; print i; }"),
+            "1\n3\n"
+        );
+    }
+
+    #[test]
+    fn fresh_loop_bindings_give_each_iteration_its_own_variable() {
+        let output = |source: &str, fresh: bool| {
+            let out = Rc::new(RefCell::new(Vec::new()));
+            let mut lox = Lox::with_output(
+                Box::new(SharedBuffer(Rc::clone(&out))),
+                Box::new(io::sink()),
+            );
+            lox.interpreter.borrow_mut().fresh_loop_bindings = fresh;
+            lox.run_source(source).unwrap();
+            let printed = String::from_utf8(out.borrow().clone()).unwrap();
+            printed
+        };
+        let closures = "var a; var b; var c;
+            for (var i = 0; i < 3; i = i + 1) {
+              fun get() { return i; }
+              if (i == 0) a = get; else if (i == 1) b = get; else c = get;
+            }
+            print a(); print b(); print c();";
+        assert_eq!(output(closures, false), "3\n3\n3\n");
+        assert_eq!(output(closures, true), "0\n1\n2\n");
+        // What the body assigns carries into the next iteration, and a
+        // closure's assignments stay with its own copy.
+        let assigned = "var bump;
+            for (var i = 0; i < 6; i = i + 1) {
+              if (i == 1) { i = 3; fun f() { i = 100; } bump = f; }
+              print i;
+            }
+            bump();";
+        assert_eq!(output(assigned, true), "0\n3\n4\n5\n");
+        assert_eq!(output(assigned, false), "0\n3\n4\n5\n");
+        let skipped = "for (var i = 0; i < 5; i = i + 1) { if (i % 2 == 0) continue; print i; }";
+        assert_eq!(output(skipped, true), "1\n3\n");
+    }
+
+    #[test]
+    fn assignments_whose_values_read_and_assign_the_target() {
+        let mut lox = Lox::new();
+        lox.run(
+            b"var x = 1;
+            var calls = 0;
+            fun f(n) {
+              calls = calls + 1;
+              x = x + 100;
+              return n + x;
+            }
+            x = f(x);
+            for (var i = 0; i < 100; i = i + 1) x = f(x) - f(0);
+            fun g() {
+              var y = 1;
+              fun h(n) { y = y * 2; return n + y; }
+              y = h(y);
+              y = h(h(y));
+              return y;
+            }
+            var a; var b; var c;
+            a = b = c = g();"
+                .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "x")), -9898.0);
+        assert_eq!(number(global(&lox, "calls")), 201.0);
+        for name in ["a", "b", "c"] {
+            assert_eq!(number(global(&lox, name)), 21.0);
+        }
+    }
+
+    #[test]
+    fn closure_cycles_are_collected() {
+        let mut lox = Lox::new();
+        lox.run(
+            b"for (var i = 0; i < 5000; i = i + 1) {
+                fun f() { return i; }
+            }
+            var kept;
+            {
+                var x = 1;
+                fun g() { return x; }
+                kept = g;
+            }"
+            .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        // Each iteration leaves a cycle behind; collections along the way
+        // keep them from piling up.
+        let live = lox.interpreter.borrow().live_environments();
+        assert!(live < 2048, "{} environments alive", live);
+
+        lox.run(b"var reclaimed = gc(); var x = kept();".to_vec());
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert!(number(global(&lox, "reclaimed")) > 0.0);
+        assert_eq!(number(global(&lox, "x")), 1.0);
+        // The globals and the block `kept` closes over.
+        assert_eq!(lox.interpreter.borrow().live_environments(), 2);
+    }
+
+    #[test]
+    fn modulo() {
+        let mut lox = Lox::new();
+        lox.run(b"var a = 10 % 3; var b = 10.5 % 3; var c = -7 % 3;".to_vec());
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "a")), 1.0);
+        assert_eq!(number(global(&lox, "b")), 1.5);
+        assert_eq!(number(global(&lox, "c")), -1.0);
+    }
+
+    #[test]
+    fn exit_codes() {
+        let path = std::env::temp_dir().join("lox_exit_codes.lox");
+        let path = path.to_str().unwrap().to_string();
+        let cases: [(&str, ExitCode); 4] = [
+            ("print 1;", ExitCode::Ok),
+            ("print ;", ExitCode::CompileError),
+            ("1 + \"a\";", ExitCode::RuntimeError),
+            ("exit(7); 1 + \"a\";", ExitCode::Exit(7)),
+        ];
+        for (source, expected) in cases {
+            fs::write(&path, source).unwrap();
+            assert_eq!(Lox::new().run_file(&path), expected, "{}", source);
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(Lox::new().run_file(&path), ExitCode::IoError);
+    }
+
+    #[test]
+    fn exit_code_precedence() {
+        use ExitCode::*;
+        assert_eq!(Ok.combine(RuntimeError), RuntimeError);
+        assert_eq!(RuntimeError.combine(CompileError), CompileError);
+        assert_eq!(CompileError.combine(Ok), CompileError);
+        assert_eq!(IoError.combine(Usage), Usage);
+        assert_eq!(Exit(3).combine(Ok), Exit(3));
+        assert_eq!(Exit(0).combine(RuntimeError), RuntimeError);
+        assert_eq!(i32::from(Usage), 64);
+    }
+
+    #[test]
+    fn strict_mode_reports_overflow() {
+        let source = b"var x = 10; var i = 0;
+            while (i < 400) { x = x * 10; i = i + 1; }"
+            .to_vec();
+        let mut lox = Lox::new();
+        lox.run(source.clone());
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "x")), f64::INFINITY);
+
+        let mut strict = Lox::new();
+        strict.interpreter.borrow_mut().strict = true;
+        strict.run(source);
+        assert_eq!(strict.exit_code(), ExitCode::RuntimeError);
+        assert_eq!(strict.warning_count, 0);
+    }
+
+    #[test]
+    fn constant_overflow_warning() {
+        // Stderr and whether the program ran.
+        let run = |source: &str, fold: bool, strict: bool| {
+            let err = Rc::new(RefCell::new(Vec::new()));
+            let mut lox = Lox::with_output(
+                Box::new(io::sink()),
+                Box::new(SharedBuffer(Rc::clone(&err))),
+            );
+            lox.fold = fold;
+            lox.interpreter.borrow_mut().strict = strict;
+            let ran = lox.run_source(source).is_ok();
+            let warnings = String::from_utf8(err.borrow().clone()).unwrap();
+            (warnings, ran)
+        };
+        let huge = format!("{:.0}", f64::MAX);
+        let source = format!("var x = 1;\nprint x + {} * 10;", huge);
+        let warning = "[line 2] Warning at '*': Arithmetic overflow in constant expression.\n";
+        assert_eq!(run(&source, true, false), (warning.to_string(), true));
+        // The expression is left as it is, so --strict still fails on it.
+        let (err, ran) = run(&source, true, true);
+        assert!(err.starts_with(warning), "{}", err);
+        assert!(!ran);
+        assert_eq!(run(&source, false, false), (String::new(), true));
+
+        for source in ["print 2 * 3 + 4;", "print 1 / 3 - 0.5;", "print 10 % 3;"] {
+            assert_eq!(run(source, true, true), (String::new(), true), "{}", source);
+        }
+    }
+
+    #[test]
+    fn strict_mode_declaration_rules() {
+        // Each source runs fine normally, or fails only at runtime, and is
+        // rejected before running under --strict.
+        let cases = [
+            (
+                "var a = 1; var a = 2;",
+                ExitCode::Ok,
+                "Already a global variable named 'a'.",
+            ),
+            (
+                "fun a() {} var a = 2;",
+                ExitCode::Ok,
+                "Already a global variable named 'a'.",
+            ),
+            (
+                "var clock = 1;",
+                ExitCode::Ok,
+                "Already a global variable named 'clock'.",
+            ),
+            (
+                "fun f(a) { { var a = 2; } } f(1);",
+                ExitCode::Ok,
+                "Variable 'a' shadows a parameter.",
+            ),
+            (
+                "fun f(a) { var a = 2; }",
+                ExitCode::CompileError,
+                "Variable 'a' shadows a parameter.",
+            ),
+            ("x = 1;", ExitCode::RuntimeError, "Undefined variable 'x'."),
+            (
+                "{ x = 1; } var x;",
+                ExitCode::RuntimeError,
+                "Undefined variable 'x'.",
+            ),
+            (
+                "fun f() { y = 1; } f();",
+                ExitCode::RuntimeError,
+                "Undefined variable 'y'.",
+            ),
+        ];
+        for (source, normal, message) in cases {
+            let mut lox = Lox::new();
+            let _ = lox.run_source(source);
+            assert_eq!(lox.exit_code(), normal, "{}", source);
+
+            let mut strict = Lox::new();
+            strict.interpreter.borrow_mut().strict = true;
+            match strict.run_source(source).unwrap_err().as_slice() {
+                [LoxError::ParseError { message: m, .. }] => assert_eq!(m, message, "{}", source),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+
+        // What strict mode still allows.
+        let allowed = [
+            "var a = 1; a = 2; { var a = 3; }",
+            "fun f() { g = 1; } var g; f();",
+            "fun f(a) { fun h(b) { var a = 2; } h(1); } f(1);",
+            "fun f() { var a = 1; a = 2; } f();",
+        ];
+        for source in allowed {
+            let mut strict = Lox::new();
+            strict.interpreter.borrow_mut().strict = true;
+            assert!(strict.run_source(source).is_ok(), "{}", source);
+        }
+
+        // Globals from earlier runs count as declared.
+        let mut strict = Lox::new();
+        strict.interpreter.borrow_mut().strict = true;
+        assert!(strict.run_source("var a = 1;").is_ok());
+        assert!(strict.run_source("a = 2;").is_ok());
+        assert!(strict.run_source("var a = 3;").is_err());
+    }
+
+    #[test]
+    fn string_repetition() {
+        let mut lox = Lox::new();
+        lox.run(b"var a = \"ab\" * 3; var b = 3 * \"ab\"; var c = 0 * \"ab\";".to_vec());
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(string(global(&lox, "a")), "ababab");
+        assert_eq!(string(global(&lox, "b")), "ababab");
+        assert_eq!(string(global(&lox, "c")), "");
+
+        for source in [
+            "\"ab\" * -1;",
+            "\"ab\" * 1.5;",
+            "\"ab\" * \"ab\";",
+            "\"ab\" * 100000000000000000000;",
+            "(\"ab\" * 1000) * 1000000;",
+        ] {
+            let mut lox = Lox::new();
+            lox.run(source.as_bytes().to_vec());
+            assert_eq!(lox.exit_code(), ExitCode::RuntimeError, "{}", source);
+        }
+    }
+
+    #[test]
+    fn comma_operator() {
+        let mut lox = Lox::new();
+        lox.run(
+            b"var a; var b;
+            var x = (1, 2, 3);
+            a = 1, b = 2;
+            fun f(p, q) { return q; }
+            var y = f(1, 2);"
+                .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "x")), 3.0);
+        assert_eq!(number(global(&lox, "a")), 1.0);
+        assert_eq!(number(global(&lox, "b")), 2.0);
+        assert_eq!(number(global(&lox, "y")), 2.0);
+    }
+
+    #[test]
+    fn string_interpolation() {
+        let mut lox = Lox::new();
+        lox.run(
+            b"var a = 1; var b = 2;
+            var x = \"x=${1+1}\";
+            var y = \"sum is ${a + b}, nested ${\"<${a}>\"}, ${nil} ${true}\";"
+                .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(string(global(&lox, "x")), "x=2");
+        assert_eq!(string(global(&lox, "y")), "sum is 3, nested <1>, nil true");
+    }
+
+    #[test]
+    fn string_natives() {
+        let mut lox = Lox::new();
+        lox.run(
+            "var n = len(\"café\");
+            var s = substr(\"hello\", 1, 3);
+            var e = substr(\"hello\", 5, 5);
+            var c = char_at(\"hello\", 4);"
+                .as_bytes()
+                .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "n")), 4.0);
+        assert_eq!(string(global(&lox, "s")), "el");
+        assert_eq!(string(global(&lox, "e")), "");
+        assert_eq!(string(global(&lox, "c")), "o");
+
+        for source in [
+            "len(12);",
+            "substr(\"abc\", 0, 4);",
+            "substr(\"abc\", 0.5, 1);",
+            "substr(\"abc\", 2, 1);",
+            "char_at(\"abc\", 3);",
+            "char_at(\"abc\", -1);",
+        ] {
+            let mut lox = Lox::new();
+            lox.run(source.as_bytes().to_vec());
+            assert_eq!(lox.exit_code(), ExitCode::RuntimeError, "{}", source);
+        }
+    }
+
+    #[test]
+    fn conversion_natives() {
+        let mut lox = Lox::new();
+        lox.run(
+            b"var a = num(\"3.5\") + 1;
+            var b = num(\"abc\");
+            var c = str(true);
+            var d = str(2) + str(nil);
+            var e = floor(-1.5) + ceil(1.2) + abs(-3) + sqrt(16);
+            var f = sqrt(-1);"
+                .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "a")), 4.5);
+        assert_eq!(*global(&lox, "b"), ExprValue::Literal(Literal::NIL));
+        assert_eq!(string(global(&lox, "c")), "true");
+        assert_eq!(string(global(&lox, "d")), "2nil");
+        assert_eq!(number(global(&lox, "e")), 7.0);
+        assert!(number(global(&lox, "f")).is_nan());
+
+        for source in ["floor(\"1\");", "num(1);", "abs(1, 2);"] {
+            let mut lox = Lox::new();
+            lox.run(source.as_bytes().to_vec());
+            assert_eq!(lox.exit_code(), ExitCode::RuntimeError, "{}", source);
+        }
+    }
+
+    #[test]
+    fn truthiness() {
+        let cases = [
+            ("nil", false),
+            ("false", false),
+            ("true", true),
+            ("0", true),
+            ("-0", true),
+            ("sqrt(-1)", true),
+            ("\"\"", true),
+            ("\"false\"", true),
+            ("[]", true),
+            ("dict()", true),
+            ("clock", true),
+            ("f", true),
+        ];
+        for (value, expected) in cases {
+            let source = format!("fun f() {{}} bool({});", value);
+            assert!(
+                matches!(eval(&source), Ok(ExprValue::Literal(Literal::BOOL(b))) if b == expected),
+                "{}",
+                value
+            );
+            // Conditions agree with bool().
+            let source = format!("fun f() {{}} var t = false; if ({}) t = true; t;", value);
+            assert!(
+                matches!(eval(&source), Ok(ExprValue::Literal(Literal::BOOL(b))) if b == expected),
+                "if ({})",
+                value
+            );
+        }
+        assert!(!Interpreter::is_truthy(&Rc::new(ExprValue::Literal(
+            Literal::NIL
+        ))));
+        assert!(Interpreter::is_truthy(&Rc::new(ExprValue::Literal(
+            Literal::NUMBER(0.0)
+        ))));
+    }
+
+    #[test]
+    fn functions_print_with_their_arity() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(SharedBuffer(Rc::clone(&out))),
+            Box::new(io::sink()),
+        );
+        lox.run_source(
+            "fun add(a, b) { return a + b; }
+             fun greet(name, greeting = \"hi\") {}
+             fun none() {}
+             print add;
+             print greet;
+             print none;
+             print clock;
+             print substr;
+             print floor;
+             print assert;
+             print str(add);",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out.borrow().clone()).unwrap(),
+            "<fn add(2)>\n<fn greet(1..2)>\n<fn none(0)>\n<native fn clock(0)>\n\
+             <native fn substr(3)>\n<native fn floor(1)>\n<native fn assert(1..2)>\n\
+             <fn add(2)>\n"
+        );
+    }
+
+    #[test]
+    fn function_introspection() {
+        let cases = [
+            ("arity(add)", "2"),
+            ("arity(greet)", "1"),
+            ("arity(clock)", "0"),
+            ("arity(arity)", "1"),
+            ("name(add)", "add"),
+            ("name(alias)", "add"),
+            ("name(sqrt)", "sqrt"),
+            ("name(readLine)", "readLine"),
+        ];
+        for (expr, expected) in cases {
+            let source = format!(
+                "fun add(a, b) {{}} fun greet(n, g = 1) {{}} var alias = add; {};",
+                expr
+            );
+            match eval(&source) {
+                Ok(ExprValue::Literal(literal)) => {
+                    assert_eq!(literal.to_string(), expected, "{}", expr)
+                }
+                other => panic!("{} gave {:?}", expr, other),
+            }
+        }
+        for (source, message) in [
+            ("arity(1);", "arity() expects a function."),
+            ("name(\"add\");", "name() expects a function."),
+            ("name(nil);", "name() expects a function."),
+        ] {
+            let mut lox = Lox::new();
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::RuntimeError { message: m, .. }] => assert_eq!(m, message),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn logical_operators_return_an_operand() {
+        let cases = [
+            ("nil or \"default\";", "default"),
+            ("\"given\" or \"default\";", "given"),
+            ("0 and \"x\";", "x"),
+            ("nil and \"x\";", "nil"),
+            ("false or nil;", "nil"),
+            ("0 or 1;", "0"),
+            ("\"\" and 2;", "2"),
+            ("false and 1 or 3;", "3"),
+            ("1 and 2 and 3;", "3"),
+            ("nil or false or 7;", "7"),
+        ];
+        for (source, expected) in cases {
+            match eval(source) {
+                Ok(ExprValue::Literal(literal)) => {
+                    assert_eq!(literal.to_string(), expected, "{}", source)
+                }
+                other => panic!("{} gave {:?}", source, other),
+            }
+        }
+
+        // The right operand isn't evaluated once the left decides.
+        let mut lox = Lox::new();
+        lox.run(
+            b"var calls = 0;
+            fun touch() { calls = calls + 1; return true; }
+            var a = 0 and touch();
+            var b = 0 or touch();
+            var c = nil and touch();"
+                .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "calls")), 1.0);
+        assert_eq!(*global(&lox, "a"), ExprValue::Literal(Literal::BOOL(true)));
+        assert_eq!(number(global(&lox, "b")), 0.0);
+        assert_eq!(*global(&lox, "c"), ExprValue::Literal(Literal::NIL));
+    }
+
+    #[test]
+    fn lists() {
+        let mut lox = Lox::new();
+        lox.run(
+            b"var xs = [1, 2, 3];
+            var alias = xs;
+            alias[0] = 10;
+            var first = xs[0];
+            var n = len(xs);
+            var nested = [[1], []];
+            var same = [1, [2]] == [1, [2]];
+            var differ = [1] == [2];
+            var text = str(xs);"
+                .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "first")), 10.0);
+        assert_eq!(number(global(&lox, "n")), 3.0);
+        assert_eq!(
+            *global(&lox, "same"),
+            ExprValue::Literal(Literal::BOOL(true))
+        );
+        assert_eq!(
+            *global(&lox, "differ"),
+            ExprValue::Literal(Literal::BOOL(false))
+        );
+        assert_eq!(string(global(&lox, "text")), "[10, 2, 3]");
+
+        for source in [
+            "[1][1];",
+            "[1][-1];",
+            "[1][0.5];",
+            "1[0];",
+            "var a = [1]; a[1] = 2;",
+        ] {
+            let mut lox = Lox::new();
+            lox.run(source.as_bytes().to_vec());
+            assert_eq!(lox.exit_code(), ExitCode::RuntimeError, "{}", source);
+        }
+    }
+
+    #[test]
+    fn maps() {
+        let mut lox = Lox::new();
+        lox.run(
+            b"var m = dict();
+            m[\"key\"] = 1;
+            m[2] = \"two\";
+            m[true] = nil;
+            var alias = m;
+            alias[\"key\"] = alias[\"key\"] + 1;
+            var k = m[\"key\"];
+            var missing = m[\"nope\"];
+            var n = len(m);
+            var text = str(m);
+            var same = m == alias;
+            var differ = dict() == dict();"
+                .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "k")), 2.0);
+        assert_eq!(*global(&lox, "missing"), ExprValue::Literal(Literal::NIL));
+        assert_eq!(number(global(&lox, "n")), 3.0);
+        assert_eq!(
+            string(global(&lox, "text")),
+            "{\"key\": 2, 2: \"two\", true: nil}"
+        );
+        assert_eq!(
+            *global(&lox, "same"),
+            ExprValue::Literal(Literal::BOOL(true))
+        );
+        assert_eq!(
+            *global(&lox, "differ"),
+            ExprValue::Literal(Literal::BOOL(false))
+        );
+
+        let mut lox = Lox::new();
+        lox.run(b"var m = dict(); m[[1]] = 1;".to_vec());
+        assert_eq!(lox.exit_code(), ExitCode::RuntimeError);
+    }
+
+    #[test]
+    fn env_dumps_every_frame() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(SharedBuffer(Rc::clone(&out))),
+            Box::new(io::sink()),
+        );
+        lox.run_source(
+            "var x = \"outer\";
+             var n = 1;
+             {
+               var x = 2;
+               var y = nil;
+               {
+                 var y = true;
+                 env();
+               }
+             }",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out.borrow().clone()).unwrap(),
+            "frame 0:\n  y = true\n\
+             frame 1:\n  x = 2\n  y = nil (shadowed)\n\
+             globals:\n  n = 1\n  x = outer (shadowed)\n"
+        );
+        // At the top level only the globals are in scope.
+        assert_eq!(
+            lox.interpreter.borrow().dump_environment(),
+            "globals:\n  n = 1\n  x = outer\n"
+        );
+    }
+
+    #[test]
+    fn repl_session_persists_until_reset() {
+        let mut lox = Lox::new();
+        lox.run_repl_line(String::from("var x = 1;\n"));
+        lox.run_repl_line(String::from("fun f() { return x + 1; }\n"));
+        lox.run_repl_line(String::from("var y = f();\n"));
+        assert_eq!(number(global(&lox, "y")), 2.0);
+
+        lox.run_repl_line(String::from(":reset\n"));
+        lox.run(b"x;".to_vec());
+        assert_eq!(lox.exit_code(), ExitCode::RuntimeError);
+        lox.run_repl_line(String::from("var z = clock();\n"));
+        assert!(number(global(&lox, "z")) > 0.0);
+    }
+
+    #[test]
+    fn static_arity_checks() {
+        let cases: [(&str, ExitCode); 7] = [
+            ("fun f(a) {} f(1, 2);", ExitCode::CompileError),
+            ("fun f(a) {} { fun g() { f(); } }", ExitCode::CompileError),
+            ("\"text\"();", ExitCode::CompileError),
+            ("fun f(a) {} f(1);", ExitCode::Ok),
+            ("fun f(a) {} fun g(a, b) {} f = g; f(1, 2);", ExitCode::Ok),
+            ("fun f(a) {} var f = clock; f();", ExitCode::Ok),
+            ("fun h(f) { f(1, 2); } fun f(a) {}", ExitCode::Ok),
+        ];
+        for (source, expected) in cases {
+            let mut lox = Lox::new();
+            lox.run(source.as_bytes().to_vec());
+            assert_eq!(lox.exit_code(), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn duplicate_parameters() {
+        let cases = [
+            ("fun f(a, a) { print a; }", 10),
+            ("fun f(a, b, a) {}", 13),
+            ("fun f(a, b = 1, b = 2) {}", 17),
+            ("{ fun g(x, x) {} }", 12),
+        ];
+        for (source, column) in cases {
+            let mut lox = Lox::new();
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::ParseError { token, message }] => {
+                    assert_eq!(
+                        message,
+                        &format!("Duplicate parameter name '{}'.", token.lexeme)
+                    );
+                    assert_eq!(token.column, column, "{}", source);
+                }
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+        assert_eq!(
+            number(Rc::new(
+                eval("fun f(a, b) { return a - b; } f(5, 2);").unwrap()
+            )),
+            3.0
+        );
+        // A body variable shadowing a parameter is still its own error.
+        let mut lox = Lox::new();
+        match lox
+            .run_source("fun f(a) { var a; }")
+            .unwrap_err()
+            .as_slice()
+        {
+            [LoxError::ParseError { message, .. }] => {
+                assert_eq!(message, "Already a variable with this name in this scope.")
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn unbounded_recursion_is_a_runtime_error() {
+        // Test threads get a small stack, so keep the limit well below the
+        // default; the CLI test exercises the default on the main thread.
+        let mut lox = Lox::new();
+        lox.interpreter.borrow_mut().max_call_depth = 100;
+        lox.run(b"var depth = 0; fun f() { depth = depth + 1; f(); } f();".to_vec());
+        assert_eq!(lox.exit_code(), ExitCode::RuntimeError);
+        assert_eq!(number(global(&lox, "depth")), 100.0);
+
+        let mut lox = Lox::new();
+        lox.interpreter.borrow_mut().max_call_depth = 100;
+        lox.run(b"fun g(n) { if (n > 0) g(n - 1); } g(99);".to_vec());
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+    }
+
+    #[test]
+    fn execution_budget() {
+        let budget_exceeded = |lox: &mut Lox, source: &str| match lox.run_source(source) {
+            Err(errors) => match errors.as_slice() {
+                [LoxError::RuntimeError { message, .. }] => message == "Execution budget exceeded.",
+                _ => false,
+            },
+            Ok(_) => false,
+        };
+
+        let mut lox = Lox::new();
+        lox.interpreter.borrow_mut().set_step_limit(Some(10_000));
+        assert!(budget_exceeded(&mut lox, "while (true) {}"));
+        assert!(budget_exceeded(&mut lox, "fun f() { return f(); } f();"));
+        assert!(budget_exceeded(
+            &mut lox,
+            "var i = 0; for (;;) { i = i + 1; continue; }"
+        ));
+        // Each run gets the whole budget.
+        for _ in 0..3 {
+            assert!(lox
+                .run_source("var i = 0; while (i < 1000) i = i + 1;")
+                .is_ok());
+        }
+        lox.interpreter.borrow_mut().set_step_limit(None);
+        assert!(lox
+            .run_source("var i = 0; while (i < 10000) i = i + 1;")
+            .is_ok());
+
+        let mut lox = Lox::new();
+        let start = Instant::now();
+        lox.interpreter
+            .borrow_mut()
+            .set_deadline(Some(start + Duration::from_millis(50)));
+        assert!(budget_exceeded(&mut lox, "while (true) {}"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn tail_calls_do_not_grow_the_stack() {
+        // The depth limit is far below the recursion depth, so these only
+        // pass if calls in tail position don't nest.
+        let mut lox = Lox::new();
+        lox.interpreter.borrow_mut().max_call_depth = 100;
+        lox.run(
+            b"fun count(n) { if (n == 0) return 0; return count(n - 1); }
+              var a = count(100000);
+              fun isEven(n) { if (n == 0) return true; return isOdd(n - 1); }
+              fun isOdd(n) { if (n == 0) return false; return isEven(n - 1); }
+              var b = isEven(100001);
+              fun sum(n, total) { while (true) { if (n == 0) return total; return sum(n - 1, total + n); } }
+              var c = sum(100000, 0);"
+                .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "a")), 0.0);
+        assert!(matches!(
+            &*global(&lox, "b"),
+            ExprValue::Literal(Literal::BOOL(false))
+        ));
+        assert_eq!(number(global(&lox, "c")), 5000050000.0);
+
+        // Only a call that is the whole return value is in tail position.
+        let mut lox = Lox::new();
+        lox.interpreter.borrow_mut().max_call_depth = 20;
+        lox.run(b"fun f(n) { if (n == 0) return 0; return 1 + f(n - 1); } f(100);".to_vec());
+        assert_eq!(lox.exit_code(), ExitCode::RuntimeError);
+    }
+
+    #[test]
+    fn tail_call_errors_point_at_the_tail_call() {
+        assert_eq!(
+            rendered_errors("fun f() { return error(\"no\"); }\nf();"),
+            vec!["no\n[line 1]\n1 | fun f() { return error(\"no\"); }\n  |                  ^^^^^^^^^^^\n"]
+        );
+        assert_eq!(
+            rendered_errors("fun f(g) { return g(1); }\nf(clock);"),
+            vec!["Expected 0 arguments but got 1.\n[line 1]\n1 | fun f(g) { return g(1); }\n  |                   ^^^^\n"]
+        );
+    }
+
+    #[test]
+    fn calls_get_their_own_environment() {
+        let mut lox = Lox::new();
+        lox.run(
+            b"fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+              var result = fib(10);"
+                .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "result")), 55.0);
+
+        lox.run(b"fun f(x) { return x; } f(1); f(2);".to_vec());
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        lox.run(b"x;".to_vec());
+        assert_eq!(lox.exit_code(), ExitCode::RuntimeError);
+    }
+
+    #[test]
+    fn closures_capture_their_scope() {
+        let mut lox = Lox::new();
+        lox.run(
+            b"fun counter() { var n = 0; fun next() { n = n + 1; return n; } return next; }
+              var a = counter(); var b = counter();
+              a(); a(); b();
+              var x = a(); var y = b();"
+                .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "x")), 3.0);
+        assert_eq!(number(global(&lox, "y")), 2.0);
+    }
+
+    // Identical expressions at different places must resolve independently.
+    #[test]
+    fn locals_are_resolved_per_occurrence() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(SharedBuffer(Rc::clone(&out))),
+            Box::new(io::sink()),
+        );
+        lox.run_source(
+            "var a = \"global\";
+             {
+               fun showA() {
+                 print a;
+               }
+               showA();
+               var a = \"block\";
+               showA();
+               print a;
+             }
+             fun f() { var a = \"f\"; print a; }
+             fun g() { var a = \"g\"; { var b; print a; } }
+             fun h() { print a; }
+             f(); g(); h();",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out.borrow().clone()).unwrap(),
+            "global\nglobal\nblock\nf\ng\nglobal\n"
+        );
+    }
+
+    #[test]
+    fn eval_returns_final_expression_value() {
+        assert!(matches!(
+            eval("1 + 2;"),
+            Ok(ExprValue::Literal(Literal::NUMBER(n))) if n == 3.0
+        ));
+        assert!(matches!(
+            eval("var a = \"x\"; a + a;"),
+            Ok(ExprValue::Literal(Literal::STRING(s))) if s == "xx"
+        ));
+        assert!(matches!(
+            eval("1; var b = 2;"),
+            Ok(ExprValue::Literal(Literal::NIL))
+        ));
+        assert!(matches!(eval("print ;"), Err(ExitCode::CompileError)));
+        assert!(matches!(eval("-\"a\";"), Err(ExitCode::RuntimeError)));
+    }
+
+    #[test]
+    fn for_each_loops() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(SharedBuffer(Rc::clone(&out))),
+            Box::new(io::sink()),
+        );
+        lox.run_source(
+            "for (var x in [1, \"two\", nil]) print x;
+             for (var c in \"hé!\") print c;
+             for (var x in []) print \"never\";
+             for (var row in [[1, 2], [3]]) for (var x in row) print x * 10;
+             for (var x in [1, 2, 3, 4, 5]) {
+               if (x == 2) continue;
+               if (x == 4) break;
+               print x;
+             }
+             var in = \"in is still a name\";
+             for (var x in in.split(\" \")) print x.length();",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out.borrow().clone()).unwrap(),
+            "1\ntwo\nnil\nh\né\n!\n10\n20\n30\n1\n3\n2\n2\n5\n1\n4\n"
+        );
+
+        // Each iteration binds a fresh variable, so closures keep their own.
+        let mut lox = Lox::new();
+        lox.run_source(
+            "var fns = [nil, nil, nil];
+             var i = 0;
+             for (var x in [1, 2, 3]) {
+               fun get() { return x; }
+               fns[i] = get;
+               i = i + 1;
+             }
+             var result = fns[0]() + fns[1]() * 10 + fns[2]() * 100;",
+        )
+        .unwrap();
+        assert_eq!(number(global(&lox, "result")), 321.0);
+    }
+
+    #[test]
+    fn for_each_errors() {
+        assert_eq!(
+            rendered_errors("var n = 3;\nfor (var x in n) print x;"),
+            vec![
+                "Can only iterate over lists and strings.\n[line 2]\n\
+                 2 | for (var x in n) print x;\n  |            ^^\n"
+            ]
+        );
+        let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        // The loop variable is gone once the loop ends.
+        match lox
+            .run_source("for (var x in [1]) {} print x;")
+            .unwrap_err()
+            .as_slice()
+        {
+            [LoxError::RuntimeError { message, .. }] => {
+                assert_eq!(message, "Undefined variable 'x'.")
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(
+            rendered_errors("for (var x in [1] print x;")[0]
+                .lines()
+                .next(),
+            Some("[line 1] Error at 'print': Expect ')' after loop iterable.")
+        );
+    }
+
+    #[test]
+    fn range_builtin() {
+        let cases = [
+            ("range(4);", "[0, 1, 2, 3]"),
+            ("range(0);", "[]"),
+            ("range(-2);", "[]"),
+            ("range(2, 5);", "[2, 3, 4]"),
+            ("range(5, 2);", "[]"),
+            ("range(0, 10, 3);", "[0, 3, 6, 9]"),
+            ("range(5, 0, -2);", "[5, 3, 1]"),
+            ("range(0, 5, -1);", "[]"),
+            ("range(0, 1, 0.25);", "[0, 0.25, 0.5, 0.75]"),
+            ("len(range(0, 1, 0.1));", "10"),
+            ("range(1.5);", "[0, 1]"),
+            (
+                "var sum = 0; for (var i in range(101)) sum = sum + i; sum;",
+                "5050",
+            ),
+        ];
+        for (source, expected) in cases {
+            let value = eval(source).unwrap_or_else(|code| panic!("{}: {:?}", source, code));
+            assert_eq!(
+                Interpreter::stringify(Rc::new(value)),
+                expected,
+                "{}",
+                source
+            );
+        }
+
+        let errors = [
+            ("range(0, 1, 0);", "range() step can't be 0."),
+            ("range(\"3\");", "range() expects numbers."),
+            (
+                "range(100000000);",
+                "range() can't make more than 10000000 numbers.",
+            ),
+            (
+                "range(0, 1, 0.00000001);",
+                "range() can't make more than 10000000 numbers.",
+            ),
+        ];
+        for (source, expected) in errors {
+            let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::RuntimeError { message, .. }] => assert_eq!(message, expected),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn primitive_methods() {
+        let cases = [
+            ("\"hello\".length();", "5"),
+            ("\"héllo\".length();", "5"),
+            ("\"héllo\".upper();", "HÉLLO"),
+            ("\"HeLLo\".lower();", "hello"),
+            ("(3.7).floor();", "3"),
+            ("(-3.2).floor();", "-4"),
+            ("var n = 2.5; n.floor() + 1;", "3"),
+            ("\"a,b,,c\".split(\",\");", "[\"a\", \"b\", \"\", \"c\"]"),
+            ("\"a--b\".split(\"--\");", "[\"a\", \"b\"]"),
+            ("\"abc\".split(\"\");", "[\"a\", \"b\", \"c\"]"),
+            ("var upper = \"hi\".upper; upper();", "HI"),
+            ("\"hi\".upper;", "<native fn upper(0)>"),
+        ];
+        for (source, expected) in cases {
+            let value = eval(source).unwrap_or_else(|code| panic!("{}: {:?}", source, code));
+            assert_eq!(
+                Interpreter::stringify(Rc::new(value)),
+                expected,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_properties() {
+        let cases = [
+            ("\"a\".nope;", "Undefined property 'nope'."),
+            ("\"a\".nope();", "Undefined property 'nope'."),
+            ("(1).length();", "Undefined property 'length'."),
+            ("nil.floor();", "Undefined property 'floor'."),
+            ("[1].length();", "Undefined property 'length'."),
+            ("\"a\".split(1);", "split() expects a string."),
+            ("\"a\".upper(1);", "Expected 0 arguments but got 1."),
+        ];
+        for (source, expected) in cases {
+            let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::RuntimeError { message, .. }] => assert_eq!(message, expected),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+        assert_eq!(
+            rendered_errors("\"a\".;")[0].lines().next(),
+            Some("[line 1] Error at ';': Expect property name after '.'.")
+        );
+    }
+
+    #[test]
+    fn stray_jumps_are_errors() {
+        let err = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(io::sink()),
+            Box::new(SharedBuffer(Rc::clone(&err))),
+        );
+        lox.run(b"return 1;".to_vec());
+        assert_eq!(lox.exit_code(), ExitCode::CompileError);
+        assert_eq!(
+            String::from_utf8(err.borrow().clone()).unwrap(),
+            "[line 1] Error at 'return': Can't return from top-level code.\n\
+             1 | return 1;\n  | ^^^^^^\n"
+        );
+
+        // Without the resolver they reach the interpreter, which reports
+        // them rather than panicking.
+        let cases = [
+            ("return 1;", "Can't return from top-level code."),
+            (
+                "fun f() {} { return f(); }",
+                "Can't return from top-level code.",
+            ),
+            ("if (true) break;", "Can't use 'break' outside of a loop."),
+            ("continue;", "Can't use 'continue' outside of a loop."),
+        ];
+        for (source, expected) in cases {
+            let mut lox = Lox::new();
+            let mut scanner = Scanner::new(source.as_bytes().to_vec());
+            scanner.scan_tokens().unwrap();
+            let mut ast = AstArena::new();
+            let statements = Parser::new(&mut lox, scanner.tokens, &mut ast).parse();
+            lox.interpreter.borrow_mut().set_ast(ast);
+            let result = lox.interpreter.borrow_mut().interpret(statements);
+            match result {
+                Err(LoxError::RuntimeError { message, .. }) => assert_eq!(message, expected),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    fn rendered_errors(source: &str) -> Vec<String> {
+        let mut lox = Lox::new();
+        let errors = lox.run_source(source).unwrap_err();
+        errors.iter().map(|e| e.render(source)).collect()
+    }
+
+    #[test]
+    fn render_points_at_the_offending_token() {
+        assert_eq!(
+            rendered_errors("var a = 1;\nvar b = 2;\nprint (a + b));\n"),
+            vec![
+                "[line 3] Error at ')': Expect ';' after value.\n\
+                  3 | print (a + b));\n\
+                  \x20 |              ^\n"
+            ]
+        );
+        assert_eq!(
+            rendered_errors("var s = \"one\ntwo\" print s;"),
+            vec![
+                "[line 2] Error at 'print': Expect ';' after variable declaration.\n\
+                  2 | two\" print s;\n\
+                  \x20 |      ^^^^^\n"
+            ]
+        );
+        assert_eq!(
+            rendered_errors("var x;\nvar \"one\ntwo\";"),
+            vec![
+                "[line 3] Error at '\"one\ntwo\"': Expect variable name.\n\
+                  2 | var \"one\n\
+                  \x20 |     ^^^^\n"
+            ]
+        );
+        // The scanner ends a number at its second point.
+        assert_eq!(
+            rendered_errors("print 1.2.3;"),
+            vec![
+                "[line 1] Error at '3': Expect property name after '.'.\n\
+                  1 | print 1.2.3;\n\
+                  \x20 |           ^\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn runtime_errors_underline_the_failing_expression() {
+        assert_eq!(
+            rendered_errors("var foo = \"not a function\";\nprint foo(1, 2) + 3;\n"),
+            vec![
+                "Can only call functions and classes, got string from expression 'foo'.\n[line 2]\n\
+                  2 | print foo(1, 2) + 3;\n\
+                  \x20 |       ^^^^^^^^^\n"
+            ]
+        );
+        // The innermost failing expression wins over the ones around it.
+        assert_eq!(
+            rendered_errors("var a = [1];\nprint (a[0] + -\"x\") * 2;"),
+            vec![
+                "MINUS operands must be a number(s)\n[line 2]\n\
+                  2 | print (a[0] + -\"x\") * 2;\n\
+                  \x20 |               ^^^^\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn assignment_semantics() {
+        let mut lox = Lox::new();
+        lox.run(b"var a; var b; var c = (a = b = 2) + 1; { var d; a = d = b = c; }".to_vec());
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        assert_eq!(number(global(&lox, "a")), 3.0);
+        assert_eq!(number(global(&lox, "b")), 3.0);
+        assert_eq!(number(global(&lox, "c")), 3.0);
+
+        assert_eq!(
+            rendered_errors("var a;\na = nope = 1;\n"),
+            vec![
+                "Undefined variable 'nope'.\n[line 2]\n\
+                  2 | a = nope = 1;\n\
+                  \x20 |     ^^^^\n"
+            ]
+        );
+        // Bad targets are reported without giving up on the rest of the file.
+        assert_eq!(
+            rendered_errors("var a;\n(a) = 3;\na + 1 = 2;\nvar = 1;\n"),
+            vec![
+                "[line 2] Error at '=': Invalid assignment target.\n\
+                  2 | (a) = 3;\n\
+                  \x20 |     ^\n",
+                "[line 3] Error at '=': Invalid assignment target.\n\
+                  3 | a + 1 = 2;\n\
+                  \x20 |       ^\n",
+                "[line 4] Error at '=': Expect variable name.\n\
+                  4 | var = 1;\n\
+                  \x20 |     ^\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn for_loop_errors_point_into_the_loop() {
+        assert_eq!(
+            rendered_errors("for (;;) {\n  undefinedVar;\n}"),
+            vec![
+                "Undefined variable 'undefinedVar'.\n[line 2]\n\
+                  2 |   undefinedVar;\n\
+                  \x20 |   ^^^^^^^^^^^^\n"
+            ]
+        );
+        assert_eq!(
+            rendered_errors("for (var i = 0; i < 3;\n     i = i + nope) {}"),
+            vec![
+                "Undefined variable 'nope'.\n[line 2]\n\
+                  2 |      i = i + nope) {}\n\
+                  \x20 |              ^^^^\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn render_handles_eof_and_scan_errors() {
+        assert_eq!(
+            rendered_errors("print 1 +\n\n"),
+            vec![
+                "[line 1] Error at end: Expect expression\n\
+                  1 | print 1 +\n\
+                  \x20 |          ^\n"
+            ]
+        );
+        assert_eq!(
+            rendered_errors("\tvar x = @;"),
+            vec![
+                "[line 1] Error: Unexpected character '@'.\n\
+                  1 | \tvar x = @;\n\
+                  \x20 | \t        ^\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_every_syntax_error() {
+        let mut lox = Lox::new();
+        let errors = lox
+            .run_source("var = 1;\nprint (1;\nvar ok = 2;\nfun f( {}\nvar x = ok;")
+            .unwrap_err();
+        let messages: Vec<(usize, String)> = errors
+            .iter()
+            .map(|e| match e {
+                LoxError::ParseError { token, message } => (token.line, message.clone()),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (1, String::from("Expect variable name.")),
+                (2, String::from("Expect ')' after expression.")),
+                (4, String::from("Expect parameter name.")),
+            ]
+        );
+        // Nothing runs once the parse failed.
+        let defined = lox.interpreter.borrow().globals.borrow().get_by_name("ok");
+        assert!(defined.is_none());
+    }
+
+    #[test]
+    fn errors_in_blocks_do_not_cascade() {
+        let cases = [
+            // A missing semicolon right before the closing brace.
+            "fun f() {\n  if (true) {\n    print 1\n  }\n  print 2;\n}\nvar ok = 1;",
+            "{ var = 1; }\nvar ok = 1;",
+            "fun f() { { { 1 +; } } }\nfun g() {}",
+            "while (true) { print; break; }\nprint 1;",
+        ];
+        for source in cases {
+            let mut lox = Lox::new();
+            let errors = lox.run_source(source).unwrap_err();
+            assert_eq!(errors.len(), 1, "{}: {:?}", source, errors);
+        }
+
+        // Later errors are still found, so parsing carried on.
+        let mut lox = Lox::new();
+        let errors = lox
+            .run_source("fun f() {\n  print 1\n}\nvar a = 1;\nprint a +;")
+            .unwrap_err();
+        let lines: Vec<usize> = errors
+            .iter()
+            .map(|e| match e {
+                LoxError::ParseError { token, .. } => token.line,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(lines, vec![3, 5]);
+    }
+
+    #[test]
+    fn error_productions() {
+        let cases = [
+            ("* 3;", "Binary operator '*' missing left-hand operand."),
+            (
+                "== 1 + 2;",
+                "Binary operator '==' missing left-hand operand.",
+            ),
+            (
+                "print and true;",
+                "Binary operator 'and' missing left-hand operand.",
+            ),
+            (
+                "fun f() {} f() = 1;",
+                "Can't assign to the result of a function call.",
+            ),
+            ("1 = 2;", "Can't assign to a literal value."),
+            ("(a) = 2;", "Invalid assignment target."),
+            ("this = 1;", "Expect expression, found keyword 'this'."),
+            ("print var;", "Expect expression, found keyword 'var'."),
+        ];
+        for (source, expected) in cases {
+            let mut lox = Lox::new();
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::ParseError { message, .. }] => assert_eq!(message, expected),
+                other => panic!("{}: unexpected {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn folding_keeps_results() {
+        let huge = format!("{:.0}", f64::MAX);
+        let programs = [
+            String::from("print 2 * 3 + 4; print -(1 + 2) % 4; print 7 / 2 - 0.5;"),
+            String::from("print \"a\" + \"b\"; print \"a\" < \"b\"; print 1 == \"1\";"),
+            String::from("print nil or \"default\"; print 0 and !nil; print false or nil;"),
+            String::from("if (1 > 2) print \"no\"; else print \"yes\"; if (nil) print 1;"),
+            String::from(
+                "while (false) print 1; for (var i = 0; false; i++) print i; print \"done\";",
+            ),
+            String::from("var i = 0; while (true) { i++; if (i > 2 * 2) break; } print i;"),
+            String::from(
+                "fun f(x = 1 + 1) { return x * (3 - 1); } print f(); print \"${2 * 21}\";",
+            ),
+            String::from("print 1;\nprint (1 + 2) + \"a\";"),
+            // Errors in code that folding removes or rewrites.
+            String::from("print \"before\"; (1 + 2)();"),
+            String::from("const c = 1; print true or (c = 2);"),
+            String::from("fun f(a) {} print true or f(1, 2);"),
+            String::from("print false and undefined; print nil and [1][5];"),
+            // Locals assigned folded values are still found.
+            String::from(
+                "{ var a; a = 1 + 2; print a; fun g() { a = \"x\" + \"y\"; } g(); print a; }",
+            ),
+            String::from("print 10 / (5 - 5);"),
+            String::from("print -\"a\";"),
+            String::from("print \"ab\" * -1;"),
+            format!("print {} * 10;", huge),
+        ];
+        let run = |source: &str, fold: bool, optimize: bool, strict: bool| {
+            let out = Rc::new(RefCell::new(Vec::new()));
+            let err = Rc::new(RefCell::new(Vec::new()));
+            let mut lox = Lox::with_output(
+                Box::new(SharedBuffer(Rc::clone(&out))),
+                Box::new(SharedBuffer(Rc::clone(&err))),
+            );
+            lox.fold = fold;
+            lox.optimize = optimize;
+            lox.interpreter.borrow_mut().strict = strict;
+            let _ = lox.run_source(source);
+            let text =
+                |buffer: &Rc<RefCell<Vec<u8>>>| String::from_utf8(buffer.borrow().clone()).unwrap();
+            (text(&out), text(&err), lox.exit_code())
+        };
+        for program in &programs {
+            for strict in [false, true] {
+                let (out, err, code) = run(program, true, false, strict);
+                // Only folding warns about constant arithmetic overflowing.
+                let unfolded_err = err.replace(
+                    "[line 1] Warning at '*': Arithmetic overflow in constant expression.\n",
+                    "",
+                );
+                assert_eq!(
+                    run(program, false, false, strict),
+                    (out.clone(), unfolded_err, code),
+                    "{}",
+                    program
+                );
+                assert_eq!(
+                    run(program, true, true, strict),
+                    (out, err, code),
+                    "{}",
+                    program
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unreachable_code_warning() {
+        let err = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(io::sink()),
+            Box::new(SharedBuffer(Rc::clone(&err))),
+        );
+        let source = "fun f() {\n  return 1;\n  print \"a\" + f();\n  g();\n}\n\
+                      fun g() { if (true) { return; } return; }\n\
+                      fun h() { return; -1; }\n\
+                      print f();";
+        assert!(lox.run_source(source).is_ok());
+        assert_eq!(
+            String::from_utf8(err.borrow().clone()).unwrap(),
+            "[line 3] Warning at '\"a\"': Unreachable code after 'return'.\n\
+             [line 7] Warning at '-': Unreachable code after 'return'.\n"
+        );
+    }
+
+    #[test]
+    fn dead_code_warnings_and_elimination() {
+        // Stderr and how many statements the arena ends up with, unfolded
+        // so only --optimize drops anything.
+        let run = |source: &str, optimize: bool| {
+            let err = Rc::new(RefCell::new(Vec::new()));
+            let mut lox = Lox::with_output(
+                Box::new(io::sink()),
+                Box::new(SharedBuffer(Rc::clone(&err))),
+            );
+            lox.fold = false;
+            lox.optimize = optimize;
+            assert!(lox.run_source(source).is_ok(), "{}", source);
+            let stmts = lox.interpreter.borrow().ast().stmt_count();
+            let warnings = String::from_utf8(err.borrow().clone()).unwrap();
+            (warnings, stmts)
+        };
+
+        let source = "if (true) print 1;\nelse { print 2; }\nwhile (nil) print 3;";
+        assert_eq!(
+            run(source, false).0,
+            "[line 1] Warning at 'true': Condition is always true; the 'else' branch never runs.\n\
+             [line 3] Warning at 'nil': Condition is always false; this loop never runs.\n"
+        );
+
+        // Dropping the branch rebuilds the function around it.
+        let removable = "fun f() {\n  if (1 > 2) { var a = 1; print a; }\n  return 0;\n}";
+        let (warnings, kept) = run(removable, false);
+        assert_eq!(
+            warnings,
+            "[line 2] Warning at '1 > 2': Condition is always false; this branch never runs.\n"
+        );
+        let (optimized_warnings, optimized) = run(removable, true);
+        assert_eq!(optimized_warnings, warnings);
+        assert!(optimized > kept);
+
+        // `x` is out of scope after the branch declaring it.
+        let used = "fun f() {\n  if (false) { var x = 1; }\n  return x;\n}";
+        let (warnings, kept) = run(used, false);
+        assert_eq!(
+            warnings,
+            "[line 2] Warning at 'false': Condition is always false; this branch never runs.\n\
+             [line 3] Warning at 'x': Undefined variable 'x'; the one declared in code that never runs is out of scope here.\n"
+        );
+        let (optimized_warnings, optimized) = run(used, true);
+        assert_eq!(optimized_warnings, warnings);
+        assert!(optimized > kept);
+    }
+
+    #[test]
+    fn division_by_zero_is_a_runtime_error() {
+        for source in ["1 / 0;", "0 / 0;", "-1 / -0;", "5 % 0;", "0 % 0;"] {
+            let mut lox = Lox::new();
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::RuntimeError { token, message, .. }] => {
+                    assert_eq!(message, "Division by zero.", "{}", source);
+                    assert!(matches!(token.type_, TokenType::SLASH | TokenType::PERCENT));
+                }
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+        assert!(matches!(
+            eval("0 / 5;"),
+            Ok(ExprValue::Literal(Literal::NUMBER(n))) if n == 0.0
+        ));
+    }
+
+    #[test]
+    fn calls_on_any_expression() {
+        let cases = [
+            (
+                "fun adder(a) { fun add(b) { return a + b; } return add; } adder(1)(2);",
+                3.0,
+            ),
+            (
+                "fun f() { fun g() { fun h() { return 3; } return h; } return g; } f()()();",
+                3.0,
+            ),
+            ("fun id(x) { return x; } (id)(42);", 42.0),
+            (
+                "fun pick(first) { fun one() { return 1; } fun two() { return 2; } \
+                 if (first) return one; return two; } (pick(false))();",
+                2.0,
+            ),
+            ("var natives = [clock, len]; natives[1]([1, 2]);", 2.0),
+        ];
+        for (source, expected) in cases {
+            assert!(
+                matches!(eval(source), Ok(ExprValue::Literal(Literal::NUMBER(n))) if n == expected),
+                "{}",
+                source
+            );
+        }
+
+        // The error names what was called and what it turned out to be.
+        let errors = [
+            (
+                "var x = 1; x();",
+                "Can only call functions and classes, got number from expression 'x'.",
+            ),
+            (
+                "fun f() {} f()();",
+                "Can only call functions and classes, got nil from expression 'f()'.",
+            ),
+            (
+                "var xs = [\"a\"]; xs[0](1);",
+                "Can only call functions and classes, got string from expression 'xs[0]'.",
+            ),
+            (
+                "var m = [[]]; (m[0])();",
+                "Can only call functions and classes, got list from expression '(m[0])'.",
+            ),
+        ];
+        for (source, expected) in errors {
+            match Lox::new().run_source(source).unwrap_err().as_slice() {
+                [LoxError::RuntimeError { message, .. }] => assert_eq!(message, expected),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+        // Folding doesn't change what the callee is called.
+        for fold in [true, false] {
+            let mut lox = Lox::new();
+            lox.fold = fold;
+            match lox.run_source("(1 + 2)();").unwrap_err().as_slice() {
+                [LoxError::RuntimeError { message, .. }] => assert_eq!(
+                    message,
+                    "Can only call functions and classes, got number from expression '(1 + 2)'."
+                ),
+                other => panic!("{:?}", other),
+            }
+        }
+        // A literal callee is caught before running, and shown as written.
+        match Lox::new().run_source("\"s\"();").unwrap_err().as_slice() {
+            [LoxError::ParseError { message, .. }] => assert_eq!(
+                message,
+                "Can only call functions and classes, got string from expression '\"s\"'."
+            ),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn string_comparison() {
+        let cases = [
+            ("\"abc\" < \"abd\";", true),
+            ("\"abc\" <= \"abc\";", true),
+            ("\"abc\" > \"abc\";", false),
+            ("\"abc\" >= \"abc\";", true),
+            ("\"ab\" < \"abc\";", true),
+            ("\"b\" > \"abc\";", true),
+            ("\"Z\" < \"a\";", true),
+            ("\"\" < \"a\";", true),
+            ("\"é\" > \"z\";", true),
+        ];
+        for (source, expected) in cases {
+            assert!(
+                matches!(eval(source), Ok(ExprValue::Literal(Literal::BOOL(b))) if b == expected),
+                "{}",
+                source
+            );
+        }
+        let mut lox = Lox::new();
+        match lox.run_source("\"1\" < 2;").unwrap_err().as_slice() {
+            [LoxError::RuntimeError { token, .. }] => assert_eq!(&*token.lexeme, "<"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn equality() {
+        let prelude = "fun f() {} fun g() {} var h = f; var nan = sqrt(-1); \
+                       fun make() { fun inner() {} return inner; } \
+                       var a = [1]; a[0] = a; var b = [1]; b[0] = b; \
+                       var c = [1, 2]; c[0] = c;";
+        let cases = [
+            ("1 == 1", true),
+            ("1 == 2", false),
+            ("0 == -0", true),
+            ("nan == nan", false),
+            ("nan != nan", true),
+            ("nan == 1", false),
+            ("\"a\" == \"a\"", true),
+            ("\"a\" == \"b\"", false),
+            ("\"1\" == 1", false),
+            ("true == true", true),
+            ("true == false", false),
+            ("true == 1", false),
+            ("nil == nil", true),
+            ("nil == false", false),
+            ("nil != 0", true),
+            ("f == f", true),
+            ("f == h", true),
+            ("f == g", false),
+            ("f != g", true),
+            ("clock == clock", true),
+            ("clock == len", false),
+            ("make() == make()", false),
+            ("f == \"f\"", false),
+            ("[1, \"a\"] == [1, \"a\"]", true),
+            ("[nan] == [nan]", false),
+            // Lists that contain themselves.
+            ("a == b", true),
+            ("[a] == [b]", true),
+            ("a == c", false),
+        ];
+        for (expr, expected) in cases {
+            let source = format!("{} {};", prelude, expr);
+            assert!(
+                matches!(eval(&source), Ok(ExprValue::Literal(Literal::BOOL(b))) if b == expected),
+                "{}",
+                expr
+            );
+        }
+
+        // Equal keys find the same entry; NaN can't be a key at all.
+        assert_eq!(
+            number(Rc::new(
+                eval("var m = dict(); m[0] = 1; m[-0] = 2; len(m);").unwrap()
+            )),
+            1.0
+        );
+        let mut lox = Lox::new();
+        match lox
+            .run_source("var m = dict(); m[sqrt(-1)] = 1;")
+            .unwrap_err()
+            .as_slice()
+        {
+            [LoxError::RuntimeError { message, .. }] => {
+                assert_eq!(message, "Map keys can't be NaN.")
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn const_declarations() {
+        assert!(matches!(
+            eval("const PI = 3.5; { var PI = 1; PI = 2; } PI * 2;"),
+            Ok(ExprValue::Literal(Literal::NUMBER(n))) if n == 7.0
+        ));
+
+        let compile_errors = [
+            ("const x = 1; x = 2;", "Can't assign to constant 'x'."),
+            (
+                "fun f() { const y = 1; y = 2; }",
+                "Can't assign to constant 'y'.",
+            ),
+            ("const z;", "Const declarations must have an initializer."),
+        ];
+        for (source, expected) in compile_errors {
+            let mut lox = Lox::new();
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::ParseError { message, .. }] => assert_eq!(message, expected),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+
+        // Globals from an earlier REPL line are only known at runtime.
+        let mut lox = Lox::new();
+        lox.run_repl_line(String::from("const limit = 10;\n"));
+        match lox.run_source("limit = 11;").unwrap_err().as_slice() {
+            [LoxError::RuntimeError { message, .. }] => {
+                assert_eq!(message, "Can't assign to constant 'limit'.")
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(number(global(&lox, "limit")), 10.0);
+        lox.run_repl_line(String::from("var limit = 1;\n"));
+        assert!(lox.run_source("limit = 2;").is_ok());
+    }
+
+    #[test]
+    fn increment_and_decrement() {
+        let mut lox = Lox::new();
+        lox.run(
+            b"var sum = 0;
+              for (var i = 0; i < 5; i++) sum = sum + i;
+              var down = 0;
+              for (var j = 3; j > 0; --j) down = down * 10 + j;
+              var a = 1; var post = a++; var pre = ++a;
+              var b = 1; var postd = b--; var pred = --b;
+              var c = 1 - -2;"
+                .to_vec(),
+        );
+        assert_eq!(lox.exit_code(), ExitCode::Ok);
+        let expected = [
+            ("sum", 10.0),
+            ("down", 321.0),
+            ("post", 1.0),
+            ("pre", 3.0),
+            ("a", 3.0),
+            ("postd", 1.0),
+            ("pred", -1.0),
+            ("b", -1.0),
+            ("c", 3.0),
+        ];
+        for (name, value) in expected {
+            assert_eq!(number(global(&lox, name)), value, "{}", name);
+        }
+
+        for source in ["1++;", "++f();", "var l = [1]; l[0]++;"] {
+            let mut lox = Lox::new();
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::ParseError { message, .. }] => {
+                    assert_eq!(message, "Invalid increment target.", "{}", source)
+                }
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        let (out, err) = (
+            Rc::new(RefCell::new(Vec::new())),
+            Rc::new(RefCell::new(Vec::new())),
+        );
+        let mut lox = Lox::with_output(
+            Box::new(SharedBuffer(Rc::clone(&out))),
+            Box::new(SharedBuffer(Rc::clone(&err))),
+        );
+        lox.run_source(
+            "print 5 & 3; print 5 | 3; print 5 ^ 3; print 1 << 10; print -16 >> 2;
+             print 1 | 2 & 3; print 1 << 2 < 5; print 6 & 3 ^ 1;",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out.borrow().clone()).unwrap(),
+            "1\n7\n6\n1024\n-4\n3\ntrue\n3\n"
+        );
+
+        let errors = [
+            ("1.5 << 1;", "Operands of '<<' must be integers."),
+            ("\"a\" & 1;", "Operands of '&' must be integers."),
+            ("1 << 64;", "Shift amount must be between 0 and 63."),
+            ("1 >> -1;", "Shift amount must be between 0 and 63."),
+        ];
+        for (source, expected) in errors {
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::RuntimeError { message, .. }] => assert_eq!(message, expected),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn character_io() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(SharedBuffer(Rc::clone(&out))),
+            Box::new(io::sink()),
+        );
+        lox.interpreter
+            .borrow_mut()
+            .set_input(Box::new("hé\n".as_bytes()));
+        lox.run_source(
+            "var c = getc();
+             while (c != -1) { putc(c); putc(ord(\"|\")); c = getc(); }
+             print chr(65) + str(ord(\"é\")) + str(getc());",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out.borrow().clone()).unwrap(),
+            "h|é|\n|A233-1\n"
+        );
+
+        let errors = [
+            ("chr(-1);", "chr() expects a character code."),
+            ("chr(1.5);", "chr() expects a character code."),
+            ("chr(55296);", "chr() expects a character code."),
+            ("putc(1114112);", "putc() expects a character code."),
+            ("putc(\"a\");", "putc() expects a character code."),
+            ("ord(\"ab\");", "ord() expects a single character."),
+            ("ord(\"\");", "ord() expects a single character."),
+            ("ord(1);", "ord() expects a string."),
+        ];
+        for (source, expected) in errors {
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::RuntimeError { message, .. }] => assert_eq!(message, expected),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn composite_values_print() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(SharedBuffer(Rc::clone(&out))),
+            Box::new(io::sink()),
+        );
+        lox.run_source(
+            "print \"a\";
+             print [\"a\", 1, nil, [\"b\", [true]], []];
+             var inner = [\"x\"];
+             print [inner, inner];
+             var self = [1, 2];
+             self[1] = self;
+             print self;
+             var m = dict();
+             m[\"me\"] = m;
+             m[\"list\"] = [m];
+             print m;
+             print \"${[\"in\"]} ${\"out\"}\";
+             print str([\"s\"]) + repr(\"s\") + repr(1) + repr([\"t\"]);",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out.borrow().clone()).unwrap(),
+            "a\n\
+             [\"a\", 1, nil, [\"b\", [true]], []]\n\
+             [[\"x\"], [\"x\"]]\n\
+             [1, [...]]\n\
+             {\"list\": [{...}], \"me\": {...}}\n\
+             [\"in\"] out\n\
+             [\"s\"]\"s\"1[\"t\"]\n"
+        );
+    }
+
+    #[test]
+    fn format_native() {
+        let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        lox.run_source(
+            "var a = format(\"{0:.2} + {1}\", 1.005, \"x\");
+             var b = format(\"{{{0}}} {1}\", [\"y\"], nil);
+             var c = format(\"plain\");",
+        )
+        .unwrap();
+        assert_eq!(string(global(&lox, "a")), "1.01 + x");
+        assert_eq!(string(global(&lox, "b")), "{[\"y\"]} nil");
+        assert_eq!(string(global(&lox, "c")), "plain");
+
+        let errors = [
+            (
+                "format(\"{1}\", 1);",
+                "format() index 1 out of range for 1 argument.",
+            ),
+            (
+                "format(\"{0:x}\", 1);",
+                "format() spec '{0:x}' is malformed.",
+            ),
+            ("format(1);", "format() expects a string."),
+            ("format();", "Expected at least 1 argument but got 0."),
+        ];
+        for (source, expected) in errors {
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::RuntimeError { token, message, .. }] => {
+                    assert_eq!(message, expected);
+                    assert_eq!(token.type_, TokenType::RIGHT_PAREN, "{}", source);
+                }
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn debugger_prompt() {
+        let transcript = Rc::new(RefCell::new(Vec::new()));
+        let commands = "p n * 10\nenv\nhelp\np m\np (\np n = n + 1\nstep\nstep\np total\nc\n";
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(SharedBuffer(Rc::clone(&out))),
+            Box::new(io::sink()),
+        );
+        lox.interpreter
+            .borrow_mut()
+            .set_debugger(Some(DebugHook::new(
+                Box::new(io::Cursor::new(commands)),
+                Box::new(SharedBuffer(Rc::clone(&transcript))),
+            )));
+        lox.run_source(
+            "var total = 0;
+             fun add(n) {
+               breakpoint();
+               total = total + n;
+               print total;
+             }
+             add(2);
+             add(3);",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(transcript.borrow().clone()).unwrap(),
+            "Breakpoint at line 3.
+(debug) 20
+(debug) frame 0:
+  n = 2
+globals:
+  add = <fn add(1)>
+  total = 0
+(debug) Unknown command 'help'. Commands: p <expr>, env, step, c.
+(debug) Error: Undefined variable 'm'.
+(debug) Error: Expect expression
+(debug) 3
+(debug) Stopped at line 4.
+(debug) Stopped at line 5.
+(debug) 3
+(debug) Breakpoint at line 3.
+(debug) \n"
+        );
+        // `p` assigned the parameter, and the end of the commands let the
+        // second call run on.
+        assert_eq!(String::from_utf8(out.borrow().clone()).unwrap(), "3\n6\n");
+
+        // Without a debugger the native does nothing.
+        let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        lox.run_source("breakpoint(); var x = 1;").unwrap();
+        assert_eq!(number(global(&lox, "x")), 1.0);
+    }
+
+    #[test]
+    fn trace_levels() {
+        let source = "fun twice(n) {
+               return n * 2;
+             }
+             for (var i = 0; i < 2; i = i + 1) print twice(i);
+             twice(3);";
+        let trace = |level| {
+            let out = Rc::new(RefCell::new(Vec::new()));
+            let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+            {
+                let mut interpreter = lox.interpreter.borrow_mut();
+                interpreter.trace = level;
+                interpreter.set_trace_output(Box::new(SharedBuffer(Rc::clone(&out))));
+            }
+            lox.run_source(source).unwrap();
+            let trace = String::from_utf8(out.borrow().clone()).unwrap();
+            trace
+        };
+        assert_eq!(trace(TraceLevel::Off), "");
+        // The `for` shows once, its initializer included.
+        assert_eq!(
+            trace(TraceLevel::Statements),
+            "[line 1] exec: (fun twice (n) ...)
+[line 4] exec: (for (var i 0) (< i 2) (= i (+ i 1)) ...)
+[line 4] exec: (print (call twice i))
+  [line 2] exec: (return (* n 2))
+[line 4] exec: (print (call twice i))
+  [line 2] exec: (return (* n 2))
+[line 5] exec: (call twice 3)
+  [line 2] exec: (return (* n 2))
+"
+        );
+        let expressions = trace(TraceLevel::Expressions);
+        assert!(
+            expressions.starts_with(
+                "[line 1] exec: (fun twice (n) ...)
+[line 4] exec: (for (var i 0) (< i 2) (= i (+ i 1)) ...)
+[line 4] eval: 0
+[line 4] eval: (< i 2)
+[line 4] eval: i
+[line 4] eval: 2
+[line 4] exec: (print (call twice i))
+[line 4] eval: (call twice i)
+"
+            ),
+            "{}",
+            expressions
+        );
+        assert!(
+            expressions.ends_with(
+                "[line 5] eval: 3
+  [line 2] exec: (return (* n 2))
+  [line 2] eval: (* n 2)
+  [line 2] eval: n
+  [line 2] eval: 2
+"
+            ),
+            "{}",
+            expressions
+        );
+    }
+
+    #[test]
+    fn profiler_natives() {
+        let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        lox.run_source(
+            "var before = profileReport();
+             fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+             fun countdown(n) { if (n == 0) return n; return countdown(n - 1); }
+             profileStart();
+             fib(15);
+             countdown(10);
+             var report = profileReport();",
+        )
+        .unwrap();
+        assert_eq!(Interpreter::stringify(global(&lox, "before")), "nil");
+        let report = Interpreter::stringify(global(&lox, "report"));
+        let calls = |name: &str| -> (String, u64) {
+            let row = report
+                .lines()
+                .find(|row| row.split_whitespace().next() == Some(name))
+                .unwrap_or_else(|| panic!("no {} in\n{}", name, report));
+            let columns: Vec<&str> = row.split_whitespace().collect();
+            (columns[1].to_string(), columns[2].parse().unwrap())
+        };
+        assert!(report.starts_with("function "), "{}", report);
+        assert_eq!(calls("fib"), (String::from("2"), 1973));
+        // Tail calls run in place of their caller but still count.
+        assert_eq!(calls("countdown"), (String::from("3"), 11));
+        assert_eq!(calls("profileReport"), (String::from("-"), 1));
+    }
+
+    #[test]
+    fn random_natives() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(SharedBuffer(Rc::clone(&out))),
+            Box::new(io::sink()),
+        );
+        lox.run_source(
+            "seed(42);
+             print random();
+             print random();
+             var rolls = \"\";
+             for (var i = 0; i < 8; i++) rolls = rolls + str(randomInt(1, 6));
+             print rolls;
+             seed(42);
+             print random();
+             print randomInt(-3, -3);",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out.borrow().clone()).unwrap(),
+            "0.8587981413313663\n0.2524601084204602\n14252534\n0.8587981413313663\n-3\n"
+        );
+
+        let cases = [
+            (
+                "randomInt(2, 1);",
+                "randomInt() min 2 is greater than max 1.",
+            ),
+            ("randomInt(1.5, 2);", "randomInt() expects integers."),
+            ("randomInt(1, \"6\");", "randomInt() expects integers."),
+            (
+                "randomInt(0, 9007199254740993);",
+                "randomInt() expects integers.",
+            ),
+            ("seed(nil);", "seed() expects a number."),
+        ];
+        for (source, expected) in cases {
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::RuntimeError { message, .. }] => assert_eq!(message, expected),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    // A clock that only moves when slept on, and remembers each sleep.
+    struct FakeClock(Rc<RefCell<(Duration, Vec<Duration>)>>);
+
+    impl TimeSource for FakeClock {
+        fn now(&self) -> Duration {
+            self.0.borrow().0
+        }
+        fn sleep(&mut self, duration: Duration) {
+            let mut clock = self.0.borrow_mut();
+            clock.0 += duration;
+            clock.1.push(duration);
+        }
+    }
+
+    #[test]
+    fn time_natives() {
+        let clock = Rc::new(RefCell::new((
+            Duration::from_millis(1_709_211_909_250),
+            Vec::new(),
+        )));
+        let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        lox.interpreter
+            .borrow_mut()
+            .set_time_source(Box::new(FakeClock(Rc::clone(&clock))));
+        lox.run_source(
+            "var before = clockMillis();
+             var started = date();
+             var start = clock();
+             sleep(1.5);
+             sleep(0);
+             var elapsed = clock() - start;
+             var after = clockMillis();",
+        )
+        .unwrap();
+        assert_eq!(number(global(&lox, "before")), 1_709_211_909_250.0);
+        assert_eq!(string(global(&lox, "started")), "2024-02-29T13:05:09.250Z");
+        assert_eq!(number(global(&lox, "elapsed")), 1.5);
+        assert_eq!(number(global(&lox, "after")), 1_709_211_910_750.0);
+        assert_eq!(
+            clock.borrow().1,
+            [Duration::from_millis(1500), Duration::ZERO]
+        );
+
+        let cases = [
+            ("sleep(-0.5);", "sleep() can't wait a negative time."),
+            ("sleep(\"1\");", "sleep() expects a number."),
+            (
+                "sleep(18446744073709551616);",
+                "sleep() can't wait 18446744073709552000 seconds.",
+            ),
+        ];
+        for (source, expected) in cases {
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::RuntimeError { message, .. }] => assert_eq!(message, expected),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+        assert_eq!(clock.borrow().1.len(), 2);
+    }
+
+    #[test]
+    fn script_args_and_environment() {
+        let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        lox.run_source("var none = args();").unwrap();
+        lox.interpreter
+            .borrow_mut()
+            .set_args(vec![String::from("foo"), String::from("bar baz")]);
+        lox.run_source(
+            "var all = args();
+             var count = len(all);
+             var second = all[1];
+             all[0] = \"changed\";
+             var first = args()[0];
+             var path = getenv(\"PATH\");
+             var missing = getenv(\"LOX_SURELY_UNSET_VARIABLE\");
+             var odd = [getenv(\"\"), getenv(\"A=B\")];",
+        )
+        .unwrap();
+        assert_eq!(Interpreter::stringify(global(&lox, "none")), "[]");
+        assert_eq!(number(global(&lox, "count")), 2.0);
+        assert_eq!(string(global(&lox, "second")), "bar baz");
+        assert_eq!(string(global(&lox, "first")), "foo");
+        match std::env::var("PATH") {
+            Ok(path) => assert_eq!(string(global(&lox, "path")), path),
+            Err(_) => assert_eq!(Interpreter::stringify(global(&lox, "path")), "nil"),
+        }
+        assert_eq!(Interpreter::stringify(global(&lox, "missing")), "nil");
+        assert_eq!(Interpreter::stringify(global(&lox, "odd")), "[nil, nil]");
+
+        match lox.run_source("getenv(1);").unwrap_err().as_slice() {
+            [LoxError::RuntimeError { message, .. }] => {
+                assert_eq!(message, "getenv() expects a string.")
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn print_natives() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(SharedBuffer(Rc::clone(&out))),
+            Box::new(io::sink()),
+        );
+        lox.run_source(
+            "var p = print;
+             p(\"a\"); p(1.5); p(nil);
+             print(2);
+             var r = p([1, \"x\"]);
+             print r == nil;
+             var fns = [print];
+             fns[0](true);
+             (print)(2);",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out.borrow().clone()).unwrap(),
+            "a\n1.5\nnil\n2\n[1, \"x\"]\ntrue\ntrue\n2\n"
+        );
+
+        match lox.run_source("p();").unwrap_err().as_slice() {
+            [LoxError::RuntimeError { message, .. }] => {
+                assert_eq!(message, "Expected 1 argument but got 0.")
+            }
+            other => panic!("{:?}", other),
+        }
+        // At the start of a statement `print` is still the keyword.
+        assert!(matches!(
+            lox.run_source("print;").unwrap_err().as_slice(),
+            [LoxError::ParseError { .. }]
+        ));
+    }
+
+    #[test]
+    fn higher_order_natives() {
+        let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        lox.run_source(
+            "fun square(x) { return x * x; }
+             fun odd(x) { return x % 2 == 1; }
+             fun add(a, b) { return a + b; }
+             var squares = map(square, [1, 2, 3]);
+             var odds = filter(odd, range(7));
+             var sum = reduce(add, 0, [1, 2, 3, 4]);
+             var empty = reduce(add, \"init\", []);
+             fun adder(n) { fun add(x) { return x + n; } return add; }
+             var shifted = map(adder(10), [1, 2]);
+             var table = dict();
+             var caught;
+             fun fail(x) { if (x == 2) throw \"two\"; return x; }
+             try { map(fail, [1, 2, 3]); } catch (e) { caught = e; }",
+        )
+        .unwrap();
+        let shown = |name| Interpreter::stringify(global(&lox, name));
+        assert_eq!(shown("squares"), "[1, 4, 9]");
+        assert_eq!(shown("odds"), "[1, 3, 5]");
+        assert_eq!(number(global(&lox, "sum")), 10.0);
+        assert_eq!(string(global(&lox, "empty")), "init");
+        assert_eq!(shown("shifted"), "[11, 12]");
+        assert!(matches!(&*global(&lox, "table"), ExprValue::Map(_)));
+        assert_eq!(string(global(&lox, "caught")), "two");
+
+        let errors = [
+            (
+                "map(add, [1]);",
+                "map() callback add(): Expected 2 arguments but got 1.",
+            ),
+            (
+                "reduce(square, 0, [1]);",
+                "reduce() callback square(): Expected 1 argument but got 2.",
+            ),
+            ("map(square);", "Expected 2 arguments but got 1."),
+            ("dict(1);", "Expected 0 arguments but got 1."),
+            ("filter(1, [1]);", "filter() expects a function."),
+            ("filter(odd, \"abc\");", "filter() expects a list."),
+            ("map(square, [\"a\"]);", "STAR operands must be a number(s)"),
+        ];
+        for (source, expected) in errors {
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::RuntimeError { message, .. }] => assert_eq!(message, expected),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn sort_native() {
+        let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        lox.run_source(
+            "var numbers = [3, -1, 2.5, 10, 0];
+             var same = sort(numbers) == numbers;
+             var words = sort([\"pear\", \"apple\", \"Zoo\", \"\"]);
+             fun descending(a, b) { return b - a; }
+             var down = sort([1, 3, 2], descending);
+             fun byFirst(a, b) { return a[0] - b[0]; }
+             var pairs = sort([[2, \"a\"], [1, \"b\"], [2, \"c\"], [1, \"d\"]], byFirst);
+             var calls = 0;
+             fun failing(a, b) {
+               calls = calls + 1;
+               if (calls == 3) throw \"bad compare\";
+               return a - b;
+             }
+             var partial = [5, 4, 3, 2, 1];
+             var caught;
+             try { sort(partial, failing); } catch (e) { caught = e; }",
+        )
+        .unwrap();
+        let shown = |name| Interpreter::stringify(global(&lox, name));
+        assert_eq!(shown("numbers"), "[-1, 0, 2.5, 3, 10]");
+        assert_eq!(shown("same"), "true");
+        assert_eq!(shown("words"), "[\"\", \"Zoo\", \"apple\", \"pear\"]");
+        assert_eq!(shown("down"), "[3, 2, 1]");
+        assert_eq!(
+            shown("pairs"),
+            "[[1, \"b\"], [1, \"d\"], [2, \"a\"], [2, \"c\"]]"
+        );
+        assert_eq!(string(global(&lox, "caught")), "bad compare");
+        assert_eq!(shown("partial"), "[5, 4, 3, 2, 1]");
+
+        match lox
+            .run_source("calls = 0; sort([3, 2, 1], failing);")
+            .unwrap_err()
+            .as_slice()
+        {
+            [LoxError::Thrown { value, .. }] => assert_eq!(string(Rc::clone(value)), "bad compare"),
+            other => panic!("{:?}", other),
+        }
+        let errors = [
+            ("sort(1);", "sort() expects a list."),
+            (
+                "sort([1, \"a\"]);",
+                "sort() can only compare two numbers or two strings.",
+            ),
+            (
+                "sort([nil, nil]);",
+                "sort() can only compare two numbers or two strings.",
+            ),
+            ("sort([1, 2], 3);", "sort() expects a function."),
+            (
+                "fun one(a) { return 0; } sort([1, 2], one);",
+                "sort() callback one(): Expected 1 argument but got 2.",
+            ),
+            (
+                "fun word(a, b) { return \"x\"; } sort([1, 2], word);",
+                "sort() comparator must return a number.",
+            ),
+        ];
+        for (source, expected) in errors {
+            match lox.run_source(source).unwrap_err().as_slice() {
+                [LoxError::RuntimeError { message, .. }] => assert_eq!(message, expected),
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+        // Nothing is compared in lists too short to sort.
+        lox.run_source("sort([]); sort([nil]);").unwrap();
+    }
+
+    #[test]
+    fn line_and_file_natives() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(SharedBuffer(Rc::clone(&out))),
+            Box::new(io::sink()),
+        );
+        lox.interpreter
+            .borrow_mut()
+            .set_input(Box::new("first\r\nsecond\n\nlast".as_bytes()));
+        let path = std::env::temp_dir().join("lox_line_and_file_natives.txt");
+        let path = path.to_str().unwrap().replace('\\', "/");
+        lox.run_source(&format!(
+            "var lines = dict();
+             var line = readLine();
+             while (line != nil) {{ lines[len(lines)] = line; line = readLine(); }}
+             print len(lines);
+             var wrote = writeFile(\"{path}\", lines[0] + \"|\" + lines[3]);
+             print wrote;
+             print readFile(\"{path}\");"
+        ))
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            String::from_utf8(out.borrow().clone()).unwrap(),
+            "4\ntrue\nfirst|last\n"
+        );
+
+        match lox
+            .run_source(&format!("readFile(\"{}\");", path))
+            .unwrap_err()
+            .as_slice()
+        {
+            [LoxError::RuntimeError { message, .. }] => {
+                assert!(
+                    message.starts_with(&format!("Couldn't read file '{}': ", path)),
+                    "{}",
+                    message
+                )
+            }
+            other => panic!("{:?}", other),
+        }
+
+        lox.interpreter.borrow_mut().disable_fs();
+        match lox.run_source("readFile(\"x\");").unwrap_err().as_slice() {
+            [LoxError::RuntimeError { message, .. }] => {
+                assert_eq!(message, "Undefined variable 'readFile'.")
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn try_catch() {
+        let cases = [
+            (
+                "var m; try { var x = 1; x(); } catch (e) { m = e[\"message\"]; } m;",
+                "Can only call functions and classes, got number from expression 'x'.",
+            ),
+            (
+                "var l; try {\n\n  undefined;\n} catch (e) { l = e[\"line\"]; } l;",
+                "3",
+            ),
+            (
+                "var c; try { throw \"oops\"; } catch (e) { c = e; } c;",
+                "oops",
+            ),
+            (
+                "var c; try { throw [1, 2]; } catch (e) { c = e; } c;",
+                "[1, 2]",
+            ),
+            ("var c; try { throw nil; } catch (e) { c = e; } c;", "nil"),
+            (
+                "var r = \"unchanged\"; try { r = \"ran\"; } catch (e) { r = \"caught\"; } r;",
+                "ran",
+            ),
+            (
+                "fun f(n) { if (n == 0) throw \"deep\"; f(n - 1); }
+                 var c; try { f(10); } catch (e) { c = e; } c;",
+                "deep",
+            ),
+            (
+                "var log = \"\";
+                 try {
+                   try { throw 1; } catch (e) { log = log + \"inner \"; throw e + 1; }
+                 } catch (e) { log = log + str(e); }
+                 log;",
+                "inner 2",
+            ),
+            (
+                "var i = 0;
+                 while (true) { try { i = i + 1; if (i == 3) break; } catch (e) {} }
+                 i;",
+                "3",
+            ),
+            (
+                "fun f() { try { return \"body\"; } catch (e) {} return \"after\"; } f();",
+                "body",
+            ),
+            // The handler's scope ends with it, so the outer `e` is untouched.
+            (
+                "var e = \"outer\"; try { throw \"inner\"; } catch (e) {} e;",
+                "outer",
+            ),
+            // Scopes left by the throw are unwound before the handler runs.
+            (
+                "var x = \"global\"; var seen;
+                 try { var x = \"local\"; { var x = \"inner\"; throw 0; } }
+                 catch (e) { seen = x; }
+                 seen;",
+                "global",
+            ),
+        ];
+        for (source, expected) in cases {
+            let value = eval(source).unwrap_or_else(|code| panic!("{}: {:?}", source, code));
+            assert_eq!(
+                Interpreter::stringify(Rc::new(value)),
+                expected,
+                "{}",
+                source
+            );
+        }
+
+        let err = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(io::sink()),
+            Box::new(SharedBuffer(Rc::clone(&err))),
+        );
+        lox.run(b"fun f() {\n  throw \"bad\";\n}\nf();".to_vec());
+        assert_eq!(lox.exit_code(), ExitCode::RuntimeError);
+        assert_eq!(
+            String::from_utf8(err.borrow().clone())
+                .unwrap()
+                .lines()
+                .take(2)
+                .collect::<Vec<_>>(),
+            ["bad", "[line 2]"]
+        );
+
+        // Parse errors stop the whole script before anything runs.
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut lox = Lox::with_output(
+            Box::new(SharedBuffer(Rc::clone(&out))),
+            Box::new(io::sink()),
+        );
+        lox.run(b"print \"before\"; try { print ; } catch (e) { print e; }".to_vec());
+        assert_eq!(lox.exit_code(), ExitCode::CompileError);
+        assert!(out.borrow().is_empty());
+
+        // Neither can a script catch running out of budget.
+        let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        lox.interpreter.borrow_mut().set_step_limit(Some(10_000));
+        match lox
+            .run_source("try { while (true) {} } catch (e) { while (true) {} }")
+            .unwrap_err()
+            .as_slice()
+        {
+            [LoxError::RuntimeError { message, .. }] => {
+                assert_eq!(message, "Execution budget exceeded.")
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let errors = [
+            (
+                "try print 1;",
+                "[line 1] Error at 'print': Expect '{' after 'try'.",
+            ),
+            (
+                "try {} print 1;",
+                "[line 1] Error at 'print': Expect 'catch' after try block.",
+            ),
+            (
+                "try {} catch e {}",
+                "[line 1] Error at 'e': Expect '(' after 'catch'.",
+            ),
+            (
+                "try {} catch () {}",
+                "[line 1] Error at ')': Expect name for the caught error.",
+            ),
+            (
+                "throw 1",
+                "[line 1] Error at end: Expect ';' after thrown value.",
+            ),
+        ];
+        for (source, expected) in errors {
+            assert_eq!(
+                rendered_errors(source)[0].lines().next(),
+                Some(expected),
+                "{}",
+                source
+            );
+        }
+    }
+}
@@ -1,65 +1,135 @@
 use crate::environment::Environment;
-use crate::interpreter::{ExprValue, ExprValueResult, LoxCallable};
+use crate::interpreter::{Arity, ControlFlow, ExprValue, ExprValueResult, LoxCallable};
 use crate::lox::LoxError;
-use crate::stmt::{RcStmt, Stmt};
-use crate::token::Literal;
+use crate::span::Span;
+use crate::stmt::{Parameter, Stmt, StmtId};
+use crate::token::RcToken;
 use crate::Interpreter;
-use std::borrow::Borrow;
 use std::cell::RefCell;
 use std::rc::Rc;
 
+// Parameters with defaults come last, so any count from the required ones
+// up to all of them will do.
+pub fn parameter_arity(params: &[Parameter]) -> Arity {
+    let required = params
+        .iter()
+        .take_while(|(_, default)| default.is_none())
+        .count();
+    if required == params.len() {
+        Arity::Exact(required)
+    } else {
+        Arity::Range(required, params.len())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct LoxFunction {
-    pub declaration: RcStmt,
+    pub declaration: StmtId,
+    // Copied from the declaration, which can only be looked up through the
+    // interpreter's arena.
+    pub name: RcToken,
+    pub arity: Arity,
     pub closure: Rc<RefCell<Environment>>,
 }
 impl LoxCallable for LoxFunction {
-    fn arity(&self) -> usize {
-        match self.declaration.borrow() {
-            Stmt::Function {
-                name: _,
-                params,
-                body: _,
-            } => params.len(),
-            _ => unreachable!("Non-function statement in function call?"),
-        }
+    fn arity(&self) -> Arity {
+        self.arity
     }
     fn call(
         &self,
         interpreter: &mut Interpreter,
+        _paren: &RcToken,
         arguments: Vec<Rc<ExprValue>>,
     ) -> ExprValueResult {
-        let environment = Rc::clone(&self.closure);
-        match self.declaration.borrow() {
-            Stmt::Function {
-                name: _,
-                params,
-                body,
-            } => {
-                // Copy args into our environment.
-                for i in 0..params.len() {
-                    environment
-                        .borrow_mut()
-                        .define(params[i].lexeme.clone(), Some(Rc::clone(&arguments[i])))
+        // A trampoline: calls in tail position come back here to be made,
+        // rather than nesting inside the function that made them.
+        let mut function = self.clone();
+        let mut arguments = arguments;
+        let mut tail_call: Option<Span> = None;
+        loop {
+            let error = match function.invoke(interpreter, arguments) {
+                Ok(ControlFlow::Normal) => return Ok(interpreter.nil()),
+                Ok(ControlFlow::Return { value, .. }) => return Ok(value),
+                Ok(ControlFlow::TailCall {
+                    function: next,
+                    paren,
+                    arguments: next_arguments,
+                    span,
+                    ..
+                }) => match next.as_function() {
+                    Some(next) => {
+                        interpreter.profile_tail_call(next);
+                        function = next.clone();
+                        arguments = next_arguments;
+                        tail_call = Some(span);
+                        continue;
+                    }
+                    None => match next.call(interpreter, &paren, next_arguments) {
+                        Ok(value) => return Ok(value),
+                        Err(e) => e.with_span(span),
+                    },
+                },
+                Ok(flow @ (ControlFlow::Break { .. } | ControlFlow::Continue { .. })) => {
+                    flow.stray()
                 }
-                return match interpreter.execute_block(Rc::clone(body), environment) {
-                    Err(LoxError::ReturnValue { value }) => Ok(value),
-                    Err(e) => Err(e),
-                    _ => Ok(Rc::from(ExprValue::Literal(Literal::NIL))),
-                };
-                // return Ok(Rc::from(ExprValue::Literal(Literal::BOOL(true))));
-            }
-            _ => unreachable!("Non-function statement in function call?"),
+                Err(e) => e,
+            };
+            let error = match tail_call {
+                Some(span) => error.with_span(span),
+                None => error,
+            };
+            return Err(error);
         }
     }
+    fn closure(&self) -> Option<&Rc<RefCell<Environment>>> {
+        Some(&self.closure)
+    }
+    fn name(&self) -> &str {
+        &self.name.lexeme
+    }
     fn to_string(&self) -> String {
-        match self.declaration.borrow() {
-            Stmt::Function {
-                name,
-                params: _,
-                body: _,
-            } => {
-                format!("<fn {} >", name.lexeme)
+        format!("<fn {}({})>", self.name(), self.arity())
+    }
+    fn as_function(&self) -> Option<&LoxFunction> {
+        Some(self)
+    }
+}
+
+impl LoxFunction {
+    // Runs the body once, handing back how it ended for `call`.
+    fn invoke(
+        &self,
+        interpreter: &mut Interpreter,
+        arguments: Vec<Rc<ExprValue>>,
+    ) -> Result<ControlFlow, LoxError> {
+        let ast = Rc::clone(interpreter.ast());
+        match &ast[self.declaration] {
+            Stmt::Function { params, body, .. } => {
+                // Each call binds its arguments in a fresh child of the closure,
+                // where they take the first slots in parameter order. The body's
+                // own locals follow.
+                let given = arguments.len();
+                let mut slots = Vec::with_capacity(params.len());
+                slots.extend(arguments);
+                let names = params[..given]
+                    .iter()
+                    .map(|(name, _)| Rc::clone(&name.lexeme))
+                    .collect();
+                let environment = interpreter.new_environment(Environment::with_slots(
+                    &self.closure,
+                    names,
+                    slots,
+                ));
+                // Missing arguments get their defaults, evaluated anew on every
+                // call and able to use the parameters bound so far.
+                for (name, default) in &params[given..] {
+                    let default = default.as_ref().expect("arity was checked");
+                    let value = interpreter.evaluate_in(*default, &environment)?;
+                    environment
+                        .borrow_mut()
+                        .define(Rc::clone(&name.lexeme), Some(value));
+                }
+                interpreter.execute_block(body, environment)
             }
             _ => unreachable!("Non-function statement in function call?"),
         }
@@ -1,47 +1,124 @@
-// Stopped at https://craftinginterpreters.com/functions.html
+// Command-line front end for the interpreter library.
 
-#[allow(unused_imports)]
-mod ast_printer;
-mod environment;
-mod expr;
-mod interpreter;
-mod lox;
-mod lox_function;
-mod parser;
-mod resolver;
-mod scanner;
-mod stmt;
-mod token;
-mod token_type;
-
-use crate::interpreter::Interpreter;
-use std::cell::RefCell;
+use crafting_interpreters::{DebugHook, ExitCode, Lox, Mode, Reporter, TraceLevel};
 #[allow(unused_imports)]
 use std::env;
+use std::io::{self, BufReader};
 use std::process;
-use std::rc::Rc;
+use std::thread;
+use std::time::Instant;
+
+fn usage() -> ! {
+    println!("Usage: jlox [--tokens | --ast] [--vm [--dump]] [--strict] [--fresh-loop-bindings] [--no-fs] [--bench] [--debug] [--debug-resolver] [--no-fold] [--optimize] [--trace | --trace-expressions] [--profile] [--max-errors N] [--json-errors] [script [args...] | - [args...] | -e source]");
+    process::exit(ExitCode::Usage.into());
+}
+
+// Lox calls recurse on the host stack, so the interpreter runs on a thread
+// with room for the full call depth limit even in debug builds.
+const INTERPRETER_STACK_SIZE: usize = 256 * 1024 * 1024;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let interpreter = Rc::from(RefCell::from(Interpreter::new()));
-    let mut lox_runtime = lox::Lox {
-        had_error: false,
-        had_runtime_error: false,
-        interpreter,
-    };
-    if args.len() > 2 {
-        println!("Usage: jlox [script]");
-        process::exit(64);
-    } else if args.len() == 2 {
-        lox_runtime.run_file(&args[1]);
-    } else {
-        lox_runtime.run_prompt();
+    let interpreter = thread::Builder::new()
+        .stack_size(INTERPRETER_STACK_SIZE)
+        .spawn(run)
+        .unwrap();
+    match interpreter.join() {
+        Ok(code) => process::exit(code.into()),
+        Err(panic) => std::panic::resume_unwind(panic),
     }
-    // ast_printer::main();
 }
 
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn scan_file() {}
+fn run() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    let mut lox_runtime = Lox::new();
+    let mut script: Option<&String> = None;
+    let mut source: Option<&String> = None;
+    let mut bench = false;
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--tokens" => lox_runtime.mode = Mode::Tokens,
+            "--ast" => lox_runtime.mode = Mode::Ast,
+            "--vm" => lox_runtime.vm = true,
+            "--json-errors" => lox_runtime.reporter = Reporter::Json,
+            "--dump" => lox_runtime.mode = Mode::Bytecode,
+            "--strict" => lox_runtime.interpreter.borrow_mut().strict = true,
+            "--fresh-loop-bindings" => {
+                lox_runtime.interpreter.borrow_mut().fresh_loop_bindings = true
+            }
+            "--no-fs" => lox_runtime.interpreter.borrow_mut().disable_fs(),
+            "--bench" => bench = true,
+            "--debug" => {
+                // The prompt talks on stderr, leaving stdout to the program.
+                let hook = DebugHook::new(
+                    Box::new(BufReader::new(io::stdin())),
+                    Box::new(io::stderr()),
+                );
+                lox_runtime
+                    .interpreter
+                    .borrow_mut()
+                    .set_debugger(Some(hook));
+            }
+            "--trace" => lox_runtime.interpreter.borrow_mut().trace = TraceLevel::Statements,
+            "--trace-expressions" => {
+                lox_runtime.interpreter.borrow_mut().trace = TraceLevel::Expressions
+            }
+            "--debug-resolver" => lox_runtime.debug_resolver = true,
+            "--no-fold" => lox_runtime.fold = false,
+            "--optimize" => lox_runtime.optimize = true,
+            "--profile" => lox_runtime.interpreter.borrow_mut().start_profiling(),
+            "--max-errors" => {
+                i += 1;
+                match args.get(i).and_then(|n| n.parse().ok()) {
+                    Some(n) => lox_runtime.max_errors = n,
+                    None => usage(),
+                }
+            }
+            "-e" | "--eval" => {
+                i += 1;
+                match args.get(i) {
+                    Some(text) if source.is_none() => source = Some(text),
+                    _ => usage(),
+                }
+            }
+            flag if flag.starts_with("--") => usage(),
+            // Everything after the script is for the script, flags included.
+            _ => {
+                script = Some(&args[i]);
+                let script_args = args[i + 1..].to_vec();
+                lox_runtime.interpreter.borrow_mut().set_args(script_args);
+                break;
+            }
+        }
+        i += 1;
+    }
+    if lox_runtime.mode == Mode::Bytecode && !lox_runtime.vm {
+        usage();
+    }
+    let start = Instant::now();
+    let code = if let Some(text) = source {
+        if script.is_some() {
+            usage();
+        }
+        // The diagnostics are already printed; only the exit code matters.
+        let _ = lox_runtime.run_source(text);
+        lox_runtime.exit_code()
+    } else if let Some(path) = script {
+        if path == "-" {
+            lox_runtime.set_source_name(Some("<stdin>"));
+            lox_runtime.run_reader(io::stdin())
+        } else {
+            lox_runtime.run_file(path)
+        }
+    } else {
+        lox_runtime.run_prompt()
+    };
+    // On stderr, so that the program's own output can still be compared.
+    if bench {
+        eprintln!("Elapsed: {:.3} ms", start.elapsed().as_secs_f64() * 1000.0);
+    }
+    if let Some(report) = lox_runtime.interpreter.borrow().profile_report() {
+        eprint!("{}", report);
+    }
+    code
 }
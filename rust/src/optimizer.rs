@@ -0,0 +1,777 @@
+// Constant folding, run after resolution unless --no-fold, so the code it
+// removes is checked like any other. Expressions made only of literals
+// become one literal, logical operators with a constant left operand
+// become the side they'd pick, and `if`, `while` and `for` with constant
+// conditions keep only the code that could run. A callee is left as
+// written, so an error calling it names the same expression either way.
+//
+// Anything that would fail at runtime, like `1 + "a"` or `1 / 0`, is left
+// for the interpreter to report as it always has. So is arithmetic that
+// overflows to infinity, which is an error in strict mode; the operator is
+// reported so it can be warned about.
+//
+// `find_dead_code` finds the same branches without changing anything, to
+// warn about them, and `eliminate` drops just those under --optimize when
+// nothing is folded.
+//
+// The arena only grows, so a changed node is added anew and its parents
+// with it; untouched subtrees keep their ids. Each expression that moves is
+// reported with its old id, so what the resolver found about it can follow.
+
+use crate::arena::AstArena;
+use crate::expr::{Expr, ExprId};
+use crate::span::Span;
+use crate::stmt::{Stmt, StmtId};
+use crate::token::{Literal, RcToken};
+use crate::token_type::TokenType::{self, *};
+use std::collections::HashSet;
+use std::rc::Rc;
+
+// What a pass over the arena leaves behind.
+pub struct Folded {
+    pub statements: Vec<StmtId>,
+    // Each expression rebuilt on the way, as (old id, new id).
+    pub moved: Vec<(ExprId, ExprId)>,
+    // The operators of constant arithmetic that overflowed.
+    pub overflows: Vec<RcToken>,
+}
+
+pub fn fold(ast: &mut AstArena, stmts: Vec<StmtId>) -> Folded {
+    let folder = Folder {
+        ast,
+        dead: None,
+        moved: Vec::new(),
+        overflows: Vec::new(),
+    };
+    folder.run(&stmts)
+}
+
+// Drops the branches `find_dead_code` found in the `if`, `while` and `for`
+// statements in `dead`.
+pub fn eliminate(ast: &mut AstArena, stmts: Vec<StmtId>, dead: &HashSet<StmtId>) -> Folded {
+    let folder = Folder {
+        ast,
+        dead: Some(dead),
+        moved: Vec::new(),
+        overflows: Vec::new(),
+    };
+    folder.run(&stmts)
+}
+
+// A branch that a constant condition keeps from ever running.
+pub struct DeadCode {
+    // The `if` or loop it belongs to.
+    pub stmt: StmtId,
+    pub condition: Span,
+    pub message: &'static str,
+    pub span: Span,
+    // The variables and functions it declares in its own block. Code after
+    // it can't see them, but may look for them all the same.
+    pub declarations: Vec<RcToken>,
+}
+
+// Every branch in `stmts` that a constant condition rules out, outermost
+// first.
+pub fn find_dead_code(ast: &AstArena, stmts: &[StmtId]) -> Vec<DeadCode> {
+    let mut found = Vec::new();
+    for &stmt in stmts {
+        dead_code_in(ast, stmt, &mut found);
+    }
+    found
+}
+
+fn dead_code_in(ast: &AstArena, stmt: StmtId, found: &mut Vec<DeadCode>) {
+    let constant = |condition: ExprId| {
+        constant(ast, condition).map(|value| (is_truthy(&value), ast[condition].span()))
+    };
+    let dead = |branch: StmtId, condition: Span, message| {
+        let declarations = match &ast[branch] {
+            Stmt::Block { statements, .. } => statements
+                .iter()
+                .filter_map(|&stmt| match &ast[stmt] {
+                    Stmt::Var { name, .. } | Stmt::Function { name, .. } => Some(Rc::clone(name)),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        DeadCode {
+            stmt,
+            condition,
+            message,
+            span: ast[branch].span(),
+            declarations,
+        }
+    };
+    match &ast[stmt] {
+        Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            match (constant(*condition), else_branch) {
+                (Some((false, span)), _) => found.push(dead(
+                    *then_branch,
+                    span,
+                    "Condition is always false; this branch never runs.",
+                )),
+                (Some((true, span)), Some(else_branch)) => found.push(dead(
+                    *else_branch,
+                    span,
+                    "Condition is always true; the 'else' branch never runs.",
+                )),
+                _ => {}
+            }
+            dead_code_in(ast, *then_branch, found);
+            if let Some(else_branch) = else_branch {
+                dead_code_in(ast, *else_branch, found);
+            }
+        }
+        Stmt::While {
+            condition, body, ..
+        }
+        | Stmt::For {
+            condition: Some(condition),
+            body,
+            ..
+        } => {
+            if let Some((false, span)) = constant(*condition) {
+                found.push(dead(
+                    *body,
+                    span,
+                    "Condition is always false; this loop never runs.",
+                ));
+            }
+            dead_code_in(ast, *body, found);
+        }
+        Stmt::Block { statements, .. }
+        | Stmt::Function {
+            body: statements, ..
+        } => {
+            for &stmt in statements {
+                dead_code_in(ast, stmt, found);
+            }
+        }
+        Stmt::For { body, .. } | Stmt::ForEach { body, .. } => dead_code_in(ast, *body, found),
+        Stmt::Try { body, handler, .. } => {
+            for &stmt in body.iter().chain(handler) {
+                dead_code_in(ast, stmt, found);
+            }
+        }
+        Stmt::Break { .. }
+        | Stmt::Continue { .. }
+        | Stmt::Expression { .. }
+        | Stmt::Import { .. }
+        | Stmt::Print { .. }
+        | Stmt::Return { .. }
+        | Stmt::Throw { .. }
+        | Stmt::Var { .. } => {}
+    }
+}
+
+struct Folder<'a> {
+    ast: &'a mut AstArena,
+    // The statements whose dead branches go, or None for all of them.
+    dead: Option<&'a HashSet<StmtId>>,
+    moved: Vec<(ExprId, ExprId)>,
+    overflows: Vec<RcToken>,
+}
+
+fn is_truthy(value: &Literal) -> bool {
+    !matches!(value, Literal::NIL | Literal::BOOL(false))
+}
+
+// The literal `expr` folds to, if it folds to one.
+fn constant(ast: &AstArena, expr: ExprId) -> Option<Literal> {
+    match &ast[expr] {
+        Expr::Literal { value, .. } => Some(value.clone()),
+        Expr::Grouping { expression, .. } => constant(ast, *expression),
+        Expr::Unary {
+            operator, right, ..
+        } => unary(&operator.type_, &constant(ast, *right)?),
+        Expr::Binary {
+            left,
+            operator,
+            right,
+            ..
+        } => binary(
+            &operator.type_,
+            &constant(ast, *left)?,
+            &constant(ast, *right)?,
+        ),
+        Expr::Logical {
+            left,
+            operator,
+            right,
+            ..
+        } => {
+            let left = constant(ast, *left)?;
+            if is_truthy(&left) == (operator.type_ == OR) {
+                Some(left)
+            } else {
+                constant(ast, *right)
+            }
+        }
+        _ => None,
+    }
+}
+
+fn unary(operator: &TokenType, right: &Literal) -> Option<Literal> {
+    match (operator, right) {
+        (MINUS, Literal::NUMBER(n)) => Some(Literal::NUMBER(-n)),
+        (BANG, value) => Some(Literal::BOOL(!is_truthy(value))),
+        _ => None,
+    }
+}
+
+// What `left operator right` evaluates to, unless that would be an error
+// or isn't worth working out ahead of time.
+fn binary(operator: &TokenType, left: &Literal, right: &Literal) -> Option<Literal> {
+    use Literal::*;
+    match (operator, left, right) {
+        (COMMA, _, right) => Some(right.clone()),
+        (EQUAL_EQUAL, left, right) => Some(BOOL(left == right)),
+        (BANG_EQUAL, left, right) => Some(BOOL(left != right)),
+        (PLUS, STRING(left), STRING(right)) => Some(STRING(format!("{}{}", left, right))),
+        (GREATER, STRING(left), STRING(right)) => Some(BOOL(left > right)),
+        (GREATER_EQUAL, STRING(left), STRING(right)) => Some(BOOL(left >= right)),
+        (LESS, STRING(left), STRING(right)) => Some(BOOL(left < right)),
+        (LESS_EQUAL, STRING(left), STRING(right)) => Some(BOOL(left <= right)),
+        (_, NUMBER(left), NUMBER(right)) => arithmetic(operator, *left, *right),
+        _ => None,
+    }
+}
+
+fn arithmetic(operator: &TokenType, left: f64, right: f64) -> Option<Literal> {
+    match operator {
+        GREATER => Some(Literal::BOOL(left > right)),
+        GREATER_EQUAL => Some(Literal::BOOL(left >= right)),
+        LESS => Some(Literal::BOOL(left < right)),
+        LESS_EQUAL => Some(Literal::BOOL(left <= right)),
+        _ if overflows(operator, left, right) => None,
+        _ => calculate(operator, left, right).map(Literal::NUMBER),
+    }
+}
+
+fn calculate(operator: &TokenType, left: f64, right: f64) -> Option<f64> {
+    match operator {
+        PLUS => Some(left + right),
+        MINUS => Some(left - right),
+        STAR => Some(left * right),
+        SLASH if right != 0.0 => Some(left / right),
+        PERCENT if right != 0.0 => Some(left % right),
+        _ => None,
+    }
+}
+
+// Whether arithmetic on finite numbers gives a result that isn't.
+fn overflows(operator: &TokenType, left: f64, right: f64) -> bool {
+    left.is_finite()
+        && right.is_finite()
+        && calculate(operator, left, right).is_some_and(|result| !result.is_finite())
+}
+
+impl Folder<'_> {
+    fn run(mut self, stmts: &[StmtId]) -> Folded {
+        let statements = self.stmts(stmts);
+        Folded {
+            statements,
+            moved: self.moved,
+            overflows: self.overflows,
+        }
+    }
+    // Whether `stmt` may lose a branch its condition rules out.
+    fn is_dead(&self, stmt: StmtId) -> bool {
+        self.dead.is_none_or(|dead| dead.contains(&stmt))
+    }
+    fn literal(&self, expr: ExprId) -> Option<&Literal> {
+        match &self.ast[expr] {
+            Expr::Literal { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+    fn new_literal(&mut self, value: Literal, span: Span) -> ExprId {
+        self.ast.add_expr(Expr::Literal { value, span })
+    }
+    // `expr` again if `folded` is no different, so unchanged nodes aren't
+    // copied.
+    fn rebuild(&mut self, expr: ExprId, folded: Expr) -> ExprId {
+        if self.ast[expr] == folded {
+            expr
+        } else {
+            let moved = self.ast.add_expr(folded);
+            self.moved.push((expr, moved));
+            moved
+        }
+    }
+    fn rebuild_stmt(&mut self, stmt: StmtId, folded: Stmt) -> StmtId {
+        if self.ast[stmt] == folded {
+            stmt
+        } else {
+            self.ast.add_stmt(folded)
+        }
+    }
+    fn exprs(&mut self, exprs: &[ExprId]) -> Vec<ExprId> {
+        exprs.iter().map(|&expr| self.expr(expr)).collect()
+    }
+    fn expr(&mut self, expr: ExprId) -> ExprId {
+        let folded = match self.ast[expr].clone() {
+            Expr::Assign { name, value, span } => Expr::Assign {
+                name,
+                value: self.expr(value),
+                span,
+            },
+            Expr::Binary {
+                left,
+                operator,
+                right,
+                span,
+            } => {
+                let (left, right) = (self.expr(left), self.expr(right));
+                let (value, overflowed) = match (self.literal(left), self.literal(right)) {
+                    (Some(Literal::NUMBER(l)), Some(Literal::NUMBER(r)))
+                        if overflows(&operator.type_, *l, *r) =>
+                    {
+                        (None, true)
+                    }
+                    (Some(l), Some(r)) => (binary(&operator.type_, l, r), false),
+                    _ => (None, false),
+                };
+                if overflowed {
+                    self.overflows.push(Rc::clone(&operator));
+                }
+                if let Some(value) = value {
+                    return self.new_literal(value, span);
+                }
+                Expr::Binary {
+                    left,
+                    operator,
+                    right,
+                    span,
+                }
+            }
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+                span,
+            } => Expr::Call {
+                callee,
+                paren,
+                arguments: self.exprs(&arguments),
+                span,
+            },
+            Expr::Get { object, name, span } => Expr::Get {
+                object: self.expr(object),
+                name,
+                span,
+            },
+            Expr::Grouping { expression, span } => {
+                let expression = self.expr(expression);
+                if self.literal(expression).is_some() {
+                    return expression;
+                }
+                Expr::Grouping { expression, span }
+            }
+            Expr::Index {
+                object,
+                bracket,
+                index,
+                span,
+            } => Expr::Index {
+                object: self.expr(object),
+                bracket,
+                index: self.expr(index),
+                span,
+            },
+            Expr::IndexAssign {
+                object,
+                bracket,
+                index,
+                value,
+                span,
+            } => Expr::IndexAssign {
+                object: self.expr(object),
+                bracket,
+                index: self.expr(index),
+                value: self.expr(value),
+                span,
+            },
+            Expr::Interpolation { parts, span } => Expr::Interpolation {
+                parts: self.exprs(&parts),
+                span,
+            },
+            Expr::ListLiteral { elements, span } => Expr::ListLiteral {
+                elements: self.exprs(&elements),
+                span,
+            },
+            Expr::Literal { .. } | Expr::Variable { .. } => return expr,
+            Expr::Logical {
+                left,
+                operator,
+                right,
+                span,
+            } => {
+                let left = self.expr(left);
+                let right = self.expr(right);
+                if let Some(value) = self.literal(left) {
+                    // `or` stops at a truthy left operand, `and` at a falsy one.
+                    let stops = is_truthy(value) == (operator.type_ == OR);
+                    return if stops { left } else { right };
+                }
+                Expr::Logical {
+                    left,
+                    operator,
+                    right,
+                    span,
+                }
+            }
+            Expr::Postfix {
+                operator,
+                target,
+                update,
+                span,
+            } => Expr::Postfix {
+                operator,
+                target,
+                update: self.expr(update),
+                span,
+            },
+            Expr::Unary {
+                operator,
+                right,
+                span,
+            } => {
+                let right = self.expr(right);
+                let value = self
+                    .literal(right)
+                    .and_then(|value| unary(&operator.type_, value));
+                if let Some(value) = value {
+                    return self.new_literal(value, span);
+                }
+                Expr::Unary {
+                    operator,
+                    right,
+                    span,
+                }
+            }
+        };
+        self.rebuild(expr, folded)
+    }
+    fn stmts(&mut self, stmts: &[StmtId]) -> Vec<StmtId> {
+        stmts.iter().filter_map(|&stmt| self.stmt(stmt)).collect()
+    }
+    // Where a statement is required, one folded away leaves an empty block.
+    fn required_stmt(&mut self, stmt: StmtId) -> StmtId {
+        match self.stmt(stmt) {
+            Some(folded) => folded,
+            None => {
+                let span = self.ast[stmt].span();
+                self.ast.add_stmt(Stmt::Block {
+                    statements: Vec::new(),
+                    span,
+                })
+            }
+        }
+    }
+    // The folded statement, or None if it can't do anything.
+    fn stmt(&mut self, stmt: StmtId) -> Option<StmtId> {
+        let folded = match self.ast[stmt].clone() {
+            Stmt::Block { statements, span } => Stmt::Block {
+                statements: self.stmts(&statements),
+                span,
+            },
+            Stmt::Break { .. } | Stmt::Continue { .. } | Stmt::Import { .. } => return Some(stmt),
+            Stmt::Expression { expr, span } => Stmt::Expression {
+                expr: self.expr(expr),
+                span,
+            },
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                span,
+            } => {
+                let initializer = initializer.map(|initializer| self.required_stmt(initializer));
+                let condition = condition.map(|condition| self.expr(condition));
+                if self.is_dead(stmt)
+                    && condition
+                        .and_then(|condition| self.literal(condition))
+                        .is_some_and(|value| !is_truthy(value))
+                {
+                    // The initializer still runs, in a scope of its own.
+                    return initializer.map(|initializer| {
+                        self.ast.add_stmt(Stmt::Block {
+                            statements: vec![initializer],
+                            span,
+                        })
+                    });
+                }
+                Stmt::For {
+                    initializer,
+                    condition,
+                    increment: increment.map(|increment| self.expr(increment)),
+                    body: self.required_stmt(body),
+                    span,
+                }
+            }
+            Stmt::ForEach {
+                variable,
+                keyword,
+                iterable,
+                body,
+                span,
+            } => Stmt::ForEach {
+                variable,
+                keyword,
+                iterable: self.expr(iterable),
+                body: self.required_stmt(body),
+                span,
+            },
+            Stmt::Function {
+                name,
+                params,
+                body,
+                span,
+            } => Stmt::Function {
+                name,
+                params: params
+                    .into_iter()
+                    .map(|(name, default)| (name, default.map(|default| self.expr(default))))
+                    .collect(),
+                body: self.stmts(&body),
+                span,
+            },
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                span,
+            } => {
+                let condition = self.expr(condition);
+                let value = self.literal(condition).filter(|_| self.is_dead(stmt));
+                if let Some(value) = value {
+                    return if is_truthy(value) {
+                        self.stmt(then_branch)
+                    } else {
+                        else_branch.and_then(|else_branch| self.stmt(else_branch))
+                    };
+                }
+                Stmt::If {
+                    condition,
+                    then_branch: self.required_stmt(then_branch),
+                    else_branch: else_branch.and_then(|else_branch| self.stmt(else_branch)),
+                    span,
+                }
+            }
+            Stmt::Print { expr, span } => Stmt::Print {
+                expr: self.expr(expr),
+                span,
+            },
+            Stmt::Return {
+                keyword,
+                value,
+                span,
+            } => Stmt::Return {
+                keyword,
+                value: self.expr(value),
+                span,
+            },
+            Stmt::Throw {
+                keyword,
+                value,
+                span,
+            } => Stmt::Throw {
+                keyword,
+                value: self.expr(value),
+                span,
+            },
+            Stmt::Try {
+                body,
+                binding,
+                handler,
+                span,
+            } => Stmt::Try {
+                body: self.stmts(&body),
+                binding,
+                handler: self.stmts(&handler),
+                span,
+            },
+            Stmt::Var {
+                name,
+                initializer,
+                constant,
+                span,
+            } => Stmt::Var {
+                name,
+                initializer: initializer.map(|initializer| self.expr(initializer)),
+                constant,
+                span,
+            },
+            Stmt::While {
+                condition,
+                body,
+                span,
+            } => {
+                let condition = self.expr(condition);
+                if self.is_dead(stmt)
+                    && self
+                        .literal(condition)
+                        .is_some_and(|value| !is_truthy(value))
+                {
+                    return None;
+                }
+                Stmt::While {
+                    condition,
+                    body: self.required_stmt(body),
+                    span,
+                }
+            }
+        };
+        Some(self.rebuild_stmt(stmt, folded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast_printer::stmt_to_string;
+    use crate::lox::Lox;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn parsed(source: &str) -> (AstArena, Vec<StmtId>) {
+        let mut scanner = Scanner::new(source.as_bytes().to_vec());
+        scanner.scan_tokens().unwrap();
+        let mut ast = AstArena::new();
+        let mut lox = Lox::with_output(Box::new(std::io::sink()), Box::new(std::io::sink()));
+        let statements = Parser::new(&mut lox, scanner.tokens, &mut ast).parse();
+        assert!(!lox.had_error, "{}", source);
+        (ast, statements)
+    }
+
+    fn printed(ast: &AstArena, statements: &[StmtId]) -> Vec<String> {
+        statements
+            .iter()
+            .map(|&stmt| stmt_to_string(ast, stmt))
+            .collect()
+    }
+
+    fn folded(source: &str) -> Vec<String> {
+        let (mut ast, statements) = parsed(source);
+        let statements = fold(&mut ast, statements).statements;
+        printed(&ast, &statements)
+    }
+
+    // With just the dead branches `find_dead_code` reports dropped.
+    fn eliminated(source: &str) -> Vec<String> {
+        let (mut ast, statements) = parsed(source);
+        let dead = find_dead_code(&ast, &statements)
+            .iter()
+            .map(|code| code.stmt)
+            .collect();
+        let statements = eliminate(&mut ast, statements, &dead).statements;
+        printed(&ast, &statements)
+    }
+
+    #[test]
+    fn folds_constants() {
+        let cases = [
+            ("2 * 3 + 4;", "10"),
+            ("-(1 + 2);", "-3"),
+            ("!nil;", "true"),
+            ("\"a\" + \"b\" == \"ab\";", "true"),
+            ("\"a\" < \"b\";", "true"),
+            ("nil or x;", "x"),
+            ("1 and x;", "x"),
+            ("false and x;", "false"),
+            ("x + 1 * 2;", "(+ x 2)"),
+            ("if (1 < 2) print 1; else print 2;", "(print 1)"),
+            ("if (nil) print 1; else print 2;", "(print 2)"),
+            ("while (x) if (false) print 1;", "(while x (block))"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(folded(source), vec![expected], "{}", source);
+        }
+        assert!(folded("if (false) print 1; while (false) print 2;").is_empty());
+    }
+
+    #[test]
+    fn eliminates_dead_branches() {
+        let cases = [
+            ("if (1 < 2) print 1; else print 2;", "(print 1)"),
+            ("if (nil) print 1; else print 2;", "(print 2)"),
+            ("if (true) print 1;", "(if true (print 1))"),
+            ("while (x) if (false) print 1;", "(while x (block))"),
+            // A loop that never runs still runs its initializer.
+            (
+                "for (var i = f(); !1; i++) print i;",
+                "(block (var i (call f)))",
+            ),
+            ("for (;;) if (false) print 1;", "(for (block))"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(eliminated(source), vec![expected], "{}", source);
+        }
+        assert!(
+            eliminated("if (false) print 1; while (false) print 2; for (; nil;) {}").is_empty()
+        );
+
+        let (ast, statements) =
+            parsed("fun f() {\n  while (1 > 2) { var a; fun g() {} print a; }\n}");
+        let dead = find_dead_code(&ast, &statements);
+        assert_eq!(dead.len(), 1);
+        assert_eq!(
+            dead[0].message,
+            "Condition is always false; this loop never runs."
+        );
+        assert_eq!(
+            (dead[0].condition.start_line, dead[0].condition.start_col),
+            (2, 10)
+        );
+        assert_eq!((dead[0].span.start_line, dead[0].span.start_col), (2, 17));
+        let declared: Vec<&str> = dead[0]
+            .declarations
+            .iter()
+            .map(|name| &*name.lexeme)
+            .collect();
+        assert_eq!(declared, ["a", "g"]);
+    }
+
+    #[test]
+    fn leaves_errors_for_runtime() {
+        for source in [
+            "1 + \"a\";",
+            "1 / 0;",
+            "1 % 0;",
+            "-\"a\";",
+            "\"ab\" * 2;",
+            "1 < \"a\";",
+            "1 & 2;",
+        ] {
+            let printed = folded(source);
+            assert!(printed[0].starts_with('('), "{} gave {:?}", source, printed);
+        }
+        let huge = format!("{:.0}", f64::MAX);
+        let printed = folded(&format!("{} * 2;", huge));
+        assert!(printed[0].starts_with("(*"), "{:?}", printed);
+    }
+
+    #[test]
+    fn reports_overflowing_operators() {
+        let huge = format!("{:.0}", f64::MAX);
+        let (mut ast, statements) = parsed(&format!("1 + {} * 2 - (1 / 0);\n{0} + {0};", huge));
+        let folded = fold(&mut ast, statements);
+        let overflows: Vec<(&str, usize)> = folded
+            .overflows
+            .iter()
+            .map(|operator| (&*operator.lexeme, operator.line))
+            .collect();
+        assert_eq!(overflows, [("*", 1), ("+", 2)]);
+
+        let (mut ast, statements) = parsed("2 * 3 + 4; -1 / 2; 0.1 * 0.2;");
+        assert!(fold(&mut ast, statements).overflows.is_empty());
+    }
+}
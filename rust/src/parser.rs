@@ -1,20 +1,35 @@
+use crate::arena::AstArena;
 use crate::expr::*;
 use crate::lox::{Lox, LoxError};
-use crate::stmt::{RcStmt, Stmt};
+use crate::span::Span;
+use crate::stmt::{Parameter, Stmt, StmtId};
 use crate::token::*;
 use crate::token_type::TokenType::*;
-use std::fmt::Display;
 use std::rc::Rc;
 
 pub struct Parser<'a> {
     lox: &'a mut Lox,
+    // Where the nodes go; the statements `parse` returns are ids into it.
+    ast: &'a mut AstArena,
     tokens: Vec<RcToken>,
     current: usize,
+    // How many blocks enclose the current statement; their closing braces
+    // are where recovery stops.
+    block_depth: usize,
+    // How deeply the statement or expression being parsed is nested.
+    nesting: usize,
+    // Whether an expression enclosing the one being parsed will be measured
+    // by `outermost`.
+    in_expression: bool,
 }
 
-type ExprResult = Result<Expr, LoxError<String>>;
+// Parsing recurses once per level of nesting, so input nested deeper than
+// this is reported rather than left to overflow the stack.
+const MAX_NESTING: usize = 1000;
 
-type StmtResult = Result<Stmt, LoxError<String>>;
+type ExprResult = Result<Expr, LoxError>;
+
+type StmtResult = Result<Stmt, LoxError>;
 
 macro_rules! check {
     ($self:ident, $types:pat) => {
@@ -54,42 +69,153 @@ macro_rules! consume {
     };
 }
 
-#[allow(dead_code)]
-impl Parser<'_> {
-    pub fn new(lox: &mut Lox, tokens: Vec<RcToken>) -> Parser {
+impl<'a> Parser<'a> {
+    // `tokens` normally comes from a scanner that reached the end of its
+    // input. If it doesn't end with an EOF, say because scanning stopped at
+    // an error, one is added, so there is always a token to look at.
+    pub fn new(lox: &'a mut Lox, mut tokens: Vec<RcToken>, ast: &'a mut AstArena) -> Parser<'a> {
+        if !matches!(tokens.last(), Some(token) if token.type_ == EOF) {
+            let (line, column) = tokens
+                .last()
+                .map_or((1, 1), |last| (last.line, last.column + last.lexeme.len()));
+            let file = tokens.last().and_then(|last| last.file.clone());
+            tokens.push(Rc::new(Token {
+                file,
+                ..Token::new(EOF, Vec::new(), Literal::NIL, line, column)
+            }));
+        }
         Parser {
             lox,
+            ast,
             tokens,
             current: 0,
+            block_depth: 0,
+            nesting: 0,
+            in_expression: false,
         }
     }
-    pub fn parse(&mut self) -> Result<Vec<RcStmt>, LoxError<String>> {
-        let mut statements: Vec<RcStmt> = Vec::new();
+    // Reports each syntax error and carries on after synchronizing, so one
+    // run finds them all. Check `had_error` before using the statements.
+    pub fn parse(&mut self) -> Vec<StmtId> {
+        let mut statements: Vec<StmtId> = Vec::new();
         while !self.is_at_end() {
-            statements.push(Rc::from(self.declaration()?));
+            if let Some(stmt) = self.declaration() {
+                statements.push(self.ast.add_stmt(stmt));
+            }
         }
-        Ok(statements)
+        statements
+    }
+    // A lone expression, as typed at the debugger's `p` command.
+    pub(crate) fn parse_expression(&mut self) -> Result<ExprId, LoxError> {
+        let expr = self.expression()?;
+        if !self.is_at_end() {
+            return Err(Parser::error(
+                self.peek(),
+                String::from("Expect end of expression."),
+            ));
+        }
+        Ok(self.ast.add_expr(expr))
     }
     fn expression(&mut self) -> ExprResult {
-        self.assignment()
+        self.outermost(Parser::comma)
     }
-    fn declaration(&mut self) -> StmtResult {
+    // A declaration, or None once its error has been reported.
+    fn declaration(&mut self) -> Option<Stmt> {
         let res = if match_!(self, FUN) {
             self.function("function")
         } else if match_!(self, VAR) {
-            self.var_declaration()
+            self.var_declaration(false)
+        } else if match_!(self, CONST) {
+            self.var_declaration(true)
+        } else if match_!(self, IMPORT) {
+            self.import_statement()
         } else {
             self.statement()
         };
         match res {
-            Err(res) => {
-                self.synchronize();
-                Err(res)
+            Ok(stmt) => Some(stmt),
+            Err(e) => {
+                self.lox.error(e);
+                // Recovery is wasted work once no more errors will be shown,
+                // so the rest of the input is skipped.
+                if self.lox.error_limit_reached() {
+                    self.current = self.tokens.len() - 1;
+                } else {
+                    self.synchronize();
+                }
+                None
             }
-            _ => res,
         }
     }
+    // Statements, blocks and unary expressions are where parsing recurses,
+    // so they are parsed through here.
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, LoxError>,
+    ) -> Result<T, LoxError> {
+        if self.nesting == MAX_NESTING {
+            return Err(Parser::error(self.peek(), "Too much nesting."));
+        }
+        self.nesting += 1;
+        let result = parse(self);
+        self.nesting -= 1;
+        result
+    }
+    // Binary and logical operators build their trees in loops rather than
+    // by recursing, so a long chain of them nests deeply without `nested`
+    // seeing it. Everything that walks the tree afterwards recurses all the
+    // same, so each expression not inside another is measured once built.
+    fn outermost(&mut self, parse: impl FnOnce(&mut Self) -> ExprResult) -> ExprResult {
+        if self.in_expression {
+            return parse(self);
+        }
+        self.in_expression = true;
+        let expr = parse(self);
+        self.in_expression = false;
+        let expr = expr?;
+        if let Some(operator) = self.too_deep(&expr) {
+            return Err(Parser::error(&operator, "Too much nesting."));
+        }
+        Ok(expr)
+    }
+    // Where `expr` goes deeper than the nesting left allows: the innermost
+    // binary or logical operator on the way there, since those are what
+    // make expressions that deep.
+    fn too_deep(&self, expr: &Expr) -> Option<RcToken> {
+        let operator = |expr: &Expr| match expr {
+            Expr::Binary { operator, .. } | Expr::Logical { operator, .. } => {
+                Some(Rc::clone(operator))
+            }
+            _ => None,
+        };
+        let limit = MAX_NESTING - self.nesting;
+        let mut pending: Vec<_> = expr
+            .children()
+            .into_iter()
+            .map(|child| (child, 2, operator(expr)))
+            .collect();
+        while let Some((id, depth, outer)) = pending.pop() {
+            let expr = &self.ast[id];
+            let operator = operator(expr).or(outer);
+            if depth > limit {
+                return Some(operator.unwrap_or_else(|| self.previous()));
+            }
+            for child in expr.children() {
+                pending.push((child, depth + 1, operator.clone()));
+            }
+        }
+        None
+    }
     fn statement(&mut self) -> StmtResult {
+        self.nested(Parser::any_statement)
+    }
+    fn any_statement(&mut self) -> StmtResult {
+        if match_!(self, BREAK) {
+            return self.loop_control_statement();
+        }
+        if match_!(self, CONTINUE) {
+            return self.loop_control_statement();
+        }
         if match_!(self, FOR) {
             return self.for_statement();
         }
@@ -102,23 +228,36 @@ impl Parser<'_> {
         if match_!(self, RETURN) {
             return self.return_statement();
         }
+        if match_!(self, THROW) {
+            return self.throw_statement();
+        }
+        if match_!(self, TRY) {
+            return self.try_statement();
+        }
         if match_!(self, WHILE) {
             return self.while_statement();
         }
         if match_!(self, LEFT_BRACE) {
+            let start = self.current - 1;
+            let statements = self.block()?;
             return Ok(Stmt::Block {
-                statements: Rc::from(self.block()?),
+                statements,
+                span: self.span_from(start),
             });
         }
         self.expression_statement()
     }
     fn for_statement(&mut self) -> StmtResult {
+        let start = self.current - 1;
         consume!(self, LEFT_PAREN, "Expect '(' after 'for'.")?;
+        if self.at_for_each() {
+            return self.for_each_statement(start);
+        }
 
         let initializer = if match_!(self, SEMICOLON) {
             None
         } else if match_!(self, VAR) {
-            Some(self.var_declaration()?)
+            Some(self.var_declaration(false)?)
         } else {
             Some(self.expression_statement()?)
         };
@@ -133,101 +272,191 @@ impl Parser<'_> {
             increment = Some(self.expression()?);
         }
         consume!(self, RIGHT_PAREN, "Expect ')' after for clauses.")?;
-        let mut body = self.statement()?;
-
-        if let Some(increment) = increment {
-            body = Stmt::Block {
-                statements: Rc::from(vec![
-                    Rc::from(body),
-                    Rc::from(Stmt::Expression {
-                        expr: Rc::from(increment),
-                    }),
-                ]),
+        let body = self.statement()?;
+        Ok(Stmt::For {
+            initializer: initializer.map(|initializer| self.ast.add_stmt(initializer)),
+            condition: condition.map(|condition| self.ast.add_expr(condition)),
+            increment: increment.map(|increment| self.ast.add_expr(increment)),
+            body: self.ast.add_stmt(body),
+            span: self.span_from(start),
+        })
+    }
+    // `var name in` after `for (`. `in` is only special here, so it's
+    // still free to use as a name elsewhere.
+    fn at_for_each(&self) -> bool {
+        match self.tokens.get(self.current..self.current + 3) {
+            Some([var, name, in_]) => {
+                matches!(var.type_, VAR)
+                    && matches!(name.type_, IDENTIFIER)
+                    && matches!(in_.type_, IDENTIFIER)
+                    && &*in_.lexeme == "in"
             }
+            _ => false,
         }
-
-        if condition.is_none() {
-            condition = Some(Expr::Literal(Literal::BOOL(true)));
-        }
-        body = Stmt::While {
-            condition: Rc::from(condition.unwrap()),
-            body: Rc::from(body),
-        };
-        if initializer.is_some() {
-            body = Stmt::Block {
-                statements: Rc::from(vec![Rc::from(initializer.unwrap()), Rc::from(body)]),
-            };
-        }
-        Ok(body)
+    }
+    fn for_each_statement(&mut self, start: usize) -> StmtResult {
+        self.advance();
+        let variable = self.advance();
+        let keyword = self.advance();
+        let iterable = self.expression()?;
+        consume!(self, RIGHT_PAREN, "Expect ')' after loop iterable.")?;
+        let body = self.statement()?;
+        Ok(Stmt::ForEach {
+            variable,
+            keyword,
+            iterable: self.ast.add_expr(iterable),
+            body: self.ast.add_stmt(body),
+            span: self.span_from(start),
+        })
     }
     fn if_statement(&mut self) -> StmtResult {
+        let start = self.current - 1;
         consume!(self, LEFT_PAREN, "Expect '(' after 'if'.")?;
         let condition = self.expression()?;
         consume!(self, RIGHT_PAREN, "Expect ')' after 'if'.")?;
 
         let then_branch = self.statement()?;
         let else_branch = if match_!(self, ELSE) {
-            Some(Rc::from(self.statement()?))
+            let else_branch = self.statement()?;
+            Some(self.ast.add_stmt(else_branch))
         } else {
             None
         };
         Ok(Stmt::If {
-            condition: Rc::from(condition),
-            then_branch: Rc::from(then_branch),
+            condition: self.ast.add_expr(condition),
+            then_branch: self.ast.add_stmt(then_branch),
             else_branch,
+            span: self.span_from(start),
         })
     }
     fn print_statement(&mut self) -> StmtResult {
+        let start = self.current - 1;
         let value = self.expression()?;
         consume!(self, SEMICOLON, "Expect ';' after value.")?;
         Ok(Stmt::Print {
-            expr: Rc::from(value),
+            expr: self.ast.add_expr(value),
+            span: self.span_from(start),
         })
     }
     fn return_statement(&mut self) -> StmtResult {
+        let start = self.current - 1;
         let keyword = self.previous();
         let value = if !check!(self, SEMICOLON) {
             self.expression()?
         } else {
-            Expr::Literal(Literal::NIL)
+            Expr::Literal {
+                value: Literal::NIL,
+                span: Span::default(),
+            }
         };
 
         consume!(self, SEMICOLON, "Expect ';' after return value.")?;
         Ok(Stmt::Return {
             keyword,
-            value: Rc::from(value),
+            value: self.ast.add_expr(value),
+            span: self.span_from(start),
+        })
+    }
+    fn throw_statement(&mut self) -> StmtResult {
+        let start = self.current - 1;
+        let keyword = self.previous();
+        let value = self.expression()?;
+        consume!(self, SEMICOLON, "Expect ';' after thrown value.")?;
+        Ok(Stmt::Throw {
+            keyword,
+            value: self.ast.add_expr(value),
+            span: self.span_from(start),
         })
     }
-    fn var_declaration(&mut self) -> StmtResult {
+    fn try_statement(&mut self) -> StmtResult {
+        let start = self.current - 1;
+        consume!(self, LEFT_BRACE, "Expect '{' after 'try'.")?;
+        let body = self.block()?;
+        consume!(self, CATCH, "Expect 'catch' after try block.")?;
+        consume!(self, LEFT_PAREN, "Expect '(' after 'catch'.")?;
+        let binding = consume!(self, IDENTIFIER, "Expect name for the caught error.")?;
+        consume!(self, RIGHT_PAREN, "Expect ')' after caught error name.")?;
+        consume!(self, LEFT_BRACE, "Expect '{' before catch block.")?;
+        let handler = self.block()?;
+        Ok(Stmt::Try {
+            body,
+            binding,
+            handler,
+            span: self.span_from(start),
+        })
+    }
+    // Imported definitions become globals, so only the top level of a file
+    // can import.
+    fn import_statement(&mut self) -> StmtResult {
+        let start = self.current - 1;
+        let keyword = self.previous();
+        if self.block_depth > 0 {
+            return Err(Parser::error(&keyword, "Can only import at the top level."));
+        }
+        let path = consume!(self, STRING, "Expect file path string after 'import'.")?;
+        consume!(self, SEMICOLON, "Expect ';' after import.")?;
+        Ok(Stmt::Import {
+            keyword,
+            path,
+            span: self.span_from(start),
+        })
+    }
+    fn var_declaration(&mut self, constant: bool) -> StmtResult {
+        let start = self.current - 1;
         let name = consume!(self, IDENTIFIER, "Expect variable name.")?;
-        let mut initializer: Option<RcExpr> = None;
+        let mut initializer: Option<ExprId> = None;
         if match_!(self, EQUAL) {
-            initializer = Some(Rc::from(self.expression()?));
+            let value = self.expression()?;
+            initializer = Some(self.ast.add_expr(value));
+        } else if constant {
+            return Err(Parser::error(
+                self.peek(),
+                String::from("Const declarations must have an initializer."),
+            ));
         }
         consume!(self, SEMICOLON, "Expect ';' after variable declaration.")?;
-        Ok(Stmt::Var { name, initializer })
+        Ok(Stmt::Var {
+            name,
+            initializer,
+            constant,
+            span: self.span_from(start),
+        })
     }
     fn while_statement(&mut self) -> StmtResult {
+        let start = self.current - 1;
         consume!(self, LEFT_PAREN, "Expect '(', after 'while'.")?;
         let condition = self.expression()?;
         consume!(self, RIGHT_PAREN, "Expect ')' after condition.")?;
         let body = self.statement()?;
         Ok(Stmt::While {
-            condition: Rc::from(condition),
-            body: Rc::from(body),
+            condition: self.ast.add_expr(condition),
+            body: self.ast.add_stmt(body),
+            span: self.span_from(start),
+        })
+    }
+    fn loop_control_statement(&mut self) -> StmtResult {
+        let keyword = self.previous();
+        consume!(self, SEMICOLON, "Expect ';' after '{}'.", (keyword.lexeme))?;
+        let span = self.span_from(self.current - 2);
+        Ok(match keyword.type_ {
+            BREAK => Stmt::Break { keyword, span },
+            _ => Stmt::Continue { keyword, span },
         })
     }
     fn expression_statement(&mut self) -> StmtResult {
+        let start = self.current;
         let expr = self.expression()?;
         consume!(self, SEMICOLON, "Expect ';' after expression.")?;
         Ok(Stmt::Expression {
-            expr: Rc::from(expr),
+            expr: self.ast.add_expr(expr),
+            span: self.span_from(start),
         })
     }
     fn function(&mut self, kind: &'static str) -> StmtResult {
+        let start = self.current - 1;
         let name = consume!(self, IDENTIFIER, "Expect {} name.", kind)?;
         consume!(self, LEFT_PAREN, "Expect '(' after {} name.", kind)?;
-        let mut parameters: Vec<RcToken> = Vec::new();
+        let mut parameters: Vec<Parameter> = Vec::new();
         if !check!(self, RIGHT_PAREN) {
             loop {
                 if parameters.len() >= 255 {
@@ -236,7 +465,21 @@ impl Parser<'_> {
                         "Can't have more than 255 parameters.",
                     ));
                 }
-                parameters.push(consume!(self, IDENTIFIER, "Expect parameter name.")?);
+                let name = consume!(self, IDENTIFIER, "Expect parameter name.")?;
+                let default = if match_!(self, EQUAL) {
+                    let default = self.outermost(Parser::assignment)?;
+                    Some(self.ast.add_expr(default))
+                } else {
+                    None
+                };
+                let follows_optional = matches!(parameters.last(), Some((_, Some(_))));
+                if default.is_none() && follows_optional {
+                    self.lox.error(Parser::error(
+                        &name,
+                        "A parameter without a default can't follow one with a default.",
+                    ));
+                }
+                parameters.push((name, default));
                 if !match_!(self, COMMA) {
                     break;
                 }
@@ -249,29 +492,77 @@ impl Parser<'_> {
         Ok(Stmt::Function {
             name,
             params: parameters,
-            body: Rc::from(body),
+            body,
+            span: self.span_from(start),
         })
     }
-    fn block(&mut self) -> Result<Vec<RcStmt>, LoxError<String>> {
-        let mut statements = Vec::<RcStmt>::new();
+    fn block(&mut self) -> Result<Vec<StmtId>, LoxError> {
+        self.nested(Parser::block_statements)
+    }
+    fn block_statements(&mut self) -> Result<Vec<StmtId>, LoxError> {
+        // A bad statement is reported here and the block carries on, so
+        // the error doesn't take the enclosing statement down with it.
+        let mut statements = Vec::<StmtId>::new();
+        self.block_depth += 1;
         while !check!(self, RIGHT_BRACE) && !self.is_at_end() {
-            statements.push(Rc::from(self.declaration()?));
+            if let Some(stmt) = self.declaration() {
+                statements.push(self.ast.add_stmt(stmt));
+            }
         }
+        self.block_depth -= 1;
         consume!(self, RIGHT_BRACE, "Expect '}' after block.")?;
         Ok(statements)
     }
+    fn comma(&mut self) -> ExprResult {
+        let mut expr = self.assignment()?;
+        while match_!(self, COMMA) {
+            let operator = self.previous();
+            let right = self.assignment()?;
+            let span = expr.span().to(right.span());
+            expr = Expr::Binary {
+                left: self.ast.add_expr(expr),
+                operator,
+                right: self.ast.add_expr(right),
+                span,
+            };
+        }
+        Ok(expr)
+    }
     fn assignment(&mut self) -> ExprResult {
         let expr = self.or()?;
         if match_!(self, EQUAL) {
             let equals = self.previous();
             let value = self.assignment()?;
+            let span = expr.span().to(value.span());
             match expr {
-                Expr::Variable { name } => {
+                Expr::Variable { name, .. } => {
                     return Ok(Expr::Assign {
                         name,
-                        value: Rc::from(value),
+                        value: self.ast.add_expr(value),
+                        span,
+                    });
+                }
+                Expr::Index {
+                    object,
+                    bracket,
+                    index,
+                    ..
+                } => {
+                    return Ok(Expr::IndexAssign {
+                        object,
+                        bracket,
+                        index,
+                        value: self.ast.add_expr(value),
+                        span,
                     });
                 }
+                Expr::Call { .. } => self.lox.error(Parser::error(
+                    &equals,
+                    "Can't assign to the result of a function call.",
+                )),
+                Expr::Literal { .. } => self
+                    .lox
+                    .error(Parser::error(&equals, "Can't assign to a literal value.")),
                 _ => self
                     .lox
                     .error(Parser::error(&equals, "Invalid assignment target.")),
@@ -284,23 +575,73 @@ impl Parser<'_> {
         while match_!(self, OR) {
             let operator = self.previous();
             let right = self.and()?;
+            let span = expr.span().to(right.span());
             expr = Expr::Logical {
-                left: Rc::from(expr),
+                left: self.ast.add_expr(expr),
                 operator,
-                right: Rc::from(right),
+                right: self.ast.add_expr(right),
+                span,
             };
         }
         Ok(expr)
     }
     fn and(&mut self) -> ExprResult {
-        let mut expr = self.equality()?;
+        let mut expr = self.bit_or()?;
         while match_!(self, AND) {
             let operator = self.previous();
-            let right = self.equality()?;
+            let right = self.bit_or()?;
+            let span = expr.span().to(right.span());
             expr = Expr::Logical {
-                left: Rc::from(expr),
+                left: self.ast.add_expr(expr),
+                operator,
+                right: self.ast.add_expr(right),
+                span,
+            };
+        }
+        Ok(expr)
+    }
+    // Bitwise operators bind looser than equality, as in C.
+    fn bit_or(&mut self) -> ExprResult {
+        let mut expr = self.bit_xor()?;
+        while match_!(self, PIPE) {
+            let operator = self.previous();
+            let right = self.bit_xor()?;
+            let span = expr.span().to(right.span());
+            expr = Expr::Binary {
+                left: self.ast.add_expr(expr),
+                operator,
+                right: self.ast.add_expr(right),
+                span,
+            };
+        }
+        Ok(expr)
+    }
+    fn bit_xor(&mut self) -> ExprResult {
+        let mut expr = self.bit_and()?;
+        while match_!(self, CARET) {
+            let operator = self.previous();
+            let right = self.bit_and()?;
+            let span = expr.span().to(right.span());
+            expr = Expr::Binary {
+                left: self.ast.add_expr(expr),
+                operator,
+                right: self.ast.add_expr(right),
+                span,
+            };
+        }
+        Ok(expr)
+    }
+    fn bit_and(&mut self) -> ExprResult {
+        let mut expr = self.equality()?;
+        while match_!(self, AMPERSAND) {
+            let operator = self.previous();
+            let right = self.equality()?;
+            let span = expr.span().to(right.span());
+            expr = Expr::Binary {
+                left: self.ast.add_expr(expr),
                 operator,
-                right: Rc::from(right),
+                right: self.ast.add_expr(right),
+                span,
             };
         }
         Ok(expr)
@@ -310,10 +651,12 @@ impl Parser<'_> {
         while match_!(self, BANG_EQUAL | EQUAL_EQUAL) {
             let operator = self.previous();
             let right = self.comparison()?;
+            let span = expr.span().to(right.span());
             expr = Expr::Binary {
-                left: Rc::from(expr),
+                left: self.ast.add_expr(expr),
                 operator,
-                right: Rc::from(right),
+                right: self.ast.add_expr(right),
+                span,
             };
         }
         Ok(expr)
@@ -325,14 +668,31 @@ impl Parser<'_> {
         self.previous()
     }
     fn comparison(&mut self) -> ExprResult {
-        let mut expr: Expr = self.term()?;
+        let mut expr: Expr = self.shift()?;
         while match_!(self, GREATER | GREATER_EQUAL | LESS | LESS_EQUAL) {
+            let operator = self.previous();
+            let right = self.shift()?;
+            let span = expr.span().to(right.span());
+            expr = Expr::Binary {
+                left: self.ast.add_expr(expr),
+                operator,
+                right: self.ast.add_expr(right),
+                span,
+            };
+        }
+        Ok(expr)
+    }
+    fn shift(&mut self) -> ExprResult {
+        let mut expr = self.term()?;
+        while match_!(self, LESS_LESS | GREATER_GREATER) {
             let operator = self.previous();
             let right = self.term()?;
+            let span = expr.span().to(right.span());
             expr = Expr::Binary {
-                left: Rc::from(expr),
+                left: self.ast.add_expr(expr),
                 operator,
-                right: Rc::from(right),
+                right: self.ast.add_expr(right),
+                span,
             };
         }
         Ok(expr)
@@ -342,40 +702,57 @@ impl Parser<'_> {
         while match_!(self, MINUS | PLUS) {
             let operator = self.previous();
             let right = self.factor()?;
+            let span = expr.span().to(right.span());
             expr = Expr::Binary {
-                left: Rc::from(expr),
+                left: self.ast.add_expr(expr),
                 operator,
-                right: Rc::from(right),
+                right: self.ast.add_expr(right),
+                span,
             };
         }
         Ok(expr)
     }
     fn factor(&mut self) -> ExprResult {
         let mut expr: Expr = self.unary()?;
-        while match_!(self, SLASH | STAR) {
+        while match_!(self, SLASH | STAR | PERCENT) {
             let operator = self.previous();
             let right = self.unary()?;
+            let span = expr.span().to(right.span());
             expr = Expr::Binary {
-                left: Rc::from(expr),
+                left: self.ast.add_expr(expr),
                 operator,
-                right: Rc::from(right),
+                right: self.ast.add_expr(right),
+                span,
             };
         }
         Ok(expr)
     }
     fn unary(&mut self) -> ExprResult {
+        self.nested(Parser::prefix)
+    }
+    fn prefix(&mut self) -> ExprResult {
+        if match_!(self, PLUS_PLUS | MINUS_MINUS) {
+            let operator = self.previous();
+            let operand = self.unary()?;
+            return Ok(match self.increment(&operator, &operand) {
+                Some(update) => update,
+                None => operand,
+            });
+        }
         if match_!(self, BANG | MINUS) {
             let operator = self.previous();
             let right = self.unary()?;
+            let span = Span::of(&operator).to(right.span());
             return Ok(Expr::Unary {
                 operator,
-                right: Rc::from(right),
+                right: self.ast.add_expr(right),
+                span,
             });
         }
         self.call()
     }
     fn finish_call(&mut self, callee: Expr) -> ExprResult {
-        let mut arguments = Vec::<RcExpr>::new();
+        let mut arguments = Vec::<ExprId>::new();
         if !check!(self, RIGHT_PAREN) {
             loop {
                 if arguments.len() >= 255 {
@@ -384,7 +761,10 @@ impl Parser<'_> {
                         "Can't have more than 255 arguments",
                     ));
                 }
-                arguments.push(Rc::from(self.expression()?));
+                // Arguments are parsed above the comma operator so that
+                // the commas separate them instead.
+                let argument = self.assignment()?;
+                arguments.push(self.ast.add_expr(argument));
                 if !match_!(self, COMMA) {
                     break;
                 }
@@ -392,10 +772,12 @@ impl Parser<'_> {
         }
         let paren = consume!(self, RIGHT_PAREN, "Expect ')' after arguments.")?;
 
+        let span = callee.span().to(Span::of(&paren));
         Ok(Expr::Call {
-            callee: Rc::from(callee),
+            callee: self.ast.add_expr(callee),
             paren,
             arguments,
+            span,
         })
     }
     fn call(&mut self) -> ExprResult {
@@ -404,34 +786,206 @@ impl Parser<'_> {
         loop {
             if match_!(self, LEFT_PAREN) {
                 expr = self.finish_call(expr)?;
+            } else if match_!(self, LEFT_BRACKET) {
+                let index = self.expression()?;
+                let bracket = consume!(self, RIGHT_BRACKET, "Expect ']' after index.")?;
+                let span = expr.span().to(Span::of(&bracket));
+                expr = Expr::Index {
+                    object: self.ast.add_expr(expr),
+                    bracket,
+                    index: self.ast.add_expr(index),
+                    span,
+                };
+            } else if match_!(self, DOT) {
+                let name = consume!(self, IDENTIFIER, "Expect property name after '.'.")?;
+                let span = expr.span().to(Span::of(&name));
+                expr = Expr::Get {
+                    object: self.ast.add_expr(expr),
+                    name,
+                    span,
+                };
             } else {
                 break;
             }
         }
+        if match_!(self, PLUS_PLUS | MINUS_MINUS) {
+            let operator = self.previous();
+            if let Some(update) = self.increment(&operator, &expr) {
+                let span = expr.span().to(Span::of(&operator));
+                expr = Expr::Postfix {
+                    operator,
+                    target: self.ast.add_expr(expr),
+                    update: self.ast.add_expr(update),
+                    span,
+                };
+            }
+        }
         Ok(expr)
     }
+    // Desugars `++a` / `--a` to `a = a + 1` / `a = a - 1`, reporting any
+    // target other than a plain variable.
+    fn increment(&mut self, operator: &RcToken, target: &Expr) -> Option<Expr> {
+        let name = match target {
+            Expr::Variable { name, .. } => Rc::clone(name),
+            _ => {
+                self.lox
+                    .error(Parser::error(operator, "Invalid increment target."));
+                return None;
+            }
+        };
+        let (type_, lexeme) = match operator.type_ {
+            PLUS_PLUS => (PLUS, "+"),
+            _ => (MINUS, "-"),
+        };
+        let arithmetic = Rc::new(Token {
+            file: operator.file.clone(),
+            ..Token::new(
+                type_,
+                lexeme.as_bytes().to_vec(),
+                Literal::NIL,
+                operator.line,
+                operator.column,
+            )
+        });
+        let span = Span::of(operator).to(target.span());
+        let left = self.ast.add_expr(Expr::Variable {
+            name: Rc::clone(&name),
+            span: Span::of(&name),
+        });
+        let right = self.ast.add_expr(Expr::Literal {
+            value: Literal::NUMBER(1.0),
+            span: Span::default(),
+        });
+        let value = self.ast.add_expr(Expr::Binary {
+            left,
+            operator: arithmetic,
+            right,
+            span,
+        });
+        Some(Expr::Assign { name, value, span })
+    }
     fn primary(&mut self) -> ExprResult {
         if match_!(self, FALSE) {
-            return Ok(Expr::Literal(Literal::BOOL(false)));
+            return Ok(self.literal(Literal::BOOL(false)));
         }
         if match_!(self, TRUE) {
-            return Ok(Expr::Literal(Literal::BOOL(true)));
+            return Ok(self.literal(Literal::BOOL(true)));
         }
         if match_!(self, NIL) {
-            return Ok(Expr::Literal(Literal::NIL));
+            return Ok(self.literal(Literal::NIL));
+        }
+        if match_!(self, NUMBER) {
+            let number = self.previous();
+            if let Some(warning) = precision_warning(&number) {
+                self.lox.warning(&number, &warning);
+            }
+            return Ok(self.literal(number.literal.clone()));
+        }
+        if match_!(self, STRING) {
+            return Ok(self.literal(self.previous().literal.clone()));
         }
-        if match_!(self, NUMBER | STRING) {
-            return Ok(Expr::Literal(self.previous().literal.clone()));
+        if match_!(self, STRING_PART) {
+            return self.interpolation();
         }
-        if match_!(self, IDENTIFIER) {
+        // `print` starts a print statement, so `print(x);` keeps its old
+        // meaning; anywhere else it names the native, as in `var p = print;`.
+        if match_!(self, IDENTIFIER | PRINT) {
+            let name = self.previous();
             return Ok(Expr::Variable {
-                name: self.previous(),
+                span: Span::of(&name),
+                name,
+            });
+        }
+        let start = self.current;
+        if match_!(self, LEFT_BRACKET) {
+            let mut elements = Vec::<ExprId>::new();
+            if !check!(self, RIGHT_BRACKET) {
+                loop {
+                    let element = self.assignment()?;
+                    elements.push(self.ast.add_expr(element));
+                    if !match_!(self, COMMA) {
+                        break;
+                    }
+                }
+            }
+            consume!(self, RIGHT_BRACKET, "Expect ']' after list elements.")?;
+            return Ok(Expr::ListLiteral {
+                elements,
+                span: self.span_from(start),
             });
         }
         if match_!(self, LEFT_PAREN) {
             let expr = self.expression()?;
             consume!(self, RIGHT_PAREN, "Expect ')' after expression.")?;
-            return Ok(Expr::Grouping(Rc::from(expr)));
+            return Ok(Expr::Grouping {
+                expression: self.ast.add_expr(expr),
+                span: self.span_from(start),
+            });
+        }
+        // Error production: a binary operator with nothing on its left. Report
+        // it, then parse the right operand at the operator's own precedence so
+        // the rest of the expression doesn't produce a cascade of errors.
+        if match_!(
+            self,
+            OR | AND
+                | PIPE
+                | CARET
+                | AMPERSAND
+                | BANG_EQUAL
+                | EQUAL_EQUAL
+                | GREATER
+                | GREATER_EQUAL
+                | LESS
+                | LESS_EQUAL
+                | LESS_LESS
+                | GREATER_GREATER
+                | PLUS
+                | SLASH
+                | STAR
+                | PERCENT
+        ) {
+            let operator = self.previous();
+            self.lox.error(Parser::error(
+                &operator,
+                format!(
+                    "Binary operator '{}' missing left-hand operand.",
+                    operator.lexeme
+                ),
+            ));
+            return match operator.type_ {
+                OR => self.and(),
+                AND => self.bit_or(),
+                PIPE => self.bit_xor(),
+                CARET => self.bit_and(),
+                AMPERSAND => self.equality(),
+                BANG_EQUAL | EQUAL_EQUAL => self.comparison(),
+                GREATER | GREATER_EQUAL | LESS | LESS_EQUAL => self.shift(),
+                LESS_LESS | GREATER_GREATER => self.term(),
+                PLUS => self.factor(),
+                _ => self.unary(),
+            };
+        }
+        if matches!(
+            self.peek().type_,
+            BREAK
+                | CLASS
+                | CONST
+                | CONTINUE
+                | ELSE
+                | FUN
+                | FOR
+                | IF
+                | PRINT
+                | RETURN
+                | SUPER
+                | THIS
+                | VAR
+                | WHILE
+        ) {
+            return Err(Parser::error(
+                self.peek(),
+                format!("Expect expression, found keyword '{}'.", self.peek().lexeme),
+            ));
         }
         Err(Parser::error(
             self.peek(),
@@ -439,26 +993,70 @@ impl Parser<'_> {
         ))
     }
 
+    fn interpolation(&mut self) -> ExprResult {
+        let start = self.current - 1;
+        let mut parts = vec![self.part()];
+        loop {
+            let part = self.expression()?;
+            parts.push(self.ast.add_expr(part));
+            if match_!(self, STRING_PART) {
+                parts.push(self.part());
+            } else {
+                consume!(self, STRING, "Expect '}' after interpolated expression.")?;
+                parts.push(self.part());
+                return Ok(Expr::Interpolation {
+                    parts,
+                    span: self.span_from(start),
+                });
+            }
+        }
+    }
+    // A literal read from the token just consumed.
+    fn literal(&self, value: Literal) -> Expr {
+        Expr::Literal {
+            value,
+            span: Span::of(&self.previous()),
+        }
+    }
+    // The string piece of an interpolation just consumed.
+    fn part(&mut self) -> ExprId {
+        let part = self.literal(self.previous().literal.clone());
+        self.ast.add_expr(part)
+    }
+    // Everything from the token at `start` through the last one consumed.
+    fn span_from(&self, start: usize) -> Span {
+        Span::new(&self.tokens[start], &self.previous())
+    }
+
     /* Non-production rule functions */
+    // `new` guarantees an EOF, and `advance` never moves past the first
+    // one, so `current` always points at a token.
     #[inline(always)]
     fn is_at_end(&self) -> bool {
-        self.current >= self.tokens.len() || matches!(self.peek().type_, EOF)
+        matches!(self.peek().type_, EOF)
     }
     #[inline(always)]
     fn peek(&self) -> &RcToken {
         &self.tokens[self.current]
     }
+    // Before anything has been consumed, this is the first token.
     #[inline(always)]
     fn previous(&self) -> RcToken {
-        Rc::clone(&self.tokens[self.current - 1])
+        Rc::clone(&self.tokens[self.current.saturating_sub(1)])
     }
-    fn error<T: Display>(token: &RcToken, message: T) -> LoxError<T> {
+    fn error(token: &RcToken, message: impl Into<String>) -> LoxError {
         LoxError::ParseError {
             token: Rc::clone(token),
-            message,
+            message: message.into(),
         }
     }
     fn synchronize(&mut self) {
+        // The brace closing an enclosing block ends the bad statement too,
+        // and is left for the block to consume.
+        let in_block = self.block_depth > 0;
+        if in_block && check!(self, RIGHT_BRACE) {
+            return;
+        }
         self.advance();
         while !self.is_at_end() {
             if matches!(self.previous().type_, SEMICOLON) {
@@ -466,9 +1064,204 @@ impl Parser<'_> {
             }
 
             match self.peek().type_ {
-                CLASS | FUN | VAR | FOR | IF | WHILE | PRINT | RETURN => return,
+                CLASS | FUN | VAR | CONST | FOR | IF | WHILE | PRINT | RETURN | BREAK
+                | CONTINUE | THROW | TRY => return,
+                RIGHT_BRACE if in_block => return,
                 _ => self.advance(),
             };
         }
     }
 }
+
+// Integral literals past 2^53 silently round to the nearest f64.
+fn precision_warning(token: &RcToken) -> Option<String> {
+    let value = match token.literal {
+        Literal::NUMBER(n) => n,
+        _ => return None,
+    };
+    if !token.lexeme.bytes().all(|b| b.is_ascii_digit()) || !value.is_finite() {
+        return None;
+    }
+    let closest = format!("{:.0}", value);
+    let digits = token.lexeme.trim_start_matches('0');
+    if closest == digits || (digits.is_empty() && closest == "0") {
+        return None;
+    }
+    Some(format!(
+        "Integer literal loses precision; closest representable value is {}.",
+        closest
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token_type::TokenType;
+
+    fn number_token(lexeme: &str) -> RcToken {
+        Rc::new(Token::new(
+            TokenType::NUMBER,
+            lexeme.as_bytes().to_vec(),
+            Literal::NUMBER(lexeme.parse().unwrap()),
+            1,
+            0,
+        ))
+    }
+
+    fn parse(source: &str) -> (AstArena, Vec<StmtId>) {
+        let mut scanner = crate::scanner::Scanner::new(source.as_bytes().to_vec());
+        scanner.scan_tokens().unwrap();
+        let mut ast = AstArena::new();
+        let statements = Parser::new(&mut Lox::new(), scanner.tokens, &mut ast).parse();
+        (ast, statements)
+    }
+
+    #[test]
+    fn recovery_stops_at_the_closing_brace() {
+        let (ast, statements) =
+            parse("fun f() {\n  print 1\n  print 2;\n}\nvar a = 1;\nfun g() {}");
+        assert_eq!(statements.len(), 3);
+        match &ast[statements[0]] {
+            Stmt::Function { body, .. } => assert_eq!(body.len(), 0),
+            other => panic!("expected a function, got {:?}", other),
+        }
+        assert!(matches!(&ast[statements[1]], Stmt::Var { .. }));
+        assert!(matches!(&ast[statements[2]], Stmt::Function { .. }));
+    }
+
+    fn quiet_lox() -> Lox {
+        Lox::with_output(Box::new(std::io::sink()), Box::new(std::io::sink()))
+    }
+
+    #[test]
+    fn empty_token_list() {
+        let mut lox = quiet_lox();
+        let mut ast = AstArena::new();
+        let mut parser = Parser::new(&mut lox, Vec::new(), &mut ast);
+        assert_eq!(parser.previous().type_, TokenType::EOF);
+        assert!(parser.parse().is_empty());
+        assert!(!lox.had_error);
+    }
+
+    #[test]
+    fn token_list_without_eof() {
+        let print = Rc::new(Token::new(
+            TokenType::PRINT,
+            b"print".to_vec(),
+            Literal::NIL,
+            1,
+            1,
+        ));
+        let mut lox = quiet_lox();
+        let mut ast = AstArena::new();
+        let mut parser = Parser::new(&mut lox, vec![print, number_token("1")], &mut ast);
+        // Nothing has been consumed yet, so there is no real previous token.
+        assert_eq!(parser.previous().type_, TokenType::PRINT);
+        assert!(parser.declaration().is_none());
+        assert!(parser.is_at_end());
+        match lox.errors() {
+            [LoxError::ParseError { token, message }] => {
+                assert_eq!(message, "Expect ';' after value.");
+                assert_eq!(token.type_, TokenType::EOF);
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+
+        // What a scanner leaves behind when it stops at an error.
+        let mut scanner = crate::scanner::Scanner::new(b"var a = (1 + \"".to_vec());
+        assert!(scanner.scan_tokens().is_err());
+        let mut lox = quiet_lox();
+        let statements = Parser::new(&mut lox, scanner.tokens, &mut ast).parse();
+        assert!(statements.is_empty());
+        assert!(lox.had_error);
+    }
+
+    // Dropping this chain as nested `Rc`s recursed once per operator and
+    // overflowed the stack; the arena frees it as two flat vectors. The
+    // chain is too deep to keep, but it's in the arena all the same.
+    #[test]
+    fn deep_expressions_parse_and_drop() {
+        let source = format!("{}1;", "1 +\n".repeat(100_000));
+        let (ast, statements) = parse(&source);
+        assert!(statements.is_empty());
+        assert_eq!(ast.expr_count(), 200_000);
+        drop(ast);
+    }
+
+    #[test]
+    fn operator_chains_count_toward_the_nesting_limit() {
+        let chain = |operator: &str, operands: usize| {
+            format!(
+                "var x = 1;\nprint {}x;",
+                format!("x {} ", operator).repeat(operands - 1)
+            )
+        };
+        for operator in ["+", "*", "or", "and", "==", "<<", ","] {
+            let (_, statements) = parse(&chain(operator, MAX_NESTING - 1));
+            assert_eq!(statements.len(), 2, "{}", operator);
+            let (_, statements) = parse(&chain(operator, 40_000));
+            assert_eq!(statements.len(), 1, "{}", operator);
+        }
+
+        // Nesting adds up across blocks, which count twice, and chains.
+        let in_blocks = |operands: usize| {
+            let source = format!(
+                "{}print {}1;{}",
+                "{".repeat(200),
+                "1 + ".repeat(operands - 1),
+                "}".repeat(200)
+            );
+            let mut scanner = crate::scanner::Scanner::new(source.into_bytes());
+            scanner.scan_tokens().unwrap();
+            let mut lox = Lox::new();
+            Parser::new(&mut lox, scanner.tokens, &mut AstArena::new()).parse();
+            lox.had_error
+        };
+        assert!(!in_blocks(500));
+        assert!(in_blocks(700));
+
+        // Lots of short chains side by side aren't deep.
+        let (_, statements) = parse(&format!("print [{}1];", "1 + 1, ".repeat(5000)));
+        assert_eq!(statements.len(), 1);
+    }
+
+    #[test]
+    fn for_loops_keep_their_clauses() {
+        let (ast, statements) = parse("var x;\nfor (var i = 0;;\n  i = i + 1) {\n  x;\n}");
+        match &ast[statements[1]] {
+            Stmt::For {
+                initializer: Some(initializer),
+                condition: None,
+                increment: Some(increment),
+                body,
+                span,
+            } => {
+                assert_eq!((span.start_line, span.start_col), (2, 1));
+                assert_eq!((span.end_line, span.end_col), (5, 1));
+                assert!(
+                    matches!(&ast[*initializer], Stmt::Var { name, .. } if &*name.lexeme == "i")
+                );
+                assert_eq!(ast[*increment].span().start_line, 3);
+                assert!(matches!(&ast[*body], Stmt::Block { .. }));
+            }
+            other => panic!("expected a for loop, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn precision_warning_for_large_integers() {
+        assert_eq!(
+            precision_warning(&number_token("9007199254740993")),
+            Some(String::from(
+                "Integer literal loses precision; closest representable value is 9007199254740992."
+            ))
+        );
+    }
+
+    #[test]
+    fn no_precision_warning_for_ordinary_literals() {
+        for lexeme in ["0", "007", "42", "9007199254740992", "1.5", "0.1"] {
+            assert_eq!(precision_warning(&number_token(lexeme)), None, "{}", lexeme);
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// A function as the profiler tells them apart: its name and the line it's
+// declared on, 0 for natives.
+pub(crate) type ProfileKey = (Rc<str>, usize);
+
+// Call counts and times per function, for `--profile` and the
+// `profileStart()` / `profileReport()` natives.
+#[derive(Default)]
+pub(crate) struct Profiler {
+    entries: HashMap<ProfileKey, Entry>,
+}
+
+#[derive(Default)]
+struct Entry {
+    calls: u64,
+    // Wall time from entering the function to leaving it, so its callees
+    // are included. Of recursive calls only the outermost is timed, which
+    // keeps the nested ones from being counted twice.
+    total: Duration,
+    // Calls to it that haven't returned yet.
+    active: usize,
+}
+
+impl Profiler {
+    // Counts a call starting, returning when it did for `exit`.
+    pub(crate) fn enter(&mut self, key: &ProfileKey) -> Instant {
+        let entry = self.entries.entry(key.clone()).or_default();
+        entry.calls += 1;
+        entry.active += 1;
+        Instant::now()
+    }
+    // Ends a call `enter` started. It may have been forgotten meanwhile by a
+    // `profileStart()`, in which case there's nothing to add it to.
+    pub(crate) fn exit(&mut self, key: &ProfileKey, start: Instant) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.active = entry.active.saturating_sub(1);
+            if entry.active == 0 {
+                entry.total += start.elapsed();
+            }
+        }
+    }
+    // A tail call runs in place of the call that made it, so it's counted
+    // but its time goes to that call.
+    pub(crate) fn count(&mut self, key: &ProfileKey) {
+        self.entries.entry(key.clone()).or_default().calls += 1;
+    }
+    // One row per function, the most time first.
+    pub(crate) fn report(&self) -> String {
+        let mut rows: Vec<(&ProfileKey, &Entry)> = self.entries.iter().collect();
+        rows.sort_by(|(a_key, a), (b_key, b)| {
+            b.total
+                .cmp(&a.total)
+                .then(b.calls.cmp(&a.calls))
+                .then(a_key.cmp(b_key))
+        });
+        let mut table = format!(
+            "{:<16} {:>5} {:>8} {:>10}\n",
+            "function", "line", "calls", "total ms"
+        );
+        for ((name, line), entry) in rows {
+            let line = match line {
+                0 => String::from("-"),
+                line => line.to_string(),
+            };
+            table.push_str(&format!(
+                "{:<16} {:>5} {:>8} {:>10.3}\n",
+                name,
+                line,
+                entry.calls,
+                entry.total.as_secs_f64() * 1000.0
+            ));
+        }
+        table.push_str("Total times include callees; recursive calls are timed once.\n");
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recursion_is_timed_once() {
+        let mut profiler = Profiler::default();
+        let f: ProfileKey = (Rc::from("f"), 1);
+        let outer = profiler.enter(&f);
+        let inner = profiler.enter(&f);
+        std::thread::sleep(Duration::from_millis(2));
+        profiler.exit(&f, inner);
+        let nested = profiler.entries[&f].total;
+        profiler.count(&f);
+        profiler.exit(&f, outer);
+        let entry = &profiler.entries[&f];
+        assert_eq!(nested, Duration::ZERO);
+        assert_eq!(entry.calls, 3);
+        assert!(entry.total >= Duration::from_millis(2));
+        // Forgotten calls end quietly.
+        profiler.exit(&(Rc::from("g"), 2), Instant::now());
+        assert!(profiler
+            .report()
+            .contains("\nf                    1        3 "));
+    }
+}
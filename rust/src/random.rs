@@ -0,0 +1,85 @@
+// The generator behind `random()` and `randomInt()`: xoshiro256**, seeded
+// through splitmix64 as its authors suggest. Only integer arithmetic is
+// involved, so a seed gives the same sequence on every platform.
+#[derive(Clone, Debug)]
+pub struct Random {
+    state: [u64; 4],
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        let mut mix = seed;
+        let mut splitmix = || {
+            mix = mix.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = mix;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+        Random {
+            state: [splitmix(), splitmix(), splitmix(), splitmix()],
+        }
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+    // Uniform in [0, 1), from the top 53 bits.
+    pub fn float(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    // Uniform in [0, n). Draws that would favour the low numbers are
+    // thrown away and drawn again.
+    pub fn below(&mut self, n: u64) -> u64 {
+        let threshold = n.wrapping_neg() % n;
+        loop {
+            let r = self.next_u64();
+            if r >= threshold {
+                return r % n;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stays_in_range() {
+        let mut random = Random::new(7);
+        for _ in 0..10_000 {
+            let x = random.float();
+            assert!((0.0..1.0).contains(&x), "{}", x);
+            assert!(random.below(6) < 6);
+        }
+        assert_eq!(random.below(1), 0);
+        let mut counts = [0; 3];
+        for _ in 0..30_000 {
+            counts[random.below(3) as usize] += 1;
+        }
+        assert!(
+            counts.iter().all(|&c| (9_000..11_000).contains(&c)),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn seeds_repeat() {
+        let draw = |seed| {
+            let mut random = Random::new(seed);
+            (0..4).map(|_| random.next_u64()).collect::<Vec<_>>()
+        };
+        assert_eq!(draw(42), draw(42));
+        assert_ne!(draw(42), draw(43));
+    }
+}
@@ -0,0 +1,124 @@
+// How diagnostics are written to `Lox`'s error output: rendered for people
+// with the offending source quoted, or, with --json-errors, as one JSON
+// object per line for editors to parse, e.g.
+//   {"severity":"error","line":3,"col":7,"message":"Expect ';' after value.","phase":"parse","file":"a.lox"}
+
+use crate::lox::{heading, LoxError};
+use std::fmt::Write;
+use std::rc::Rc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reporter {
+    Human,
+    Json,
+}
+
+// The stage of the pipeline a diagnostic comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Scan,
+    Parse,
+    Resolve,
+    Runtime,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Scan => "scan",
+            Phase::Parse => "parse",
+            Phase::Resolve => "resolve",
+            Phase::Runtime => "runtime",
+        }
+    }
+}
+
+impl Reporter {
+    // Formats an error from the program in `source`, ending with a newline.
+    pub fn error(self, error: &LoxError, source: &str) -> String {
+        match self {
+            Reporter::Human => error.render(source),
+            Reporter::Json => {
+                let phase = match error {
+                    LoxError::ScanError { .. } => Phase::Scan,
+                    // The resolver's errors are parse errors too.
+                    LoxError::ParseError { .. } => Phase::Parse,
+                    _ => Phase::Runtime,
+                };
+                let (line, column) = error.position(source);
+                let message = error.message();
+                json("error", phase, error.file(), line, column, message)
+            }
+        }
+    }
+
+    // Formats a warning about `text`, which starts at `line` and `column`.
+    pub fn warning(
+        self,
+        phase: Phase,
+        file: Option<&Rc<str>>,
+        (line, column): (usize, usize),
+        text: &str,
+        message: &str,
+    ) -> String {
+        match self {
+            Reporter::Human => {
+                let heading = heading(&file.cloned(), line);
+                format!("{} Warning at '{}': {}\n", heading, text, message)
+            }
+            Reporter::Json => json("warning", phase, file, line, column, message),
+        }
+    }
+}
+
+fn json(
+    severity: &str,
+    phase: Phase,
+    file: Option<&Rc<str>>,
+    line: usize,
+    column: usize,
+    message: &str,
+) -> String {
+    let file = file.map_or(String::from("null"), |file| json_string(file));
+    format!(
+        "{{\"severity\":\"{}\",\"line\":{},\"col\":{},\"message\":{},\"phase\":\"{}\",\"file\":{}}}\n",
+        severity,
+        line,
+        column,
+        json_string(message),
+        phase.name(),
+        file
+    )
+}
+
+// `text` as a quoted JSON string.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(
+            json_string("say \"hi\"\\n\ttab\nline\u{1}é"),
+            "\"say \\\"hi\\\"\\\\n\\ttab\\nline\\u0001é\""
+        );
+    }
+}
@@ -1,27 +1,25 @@
-use crate::expr::{Expr, RcExpr};
-use crate::interpreter::SharedInterpreter;
+use crate::arena::AstArena;
+use crate::expr::{Expr, ExprId};
+use crate::formatter::expr_to_source;
+use crate::interpreter::{not_callable, Arity, ExprValue, SharedInterpreter};
 use crate::lox::LoxError;
-use crate::stmt::{RcStmt, Stmt};
-use crate::token::{Literal, RcToken};
-use std::collections::HashMap;
+use crate::lox_function::parameter_arity;
+use crate::stmt::{Parameter, Stmt, StmtId};
+use crate::token::{Literal, RcToken, Token};
+use crate::token_type::TokenType;
+use crate::visitor::{walk_expr, walk_stmt, ExprVisitor, StmtVisitor};
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
-type ScopesStack = Vec<HashMap<String, bool>>;
+type ScopesStack = Vec<HashMap<Rc<str>, Local>>;
+type DeclarationsStack = Vec<HashMap<Rc<str>, usize>>;
 
-enum StmtOrExpr {
-    S(RcStmt),
-    E(RcExpr),
-}
-
-macro_rules! to_expr {
-    ($op:ident) => {
-        StmtOrExpr::E(Rc::clone($op))
-    };
-}
-macro_rules! to_stmt {
-    ($op:ident) => {
-        StmtOrExpr::S(Rc::clone($op))
-    };
+// A name declared in a local scope. Slots are handed out in declaration
+// order, which is the order the interpreter defines them in at runtime.
+#[derive(Debug)]
+struct Local {
+    defined: bool,
+    slot: usize,
 }
 
 #[derive(Clone)]
@@ -32,163 +30,237 @@ enum FunctionType {
 
 pub struct Resolver {
     interpreter: SharedInterpreter,
+    ast: Rc<AstArena>,
     scopes: ScopesStack,
     current_function: FunctionType,
+    loop_depth: usize,
+    // Every declaration gets an id; its entry holds the function's arity
+    // while the name is known to be bound to that function declaration.
+    declarations: DeclarationsStack,
+    global_declarations: HashMap<Rc<str>, usize>,
+    arities: Vec<Option<Arity>>,
+    // Declarations made with `const`.
+    constants: HashSet<usize>,
+    // Calls to named functions, checked once every assignment has been seen.
+    calls: Vec<(usize, usize, RcToken)>,
+    // Non-fatal diagnostics, handed to `Lox` once resolution is done.
+    warnings: Vec<(RcToken, String)>,
+    // Under --debug-resolver, every variable use and where it was found:
+    // (depth, slot) for locals, None for globals.
+    pub debug: bool,
+    resolutions: Vec<(RcToken, Option<(usize, usize)>)>,
+    // Under --strict, redeclaring a global, shadowing a parameter and
+    // assigning to a name that is never declared are errors.
+    pub strict: bool,
+    // The globals declared so far, those from earlier runs included.
+    globals: HashSet<Rc<str>>,
+    // Every use of a name that isn't a local, some of which may never be
+    // declared at all.
+    global_uses: Vec<RcToken>,
+    // The parameters of the function being resolved.
+    parameters: HashSet<Rc<str>>,
+    // Assignments in functions to globals not yet declared, which may be
+    // declared before the function runs.
+    global_assignments: Vec<RcToken>,
 }
 
-type ResolverResult = Result<(), LoxError<&'static str>>;
+type ResolverResult = Result<(), LoxError>;
 
 impl Resolver {
     pub fn new(interpreter: &SharedInterpreter) -> Self {
         Resolver {
             interpreter: Rc::clone(interpreter),
+            ast: Rc::clone(interpreter.borrow().ast()),
             scopes: Vec::new(),
             current_function: FunctionType::NONE,
+            loop_depth: 0,
+            declarations: Vec::new(),
+            global_declarations: HashMap::new(),
+            arities: Vec::new(),
+            constants: HashSet::new(),
+            calls: Vec::new(),
+            warnings: Vec::new(),
+            debug: false,
+            resolutions: Vec::new(),
+            strict: false,
+            globals: HashSet::new(),
+            global_uses: Vec::new(),
+            parameters: HashSet::new(),
+            global_assignments: Vec::new(),
         }
     }
-    pub fn resolve_statements(&mut self, stmts: &Vec<RcStmt>) -> ResolverResult {
-        for st in stmts.iter() {
-            self.resolve(to_stmt!(st))?;
+    pub fn resolve_program(&mut self, stmts: &[StmtId]) -> ResolverResult {
+        {
+            let interpreter = self.interpreter.borrow();
+            let globals = interpreter.globals.borrow().names();
+            self.globals.extend(globals.into_iter().map(Rc::from));
+        }
+        self.resolve_statements(stmts)?;
+        for name in self.global_assignments.drain(..) {
+            if !self.globals.contains(&name.lexeme) {
+                return Err(undeclared(name));
+            }
+        }
+        for (id, argc, paren) in self.calls.drain(..) {
+            if let Some(arity) = self.arities[id] {
+                if let Err(message) = arity.check(argc) {
+                    return Err(LoxError::ParseError {
+                        token: paren,
+                        message,
+                    });
+                }
+            }
         }
         Ok(())
     }
-    fn resolve(&mut self, stmt_or_expr: StmtOrExpr) -> ResolverResult {
-        match stmt_or_expr {
-            StmtOrExpr::S(stmt) => match &*stmt {
-                Stmt::Block { statements } => {
-                    self.begin_scope();
-                    self.resolve_statements(statements)?;
-                    self.end_scope();
-                    Ok(())
-                }
-                Stmt::Expression { expr } => self.resolve(to_expr!(expr)),
-                Stmt::Var { name, initializer } => {
-                    self.declare(name)?;
-                    if let Some(i) = initializer {
-                        self.resolve(to_expr!(i))?;
-                    }
-                    self.define(name);
-                    Ok(())
-                }
-                Stmt::Function { name, params, body } => {
-                    self.declare(name)?;
-                    self.define(name);
-
-                    self.resolve_function(params, body, FunctionType::FUNCTION)?;
-                    Ok(())
-                }
-                Stmt::If {
-                    condition,
-                    then_branch,
-                    else_branch,
-                } => {
-                    self.resolve(to_expr!(condition))?;
-                    self.resolve(to_stmt!(then_branch))?;
-                    if let Some(el) = else_branch {
-                        self.resolve(to_stmt!(el))?;
-                    }
-                    Ok(())
-                }
-                Stmt::Print { expr } => self.resolve(to_expr!(expr)),
-                Stmt::Return { keyword, value } => {
-                    if matches!(self.current_function, FunctionType::NONE) {
-                        return Err(LoxError::ParseError {
-                            token: Rc::clone(keyword),
-                            message: "Can't return from top-level code.",
-                        });
-                    }
-
-                    let ex = Rc::clone(value);
-                    match &*ex {
-                        Expr::Literal(Literal::NIL) => {}
-                        _ => {
-                            self.resolve(to_expr!(value))?;
-                        }
-                    }
-                    Ok(())
-                }
-                Stmt::While { condition, body } => {
-                    self.resolve(to_expr!(condition))?;
-                    self.resolve(to_stmt!(body))
-                }
-            },
-            StmtOrExpr::E(expr) => match &*expr {
-                Expr::Variable { name } => {
-                    if !self.scopes.is_empty() {
-                        if let Some(v) = self.scopes.last().unwrap().get(&name.lexeme) {
-                            if !v {
-                                return Err(LoxError::ParseError {
-                                    token: Rc::clone(name),
-                                    message: "Can't read local variable in its own initializer.",
-                                });
-                            }
-                        }
-                        self.resolve_local(&expr, Rc::clone(name));
-                    }
-                    Ok(())
-                }
-                Expr::Assign { name, value } => {
-                    self.resolve(StmtOrExpr::E(Rc::clone(value)))?;
-                    self.resolve_local(&expr, Rc::clone(name));
-                    Ok(())
-                }
-                Expr::Binary {
-                    left,
-                    operator: _,
-                    right,
-                } => {
-                    self.resolve(to_expr!(left))?;
-                    self.resolve(to_expr!(right))
-                }
-                Expr::Grouping(e) => self.resolve(to_expr!(e)),
-                Expr::Literal(_e) => Ok(()),
-                Expr::Logical {
-                    left,
-                    operator: _,
-                    right,
-                } => {
-                    self.resolve(to_expr!(left))?;
-                    self.resolve(to_expr!(right))
-                }
-                Expr::Unary { operator: _, right } => self.resolve(to_expr!(right)),
-                _ => Ok(()),
-            },
+    // Globals defined elsewhere, such as by imported files, which strict
+    // mode would otherwise take to be undeclared.
+    pub fn declare_globals(&mut self, names: impl IntoIterator<Item = Rc<str>>) {
+        self.globals.extend(names);
+    }
+    // The uses of globals that nothing declares, once resolution is done.
+    pub fn undeclared_uses(&self) -> impl Iterator<Item = &RcToken> {
+        self.global_uses
+            .iter()
+            .filter(|name| !self.globals.contains(&name.lexeme))
+    }
+    pub fn take_warnings(&mut self) -> Vec<(RcToken, String)> {
+        std::mem::take(&mut self.warnings)
+    }
+    // The variable uses recorded under `debug`, as a table with one row
+    // per use in source order.
+    pub fn resolution_table(&self) -> String {
+        let mut table = format!(
+            "{:<16} {:>5} {:>6} {:>4}\n",
+            "name", "line", "depth", "slot"
+        );
+        for (name, resolution) in &self.resolutions {
+            let (depth, slot) = match resolution {
+                Some((depth, slot)) => (depth.to_string(), slot.to_string()),
+                None => (String::from("global"), String::from("-")),
+            };
+            table.push_str(&format!(
+                "{:<16} {:>5} {:>6} {:>4}\n",
+                name.lexeme, name.line, depth, slot
+            ));
         }
+        table
     }
-    fn resolve_local(&mut self, expr: &RcExpr, name: RcToken) {
-        for (depth, scope) in self.scopes.iter().rev().enumerate() {
-            println!("scope: {:?}, name: {}", scope, &name.lexeme);
-            if scope.contains_key(&name.lexeme) {
-                println!("contains!");
-                self.interpreter.borrow_mut().resolve(expr, depth);
-                return;
+    pub fn resolve_statements(&mut self, stmts: &[StmtId]) -> ResolverResult {
+        let ast = Rc::clone(&self.ast);
+        let returns = stmts
+            .iter()
+            .position(|&st| matches!(&ast[st], Stmt::Return { .. }));
+        if let Some(index) = returns.filter(|&index| index + 1 < stmts.len()) {
+            if let Stmt::Return { keyword, .. } = &ast[stmts[index]] {
+                let token = first_token(&ast, &ast[stmts[index + 1]], &keyword.file)
+                    .unwrap_or_else(|| Rc::clone(keyword));
+                self.warnings
+                    .push((token, String::from("Unreachable code after 'return'.")));
             }
         }
+        for &st in stmts {
+            self.resolve_stmt(st)?;
+        }
+        Ok(())
+    }
+    fn resolve_stmt(&mut self, stmt: StmtId) -> ResolverResult {
+        let ast = Rc::clone(&self.ast);
+        walk_stmt(self, &ast, stmt)
+    }
+    fn resolve_expr(&mut self, expr: ExprId) -> ResolverResult {
+        let ast = Rc::clone(&self.ast);
+        walk_expr(self, &ast, expr)
+    }
+    fn resolve_local(&mut self, expr: ExprId, name: RcToken) {
+        let found = self
+            .scopes
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(depth, scope)| scope.get(&name.lexeme).map(|local| (depth, local.slot)));
+        match found {
+            Some((depth, slot)) => self.interpreter.borrow_mut().resolve(expr, depth, slot),
+            None => self.global_uses.push(Rc::clone(&name)),
+        }
+        if self.debug {
+            self.resolutions.push((name, found));
+        }
     }
     fn resolve_function(
         &mut self,
-        params: &Vec<RcToken>,
-        body: &Rc<Vec<RcStmt>>,
+        params: &[Parameter],
+        body: &[StmtId],
         func_type: FunctionType,
     ) -> ResolverResult {
         let enclosing_function = self.current_function.clone();
+        let enclosing_parameters = std::mem::replace(
+            &mut self.parameters,
+            params.iter().map(|(p, _)| Rc::clone(&p.lexeme)).collect(),
+        );
+        let enclosing_loop_depth = self.loop_depth;
         self.current_function = func_type;
+        self.loop_depth = 0;
         self.begin_scope();
-        for param in params {
+        // Restored whether or not the body resolves, so an enclosing loop
+        // finds its own depth again.
+        let res = self.resolve_function_body(params, body);
+        self.end_scope();
+        self.current_function = enclosing_function;
+        self.parameters = enclosing_parameters;
+        self.loop_depth = enclosing_loop_depth;
+        res
+    }
+    fn resolve_function_body(&mut self, params: &[Parameter], body: &[StmtId]) -> ResolverResult {
+        // Defaults are evaluated in the call's scope, so they see the
+        // parameters before them.
+        for (param, default) in params {
+            // Caught here rather than by `declare`, whose message is about
+            // variables.
+            if self.scopes.last().unwrap().contains_key(&param.lexeme) {
+                return Err(LoxError::ParseError {
+                    token: Rc::clone(param),
+                    message: format!("Duplicate parameter name '{}'.", param.lexeme),
+                });
+            }
             self.declare(param)?;
+            self.bind(param, None);
+            if let Some(default) = default {
+                self.resolve_expr(*default)?;
+            }
             self.define(param);
         }
-        self.resolve_statements(body)?;
-        self.end_scope();
-        self.current_function = enclosing_function;
-        Ok(())
+        self.resolve_statements(body)
     }
     fn begin_scope(&mut self) {
         self.scopes.push(HashMap::new());
-        println!("{:?}", self.scopes)
+        self.declarations.push(HashMap::new());
     }
     fn end_scope(&mut self) {
         self.scopes.pop();
+        self.declarations.pop();
+    }
+    fn bind(&mut self, name: &RcToken, arity: Option<Arity>) -> usize {
+        let id = self.arities.len();
+        self.arities.push(arity);
+        let previous = match self.declarations.last_mut() {
+            Some(scope) => scope.insert(name.lexeme.clone(), id),
+            None => self.global_declarations.insert(name.lexeme.clone(), id),
+        };
+        // Redefining a global replaces its value for code that already
+        // captured the name, so the old declaration is no longer reliable.
+        if let Some(old) = previous {
+            self.arities[old] = None;
+        }
+        id
+    }
+    fn lookup_declaration(&self, name: &str) -> Option<usize> {
+        for scope in self.declarations.iter().rev() {
+            if let Some(id) = scope.get(name) {
+                return Some(*id);
+            }
+        }
+        self.global_declarations.get(name).copied()
     }
     fn declare(&mut self, name: &RcToken) -> ResolverResult {
         if self.scopes.is_empty() {
@@ -198,10 +270,17 @@ impl Resolver {
         if scope.contains_key(&name.lexeme) {
             return Err(LoxError::ParseError {
                 token: Rc::clone(name),
-                message: "Already a variable with this name in this scope.",
+                message: "Already a variable with this name in this scope.".to_string(),
             });
         }
-        scope.insert(name.lexeme.clone(), false);
+        let slot = scope.len();
+        scope.insert(
+            name.lexeme.clone(),
+            Local {
+                defined: false,
+                slot,
+            },
+        );
         Ok(())
     }
     fn define(&mut self, name: &RcToken) {
@@ -209,6 +288,408 @@ impl Resolver {
             return;
         }
         let scope = self.scopes.last_mut().unwrap();
-        *scope.get_mut(&name.lexeme).unwrap() = true;
+        scope.get_mut(&name.lexeme).unwrap().defined = true;
+    }
+}
+
+impl StmtVisitor<ResolverResult> for Resolver {
+    fn visit_block(&mut self, statements: &[StmtId]) -> ResolverResult {
+        self.begin_scope();
+        self.resolve_statements(statements)?;
+        self.end_scope();
+        Ok(())
+    }
+    fn visit_break(&mut self, keyword: &RcToken) -> ResolverResult {
+        if self.loop_depth == 0 {
+            return Err(LoxError::ParseError {
+                token: Rc::clone(keyword),
+                message: "Can't use 'break' outside of a loop.".to_string(),
+            });
+        }
+        Ok(())
+    }
+    fn visit_continue(&mut self, keyword: &RcToken) -> ResolverResult {
+        if self.loop_depth == 0 {
+            return Err(LoxError::ParseError {
+                token: Rc::clone(keyword),
+                message: "Can't use 'continue' outside of a loop.".to_string(),
+            });
+        }
+        Ok(())
+    }
+    fn visit_expression(&mut self, expr: ExprId) -> ResolverResult {
+        self.resolve_expr(expr)
+    }
+    // The initializer's variable lives in a scope of the loop's own.
+    fn visit_for(
+        &mut self,
+        initializer: Option<StmtId>,
+        condition: Option<ExprId>,
+        increment: Option<ExprId>,
+        body: StmtId,
+    ) -> ResolverResult {
+        self.begin_scope();
+        if let Some(initializer) = initializer {
+            self.resolve_stmt(initializer)?;
+        }
+        if let Some(condition) = condition {
+            self.resolve_expr(condition)?;
+        }
+        self.loop_depth += 1;
+        let res = self.resolve_stmt(body);
+        self.loop_depth -= 1;
+        res?;
+        if let Some(increment) = increment {
+            self.resolve_expr(increment)?;
+        }
+        self.end_scope();
+        Ok(())
+    }
+    // The loop variable gets a scope of its own around the body, as each
+    // iteration binds it afresh.
+    fn visit_for_each(
+        &mut self,
+        variable: &RcToken,
+        _keyword: &RcToken,
+        iterable: ExprId,
+        body: StmtId,
+    ) -> ResolverResult {
+        self.resolve_expr(iterable)?;
+        self.begin_scope();
+        self.declare(variable)?;
+        self.bind(variable, None);
+        self.define(variable);
+        self.loop_depth += 1;
+        let res = self.resolve_stmt(body);
+        self.loop_depth -= 1;
+        self.end_scope();
+        res
+    }
+    fn visit_function(
+        &mut self,
+        name: &RcToken,
+        params: &[Parameter],
+        body: &[StmtId],
+    ) -> ResolverResult {
+        if self.scopes.is_empty() {
+            self.globals.insert(Rc::clone(&name.lexeme));
+        }
+        self.declare(name)?;
+        self.bind(name, Some(parameter_arity(params)));
+        self.define(name);
+
+        self.resolve_function(params, body, FunctionType::FUNCTION)?;
+        Ok(())
+    }
+    fn visit_if(
+        &mut self,
+        condition: ExprId,
+        then_branch: StmtId,
+        else_branch: Option<StmtId>,
+    ) -> ResolverResult {
+        self.resolve_expr(condition)?;
+        self.resolve_stmt(then_branch)?;
+        if let Some(el) = else_branch {
+            self.resolve_stmt(el)?;
+        }
+        Ok(())
+    }
+    fn visit_print(&mut self, expr: ExprId) -> ResolverResult {
+        self.resolve_expr(expr)
+    }
+    fn visit_return(&mut self, keyword: &RcToken, value: ExprId) -> ResolverResult {
+        if matches!(self.current_function, FunctionType::NONE) {
+            return Err(LoxError::ParseError {
+                token: Rc::clone(keyword),
+                message: "Can't return from top-level code.".to_string(),
+            });
+        }
+
+        let ast = Rc::clone(&self.ast);
+        match &ast[value] {
+            Expr::Literal {
+                value: Literal::NIL,
+                ..
+            } => {}
+            _ => {
+                self.resolve_expr(value)?;
+            }
+        }
+        Ok(())
+    }
+    // The imported file was resolved on its own when it was loaded.
+    fn visit_import(&mut self, _keyword: &RcToken, _path: &RcToken) -> ResolverResult {
+        Ok(())
+    }
+    fn visit_throw(&mut self, _keyword: &RcToken, value: ExprId) -> ResolverResult {
+        self.resolve_expr(value)
+    }
+    // The handler's statements share a scope with the caught error, as a
+    // function body does with its parameters.
+    fn visit_try(
+        &mut self,
+        body: &[StmtId],
+        binding: &RcToken,
+        handler: &[StmtId],
+    ) -> ResolverResult {
+        self.visit_block(body)?;
+        self.begin_scope();
+        self.declare(binding)?;
+        self.bind(binding, None);
+        self.define(binding);
+        self.resolve_statements(handler)?;
+        self.end_scope();
+        Ok(())
+    }
+    fn visit_var(
+        &mut self,
+        name: &RcToken,
+        initializer: Option<ExprId>,
+        constant: bool,
+    ) -> ResolverResult {
+        if self.scopes.is_empty() {
+            if !self.globals.insert(Rc::clone(&name.lexeme)) && self.strict {
+                return Err(LoxError::ParseError {
+                    token: Rc::clone(name),
+                    message: format!("Already a global variable named '{}'.", name.lexeme),
+                });
+            }
+        } else if self.strict && self.parameters.contains(&name.lexeme) {
+            return Err(LoxError::ParseError {
+                token: Rc::clone(name),
+                message: format!("Variable '{}' shadows a parameter.", name.lexeme),
+            });
+        }
+        self.declare(name)?;
+        let id = self.bind(name, None);
+        if constant {
+            self.constants.insert(id);
+        }
+        if let Some(i) = initializer {
+            self.resolve_expr(i)?;
+        }
+        self.define(name);
+        Ok(())
+    }
+    fn visit_while(&mut self, condition: ExprId, body: StmtId) -> ResolverResult {
+        self.resolve_expr(condition)?;
+        self.loop_depth += 1;
+        let res = self.resolve_stmt(body);
+        self.loop_depth -= 1;
+        res
+    }
+}
+
+impl ExprVisitor<ResolverResult> for Resolver {
+    fn visit_assign(&mut self, expr: ExprId, name: &RcToken, value: ExprId) -> ResolverResult {
+        self.resolve_expr(value)?;
+        if let Some(id) = self.lookup_declaration(&name.lexeme) {
+            if self.constants.contains(&id) {
+                return Err(LoxError::ParseError {
+                    token: Rc::clone(name),
+                    message: format!("Can't assign to constant '{}'.", name.lexeme),
+                });
+            }
+            self.arities[id] = None;
+        }
+        self.resolve_local(expr, Rc::clone(name));
+        let local = self.scopes.iter().any(|s| s.contains_key(&name.lexeme));
+        if self.strict && !local && !self.globals.contains(&name.lexeme) {
+            // Top-level code runs in order, so the declaration had to come
+            // first; a function may run after a later one.
+            match self.current_function {
+                FunctionType::NONE => return Err(undeclared(Rc::clone(name))),
+                FunctionType::FUNCTION => self.global_assignments.push(Rc::clone(name)),
+            }
+        }
+        Ok(())
+    }
+    fn visit_binary(&mut self, left: ExprId, _operator: &RcToken, right: ExprId) -> ResolverResult {
+        self.resolve_expr(left)?;
+        self.resolve_expr(right)
+    }
+    fn visit_call(
+        &mut self,
+        callee: ExprId,
+        paren: &RcToken,
+        arguments: &[ExprId],
+    ) -> ResolverResult {
+        let ast = Rc::clone(&self.ast);
+        match &ast[callee] {
+            Expr::Literal { value, .. } => {
+                let value = ExprValue::Literal(value.clone());
+                return Err(LoxError::ParseError {
+                    token: Rc::clone(paren),
+                    message: not_callable(&value, &expr_to_source(&ast, callee)),
+                });
+            }
+            Expr::Variable { name, .. } => {
+                if let Some(id) = self.lookup_declaration(&name.lexeme) {
+                    self.calls.push((id, arguments.len(), Rc::clone(paren)));
+                }
+            }
+            _ => {}
+        }
+        self.resolve_expr(callee)?;
+        for &argument in arguments {
+            self.resolve_expr(argument)?;
+        }
+        Ok(())
+    }
+    fn visit_get(&mut self, object: ExprId, _name: &RcToken) -> ResolverResult {
+        self.resolve_expr(object)
+    }
+    fn visit_grouping(&mut self, expression: ExprId) -> ResolverResult {
+        self.resolve_expr(expression)
+    }
+    fn visit_index(&mut self, object: ExprId, _bracket: &RcToken, index: ExprId) -> ResolverResult {
+        self.resolve_expr(object)?;
+        self.resolve_expr(index)
+    }
+    fn visit_index_assign(
+        &mut self,
+        object: ExprId,
+        _bracket: &RcToken,
+        index: ExprId,
+        value: ExprId,
+    ) -> ResolverResult {
+        self.resolve_expr(object)?;
+        self.resolve_expr(index)?;
+        self.resolve_expr(value)
+    }
+    fn visit_interpolation(&mut self, parts: &[ExprId]) -> ResolverResult {
+        for &part in parts {
+            self.resolve_expr(part)?;
+        }
+        Ok(())
+    }
+    fn visit_list_literal(&mut self, elements: &[ExprId]) -> ResolverResult {
+        self.visit_interpolation(elements)
+    }
+    fn visit_literal(&mut self, _value: &Literal) -> ResolverResult {
+        Ok(())
+    }
+    fn visit_logical(
+        &mut self,
+        left: ExprId,
+        _operator: &RcToken,
+        right: ExprId,
+    ) -> ResolverResult {
+        self.resolve_expr(left)?;
+        self.resolve_expr(right)
+    }
+    fn visit_postfix(
+        &mut self,
+        _operator: &RcToken,
+        target: ExprId,
+        update: ExprId,
+    ) -> ResolverResult {
+        self.resolve_expr(target)?;
+        self.resolve_expr(update)
+    }
+    fn visit_unary(&mut self, _operator: &RcToken, right: ExprId) -> ResolverResult {
+        self.resolve_expr(right)
+    }
+    fn visit_variable(&mut self, expr: ExprId, name: &RcToken) -> ResolverResult {
+        if !self.scopes.is_empty() {
+            if let Some(v) = self.scopes.last().unwrap().get(&name.lexeme) {
+                if !v.defined {
+                    return Err(LoxError::ParseError {
+                        token: Rc::clone(name),
+                        message: "Can't read local variable in its own initializer.".to_string(),
+                    });
+                }
+            }
+        }
+        self.resolve_local(expr, Rc::clone(name));
+        Ok(())
+    }
+}
+
+fn undeclared(name: RcToken) -> LoxError {
+    let message = format!("Undefined variable '{}'.", name.lexeme);
+    LoxError::ParseError {
+        token: name,
+        message,
+    }
+}
+
+/// The token a statement starts at, or the closest one it keeps: statements
+/// led by a keyword don't hold on to it, so those point at what follows.
+/// Literals keep no token, so one is made up for them in `file`.
+fn first_token(ast: &AstArena, stmt: &Stmt, file: &Option<Rc<str>>) -> Option<RcToken> {
+    match stmt {
+        Stmt::Block { statements, .. } => statements
+            .first()
+            .and_then(|&st| first_token(ast, &ast[st], file)),
+        Stmt::Break { keyword, .. }
+        | Stmt::Continue { keyword, .. }
+        | Stmt::Import { keyword, .. }
+        | Stmt::Return { keyword, .. }
+        | Stmt::Throw { keyword, .. } => Some(Rc::clone(keyword)),
+        Stmt::Try { body, .. } => body
+            .first()
+            .and_then(|&st| first_token(ast, &ast[st], file)),
+        Stmt::Function { name, .. } | Stmt::Var { name, .. } => Some(Rc::clone(name)),
+        Stmt::For {
+            initializer,
+            condition,
+            increment,
+            body,
+            ..
+        } => initializer
+            .and_then(|st| first_token(ast, &ast[st], file))
+            .or_else(|| condition.and_then(|expr| first_expr_token(ast, expr, file)))
+            .or_else(|| increment.and_then(|expr| first_expr_token(ast, expr, file)))
+            .or_else(|| first_token(ast, &ast[*body], file)),
+        Stmt::ForEach { variable, .. } => Some(Rc::clone(variable)),
+        Stmt::Expression { expr, .. } | Stmt::Print { expr, .. } => {
+            first_expr_token(ast, *expr, file)
+        }
+        Stmt::If { condition, .. } | Stmt::While { condition, .. } => {
+            first_expr_token(ast, *condition, file)
+        }
+    }
+}
+
+fn first_expr_token(ast: &AstArena, expr: ExprId, file: &Option<Rc<str>>) -> Option<RcToken> {
+    match &ast[expr] {
+        Expr::Assign { name, .. } | Expr::Variable { name, .. } => Some(Rc::clone(name)),
+        Expr::Unary { operator, .. } => Some(Rc::clone(operator)),
+        Expr::Binary { left, .. } | Expr::Logical { left, .. } => {
+            first_expr_token(ast, *left, file)
+        }
+        Expr::Call { callee, .. } => first_expr_token(ast, *callee, file),
+        Expr::Get { object, .. }
+        | Expr::Index { object, .. }
+        | Expr::IndexAssign { object, .. } => first_expr_token(ast, *object, file),
+        Expr::Postfix { target, .. } => first_expr_token(ast, *target, file),
+        Expr::Grouping {
+            expression: inner, ..
+        } => first_expr_token(ast, *inner, file),
+        Expr::Interpolation { parts, .. }
+        | Expr::ListLiteral {
+            elements: parts, ..
+        } => parts
+            .iter()
+            .find_map(|&part| first_expr_token(ast, part, file)),
+        Expr::Literal { value, span } => {
+            let type_ = match value {
+                Literal::NUMBER(_) => TokenType::NUMBER,
+                Literal::STRING(_) => TokenType::STRING,
+                Literal::BOOL(true) => TokenType::TRUE,
+                Literal::BOOL(false) => TokenType::FALSE,
+                Literal::NIL => TokenType::NIL,
+                Literal::IDENTIFIER(_) => TokenType::IDENTIFIER,
+            };
+            Some(Rc::new(Token {
+                type_,
+                lexeme: Rc::from(expr_to_source(ast, expr)),
+                literal: value.clone(),
+                line: span.start_line,
+                column: span.start_col,
+                file: file.clone(),
+            }))
+        }
     }
 }
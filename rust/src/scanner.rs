@@ -3,45 +3,51 @@ use crate::token::{Literal, RcToken, Token};
 use crate::token_type::TokenType;
 use crate::token_type::TokenType::*;
 
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::rc::Rc;
 use std::str;
 
-trait Sub {
-    fn substr(&self, start: usize, stop: usize) -> Self;
-    fn char_at(&self, index: usize) -> char;
-}
-
-impl Sub for String {
-    fn substr(&self, start: usize, stop: usize) -> Self {
-        self.chars()
-            .skip(start as usize)
-            .take((start - stop) as usize)
-            .collect()
-    }
-    fn char_at(&self, index: usize) -> char {
-        self.as_bytes()[index] as char
-    }
-}
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
 
-trait Alpha {
-    fn is_ascii_identifier(&self) -> bool;
-}
-
-impl Alpha for u8 {
-    fn is_ascii_identifier(&self) -> bool {
-        self.is_ascii_alphanumeric() || *self == b'_'
-    }
+// Where a scanner is in its input.
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Scanning,
+    // The input ended inside a string, comment or interpolation; scanning
+    // resumes from its start once `push_source` supplies more.
+    Waiting,
+    // EOF has been handed out. More input resumes scanning too.
+    Ended,
+    // The input wasn't UTF-8, which more input can't fix.
+    Invalid,
 }
 
+// Produces tokens one at a time as an iterator, or all at once into
+// `tokens` with `scan_tokens`.
 pub struct Scanner {
     source: Vec<u8>,
     pub tokens: Vec<RcToken>,
     start: usize,
     current: usize,
     line: usize,
-
-    keywords: HashMap<&'static str, TokenType>,
+    // Where the current line starts in `source`.
+    line_start: usize,
+    state: State,
+    // How much of `source` is known to be UTF-8, which is as far as
+    // scanning goes. Past it there may be the start of a character that
+    // the next `push_source` finishes.
+    validated: usize,
+    // The token the last `scan_token` produced, if any.
+    token: Option<RcToken>,
+    // Whether the last error was running out of input.
+    incomplete: bool,
+    // (brace depth, line, column) of each `${` we are currently inside.
+    interpolations: Vec<(usize, usize, usize)>,
+    // Every distinct lexeme seen so far, so tokens with the same text share
+    // one allocation.
+    lexemes: HashSet<Rc<str>>,
+    // What the source is called in diagnostics; copied onto every token.
+    pub file: Option<Rc<str>>,
 }
 
 macro_rules! match_ {
@@ -60,66 +66,178 @@ macro_rules! match_ {
 }
 
 impl Scanner {
-    pub fn new(source: Vec<u8>) -> Self {
+    pub fn new(mut source: Vec<u8>) -> Self {
+        // A UTF-8 byte order mark is not part of the program.
+        if source.starts_with(UTF8_BOM) {
+            source.drain(..UTF8_BOM.len());
+        }
         Scanner {
             source,
             tokens: Vec::new(),
             start: 0,
             current: 0,
             line: 1,
-            keywords: HashMap::from([
-                ("and", AND),
-                ("class", CLASS),
-                ("else", ELSE),
-                ("false", FALSE),
-                ("for", FOR),
-                ("fun", FUN),
-                ("if", IF),
-                ("nil", NIL),
-                ("or", OR),
-                ("print", PRINT),
-                ("return", RETURN),
-                ("super", SUPER),
-                ("this", THIS),
-                ("true", TRUE),
-                ("var", VAR),
-                ("while", WHILE),
-            ]),
-        }
-    }
-
-    pub fn scan_tokens(&mut self) -> Result<(), LoxError<&'static str>> {
+            line_start: 0,
+            state: State::Scanning,
+            validated: 0,
+            token: None,
+            incomplete: false,
+            interpolations: Vec::new(),
+            lexemes: HashSet::new(),
+            file: None,
+        }
+    }
+
+    // Scans the whole input into `tokens`, stopping at the first error.
+    pub fn scan_tokens(&mut self) -> Result<(), LoxError> {
+        while let Some(token) = self.next_token() {
+            self.tokens.push(token?);
+        }
+        Ok(())
+    }
+
+    // Appends to the input, so scanning can carry on where it stopped.
+    pub fn push_source(&mut self, more: &[u8]) {
+        self.source.extend_from_slice(more);
+        if matches!(self.state, State::Waiting | State::Ended) {
+            self.state = State::Scanning;
+        }
+    }
+
+    // The next token, ending with EOF, or an error after which scanning
+    // goes on from the next character. None once the input runs out.
+    pub fn next_token(&mut self) -> Option<Result<RcToken, LoxError>> {
+        if self.state != State::Scanning {
+            return None;
+        }
+        if let Err(e) = self.validate() {
+            self.state = State::Invalid;
+            return Some(Err(e));
+        }
         while !self.is_at_end() {
             self.start = self.current;
-            self.scan_token()?;
-        }
-        self.tokens.push(Rc::from(Token::new(
-            EOF,
-            Vec::new(),
-            Literal::NIL,
-            self.line,
-        )));
+            let (line, line_start) = (self.line, self.line_start);
+            if let Err(e) = self.scan_token() {
+                if self.incomplete {
+                    self.incomplete = false;
+                    self.current = self.start;
+                    self.line = line;
+                    self.line_start = line_start;
+                    self.state = State::Waiting;
+                }
+                return Some(Err(e));
+            }
+            if let Some(token) = self.token.take() {
+                return Some(Ok(token));
+            }
+        }
+        if let Some(&(_, line, column)) = self.interpolations.last() {
+            self.state = State::Waiting;
+            return Some(Err(LoxError::ScanError {
+                line,
+                column,
+                file: self.file.clone(),
+                message: String::from("Unterminated string interpolation."),
+            }));
+        }
+        if self.validated < self.source.len() {
+            self.state = State::Waiting;
+            return Some(Err(self.invalid_utf8(self.validated)));
+        }
+        self.state = State::Ended;
+        Some(Ok(Rc::from(Token {
+            file: self.file.clone(),
+            ..Token::new(
+                EOF,
+                Vec::new(),
+                Literal::NIL,
+                self.line,
+                self.column_at(self.current),
+            )
+        })))
+    }
+
+    // Checks the input pushed since the last check. Input that stops
+    // partway through a character is fine until it turns out to be the end.
+    fn validate(&mut self) -> Result<(), LoxError> {
+        match str::from_utf8(&self.source[self.validated..]) {
+            Ok(_) => self.validated = self.source.len(),
+            Err(e) => {
+                self.validated += e.valid_up_to();
+                if e.error_len().is_some() {
+                    return Err(self.invalid_utf8(self.validated));
+                }
+            }
+        }
         Ok(())
     }
 
+    fn invalid_utf8(&self, position: usize) -> LoxError {
+        let before = &self.source[..position];
+        let line_start = before
+            .iter()
+            .rposition(|&c| c == b'\n')
+            .map_or(0, |newline| newline + 1);
+        LoxError::ScanError {
+            line: before.iter().filter(|&&c| c == b'\n').count() + 1,
+            column: position - line_start + 1,
+            file: self.file.clone(),
+            message: String::from("Source is not valid UTF-8."),
+        }
+    }
+
     #[inline(always)]
     fn is_at_end(&self) -> bool {
-        self.current >= self.source.len()
+        self.current >= self.validated
     }
 
-    fn scan_token(&mut self) -> Result<(), LoxError<&'static str>> {
+    fn scan_token(&mut self) -> Result<(), LoxError> {
         let c = self.advance();
         match c {
             b'(' => self.add_token(LEFT_PAREN),
             b')' => self.add_token(RIGHT_PAREN),
-            b'{' => self.add_token(LEFT_BRACE),
-            b'}' => self.add_token(RIGHT_BRACE),
+            b'{' => {
+                if let Some((depth, _, _)) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.add_token(LEFT_BRACE)
+            }
+            b'}' => match self.interpolations.last_mut() {
+                // Closes a `${`, so carry on with the rest of the string.
+                Some((0, _, _)) => {
+                    let interpolation = self.interpolations.pop();
+                    let result = self.string();
+                    // Scanning this again once there's more input still
+                    // needs to know it's inside the interpolation.
+                    if self.incomplete {
+                        self.interpolations.extend(interpolation);
+                    }
+                    return result;
+                }
+                Some((depth, _, _)) => {
+                    *depth -= 1;
+                    self.add_token(RIGHT_BRACE)
+                }
+                None => self.add_token(RIGHT_BRACE),
+            },
+            b'[' => self.add_token(LEFT_BRACKET),
+            b']' => self.add_token(RIGHT_BRACKET),
             b',' => self.add_token(COMMA),
             b'.' => self.add_token(DOT),
-            b'-' => self.add_token(MINUS),
-            b'+' => self.add_token(PLUS),
+            b'-' => {
+                let matches = match_!(self, b'-');
+                self.add_token(if matches { MINUS_MINUS } else { MINUS })
+            }
+            b'+' => {
+                let matches = match_!(self, b'+');
+                self.add_token(if matches { PLUS_PLUS } else { PLUS })
+            }
             b';' => self.add_token(SEMICOLON),
             b'*' => self.add_token(STAR),
+            b'%' => self.add_token(PERCENT),
+            b'&' => self.add_token(AMPERSAND),
+            b'|' => self.add_token(PIPE),
+            b'^' => self.add_token(CARET),
             b'!' => {
                 let matches = match_!(self, b'=');
                 self.add_token(if matches { BANG_EQUAL } else { BANG })
@@ -129,12 +247,24 @@ impl Scanner {
                 self.add_token(if matches { EQUAL_EQUAL } else { EQUAL })
             }
             b'<' => {
-                let matches = match_!(self, b'=');
-                self.add_token(if matches { LESS_EQUAL } else { LESS })
+                let type_ = if match_!(self, b'=') {
+                    LESS_EQUAL
+                } else if match_!(self, b'<') {
+                    LESS_LESS
+                } else {
+                    LESS
+                };
+                self.add_token(type_)
             }
             b'>' => {
-                let matches = match_!(self, b'=');
-                self.add_token(if matches { GREATER_EQUAL } else { GREATER })
+                let type_ = if match_!(self, b'=') {
+                    GREATER_EQUAL
+                } else if match_!(self, b'>') {
+                    GREATER_GREATER
+                } else {
+                    GREATER
+                };
+                self.add_token(type_)
             }
             b'/' => {
                 let matches = match_!(self, b'/');
@@ -143,23 +273,36 @@ impl Scanner {
                     while self.peek() != b'\n' && !self.is_at_end() {
                         self.advance();
                     }
+                } else if match_!(self, b'*') {
+                    return self.block_comment();
                 } else {
                     self.add_token(SLASH);
                 }
             }
             // ignore whitespace
             b' ' | b'\r' | b'\t' => {}
-            b'\n' => self.line += 1,
+            b'\n' => self.new_line(),
             b'"' => return self.string(),
             // numbers
-            b'0'..=b'9' => self.number(),
+            b'0'..=b'9' => return self.number(),
             // identifiers (alpha)
             b'A'..=b'Z' | b'a'..=b'z' | b'_' => self.identifier(),
             _ => {
-                return Err(LoxError::ScanError {
-                    line: self.line,
-                    message: &"Unexpected character.",
-                });
+                // Non-ASCII: take the whole character, which may start an
+                // identifier.
+                self.current = self.start;
+                let c = self.peek_char();
+                self.current += c.len_utf8();
+                if c.is_alphabetic() {
+                    self.identifier();
+                } else {
+                    return Err(LoxError::ScanError {
+                        line: self.line,
+                        column: self.column_at(self.start),
+                        file: self.file.clone(),
+                        message: format!("Unexpected character '{}'.", c),
+                    });
+                }
             }
         }
         Ok(())
@@ -171,13 +314,45 @@ impl Scanner {
     }
 
     fn add_token_literal(&mut self, type_: TokenType, literal: Literal) {
-        let text = &self.source[self.start..self.current];
-        self.tokens.push(Rc::from(Token::new(
+        let text = String::from_utf8_lossy(&self.source[self.start..self.current]);
+        let lexeme = match self.lexemes.get(&*text) {
+            Some(lexeme) => Rc::clone(lexeme),
+            None => {
+                let lexeme: Rc<str> = Rc::from(&*text);
+                self.lexemes.insert(Rc::clone(&lexeme));
+                lexeme
+            }
+        };
+        self.token = Some(Rc::from(Token {
             type_,
-            text.to_vec(),
+            lexeme,
             literal,
-            self.line,
-        )));
+            line: self.line,
+            column: self.column_at(self.start),
+            file: self.file.clone(),
+        }));
+    }
+
+    // Column of the byte at `position`, which is on the current line or
+    // before it. Only a token spanning lines starts before the current
+    // line, so only those look back for the newline; doing that for every
+    // token made long lines quadratic to scan.
+    fn column_at(&self, position: usize) -> usize {
+        let line_start = if position >= self.line_start {
+            self.line_start
+        } else {
+            self.source[..position]
+                .iter()
+                .rposition(|&c| c == b'\n')
+                .map_or(0, |newline| newline + 1)
+        };
+        position - line_start + 1
+    }
+
+    // Called just past a newline.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
     }
 
     fn add_token(&mut self, type_: TokenType) {
@@ -203,37 +378,111 @@ impl Scanner {
         self.source[self.current]
     }
 
-    fn string(&mut self) -> Result<(), LoxError<&'static str>> {
+    fn string(&mut self) -> Result<(), LoxError> {
+        let start_line = self.line;
+        let start_column = self.column_at(self.start);
         // read till closing quote
         while self.peek() != b'"' && !self.is_at_end() {
-            if self.peek() == b'\n' {
-                self.line += 1;
+            if self.peek() == b'$' && self.peek_next() == b'{' {
+                self.advance();
+                self.advance();
+                let value = String::from_utf8_lossy(&self.source[self.start + 1..self.current - 2])
+                    .into_owned();
+                self.add_token_literal(STRING_PART, Literal::STRING(value));
+                let column = self.column_at(self.current - 2);
+                self.interpolations.push((0, self.line, column));
+                return Ok(());
+            }
+            if self.advance() == b'\n' {
+                self.new_line();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
+            self.incomplete = true;
+            // A quote inside an interpolation that was never closed is more
+            // likely a stray than the start of a string.
+            if let Some(&(_, line, column)) = self.interpolations.last() {
+                return Err(LoxError::ScanError {
+                    line,
+                    column,
+                    file: self.file.clone(),
+                    message: String::from("Unterminated string interpolation."),
+                });
+            }
             return Err(LoxError::ScanError {
-                line: self.line,
-                message: &"Unterminated string",
+                line: start_line,
+                column: start_column,
+                file: self.file.clone(),
+                message: format!(
+                    "Unterminated string starting with \"{}\".",
+                    self.excerpt(self.start + 1)
+                ),
             });
         }
         // the closing "
         self.advance();
         // Trim the surrounding quotes.
-        let value = str::from_utf8(&self.source[self.start + 1..self.current - 1])
-            .expect("Invalid UTF8")
-            .to_string();
+        let value =
+            String::from_utf8_lossy(&self.source[self.start + 1..self.current - 1]).into_owned();
         self.add_token_literal(STRING, Literal::STRING(value));
         Ok(())
     }
 
+    fn block_comment(&mut self) -> Result<(), LoxError> {
+        let start_line = self.line;
+        let start_column = self.column_at(self.start);
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                self.incomplete = true;
+                return Err(LoxError::ScanError {
+                    line: start_line,
+                    column: start_column,
+                    file: self.file.clone(),
+                    message: format!(
+                        "Unterminated block comment starting with \"{}\".",
+                        self.excerpt(self.start + 2)
+                    ),
+                });
+            }
+            match self.advance() {
+                b'\n' => self.new_line(),
+                b'/' if self.peek() == b'*' => {
+                    self.advance();
+                    depth += 1;
+                }
+                b'*' if self.peek() == b'/' => {
+                    self.advance();
+                    depth -= 1;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The first line of text at `position`, cut down to a length that
+    /// fits in an error message.
+    fn excerpt(&self, position: usize) -> String {
+        const EXCERPT_LENGTH: usize = 20;
+        let text = String::from_utf8_lossy(&self.source[position..]);
+        let line = text.lines().next().unwrap_or("");
+        let mut excerpt: String = line.chars().take(EXCERPT_LENGTH).collect();
+        if excerpt.len() < text.trim_end().len() {
+            excerpt.push_str("...");
+        }
+        excerpt
+    }
+
     #[inline(always)]
     fn is_digit(c: u8) -> bool {
         matches!(c, b'0'..=b'9')
     }
 
-    fn number(&mut self) {
+    // A second `.` ends the number, so `1.2.3` is 1.2, a dot and 3, which
+    // the parser then rejects.
+    fn number(&mut self) -> Result<(), LoxError> {
         while Scanner::is_digit(self.peek()) {
             self.advance();
         }
@@ -244,34 +493,546 @@ impl Scanner {
                 self.advance();
             }
         }
-        self.add_token_literal(
-            NUMBER,
-            Literal::NUMBER(
-                str::from_utf8(&self.source[self.start..self.current])
-                    .expect("Invalid UTF8")
-                    .parse()
-                    .expect("Invalid float"),
-            ),
-        );
+        // Digits with at most one point always parse, and a number too big
+        // for an f64 comes out as infinity, but an error beats a panic if
+        // that ever changes.
+        let lexeme = String::from_utf8_lossy(&self.source[self.start..self.current]);
+        let value = lexeme.parse().map_err(|_| LoxError::ScanError {
+            line: self.line,
+            column: self.column_at(self.start),
+            file: self.file.clone(),
+            message: format!("Invalid number '{}'.", lexeme),
+        })?;
+        self.add_token_literal(NUMBER, Literal::NUMBER(value));
+        Ok(())
+    }
+
+    // The character starting at `current`, or '\0' at the end. The source
+    // was checked to be valid UTF-8 before scanning began.
+    fn peek_char(&self) -> char {
+        let end = (self.current + 4).min(self.validated);
+        let bytes = &self.source[self.current..end];
+        let valid = match str::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(e) => str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+        };
+        valid.chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&mut self) -> u8 {
-        if self.current + 1 >= self.source.len() {
+        if self.current + 1 >= self.validated {
             return b'\0';
         }
         self.source[self.current + 1]
     }
 
     fn identifier(&mut self) {
-        while self.peek().is_ascii_identifier() {
-            self.advance();
+        loop {
+            let c = self.peek_char();
+            if !(c.is_alphanumeric() || c == '_') {
+                break;
+            }
+            self.current += c.len_utf8();
+        }
+        let text = String::from_utf8_lossy(&self.source[self.start..self.current]);
+        self.add_token(keyword(&text).unwrap_or(IDENTIFIER));
+    }
+}
+
+// The keyword `text` spells, if any.
+fn keyword(text: &str) -> Option<TokenType> {
+    Some(match text {
+        "and" => AND,
+        "break" => BREAK,
+        "catch" => CATCH,
+        "class" => CLASS,
+        "const" => CONST,
+        "continue" => CONTINUE,
+        "else" => ELSE,
+        "false" => FALSE,
+        "for" => FOR,
+        "fun" => FUN,
+        "if" => IF,
+        "import" => IMPORT,
+        "nil" => NIL,
+        "or" => OR,
+        "print" => PRINT,
+        "return" => RETURN,
+        "super" => SUPER,
+        "this" => THIS,
+        "throw" => THROW,
+        "true" => TRUE,
+        "try" => TRY,
+        "var" => VAR,
+        "while" => WHILE,
+        _ => return None,
+    })
+}
+
+impl Iterator for Scanner {
+    type Item = Result<RcToken, LoxError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_token()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(source: &str) -> Result<Vec<RcToken>, LoxError> {
+        let mut scanner = Scanner::new(source.as_bytes().to_vec());
+        scanner.scan_tokens()?;
+        Ok(scanner.tokens)
+    }
+
+    #[test]
+    fn nested_block_comments() {
+        let tokens = scan("1 /* outer /* inner */\n still comment */ 2\n)").unwrap();
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.type_.clone()).collect();
+        assert_eq!(types, vec![NUMBER, NUMBER, RIGHT_PAREN, EOF]);
+        // The stray ')' after the comment is reported on the right line.
+        assert_eq!(tokens[1].line, 2);
+        assert_eq!(tokens[2].line, 3);
+    }
+
+    #[test]
+    fn unterminated_block_comment() {
+        match scan("var a;\n/* never /* closed */\n\n") {
+            Err(LoxError::ScanError { line, message, .. }) => {
+                assert_eq!(line, 2);
+                assert_eq!(
+                    message,
+                    "Unterminated block comment starting with \" never /* closed */\"."
+                );
+            }
+            other => panic!("expected a scan error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn string_interpolation_tokens() {
+        let tokens = scan("\"a ${x + \"b ${y}\"} c ${ {} } d\"").unwrap();
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.type_.clone()).collect();
+        assert_eq!(
+            types,
+            vec![
+                STRING_PART,
+                IDENTIFIER,
+                PLUS,
+                STRING_PART,
+                IDENTIFIER,
+                STRING,
+                STRING_PART,
+                LEFT_BRACE,
+                RIGHT_BRACE,
+                STRING,
+                EOF
+            ]
+        );
+        assert_eq!(tokens[0].literal, Literal::STRING(String::from("a ")));
+        assert_eq!(tokens[6].literal, Literal::STRING(String::from(" c ")));
+        assert_eq!(tokens[9].literal, Literal::STRING(String::from(" d")));
+    }
+
+    #[test]
+    fn unterminated_interpolation() {
+        for source in ["\n\"a ${x + 1", "\n\"a ${f(\"x) + 1", "\n\"a ${x \";"] {
+            match scan(source) {
+                Err(LoxError::ScanError {
+                    line,
+                    column,
+                    message,
+                    ..
+                }) => {
+                    assert_eq!((line, column), (2, 4), "{:?}", source);
+                    assert_eq!(message, "Unterminated string interpolation.");
+                }
+                other => panic!("expected a scan error, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn token_columns() {
+        let tokens = scan("var a = 1;\n  print \"x\ny\" + a;").unwrap();
+        let columns: Vec<(usize, usize)> = tokens.iter().map(|t| (t.line, t.column)).collect();
+        assert_eq!(
+            columns,
+            vec![
+                (1, 1),
+                (1, 5),
+                (1, 7),
+                (1, 9),
+                (1, 10),
+                (2, 3),
+                (3, 9),
+                (3, 4),
+                (3, 6),
+                (3, 7),
+                (3, 8)
+            ]
+        );
+    }
+
+    #[test]
+    fn long_lines_scan_quickly() {
+        // Columns used to be found by looking back for the newline from
+        // every token, which took minutes on a line this long.
+        let source = format!("print {}1;", "1 + ".repeat(100_000));
+        let tokens = scan(&source).unwrap();
+        let semicolon = &tokens[tokens.len() - 2];
+        assert_eq!((semicolon.line, semicolon.column), (1, source.len()));
+    }
+
+    #[test]
+    fn increment_tokens() {
+        let types: Vec<TokenType> = scan("--i; i++ + 1 - -2")
+            .unwrap()
+            .iter()
+            .map(|t| t.type_.clone())
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                MINUS_MINUS,
+                IDENTIFIER,
+                SEMICOLON,
+                IDENTIFIER,
+                PLUS_PLUS,
+                PLUS,
+                NUMBER,
+                MINUS,
+                MINUS,
+                NUMBER,
+                EOF
+            ]
+        );
+    }
+
+    #[test]
+    fn bitwise_tokens() {
+        let types: Vec<TokenType> = scan("a<<=b <= c >> d >= e & f | g ^ h")
+            .unwrap()
+            .iter()
+            .map(|t| t.type_.clone())
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                IDENTIFIER,
+                LESS_LESS,
+                EQUAL,
+                IDENTIFIER,
+                LESS_EQUAL,
+                IDENTIFIER,
+                GREATER_GREATER,
+                IDENTIFIER,
+                GREATER_EQUAL,
+                IDENTIFIER,
+                AMPERSAND,
+                IDENTIFIER,
+                PIPE,
+                IDENTIFIER,
+                CARET,
+                IDENTIFIER,
+                EOF
+            ]
+        );
+    }
+
+    #[test]
+    fn non_ascii_source() {
+        let tokens = scan("\u{feff}var café = \"🦀 ok\"; π;").unwrap();
+        assert_eq!(tokens[0].type_, VAR);
+        assert_eq!(tokens[0].column, 1);
+        assert_eq!(&*tokens[1].lexeme, "café");
+        assert_eq!(tokens[3].literal, Literal::STRING(String::from("🦀 ok")));
+        assert_eq!(&*tokens[5].lexeme, "π");
+
+        match scan("var a = 1;\nprint a → 2;") {
+            Err(LoxError::ScanError {
+                line,
+                column,
+                message,
+                ..
+            }) => {
+                assert_eq!((line, column), (2, 9));
+                assert_eq!(message, "Unexpected character '→'.");
+            }
+            other => panic!("expected a scan error, got {:?}", other.map(|_| ())),
+        }
+
+        let mut scanner = Scanner::new(b"print \"a\";\n\x80".to_vec());
+        match scanner.scan_tokens() {
+            Err(LoxError::ScanError { line, message, .. }) => {
+                assert_eq!(line, 2);
+                assert_eq!(message, "Source is not valid UTF-8.");
+            }
+            other => panic!("expected a scan error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unterminated_string_reports_opening_line() {
+        let source = "var a = 1;\nvar b = \"hello world, this never ends;\n\n\n\n\n\n\n\nprint b;";
+        match scan(source) {
+            Err(LoxError::ScanError {
+                line,
+                column,
+                message,
+                ..
+            }) => {
+                assert_eq!((line, column), (2, 9));
+                assert_eq!(
+                    message,
+                    "Unterminated string starting with \"hello world, this ne...\"."
+                );
+            }
+            other => panic!("expected a scan error, got {:?}", other.map(|_| ())),
+        }
+
+        match scan("print \"short") {
+            Err(LoxError::ScanError { message, .. }) => {
+                assert_eq!(message, "Unterminated string starting with \"short\".");
+            }
+            other => panic!("expected a scan error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    fn types(tokens: &[Result<RcToken, LoxError>]) -> Vec<Option<TokenType>> {
+        tokens
+            .iter()
+            .map(|t| t.as_ref().ok().map(|t| t.type_.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn iterating_matches_scan_tokens() {
+        let sources = [
+            include_str!("../bench.lox"),
+            "var s = \"a ${x + \"b ${y}\"} c\";\n/* block /* nested */ */ print s; // done\n\
+             fun café(n) { return n >= 1 and n != 2 or n << 1; }",
+        ];
+        for source in sources {
+            let iterated: Vec<RcToken> = Scanner::new(source.as_bytes().to_vec())
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(iterated, scan(source).unwrap());
+        }
+    }
+
+    #[test]
+    fn errors_do_not_end_the_stream() {
+        let tokens: Vec<_> = Scanner::new(b"a @ b # c".to_vec()).collect();
+        assert_eq!(
+            types(&tokens),
+            vec![
+                Some(IDENTIFIER),
+                None,
+                Some(IDENTIFIER),
+                None,
+                Some(IDENTIFIER),
+                Some(EOF)
+            ]
+        );
+        match &tokens[3] {
+            Err(LoxError::ScanError {
+                column, message, ..
+            }) => {
+                assert_eq!(*column, 7);
+                assert_eq!(message, "Unexpected character '#'.");
+            }
+            other => panic!("expected a scan error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn more_source_resumes_scanning() {
+        // After EOF.
+        let mut scanner = Scanner::new(b"var a".to_vec());
+        assert_eq!(
+            types(&scanner.by_ref().collect::<Vec<_>>()),
+            vec![Some(VAR), Some(IDENTIFIER), Some(EOF)]
+        );
+        scanner.push_source(b" = 1;");
+        assert_eq!(
+            types(&scanner.by_ref().collect::<Vec<_>>()),
+            vec![Some(EQUAL), Some(NUMBER), Some(SEMICOLON), Some(EOF)]
+        );
+
+        // Inside a string, which is scanned again from its start.
+        let mut scanner = Scanner::new(b"print \"one".to_vec());
+        assert_eq!(
+            types(&scanner.by_ref().collect::<Vec<_>>()),
+            vec![Some(PRINT), None]
+        );
+        assert!(scanner.next().is_none());
+        scanner.push_source(b"\ntwo\";");
+        let tokens: Vec<RcToken> = scanner.by_ref().map(Result::unwrap).collect();
+        assert_eq!(tokens[0].literal, Literal::STRING(String::from("one\ntwo")));
+        assert_eq!((tokens[0].line, tokens[0].column), (2, 7));
+        assert_eq!(tokens[1].line, 2);
+
+        // Inside a comment.
+        let mut scanner = Scanner::new(b"1 /* open".to_vec());
+        assert_eq!(
+            types(&scanner.by_ref().collect::<Vec<_>>()),
+            vec![Some(NUMBER), None]
+        );
+        scanner.push_source(b" */ 2");
+        assert_eq!(
+            types(&scanner.by_ref().collect::<Vec<_>>()),
+            vec![Some(NUMBER), Some(EOF)]
+        );
+
+        // Inside an interpolation, and in the string after one.
+        let mut scanner = Scanner::new(b"\"a ${x".to_vec());
+        assert_eq!(
+            types(&scanner.by_ref().collect::<Vec<_>>()),
+            vec![Some(STRING_PART), Some(IDENTIFIER), None]
+        );
+        scanner.push_source(b"} b");
+        assert_eq!(types(&scanner.by_ref().collect::<Vec<_>>()), vec![None]);
+        scanner.push_source(b" c\"");
+        let tokens: Vec<RcToken> = scanner.by_ref().map(Result::unwrap).collect();
+        assert_eq!(tokens[0].literal, Literal::STRING(String::from(" b c")));
+        assert_eq!(tokens[1].type_, EOF);
+
+        // Partway through a character, inside a string and between tokens.
+        let source = "print \"é\"; π;".as_bytes();
+        let mut scanner = Scanner::new(source[..8].to_vec());
+        assert_eq!(
+            types(&scanner.by_ref().collect::<Vec<_>>()),
+            vec![Some(PRINT), None]
+        );
+        scanner.push_source(&source[8..13]);
+        let tokens: Vec<_> = scanner.by_ref().collect();
+        assert_eq!(types(&tokens), vec![Some(STRING), Some(SEMICOLON), None]);
+        match &tokens[2] {
+            Err(LoxError::ScanError { message, .. }) => {
+                assert_eq!(message, "Source is not valid UTF-8.")
+            }
+            other => panic!("expected a scan error, got {:?}", other),
+        }
+        scanner.push_source(&source[13..]);
+        let tokens: Vec<RcToken> = scanner.by_ref().map(Result::unwrap).collect();
+        assert_eq!(&*tokens[0].lexeme, "π");
+        assert_eq!(tokens[0].column, 13);
+        assert_eq!(tokens[2].type_, EOF);
+    }
+
+    #[test]
+    fn keywords_and_near_misses() {
+        let keywords = [
+            ("and", AND, "andy"),
+            ("break", BREAK, "breaks"),
+            ("catch", CATCH, "catcher"),
+            ("class", CLASS, "classy"),
+            ("const", CONST, "constant"),
+            ("continue", CONTINUE, "continu"),
+            ("else", ELSE, "elsewhere"),
+            ("false", FALSE, "False"),
+            ("for", FOR, "fort"),
+            ("fun", FUN, "fn"),
+            ("if", IF, "iff"),
+            ("import", IMPORT, "imports"),
+            ("nil", NIL, "nil_"),
+            ("or", OR, "orb"),
+            ("print", PRINT, "printf"),
+            ("return", RETURN, "ret"),
+            ("super", SUPER, "superb"),
+            ("this", THIS, "these"),
+            ("throw", THROW, "thrown"),
+            ("true", TRUE, "truth"),
+            ("try", TRY, "tr"),
+            ("var", VAR, "va"),
+            ("while", WHILE, "whilst"),
+        ];
+        for (text, type_, near_miss) in keywords {
+            let tokens = scan(&format!("{} {}", text, near_miss)).unwrap();
+            assert_eq!(tokens[0].type_, type_, "{}", text);
+            assert_eq!(tokens[1].type_, IDENTIFIER, "{}", near_miss);
+            assert_eq!(&*tokens[1].lexeme, near_miss);
+        }
+    }
+
+    #[test]
+    fn lexemes_are_interned() {
+        let tokens = scan("var a = a + a;").unwrap();
+        assert!(Rc::ptr_eq(&tokens[1].lexeme, &tokens[3].lexeme));
+        assert!(Rc::ptr_eq(&tokens[3].lexeme, &tokens[5].lexeme));
+        assert!(!Rc::ptr_eq(&tokens[1].lexeme, &tokens[4].lexeme));
+    }
+
+    #[test]
+    fn number_edge_cases() {
+        let tokens = scan("1.2.3 1. .5").unwrap();
+        let scanned: Vec<(TokenType, &str)> = tokens
+            .iter()
+            .map(|t| (t.type_.clone(), &*t.lexeme))
+            .collect();
+        assert_eq!(
+            scanned,
+            vec![
+                (NUMBER, "1.2"),
+                (DOT, "."),
+                (NUMBER, "3"),
+                (NUMBER, "1"),
+                (DOT, "."),
+                (DOT, "."),
+                (NUMBER, "5"),
+                (EOF, ""),
+            ]
+        );
+        assert_eq!(tokens[0].literal, Literal::NUMBER(1.2));
+
+        let huge = scan(&"9".repeat(400)).unwrap();
+        assert_eq!(huge[0].literal, Literal::NUMBER(f64::INFINITY));
+        let tiny = scan(&format!("0.{}1", "0".repeat(400))).unwrap();
+        assert_eq!(tiny[0].literal, Literal::NUMBER(0.0));
+    }
+
+    // Random runs of digits and dots, from a fixed seed so failures
+    // reproduce. Scanning must never panic, and every number it finds must
+    // be digits with at most one point between them.
+    #[test]
+    fn random_numbers_never_panic() {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..2000 {
+            let len = next() % 40;
+            let source: String = (0..len)
+                .map(|_| match next() % 12 {
+                    10 => '.',
+                    11 => ' ',
+                    d => char::from(b'0' + d as u8),
+                })
+                .collect();
+            for token in scan(&source).expect(&source) {
+                if token.type_ != NUMBER {
+                    continue;
+                }
+                let lexeme = &*token.lexeme;
+                assert!(lexeme.matches('.').count() <= 1, "{}", source);
+                assert!(
+                    !lexeme.starts_with('.') && !lexeme.ends_with('.'),
+                    "{}",
+                    source
+                );
+                assert_eq!(
+                    token.literal,
+                    Literal::NUMBER(lexeme.parse().unwrap()),
+                    "{}",
+                    source
+                );
+            }
         }
-        let text = &self.source[self.start..self.current];
-        let token_type = self
-            .keywords
-            .get(&str::from_utf8(text).expect("invalid unicode"))
-            .cloned()
-            .unwrap_or(IDENTIFIER);
-        self.add_token(token_type);
     }
 }
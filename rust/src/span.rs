@@ -0,0 +1,102 @@
+use crate::token::Token;
+
+// The stretch of source a syntax node was parsed from. Lines and columns are
+// 1-based, inclusive, and count bytes like `Token::column`. The default,
+// all-zero span marks a node the parser made up while desugaring.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Default)]
+pub struct Span {
+    pub start_line: usize,
+    pub start_col: usize,
+    pub end_line: usize,
+    pub end_col: usize,
+}
+
+impl Span {
+    // From the start of `first` to the end of `last`.
+    pub fn new(first: &Token, last: &Token) -> Span {
+        Span::of(first).to(Span::of(last))
+    }
+    pub fn of(token: &Token) -> Span {
+        if token.column == 0 {
+            return Span::default();
+        }
+        // Multi-line tokens carry the line they end on.
+        let newlines = token.lexeme.matches('\n').count();
+        let end_col = match token.lexeme.rfind('\n') {
+            Some(newline) => (token.lexeme.len() - newline - 1).max(1),
+            None => token.column + token.lexeme.len().max(1) - 1,
+        };
+        Span {
+            start_line: token.line - newlines,
+            start_col: token.column,
+            end_line: token.line,
+            end_col,
+        }
+    }
+    // The smallest span covering both; synthetic spans don't count.
+    pub fn to(self, other: Span) -> Span {
+        if self.is_synthetic() {
+            return other;
+        }
+        if other.is_synthetic() {
+            return self;
+        }
+        let (start_line, start_col) =
+            (self.start_line, self.start_col).min((other.start_line, other.start_col));
+        let (end_line, end_col) =
+            (self.end_line, self.end_col).max((other.end_line, other.end_col));
+        Span {
+            start_line,
+            start_col,
+            end_line,
+            end_col,
+        }
+    }
+    pub fn is_synthetic(&self) -> bool {
+        self.start_line == 0
+    }
+    pub fn contains(&self, other: Span) -> bool {
+        !self.is_synthetic()
+            && !other.is_synthetic()
+            && (self.start_line, self.start_col) <= (other.start_line, other.start_col)
+            && (other.end_line, other.end_col) <= (self.end_line, self.end_col)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::Literal;
+    use crate::token_type::TokenType;
+
+    fn token(lexeme: &str, line: usize, column: usize) -> Token {
+        Token::new(
+            TokenType::IDENTIFIER,
+            lexeme.as_bytes().to_vec(),
+            Literal::NIL,
+            line,
+            column,
+        )
+    }
+
+    #[test]
+    fn spans_cover_their_tokens() {
+        let span = Span::new(&token("foo", 2, 5), &token(")", 2, 14));
+        assert_eq!((span.start_line, span.start_col), (2, 5));
+        assert_eq!((span.end_line, span.end_col), (2, 14));
+
+        // A string token ending on line 3 that started on line 1.
+        let string = Span::of(&token("\"ab\ncd\nefg\"", 3, 9));
+        assert_eq!((string.start_line, string.start_col), (1, 9));
+        assert_eq!((string.end_line, string.end_col), (3, 4));
+
+        let synthetic = Span::of(&token("+", 1, 0));
+        assert!(synthetic.is_synthetic());
+        assert_eq!(synthetic.to(span), span);
+        assert_eq!(span.to(synthetic), span);
+
+        assert!(span.contains(Span::of(&token("bar", 2, 9))));
+        assert!(!span.contains(Span::of(&token("bar", 2, 13))));
+        assert!(!span.contains(synthetic));
+    }
+}
@@ -1,40 +1,120 @@
-use crate::expr::RcExpr;
+use crate::expr::ExprId;
+use crate::span::Span;
 use crate::token::RcToken;
-use std::rc::Rc;
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum Stmt {
     Block {
-        statements: Rc<Vec<RcStmt>>,
+        statements: Vec<StmtId>,
+        span: Span,
+    },
+    Break {
+        keyword: RcToken,
+        span: Span,
+    },
+    Continue {
+        keyword: RcToken,
+        span: Span,
     },
     Expression {
-        expr: RcExpr,
+        expr: ExprId,
+        span: Span,
+    },
+    // `for (initializer; condition; increment) body`. The initializer's
+    // variable belongs to the loop, and no condition means loop forever.
+    For {
+        initializer: Option<StmtId>,
+        condition: Option<ExprId>,
+        increment: Option<ExprId>,
+        body: StmtId,
+        span: Span,
+    },
+    // `for (var variable in iterable) body`. `keyword` is the `in`.
+    ForEach {
+        variable: RcToken,
+        keyword: RcToken,
+        iterable: ExprId,
+        body: StmtId,
+        span: Span,
     },
     Function {
         name: RcToken,
-        params: Vec<RcToken>,
-        body: Rc<Vec<RcStmt>>,
+        params: Vec<Parameter>,
+        body: Vec<StmtId>,
+        span: Span,
+    },
+    // `import "path";`, where `path` is the string token naming the file.
+    Import {
+        keyword: RcToken,
+        path: RcToken,
+        span: Span,
     },
     If {
-        condition: RcExpr,
-        then_branch: RcStmt,
-        else_branch: Option<RcStmt>,
+        condition: ExprId,
+        then_branch: StmtId,
+        else_branch: Option<StmtId>,
+        span: Span,
     },
     Print {
-        expr: RcExpr,
+        expr: ExprId,
+        span: Span,
     },
     Return {
         keyword: RcToken,
-        value: RcExpr,
+        value: ExprId,
+        span: Span,
+    },
+    Throw {
+        keyword: RcToken,
+        value: ExprId,
+        span: Span,
+    },
+    // `try { body } catch (binding) { handler }`
+    Try {
+        body: Vec<StmtId>,
+        binding: RcToken,
+        handler: Vec<StmtId>,
+        span: Span,
     },
     Var {
         name: RcToken,
-        initializer: Option<RcExpr>,
+        initializer: Option<ExprId>,
+        // Declared with `const`: always initialized, never reassigned.
+        constant: bool,
+        span: Span,
     },
     While {
-        condition: RcExpr,
-        body: RcStmt,
+        condition: ExprId,
+        body: StmtId,
+        span: Span,
     },
 }
 
-pub type RcStmt = Rc<Stmt>;
+impl Stmt {
+    pub fn span(&self) -> Span {
+        match self {
+            Stmt::Block { span, .. }
+            | Stmt::Break { span, .. }
+            | Stmt::Continue { span, .. }
+            | Stmt::Expression { span, .. }
+            | Stmt::For { span, .. }
+            | Stmt::ForEach { span, .. }
+            | Stmt::Function { span, .. }
+            | Stmt::If { span, .. }
+            | Stmt::Import { span, .. }
+            | Stmt::Print { span, .. }
+            | Stmt::Return { span, .. }
+            | Stmt::Throw { span, .. }
+            | Stmt::Try { span, .. }
+            | Stmt::Var { span, .. }
+            | Stmt::While { span, .. } => *span,
+        }
+    }
+}
+
+// Where a statement lives in its `AstArena`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct StmtId(pub(crate) u32);
+
+// A parameter's name and its default value, if it has one.
+pub type Parameter = (RcToken, Option<ExprId>);
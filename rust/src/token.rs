@@ -1,9 +1,10 @@
 use super::token_type::TokenType;
 use std::hash::{Hash, Hasher};
-use std::mem;
 use std::rc::Rc;
 use std::str;
 
+// Numbers compare as IEEE floats, the way Lox's `==` does: NaN equals
+// nothing, itself included, and 0 equals -0.
 #[allow(dead_code)]
 #[allow(non_camel_case_types)]
 #[derive(Clone, Debug, PartialEq)]
@@ -22,52 +23,55 @@ impl Hash for Literal {
             Literal::STRING(s) => s.hash(state),
             Literal::BOOL(b) => b.hash(state),
             Literal::NIL => state.write_u8(1),
-            Literal::NUMBER(f) => integer_decode(f.clone()).hash(state),
+            // Equal numbers must hash alike, so -0 goes in as 0.
+            Literal::NUMBER(f) if *f == 0.0 => 0f64.to_bits().hash(state),
+            Literal::NUMBER(f) => f.to_bits().hash(state),
         }
     }
 }
 
+// NaN breaks reflexivity, so it's kept out of maps; see `Interpreter::map_key`.
 impl Eq for Literal {}
 
-/* Code from https://stackoverflow.com/questions/39638363/how-can-i-use-a-hashmap-with-f64-as-key-in-rust */
-fn integer_decode(val: f64) -> (u64, i16, i8) {
-    let bits: u64 = unsafe { mem::transmute(val) };
-    let sign: i8 = if bits >> 63 == 0 { 1 } else { -1 };
-    let mut exponent: i16 = ((bits >> 52) & 0x7ff) as i16;
-    let mantissa = if exponent == 0 {
-        (bits & 0xfffffffffffff) << 1
-    } else {
-        (bits & 0xfffffffffffff) | 0x10000000000000
-    };
-
-    exponent -= 1023 + 52;
-    (mantissa, exponent, sign)
-}
-
 #[allow(dead_code)]
 #[derive(Debug, Clone, PartialEq, Hash, Eq)]
 pub struct Token {
     pub type_: TokenType,
-    pub lexeme: String,
+    // Shared between tokens with the same text; the scanner interns them.
+    pub lexeme: Rc<str>,
     pub literal: Literal,
     pub line: usize,
+    // 1-based byte column of the token's first character on the line it
+    // starts on; 0 for tokens that don't come from source.
+    pub column: usize,
+    // The name of the source the token was scanned from, e.g. a script's
+    // path or "<repl>", if it was given one.
+    pub file: Option<Rc<str>>,
 }
 
 #[allow(dead_code)]
 impl Token {
-    pub fn new(type_: TokenType, lexeme: Vec<u8>, literal: Literal, line: usize) -> Token {
-        let s = str::from_utf8(&lexeme).expect("Invalid UTF8").to_string();
+    pub fn new(
+        type_: TokenType,
+        lexeme: Vec<u8>,
+        literal: Literal,
+        line: usize,
+        column: usize,
+    ) -> Token {
+        let s = str::from_utf8(&lexeme).expect("Invalid UTF8");
         Token {
             type_,
-            lexeme: s,
+            lexeme: Rc::from(s),
             literal,
             line,
+            column,
+            file: None,
         }
     }
     pub fn to_string(&self) -> String {
         format!(
-            "[Token] type: {:?}, lexeme: {}, literal: {:?}, line: {}",
-            self.type_, self.lexeme, self.literal, self.line
+            "[Token] type: {:?}, lexeme: {}, literal: {:?}, line: {}, column: {}",
+            self.type_, self.lexeme, self.literal, self.line, self.column
         )
     }
 }
@@ -80,14 +84,71 @@ impl Literal {
             Literal::IDENTIFIER(id) => id.to_owned(),
             Literal::STRING(st) => st.to_owned(), // format!("\"{}\"", st.to_owned()),
             Literal::NUMBER(num) => {
-                let mut text = format!("{}", num);
-                if text.ends_with(".0") {
-                    text = String::from(text.strip_suffix(".0").unwrap())
+                if num.is_nan() {
+                    String::from("nan")
+                } else if num.is_infinite() {
+                    String::from(if *num > 0.0 { "inf" } else { "-inf" })
+                } else if *num == 0.0 {
+                    // Covers -0.0 as well, which would otherwise print as "-0".
+                    String::from("0")
+                } else {
+                    // Display on f64 never uses exponents and prints the shortest
+                    // text that round-trips, with no ".0" on whole numbers.
+                    format!("{}", num)
                 }
-                text
             }
             Literal::BOOL(bl) => format!("{}", bl),
             Literal::NIL => String::from("nil"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn number_formatting() {
+        let cases = [
+            (0.0, "0"),
+            (-0.0, "0"),
+            (1.0, "1"),
+            (-3.0, "-3"),
+            (100.1, "100.1"),
+            (100.10, "100.1"),
+            (2.5, "2.5"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (1.0 / 3.0, "0.3333333333333333"),
+            (1e21, "1000000000000000000000"),
+            (123456789012.0, "123456789012"),
+            (1e-7, "0.0000001"),
+            (f64::NAN, "nan"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(Literal::NUMBER(value).to_string(), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn equal_literals_hash_alike() {
+        use std::collections::hash_map::DefaultHasher;
+        fn hash(literal: &Literal) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            literal.hash(&mut hasher);
+            hasher.finish()
+        }
+        let pairs = [
+            (Literal::NUMBER(0.0), Literal::NUMBER(-0.0)),
+            (Literal::NUMBER(1.5), Literal::NUMBER(1.5)),
+            (Literal::STRING("a".into()), Literal::STRING("a".into())),
+            (Literal::NIL, Literal::NIL),
+        ];
+        for (a, b) in pairs {
+            assert_eq!(a, b);
+            assert_eq!(hash(&a), hash(&b), "{:?}", a);
+        }
+        assert_ne!(Literal::NUMBER(f64::NAN), Literal::NUMBER(f64::NAN));
+    }
+}
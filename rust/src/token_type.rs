@@ -7,13 +7,19 @@ pub enum TokenType {
     RIGHT_PAREN,
     LEFT_BRACE,
     RIGHT_BRACE,
+    LEFT_BRACKET,
+    RIGHT_BRACKET,
     COMMA,
     DOT,
     MINUS,
+    PERCENT,
     PLUS,
     SEMICOLON,
     SLASH,
     STAR,
+    AMPERSAND,
+    PIPE,
+    CARET,
 
     // One or two character tokens.
     BANG,
@@ -22,29 +28,42 @@ pub enum TokenType {
     EQUAL_EQUAL,
     GREATER,
     GREATER_EQUAL,
+    GREATER_GREATER,
     LESS,
     LESS_EQUAL,
+    LESS_LESS,
+    MINUS_MINUS,
+    PLUS_PLUS,
 
     // Literals.
     IDENTIFIER,
     STRING,
+    // The text of an interpolated string up to a `${`.
+    STRING_PART,
     NUMBER,
 
     // Keywords.
     AND,
+    BREAK,
+    CATCH,
     CLASS,
+    CONST,
+    CONTINUE,
     ELSE,
     FALSE,
     FUN,
     FOR,
     IF,
+    IMPORT,
     NIL,
     OR,
     PRINT,
     RETURN,
     SUPER,
     THIS,
+    THROW,
     TRUE,
+    TRY,
     VAR,
     WHILE,
 
@@ -0,0 +1,121 @@
+use crate::interpreter::ExprValue;
+use crate::token::Literal;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::rc::Rc;
+
+// How a value prints. At the top level a string is just its text, as
+// `print` and `str()` have always shown it; `nested` quotes strings, which is
+// how they appear inside lists and maps so that `["a", 1]` can't be mistaken
+// for `[a, 1]`.
+pub fn display(value: &ExprValue, nested: bool) -> String {
+    let mut out = String::new();
+    write_value(&mut out, value, nested, &mut HashSet::new());
+    out
+}
+
+// `open` holds the lists and maps being printed around this value, so a
+// container that contains itself prints as `[...]` or `{...}` there instead
+// of recursing forever. The same container can still appear twice side by
+// side.
+fn write_value(out: &mut String, value: &ExprValue, nested: bool, open: &mut HashSet<*const ()>) {
+    match value {
+        ExprValue::Literal(literal) => write_literal(out, literal, nested),
+        ExprValue::LoxCallable(function) => out.push_str(&function.to_string()),
+        ExprValue::List(list) => {
+            let id = list.as_ptr() as *const ();
+            if !open.insert(id) {
+                out.push_str("[...]");
+                return;
+            }
+            out.push('[');
+            for (i, element) in list.borrow().iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_value(out, element, true, open);
+            }
+            out.push(']');
+            open.remove(&id);
+        }
+        ExprValue::Map(map) => {
+            let id = map.as_ptr() as *const ();
+            if !open.insert(id) {
+                out.push_str("{...}");
+                return;
+            }
+            // Sorted so that printing a map is deterministic.
+            let mut entries: Vec<String> = map
+                .borrow()
+                .iter()
+                .map(|(key, value)| {
+                    let mut entry = String::new();
+                    write_literal(&mut entry, key, true);
+                    entry.push_str(": ");
+                    write_value(&mut entry, value, true, open);
+                    entry
+                })
+                .collect();
+            entries.sort();
+            write!(out, "{{{}}}", entries.join(", ")).unwrap();
+            open.remove(&id);
+        }
+    }
+}
+
+// `==` on values. Lists compare element by element, and everything else
+// that isn't a literal compares by identity.
+pub fn equal(left: &ExprValue, right: &ExprValue) -> bool {
+    equal_in(left, right, &mut HashSet::new())
+}
+
+// `open` holds the pairs of lists being compared around these values. A pair
+// met again inside itself is taken to be equal, since nothing found so far
+// tells them apart, so lists that contain themselves compare without
+// recursing forever.
+fn equal_in(
+    left: &ExprValue,
+    right: &ExprValue,
+    open: &mut HashSet<(*const (), *const ())>,
+) -> bool {
+    match (left, right) {
+        (ExprValue::Literal(l1), ExprValue::Literal(l2)) => l1 == l2,
+        (ExprValue::LoxCallable(c1), ExprValue::LoxCallable(c2)) => Rc::ptr_eq(c1, c2),
+        (ExprValue::List(l1), ExprValue::List(l2)) => {
+            if Rc::ptr_eq(l1, l2) {
+                return true;
+            }
+            let pair = (l1.as_ptr() as *const (), l2.as_ptr() as *const ());
+            if !open.insert(pair) {
+                return true;
+            }
+            let (l1, l2) = (l1.borrow(), l2.borrow());
+            let equal =
+                l1.len() == l2.len() && l1.iter().zip(l2.iter()).all(|(a, b)| equal_in(a, b, open));
+            open.remove(&pair);
+            equal
+        }
+        (ExprValue::Map(m1), ExprValue::Map(m2)) => Rc::ptr_eq(m1, m2),
+        _ => false,
+    }
+}
+
+// What kind of value this is, as error messages name it.
+pub fn type_name(value: &ExprValue) -> &'static str {
+    match value {
+        ExprValue::Literal(Literal::NIL) => "nil",
+        ExprValue::Literal(Literal::BOOL(_)) => "boolean",
+        ExprValue::Literal(Literal::NUMBER(_)) => "number",
+        ExprValue::Literal(Literal::STRING(_) | Literal::IDENTIFIER(_)) => "string",
+        ExprValue::LoxCallable(_) => "function",
+        ExprValue::List(_) => "list",
+        ExprValue::Map(_) => "map",
+    }
+}
+
+fn write_literal(out: &mut String, literal: &Literal, nested: bool) {
+    match literal {
+        Literal::STRING(s) if nested => write!(out, "\"{}\"", s).unwrap(),
+        _ => out.push_str(&literal.to_string()),
+    }
+}
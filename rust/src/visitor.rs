@@ -0,0 +1,261 @@
+//! Visitors over the syntax tree, for passes that would rather have one
+//! method per kind of node than a match over every variant. `walk_expr` and
+//! `walk_stmt` look a node up in its arena and call the method for its
+//! variant with its fields. Children come as ids, so a visitor recurses by
+//! walking the ones it cares about.
+//!
+//! Counting the expressions in a program:
+//!
+//! ```
+//! use crafting_interpreters::*;
+//!
+//! struct Count<'a>(&'a AstArena);
+//!
+//! impl Count<'_> {
+//!     fn walk(&mut self, expr: ExprId) -> usize {
+//!         let ast = self.0;
+//!         walk_expr(self, ast, expr)
+//!     }
+//!     fn all(&mut self, exprs: &[ExprId]) -> usize {
+//!         exprs.iter().map(|&e| self.walk(e)).sum()
+//!     }
+//! }
+//!
+//! impl ExprVisitor<usize> for Count<'_> {
+//!     fn visit_assign(&mut self, _: ExprId, _: &RcToken, value: ExprId) -> usize {
+//!         1 + self.walk(value)
+//!     }
+//!     fn visit_binary(&mut self, left: ExprId, _: &RcToken, right: ExprId) -> usize {
+//!         1 + self.walk(left) + self.walk(right)
+//!     }
+//!     fn visit_call(&mut self, callee: ExprId, _: &RcToken, arguments: &[ExprId]) -> usize {
+//!         1 + self.walk(callee) + self.all(arguments)
+//!     }
+//!     fn visit_get(&mut self, object: ExprId, _: &RcToken) -> usize {
+//!         1 + self.walk(object)
+//!     }
+//!     fn visit_grouping(&mut self, expression: ExprId) -> usize {
+//!         1 + self.walk(expression)
+//!     }
+//!     fn visit_index(&mut self, object: ExprId, _: &RcToken, index: ExprId) -> usize {
+//!         1 + self.walk(object) + self.walk(index)
+//!     }
+//!     fn visit_index_assign(
+//!         &mut self,
+//!         object: ExprId,
+//!         _: &RcToken,
+//!         index: ExprId,
+//!         value: ExprId,
+//!     ) -> usize {
+//!         1 + self.all(&[object, index, value])
+//!     }
+//!     fn visit_interpolation(&mut self, parts: &[ExprId]) -> usize {
+//!         1 + self.all(parts)
+//!     }
+//!     fn visit_list_literal(&mut self, elements: &[ExprId]) -> usize {
+//!         1 + self.all(elements)
+//!     }
+//!     fn visit_literal(&mut self, _: &Literal) -> usize {
+//!         1
+//!     }
+//!     fn visit_logical(&mut self, left: ExprId, _: &RcToken, right: ExprId) -> usize {
+//!         1 + self.walk(left) + self.walk(right)
+//!     }
+//!     fn visit_postfix(&mut self, _: &RcToken, target: ExprId, _: ExprId) -> usize {
+//!         1 + self.walk(target)
+//!     }
+//!     fn visit_unary(&mut self, _: &RcToken, right: ExprId) -> usize {
+//!         1 + self.walk(right)
+//!     }
+//!     fn visit_variable(&mut self, _: ExprId, _: &RcToken) -> usize {
+//!         1
+//!     }
+//! }
+//!
+//! let mut scanner = Scanner::new(b"print -(1 + x) * f(2);".to_vec());
+//! scanner.scan_tokens().unwrap();
+//! let mut ast = AstArena::new();
+//! let statements = Parser::new(&mut Lox::new(), scanner.tokens, &mut ast).parse();
+//! let count = match &ast[statements[0]] {
+//!     Stmt::Print { expr, .. } => Count(&ast).walk(*expr),
+//!     _ => unreachable!(),
+//! };
+//! // *, -, (), +, 1, x, f(2), f, 2
+//! assert_eq!(count, 9);
+//! ```
+
+use crate::arena::AstArena;
+use crate::expr::{Expr, ExprId};
+use crate::stmt::{Parameter, Stmt, StmtId};
+use crate::token::{Literal, RcToken};
+
+// `visit_assign` and `visit_variable` also get the node's own id, since the
+// resolver records where each variable lives keyed by it.
+pub trait ExprVisitor<T> {
+    fn visit_assign(&mut self, expr: ExprId, name: &RcToken, value: ExprId) -> T;
+    fn visit_binary(&mut self, left: ExprId, operator: &RcToken, right: ExprId) -> T;
+    fn visit_call(&mut self, callee: ExprId, paren: &RcToken, arguments: &[ExprId]) -> T;
+    fn visit_get(&mut self, object: ExprId, name: &RcToken) -> T;
+    fn visit_grouping(&mut self, expression: ExprId) -> T;
+    fn visit_index(&mut self, object: ExprId, bracket: &RcToken, index: ExprId) -> T;
+    fn visit_index_assign(
+        &mut self,
+        object: ExprId,
+        bracket: &RcToken,
+        index: ExprId,
+        value: ExprId,
+    ) -> T;
+    fn visit_interpolation(&mut self, parts: &[ExprId]) -> T;
+    fn visit_list_literal(&mut self, elements: &[ExprId]) -> T;
+    fn visit_literal(&mut self, value: &Literal) -> T;
+    fn visit_logical(&mut self, left: ExprId, operator: &RcToken, right: ExprId) -> T;
+    fn visit_postfix(&mut self, operator: &RcToken, target: ExprId, update: ExprId) -> T;
+    fn visit_unary(&mut self, operator: &RcToken, right: ExprId) -> T;
+    fn visit_variable(&mut self, expr: ExprId, name: &RcToken) -> T;
+}
+
+pub trait StmtVisitor<T> {
+    fn visit_block(&mut self, statements: &[StmtId]) -> T;
+    fn visit_break(&mut self, keyword: &RcToken) -> T;
+    fn visit_continue(&mut self, keyword: &RcToken) -> T;
+    fn visit_expression(&mut self, expr: ExprId) -> T;
+    fn visit_for(
+        &mut self,
+        initializer: Option<StmtId>,
+        condition: Option<ExprId>,
+        increment: Option<ExprId>,
+        body: StmtId,
+    ) -> T;
+    fn visit_for_each(
+        &mut self,
+        variable: &RcToken,
+        keyword: &RcToken,
+        iterable: ExprId,
+        body: StmtId,
+    ) -> T;
+    fn visit_function(&mut self, name: &RcToken, params: &[Parameter], body: &[StmtId]) -> T;
+    fn visit_if(
+        &mut self,
+        condition: ExprId,
+        then_branch: StmtId,
+        else_branch: Option<StmtId>,
+    ) -> T;
+    fn visit_import(&mut self, keyword: &RcToken, path: &RcToken) -> T;
+    fn visit_print(&mut self, expr: ExprId) -> T;
+    fn visit_return(&mut self, keyword: &RcToken, value: ExprId) -> T;
+    fn visit_throw(&mut self, keyword: &RcToken, value: ExprId) -> T;
+    fn visit_try(&mut self, body: &[StmtId], binding: &RcToken, handler: &[StmtId]) -> T;
+    fn visit_var(&mut self, name: &RcToken, initializer: Option<ExprId>, constant: bool) -> T;
+    fn visit_while(&mut self, condition: ExprId, body: StmtId) -> T;
+}
+
+pub fn walk_expr<T, V: ExprVisitor<T> + ?Sized>(
+    visitor: &mut V,
+    ast: &AstArena,
+    expr: ExprId,
+) -> T {
+    match &ast[expr] {
+        Expr::Assign { name, value, .. } => visitor.visit_assign(expr, name, *value),
+        Expr::Binary {
+            left,
+            operator,
+            right,
+            ..
+        } => visitor.visit_binary(*left, operator, *right),
+        Expr::Call {
+            callee,
+            paren,
+            arguments,
+            ..
+        } => visitor.visit_call(*callee, paren, arguments),
+        Expr::Get { object, name, .. } => visitor.visit_get(*object, name),
+        Expr::Grouping { expression, .. } => visitor.visit_grouping(*expression),
+        Expr::Index {
+            object,
+            bracket,
+            index,
+            ..
+        } => visitor.visit_index(*object, bracket, *index),
+        Expr::IndexAssign {
+            object,
+            bracket,
+            index,
+            value,
+            ..
+        } => visitor.visit_index_assign(*object, bracket, *index, *value),
+        Expr::Interpolation { parts, .. } => visitor.visit_interpolation(parts),
+        Expr::ListLiteral { elements, .. } => visitor.visit_list_literal(elements),
+        Expr::Literal { value, .. } => visitor.visit_literal(value),
+        Expr::Logical {
+            left,
+            operator,
+            right,
+            ..
+        } => visitor.visit_logical(*left, operator, *right),
+        Expr::Postfix {
+            operator,
+            target,
+            update,
+            ..
+        } => visitor.visit_postfix(operator, *target, *update),
+        Expr::Unary {
+            operator, right, ..
+        } => visitor.visit_unary(operator, *right),
+        Expr::Variable { name, .. } => visitor.visit_variable(expr, name),
+    }
+}
+
+pub fn walk_stmt<T, V: StmtVisitor<T> + ?Sized>(
+    visitor: &mut V,
+    ast: &AstArena,
+    stmt: StmtId,
+) -> T {
+    match &ast[stmt] {
+        Stmt::Block { statements, .. } => visitor.visit_block(statements),
+        Stmt::Break { keyword, .. } => visitor.visit_break(keyword),
+        Stmt::Continue { keyword, .. } => visitor.visit_continue(keyword),
+        Stmt::Expression { expr, .. } => visitor.visit_expression(*expr),
+        Stmt::For {
+            initializer,
+            condition,
+            increment,
+            body,
+            ..
+        } => visitor.visit_for(*initializer, *condition, *increment, *body),
+        Stmt::ForEach {
+            variable,
+            keyword,
+            iterable,
+            body,
+            ..
+        } => visitor.visit_for_each(variable, keyword, *iterable, *body),
+        Stmt::Function {
+            name, params, body, ..
+        } => visitor.visit_function(name, params, body),
+        Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+            ..
+        } => visitor.visit_if(*condition, *then_branch, *else_branch),
+        Stmt::Import { keyword, path, .. } => visitor.visit_import(keyword, path),
+        Stmt::Print { expr, .. } => visitor.visit_print(*expr),
+        Stmt::Return { keyword, value, .. } => visitor.visit_return(keyword, *value),
+        Stmt::Throw { keyword, value, .. } => visitor.visit_throw(keyword, *value),
+        Stmt::Try {
+            body,
+            binding,
+            handler,
+            ..
+        } => visitor.visit_try(body, binding, handler),
+        Stmt::Var {
+            name,
+            initializer,
+            constant,
+            ..
+        } => visitor.visit_var(name, *initializer, *constant),
+        Stmt::While {
+            condition, body, ..
+        } => visitor.visit_while(*condition, *body),
+    }
+}
@@ -0,0 +1,264 @@
+// Runs the bytecode compiler.rs makes, for `--vm`: a stack of values and a
+// frame per call in progress, as in clox. Globals, natives and `print` go
+// through the tree-walk interpreter, which the VM borrows for the run.
+// Tail calls still take a frame each, so deep tail recursion overflows, and
+// --trace, --debug and step limits don't apply to bytecode yet.
+
+use crate::compiler::{Chunk, OpCode, VmFunction};
+use crate::interpreter::{not_callable, ExprValue, Interpreter, Method, VoidResult};
+use crate::lox::LoxError;
+use crate::span::Span;
+use crate::token::{Literal, RcToken};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+struct CallFrame {
+    chunk: Rc<Chunk>,
+    ip: usize,
+    // Where the frame's slots start on the stack; slot 0 is the callee.
+    base: usize,
+}
+
+pub struct Vm<'a> {
+    interpreter: &'a mut Interpreter,
+    stack: Vec<Rc<ExprValue>>,
+    // The callers of the running function, innermost last.
+    frames: Vec<CallFrame>,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(interpreter: &'a mut Interpreter) -> Self {
+        Vm {
+            interpreter,
+            stack: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn run(&mut self, script: &VmFunction) -> VoidResult {
+        self.stack.push(self.interpreter.nil());
+        let mut frame = CallFrame {
+            chunk: Rc::clone(&script.chunk),
+            ip: 0,
+            base: 0,
+        };
+        loop {
+            let chunk = Rc::clone(&frame.chunk);
+            let start = frame.ip;
+            let op = OpCode::from_byte(chunk.read_byte(start));
+            frame.ip += 1;
+            // Where a failing instruction blames its errors.
+            let origin = || -> &(RcToken, Span) { &chunk.origins[&start] };
+            let at = |error: LoxError| error.with_span(origin().1);
+            match op {
+                OpCode::Constant => {
+                    let index = self.read_short(&mut frame);
+                    self.stack.push(Rc::clone(&chunk.constants[index]));
+                }
+                OpCode::Nil => self.stack.push(self.interpreter.nil()),
+                OpCode::True => self.stack.push(self.interpreter.boolean(true)),
+                OpCode::False => self.stack.push(self.interpreter.boolean(false)),
+                OpCode::Pop => {
+                    self.stack.pop();
+                }
+                OpCode::GetLocal => {
+                    let slot = self.read_byte(&mut frame);
+                    let value = Rc::clone(&self.stack[frame.base + slot]);
+                    self.stack.push(value);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.read_byte(&mut frame);
+                    self.stack[frame.base + slot] = Rc::clone(self.peek());
+                }
+                OpCode::GetGlobal => {
+                    frame.ip += 2;
+                    let value = self.interpreter.globals.borrow().get(&origin().0);
+                    self.stack.push(value.map_err(at)?);
+                }
+                OpCode::DefineGlobal | OpCode::DefineConstant => {
+                    let name = chunk.name(self.read_short(&mut frame) as u16);
+                    let value = self.stack.pop();
+                    let mut globals = self.interpreter.globals.borrow_mut();
+                    match op {
+                        OpCode::DefineConstant => globals.define_constant(name, value),
+                        _ => globals.define(name, value),
+                    }
+                }
+                OpCode::SetGlobal => {
+                    frame.ip += 2;
+                    let value = Some(Rc::clone(self.peek()));
+                    let assigned = self
+                        .interpreter
+                        .globals
+                        .borrow_mut()
+                        .assign(&origin().0, value);
+                    assigned.map_err(at)?;
+                }
+                OpCode::GetProperty => {
+                    frame.ip += 2;
+                    let object = self.stack.pop().unwrap();
+                    self.stack
+                        .push(Method::get(object, &origin().0).map_err(at)?);
+                }
+                OpCode::Index => {
+                    let index = self.stack.pop().unwrap();
+                    let object = self.stack.pop().unwrap();
+                    let value = Interpreter::get_index(&object, &index, &origin().0);
+                    self.stack.push(value.map_err(at)?);
+                }
+                OpCode::IndexSet => {
+                    let value = self.stack.pop().unwrap();
+                    let index = self.stack.pop().unwrap();
+                    let object = self.stack.pop().unwrap();
+                    Interpreter::set_index(&object, &index, Rc::clone(&value), &origin().0)
+                        .map_err(at)?;
+                    self.stack.push(value);
+                }
+                OpCode::Equal
+                | OpCode::NotEqual
+                | OpCode::Greater
+                | OpCode::GreaterEqual
+                | OpCode::Less
+                | OpCode::LessEqual
+                | OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::Modulo
+                | OpCode::BitAnd
+                | OpCode::BitOr
+                | OpCode::BitXor
+                | OpCode::ShiftLeft
+                | OpCode::ShiftRight => {
+                    // The operator's token says which operation it is.
+                    let right = self.stack.pop().unwrap();
+                    let left = self.stack.pop().unwrap();
+                    let value = self.interpreter.binary(&origin().0, left, right);
+                    self.stack.push(value.map_err(at)?);
+                }
+                OpCode::Not => {
+                    let value = self.stack.pop().unwrap();
+                    let value = self.interpreter.boolean(!Interpreter::is_truthy(&value));
+                    self.stack.push(value);
+                }
+                OpCode::Negate => {
+                    let value = self.stack.pop().unwrap();
+                    let value = self.interpreter.unary(&origin().0, value);
+                    self.stack.push(value.map_err(at)?);
+                }
+                OpCode::List => {
+                    let count = self.read_short(&mut frame);
+                    let elements = self.stack.split_off(self.stack.len() - count);
+                    let list = ExprValue::List(Rc::new(RefCell::new(elements)));
+                    self.stack.push(Rc::from(list));
+                }
+                OpCode::Interpolate => {
+                    let count = self.read_short(&mut frame);
+                    let parts = self.stack.split_off(self.stack.len() - count);
+                    let text: String = parts.into_iter().map(Interpreter::stringify).collect();
+                    self.stack
+                        .push(Rc::from(ExprValue::Literal(Literal::STRING(text))));
+                }
+                OpCode::Print => {
+                    let value = self.stack.pop().unwrap();
+                    self.interpreter.print(value);
+                }
+                OpCode::Jump => {
+                    let jump = self.read_short(&mut frame);
+                    frame.ip += jump;
+                }
+                OpCode::JumpIfFalse => {
+                    let jump = self.read_short(&mut frame);
+                    if !Interpreter::is_truthy(self.peek()) {
+                        frame.ip += jump;
+                    }
+                }
+                OpCode::Loop => {
+                    let jump = self.read_short(&mut frame);
+                    frame.ip -= jump;
+                }
+                OpCode::Call => {
+                    let argc = self.read_byte(&mut frame);
+                    let printed = &chunk.callees[&start];
+                    if let Some(callee) = self.call(argc, origin(), printed).map_err(at)? {
+                        self.frames.push(std::mem::replace(&mut frame, callee));
+                    }
+                }
+                OpCode::Return => {
+                    let value = self.stack.pop().unwrap();
+                    self.stack.truncate(frame.base);
+                    match self.frames.pop() {
+                        Some(caller) => {
+                            frame = caller;
+                            self.stack.push(value);
+                        }
+                        None => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+
+    fn read_byte(&self, frame: &mut CallFrame) -> usize {
+        let byte = frame.chunk.read_byte(frame.ip);
+        frame.ip += 1;
+        byte as usize
+    }
+    fn read_short(&self, frame: &mut CallFrame) -> usize {
+        let short = frame.chunk.read_short(frame.ip);
+        frame.ip += 2;
+        short as usize
+    }
+    fn peek(&self) -> &Rc<ExprValue> {
+        self.stack.last().unwrap()
+    }
+
+    // Calls the callee below the top `argc` values, which `printed` is the
+    // expression for. A compiled function gets a frame to run in, which is
+    // returned; anything else is called right away and leaves its result in
+    // the callee's place.
+    fn call(
+        &mut self,
+        argc: usize,
+        (paren, _): &(RcToken, Span),
+        printed: &str,
+    ) -> Result<Option<CallFrame>, LoxError> {
+        let base = self.stack.len() - argc - 1;
+        let function = match &*self.stack[base] {
+            ExprValue::LoxCallable(function) => Rc::clone(function),
+            callee => {
+                return Err(LoxError::RuntimeError {
+                    token: Rc::clone(paren),
+                    message: not_callable(callee, printed),
+                    span: None,
+                })
+            }
+        };
+        if let Err(message) = function.arity().check(argc) {
+            return Err(LoxError::RuntimeError {
+                token: Rc::clone(paren),
+                message,
+                span: None,
+            });
+        }
+        if let Some(compiled) = function.as_compiled() {
+            if self.frames.len() >= self.interpreter.max_call_depth {
+                return Err(LoxError::RuntimeError {
+                    token: Rc::clone(paren),
+                    message: String::from("Stack overflow."),
+                    span: None,
+                });
+            }
+            return Ok(Some(CallFrame {
+                chunk: Rc::clone(&compiled.chunk),
+                ip: 0,
+                base,
+            }));
+        }
+        let arguments = self.stack.split_off(base + 1);
+        self.stack.pop();
+        let value = self.interpreter.call(&function, paren, arguments)?;
+        self.stack.push(value);
+        Ok(None)
+    }
+}
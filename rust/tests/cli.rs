@@ -0,0 +1,459 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn jlox() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_crafting_interpreters"))
+}
+
+// Writes `source` to a temporary script and runs jlox on it with `flags`.
+fn run_script(name: &str, source: &str, flags: &[&str]) -> Output {
+    let path = env::temp_dir().join(name);
+    fs::write(&path, source).unwrap();
+    let output = jlox().args(flags).arg(&path).output().unwrap();
+    fs::remove_file(&path).unwrap();
+    output
+}
+
+// Runs `jlox -` with `input` piped to stdin.
+fn run_stdin(input: &[u8]) -> Output {
+    let mut child = jlox()
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn usage_error_exits_64() {
+    let status = jlox().args(["-e", "1;", "a.lox"]).output().unwrap().status;
+    assert_eq!(status.code(), Some(64));
+    let status = jlox().args(["--bogus", "a.lox"]).output().unwrap().status;
+    assert_eq!(status.code(), Some(64));
+}
+
+#[test]
+fn compile_error_exits_65() {
+    let output = run_script("lox_cli_compile_error.lox", "print ;", &[]);
+    assert_eq!(output.status.code(), Some(65));
+    // Errors in a function declared in a loop leave the loop's own
+    // bookkeeping intact.
+    for source in [
+        "while (true) { fun f() { var a = 1; var a = 2; } }",
+        "while (true) { fun f() { break; } }",
+        "for (;;) { fun f() { break; } }",
+    ] {
+        let output = jlox().args(["-e", source]).output().unwrap();
+        assert_eq!(output.status.code(), Some(65), "{}", source);
+    }
+}
+
+#[test]
+fn runtime_error_exits_70_with_line() {
+    let output = run_script("lox_cli_runtime_error.lox", "var a = 1;\n1 + \"a\";\n", &[]);
+    assert_eq!(output.status.code(), Some(70));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.ends_with("lox_cli_runtime_error.lox:2\n2 | 1 + \"a\";\n  | ^^^^^^^\n"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn arguments_after_the_script_are_its_own() {
+    let path = env::temp_dir().join("lox_cli_args.lox");
+    fs::write(&path, "print args();\n").unwrap();
+    let output = jlox()
+        .arg("--strict")
+        .arg(&path)
+        .args(["foo", "--bench", "-e"])
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[\"foo\", \"--bench\", \"-e\"]\n"
+    );
+}
+
+#[test]
+fn exit_native_sets_status() {
+    let output = run_script("lox_cli_exit.lox", "print 1;\nexit(42);\nprint 2;\n", &[]);
+    assert_eq!(output.status.code(), Some(42));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn unbounded_recursion_exits_70() {
+    let source = "fun f() { f(); }\nf();\n";
+    let output = run_script("lox_cli_recursion.lox", source, &[]);
+    assert_eq!(output.status.code(), Some(70));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let path = env::temp_dir().join("lox_cli_recursion.lox");
+    assert_eq!(
+        stderr,
+        format!(
+            "Stack overflow.\n{}:1\n1 | fun f() {{ f(); }}\n  |           ^^^\n",
+            path.display()
+        )
+    );
+}
+
+#[test]
+fn tokens_and_ast_dumps_do_not_run() {
+    let source = "print 1 + 2;";
+    let output = run_script("lox_cli_tokens.lox", source, &["--tokens"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 6);
+    assert!(stdout.starts_with("[Token] type: PRINT, lexeme: print"));
+
+    let output = run_script("lox_cli_ast.lox", source, &["--ast", "--no-fold"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "(print (+ 1 2))\n"
+    );
+
+    // The dump shows the tree after constant folding.
+    let output = run_script("lox_cli_ast_fold.lox", "2*3+4;", &["--ast"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "10\n");
+
+    let output = run_script("lox_cli_ast_error.lox", "print ;", &["--ast"]);
+    assert_eq!(output.status.code(), Some(65));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn ast_dump_shows_resolved_depths() {
+    // The shadowing program from the chapter on resolving: both calls see
+    // the global `a`, however the block's own `a` comes between them.
+    let source = "var a = \"global\";
+{
+  fun showA() {
+    print a;
+  }
+
+  showA();
+  var a = \"block\";
+  showA();
+}
+fun counter() {
+  var n = 0;
+  fun inc() { n = n + 1; return n; }
+  return inc;
+}
+";
+    let output = run_script("lox_cli_ast_depths.lox", source, &["--ast"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "(var a global)\n\
+         (block (fun showA () (block (print a@global))) (call showA@0) (var a block) (call showA@0))\n\
+         (fun counter () (block (var n 0) (fun inc () (block (= n@1 (+ n@1 1)) (return n@1))) (return inc@0)))\n"
+    );
+
+    // Nothing prints for a tree that doesn't resolve.
+    let output = run_script("lox_cli_ast_unresolved.lox", "{ var a = a; }", &["--ast"]);
+    assert_eq!(output.status.code(), Some(65));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn eval_flag_runs_source() {
+    let output = jlox().args(["-e", "print 1 + 2;"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\n");
+
+    let output = jlox()
+        .args(["--ast", "--eval", "print 1;"])
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "(print 1)\n");
+
+    let code = |args: &[&str]| jlox().args(args).output().unwrap().status.code();
+    assert_eq!(code(&["-e", "print ;"]), Some(65));
+    assert_eq!(code(&["-e", "nil + 1;"]), Some(70));
+    assert_eq!(code(&["-e"]), Some(64));
+    assert_eq!(code(&["-e", "1;", "script.lox"]), Some(64));
+}
+
+#[test]
+fn dash_reads_script_from_stdin() {
+    let output = run_stdin(b"print 1+1;\nprint \"piped\";\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "2\npiped\n");
+
+    let output = run_stdin(b"");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stdout.is_empty());
+
+    let output = run_stdin(b"print 1;\nprint \"\xff\";\n");
+    assert_eq!(output.status.code(), Some(65));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with("<stdin>:2: Error: Source is not valid UTF-8."),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn bench_reports_elapsed_time_on_stderr() {
+    let output = run_script("lox_cli_bench.lox", "print 1 + 2;\n", &["--bench"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with("Elapsed: ") && stderr.ends_with(" ms\n"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn trace_goes_to_stderr() {
+    let output = run_script(
+        "lox_cli_trace.lox",
+        "var a = 1;\nprint a + 1;\n",
+        &["--trace"],
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "2\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[line 1] exec: (var a 1)\n[line 2] exec: (print (+ a 1))\n"
+    );
+}
+
+#[test]
+fn no_fs_leaves_out_file_natives() {
+    let output = jlox()
+        .args(["--no-fs", "-e", "readFile(\"Cargo.toml\");"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(70));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with("Undefined variable 'readFile'."),
+        "{}",
+        stderr
+    );
+
+    let output = jlox()
+        .args(["-e", "print len(readFile(\"Cargo.toml\")) > 0;"])
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "true\n");
+}
+
+#[test]
+fn resolver_is_quiet_unless_asked() {
+    let source = "var a = 1;\nfun f(x) {\n  var y = x;\n  { print y + a; }\n}\nf(2);\n";
+    let output = run_script("lox_cli_resolver_quiet.lox", source, &[]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\n");
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+
+    let output = run_script("lox_cli_resolver_debug.lox", source, &["--debug-resolver"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "name              line  depth slot\n\
+         x                    3      0    0\n\
+         y                    4      1    1\n\
+         a                    4 global    -\n\
+         f                    6 global    -\n"
+    );
+}
+
+#[test]
+fn profile_reports_calls_on_stderr() {
+    let source = "fun fib(n) {\n  if (n < 2) return n;\n  return fib(n - 1) + fib(n - 2);\n}\nprint fib(15);\n";
+    let output = run_script("lox_cli_profile.lox", source, &["--profile"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "610\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("function "), "{}", stderr);
+    assert!(
+        stderr.contains("\nfib                  1     1973 "),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn vm_runs_and_dumps_bytecode() {
+    let source = "fun add(a, b) { return a + b; }\nprint add(1, 2);\n";
+    let output = run_script("lox_cli_vm.lox", source, &["--vm"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\n");
+
+    let output = run_script("lox_cli_vm_dump.lox", "print 1 + 2;", &["--vm", "--dump"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "== <script> ==\n\
+         0000    1 OP_CONSTANT           0 '3'\n\
+         0003    | OP_PRINT\n\
+         0004    | OP_NIL\n\
+         0005    | OP_RETURN\n"
+    );
+
+    let status = jlox().args(["--dump", "a.lox"]).output().unwrap().status;
+    assert_eq!(status.code(), Some(64));
+}
+
+// Parses a flat JSON object of strings, numbers and nulls, as --json-errors
+// writes them, into its fields with the strings unescaped.
+fn json_fields(line: &str) -> Vec<(String, String)> {
+    let mut chars = line.trim().chars().peekable();
+    let string = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        assert_eq!(chars.next(), Some('"'), "{}", line);
+        let mut text = String::new();
+        loop {
+            match chars.next().unwrap() {
+                '"' => return text,
+                '\\' => match chars.next().unwrap() {
+                    'n' => text.push('\n'),
+                    't' => text.push('\t'),
+                    'r' => text.push('\r'),
+                    'u' => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        text.push(char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap());
+                    }
+                    c => text.push(c),
+                },
+                c => text.push(c),
+            }
+        }
+    };
+    assert_eq!(chars.next(), Some('{'), "{}", line);
+    let mut fields = Vec::new();
+    loop {
+        let key = string(&mut chars);
+        assert_eq!(chars.next(), Some(':'), "{}", line);
+        let value = if chars.peek() == Some(&'"') {
+            string(&mut chars)
+        } else {
+            let mut raw = String::new();
+            while let Some(&c) = chars.peek() {
+                if c == ',' || c == '}' {
+                    break;
+                }
+                raw.push(chars.next().unwrap());
+            }
+            raw
+        };
+        fields.push((key, value));
+        match chars.next() {
+            Some(',') => continue,
+            Some('}') => break,
+            other => panic!("{:?} in {}", other, line),
+        }
+    }
+    assert_eq!(chars.next(), None, "{}", line);
+    fields
+}
+
+#[test]
+fn json_errors_are_one_object_per_line() {
+    let diagnostics = |source: &str| -> (Option<i32>, Vec<Vec<(String, String)>>) {
+        let output = run_script("lox_cli_json.lox", source, &["--json-errors"]);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let lines = stderr.lines().map(json_fields).collect();
+        (output.status.code(), lines)
+    };
+    let fields = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        let path = std::env::temp_dir().join("lox_cli_json.lox");
+        let mut fields: Vec<(String, String)> = pairs
+            .iter()
+            .map(|&(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        fields.push((String::from("file"), path.display().to_string()));
+        fields
+    };
+
+    assert_eq!(
+        diagnostics("var a = 1;\nprint a @ 2;"),
+        (
+            Some(65),
+            vec![fields(&[
+                ("severity", "error"),
+                ("line", "2"),
+                ("col", "9"),
+                ("message", "Unexpected character '@'."),
+                ("phase", "scan"),
+            ])]
+        )
+    );
+    assert_eq!(
+        diagnostics("print 1;\nprint \"two\nlines\"\nprint 3;"),
+        (
+            Some(65),
+            vec![fields(&[
+                ("severity", "error"),
+                ("line", "4"),
+                ("col", "1"),
+                ("message", "Expect ';' after value."),
+                ("phase", "parse"),
+            ])]
+        )
+    );
+    assert_eq!(
+        diagnostics("var s = \"x\";\nprint 1 + s();"),
+        (
+            Some(70),
+            vec![fields(&[
+                ("severity", "error"),
+                ("line", "2"),
+                ("col", "11"),
+                (
+                    "message",
+                    "Can only call functions and classes, got string from expression 's'.",
+                ),
+                ("phase", "runtime"),
+            ])]
+        )
+    );
+    // Messages are escaped: this one has quotes and a newline in it.
+    assert_eq!(
+        diagnostics("throw \"say \" + chr(34) + \"hi\" + chr(34) + \"\n\";"),
+        (
+            Some(70),
+            vec![fields(&[
+                ("severity", "error"),
+                ("line", "1"),
+                ("col", "1"),
+                ("message", "say \"hi\"\n"),
+                ("phase", "runtime"),
+            ])]
+        )
+    );
+    assert_eq!(
+        diagnostics("if (false) print 1;"),
+        (
+            Some(0),
+            vec![fields(&[
+                ("severity", "warning"),
+                ("line", "1"),
+                ("col", "5"),
+                (
+                    "message",
+                    "Condition is always false; this branch never runs."
+                ),
+                ("phase", "resolve"),
+            ])]
+        )
+    );
+}
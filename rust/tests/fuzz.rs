@@ -0,0 +1,293 @@
+// Random inputs for the front end, small enough to run under `cargo test`.
+// Every case is generated from its own seed, so a failure names the input
+// and reruns the same way. Inputs that once broke something, or sit on an
+// edge worth keeping, live in `tests/fuzz_corpus` and are replayed too.
+
+use crafting_interpreters::{AstArena, Literal, Lox, LoxError, Parser, Scanner, Token, TokenType};
+use std::env;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// Cases per test; set FUZZ_CASES to search longer.
+const CASES: u64 = 3000;
+// Far longer than any case needs; reaching it means a loop that won't end.
+const TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_LEN: u64 = 60;
+// Cases run on a large stack, as the CLI runs programs, so that deeply
+// nested input reaches the parser's nesting limit instead of the stack's.
+const STACK_SIZE: usize = 64 * 1024 * 1024;
+
+// xorshift64, which is plenty for picking inputs.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len() as u64) as usize]
+    }
+}
+
+// Runs `check` on `CASES` generated inputs on a worker thread, failing with
+// the input if a case panics or hangs.
+fn fuzz<T: Debug + 'static>(generate: fn(&mut Rng) -> T, check: fn(&T)) {
+    let (sender, receiver) = mpsc::channel::<String>();
+    let worker = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let cases = env::var("FUZZ_CASES")
+                .ok()
+                .and_then(|cases| cases.parse().ok())
+                .unwrap_or(CASES);
+            for case in 0..cases {
+                let input = generate(&mut Rng::new(case));
+                let description = format!("case {}: {:?}", case, input);
+                sender.send(description.clone()).unwrap();
+                if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| check(&input))) {
+                    eprintln!("fuzz {} panicked", description);
+                    panic::resume_unwind(panic);
+                }
+            }
+        })
+        .unwrap();
+    let mut current = String::new();
+    loop {
+        match receiver.recv_timeout(TIMEOUT) {
+            Ok(description) => current = description,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => panic!("fuzz {} didn't finish", current),
+        }
+    }
+    if let Err(panic) = worker.join() {
+        panic::resume_unwind(panic);
+    }
+}
+
+// Pieces of Lox and of things that look like it, so random sequences get
+// past the scanner often enough to reach the parser and beyond.
+const FRAGMENTS: &[&str] = &[
+    " ", "\n", "(", ")", "{", "}", "[", "]", ",", ".", ";", "+", "-", "*", "/", "%", "!", "=",
+    "==", "<", ">>", "&", "|", "^", "++", "--", "\"", "\"a", "${", "//", "/*", "*/", "0", "1.5",
+    "9", "x", "f", "é", "var", "const", "fun", "return", "if", "else", "while", "for", "in",
+    "break", "continue", "print", "and", "or", "nil", "true", "try", "catch", "throw", "class",
+    "this", "f(", "x = ", "[1, 2]", "#", "\u{feff}", "\\", "\t", "\r",
+];
+
+fn soup(rng: &mut Rng) -> String {
+    let len = rng.below(MAX_LEN);
+    (0..len).map(|_| *rng.pick(FRAGMENTS)).collect()
+}
+
+fn bytes(rng: &mut Rng) -> Vec<u8> {
+    // Half the inputs are raw bytes, including invalid UTF-8.
+    if rng.below(2) == 0 {
+        let len = rng.below(MAX_LEN);
+        (0..len).map(|_| rng.next() as u8).collect()
+    } else {
+        soup(rng).into_bytes()
+    }
+}
+
+#[test]
+fn scanner_survives_random_bytes() {
+    fuzz(bytes, |input| {
+        let mut scanner = Scanner::new(input.clone());
+        match scanner.scan_tokens() {
+            Ok(()) => {
+                let last = scanner.tokens.last().expect("no EOF token");
+                assert_eq!(last.type_, TokenType::EOF);
+            }
+            Err(LoxError::ScanError {
+                line,
+                column,
+                message,
+                ..
+            }) => {
+                assert!(line >= 1 && column >= 1, "{}:{}", line, column);
+                assert!(!message.is_empty());
+            }
+            Err(other) => panic!("scanning gave {:?}", other),
+        }
+    });
+}
+
+// One token of each type that can appear before the EOF.
+fn token_table() -> Vec<Token> {
+    use TokenType::*;
+    let plain = [
+        LEFT_PAREN,
+        RIGHT_PAREN,
+        LEFT_BRACE,
+        RIGHT_BRACE,
+        LEFT_BRACKET,
+        RIGHT_BRACKET,
+        COMMA,
+        DOT,
+        MINUS,
+        PERCENT,
+        PLUS,
+        SEMICOLON,
+        SLASH,
+        STAR,
+        AMPERSAND,
+        PIPE,
+        CARET,
+        BANG,
+        BANG_EQUAL,
+        EQUAL,
+        EQUAL_EQUAL,
+        GREATER,
+        GREATER_EQUAL,
+        GREATER_GREATER,
+        LESS,
+        LESS_EQUAL,
+        LESS_LESS,
+        MINUS_MINUS,
+        PLUS_PLUS,
+        AND,
+        BREAK,
+        CATCH,
+        CLASS,
+        CONST,
+        CONTINUE,
+        ELSE,
+        FALSE,
+        FUN,
+        FOR,
+        IF,
+        IMPORT,
+        NIL,
+        OR,
+        PRINT,
+        RETURN,
+        SUPER,
+        THIS,
+        THROW,
+        TRUE,
+        TRY,
+        VAR,
+        WHILE,
+    ];
+    let mut tokens: Vec<Token> = plain
+        .into_iter()
+        .map(|type_| {
+            let lexeme = format!("{:?}", type_).to_lowercase().into_bytes();
+            Token::new(type_, lexeme, Literal::NIL, 1, 1)
+        })
+        .collect();
+    tokens.push(Token::new(IDENTIFIER, b"x".to_vec(), Literal::NIL, 1, 1));
+    tokens.push(Token::new(IDENTIFIER, b"in".to_vec(), Literal::NIL, 1, 1));
+    tokens.push(Token::new(
+        NUMBER,
+        b"1".to_vec(),
+        Literal::NUMBER(1.0),
+        1,
+        1,
+    ));
+    tokens.push(Token::new(
+        STRING,
+        b"\"s\"".to_vec(),
+        Literal::STRING(String::from("s")),
+        1,
+        1,
+    ));
+    tokens.push(Token::new(
+        STRING_PART,
+        b"\"s${".to_vec(),
+        Literal::STRING(String::from("s")),
+        1,
+        1,
+    ));
+    tokens
+}
+
+// Indices into `token_table()`; the tokens themselves aren't `Send`.
+fn token_indices(rng: &mut Rng) -> Vec<usize> {
+    let count = token_table().len() as u64;
+    let len = rng.below(MAX_LEN);
+    (0..len).map(|_| rng.below(count) as usize).collect()
+}
+
+#[test]
+fn parser_survives_random_tokens() {
+    fuzz(token_indices, |indices| {
+        let table = token_table();
+        let mut tokens: Vec<_> = indices.iter().map(|&i| Rc::new(table[i].clone())).collect();
+        // Half the sequences end without an EOF, which the parser supplies.
+        if indices.len() % 2 == 0 {
+            tokens.push(Rc::new(Token::new(
+                TokenType::EOF,
+                Vec::new(),
+                Literal::NIL,
+                1,
+                1,
+            )));
+        }
+        let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        lox.max_errors = 0;
+        let mut ast = AstArena::new();
+        let statements = Parser::new(&mut lox, tokens, &mut ast).parse();
+        assert!(statements.len() <= indices.len());
+    });
+}
+
+// Scans, parses, resolves and runs `source` in a sandbox, and checks that
+// every error can be shown to the user.
+fn run_sandboxed(source: &str) {
+    let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+    {
+        let mut interpreter = lox.interpreter.borrow_mut();
+        interpreter.disable_fs();
+        interpreter.set_input(Box::new(io::empty()));
+        interpreter.set_step_limit(Some(10_000));
+    }
+    if let Err(errors) = lox.run_source(source) {
+        for error in errors {
+            assert!(!error.message().is_empty(), "{:?}", error);
+            error.render(source);
+        }
+    }
+}
+
+#[test]
+fn front_end_survives_random_programs() {
+    fuzz(soup, |source| run_sandboxed(source));
+}
+
+#[test]
+fn corpus_regressions() {
+    let mut paths: Vec<_> = fs::read_dir("tests/fuzz_corpus")
+        .expect("missing corpus")
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+    for path in paths {
+        let bytes = fs::read(&path).unwrap();
+        let worker = thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                let source = String::from_utf8_lossy(&bytes).into_owned();
+                let _ = Scanner::new(bytes).scan_tokens();
+                run_sandboxed(&source);
+            })
+            .unwrap();
+        assert!(worker.join().is_ok(), "{} panicked", path.display());
+    }
+}
@@ -0,0 +1,462 @@
+use crafting_interpreters::{eval, ExitCode, ExprValue, Literal, Lox, LoxError};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+#[derive(Clone, Default)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Capture {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+// Runs `source` and returns what it printed and what it reported.
+fn run(source: &str) -> (String, String) {
+    let (out, err) = (Capture::default(), Capture::default());
+    let mut lox = Lox::with_output(Box::new(out.clone()), Box::new(err.clone()));
+    let _ = lox.run_source(source);
+    (out.text(), err.text())
+}
+
+fn number(source: &str) -> f64 {
+    match eval(source) {
+        Ok(ExprValue::Literal(Literal::NUMBER(n))) => n,
+        other => panic!("{} gave {:?}", source, other),
+    }
+}
+
+#[test]
+fn evaluates_programs() {
+    assert_eq!(number("1 + 2;"), 3.0);
+    assert_eq!(
+        number("fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } fib(15);"),
+        610.0
+    );
+    assert_eq!(
+        number("var total = 0; for (var i = 1; i <= 10; i = i + 1) total = total + i; total;"),
+        55.0
+    );
+    assert_eq!(
+        number("var xs = [1, 2, 3]; xs[1] = 5; xs[0] + xs[1] + len(xs);"),
+        9.0
+    );
+    match eval("\"a${1 + 1}b\";") {
+        Ok(ExprValue::Literal(Literal::STRING(s))) => assert_eq!(s, "a2b"),
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn eval_reports_failures_as_exit_codes() {
+    assert!(matches!(eval("var;"), Err(ExitCode::CompileError)));
+    assert!(matches!(eval("nil + 1;"), Err(ExitCode::RuntimeError)));
+}
+
+#[test]
+fn exit_ends_the_program_with_its_code() {
+    let out = Capture::default();
+    let err = Capture::default();
+    let mut lox = Lox::with_output(Box::new(out.clone()), Box::new(err.clone()));
+    let errors = lox
+        .run_source("print 1; try { exit(3); } catch (e) { print e; } print 2;")
+        .unwrap_err();
+    assert!(matches!(errors.as_slice(), [LoxError::Exit { code: 3 }]));
+    assert_eq!(lox.exit_code(), ExitCode::Exit(3));
+    assert_eq!(i32::from(lox.exit_code()), 3);
+    assert_eq!(
+        (out.text(), err.text()),
+        (String::from("1\n"), String::new())
+    );
+
+    // The interpreter is still usable, and the request is forgotten.
+    assert!(lox.run_source("fun f() { exit(0); } f();").is_err());
+    assert_eq!(lox.exit_code(), ExitCode::Exit(0));
+    assert!(lox.run_source("print 3;").is_ok());
+    assert_eq!(lox.exit_code(), ExitCode::Ok);
+    assert!(matches!(eval("exit(255);"), Err(ExitCode::Exit(255))));
+
+    for code in ["256", "-1", "1.5", "\"1\""] {
+        match lox
+            .run_source(&format!("exit({});", code))
+            .unwrap_err()
+            .as_slice()
+        {
+            [LoxError::RuntimeError { message, .. }] => {
+                assert_eq!(message, "exit() code must be an integer from 0 to 255.")
+            }
+            other => panic!("{}: {:?}", code, other),
+        }
+    }
+}
+
+#[test]
+fn run_source_keeps_state_and_collects_errors() {
+    let mut lox = Lox::new();
+    assert!(lox.run_source("var a = 1;").is_ok());
+    assert!(lox.run_source("a = a + 1;").is_ok());
+
+    let errors = lox.run_source("var = 1;\nvar = 2;").unwrap_err();
+    assert!(matches!(
+        errors.as_slice(),
+        [LoxError::ParseError { .. }, LoxError::ParseError { .. }]
+    ));
+
+    match lox.run_source("a();").unwrap_err().as_slice() {
+        [LoxError::RuntimeError { message, .. }] => {
+            assert_eq!(
+                message,
+                "Can only call functions and classes, got number from expression 'a'."
+            )
+        }
+        other => panic!("{:?}", other),
+    }
+}
+
+// Errors are plain `std::error::Error`s, so they pass through `?` into
+// whatever error type an embedder uses.
+#[test]
+fn errors_convert_with_question_mark() {
+    fn first_error(source: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        if let Err(mut errors) = lox.run_source(source) {
+            Err(errors.remove(0))?;
+        }
+        Ok(())
+    }
+    assert!(first_error("print 1;").is_ok());
+    let cases = [
+        ("print @;", "[line 1] Error: Unexpected character '@'."),
+        ("var = 1;", "[line 1] Error at '=': Expect variable name."),
+        ("print 1 +", "[line 1] Error at end: Expect expression"),
+        (
+            "var f;\nf();",
+            "Can only call functions and classes, got nil from expression 'f'.\n[line 2]",
+        ),
+    ];
+    for (source, expected) in cases {
+        assert_eq!(first_error(source).unwrap_err().to_string(), expected);
+    }
+}
+
+#[test]
+fn print_output_is_captured() {
+    let (out, err) =
+        run("print 1 + 2;\nprint 2.5;\nprint -0.5 * 4;\nprint \"hi\";\nprint nil;\nprint 1 == 1;");
+    assert_eq!(out, "3\n2.5\n-2\nhi\nnil\ntrue\n");
+    assert_eq!(err, "");
+
+    let (out, _) = run("for (var i = 0; i < 3; i = i + 1) print i * 10;");
+    assert_eq!(out, "0\n10\n20\n");
+
+    let (out, _) =
+        run("fun greet(name) { print \"hello ${name}\"; } greet(\"lox\"); print [1, 2.5];");
+    assert_eq!(out, "hello lox\n[1, 2.5]\n");
+}
+
+#[test]
+fn diagnostics_are_captured() {
+    let (out, err) = run("print 1;\nprint nil + 1;\nprint 2;");
+    assert_eq!(out, "1\n");
+    assert!(
+        err.ends_with("\n[line 2]\n2 | print nil + 1;\n  |       ^^^^^^^\n"),
+        "{}",
+        err
+    );
+
+    let (out, err) = run("print ;");
+    assert_eq!(out, "");
+    assert_eq!(
+        err,
+        "[line 1] Error at ';': Expect expression\n1 | print ;\n  |       ^\n"
+    );
+}
+
+#[test]
+fn run_source_sets_exit_codes() {
+    let cases = [
+        ("print 1;", ExitCode::Ok),
+        ("print ;", ExitCode::CompileError),
+        ("nil + 1;", ExitCode::RuntimeError),
+    ];
+    for (source, expected) in cases {
+        let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        assert_eq!(lox.run_source(source).is_ok(), expected == ExitCode::Ok);
+        assert_eq!(lox.exit_code(), expected, "{}", source);
+    }
+}
+
+#[test]
+fn run_reader_runs_the_whole_input() {
+    let (out, err) = (Capture::default(), Capture::default());
+    let mut lox = Lox::with_output(Box::new(out.clone()), Box::new(err.clone()));
+    let code = lox.run_reader("var a = 2;\nprint a * 21;\n".as_bytes());
+    assert_eq!(code, ExitCode::Ok);
+    assert_eq!(out.text(), "42\n");
+
+    assert_eq!(lox.run_reader(&b""[..]), ExitCode::Ok);
+    assert_eq!(
+        lox.run_reader(&b"print \"\xc3\x28\";"[..]),
+        ExitCode::CompileError
+    );
+    assert!(err.text().contains("Source is not valid UTF-8."));
+}
+
+#[test]
+fn assert_and_error_fail_the_script() {
+    let (out, err) = run("assert(1 < 2, \"fine\");\nprint \"ok\";\nassert(nil, \"x is \" + str(1));\nprint \"unreached\";");
+    assert_eq!(out, "ok\n");
+    assert!(err.starts_with("x is 1\n[line 3]\n"), "{}", err);
+
+    let (out, err) = run("print 1;\nerror(\"giving up\");\nprint 2;");
+    assert_eq!(out, "1\n");
+    assert!(err.starts_with("giving up\n[line 2]\n"), "{}", err);
+
+    for source in ["assert(false, \"no\");", "error(42);"] {
+        let mut lox = Lox::with_output(Box::new(io::sink()), Box::new(io::sink()));
+        assert!(lox.run_source(source).is_err());
+        assert_eq!(lox.exit_code(), ExitCode::RuntimeError, "{}", source);
+    }
+    match eval("error(\"boom\");") {
+        Err(ExitCode::RuntimeError) => {}
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn natives_check_their_arity() {
+    let (_, err) = run("assert(false);");
+    assert!(err.starts_with("Assertion failed.\n"), "{}", err);
+    let (_, err) = run("assert();");
+    assert!(
+        err.starts_with("Expected at least 1 argument but got 0.\n"),
+        "{}",
+        err
+    );
+    let (_, err) = run("assert(true, \"a\", \"b\");");
+    assert!(
+        err.starts_with("Expected at most 2 arguments but got 3.\n"),
+        "{}",
+        err
+    );
+    let (_, err) = run("chr();");
+    assert!(
+        err.starts_with("Expected 1 argument but got 0.\n"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn parameters_can_have_defaults() {
+    let (out, err) = run(
+        "fun greet(name, greeting = \"hi\") { print greeting + \" \" + name; }\n\
+         greet(\"bob\");\n\
+         greet(\"bob\", \"yo\");",
+    );
+    assert_eq!(out, "hi bob\nyo bob\n", "{}", err);
+
+    assert_eq!(number("fun f(a, b = a * 2) { return a + b; } f(3);"), 9.0);
+    assert_eq!(
+        number("fun f(a, b = a * 2) { return a + b; } f(3, 1);"),
+        4.0
+    );
+    // Defaults are evaluated on each call, not when the function is declared.
+    assert_eq!(
+        number("var step = 1; fun f(x = step) { return x; } step = 5; f();"),
+        5.0
+    );
+    let (out, _) =
+        run("fun f(xs = [0]) { xs[0] = xs[0] + 1; return xs[0]; } print f(); print f();");
+    assert_eq!(out, "1\n1\n");
+
+    let (_, err) = run("fun f(a, b = 1) {}\nf();");
+    assert!(
+        err.contains("Expected at least 1 argument but got 0."),
+        "{}",
+        err
+    );
+    let (_, err) = run("fun f(a = 1, b) {}");
+    assert!(
+        err.contains("A parameter without a default can't follow one with a default."),
+        "{}",
+        err
+    );
+}
+
+// Writes `files` into a fresh directory under the temp dir and runs the
+// first one, returning the exit code, what it printed and what it reported.
+// Diagnostics name the files relative to the directory.
+fn run_files(dir: &str, files: &[(&str, &str)], no_fs: bool) -> (ExitCode, String, String) {
+    let dir = std::env::temp_dir().join(dir);
+    let _ = std::fs::remove_dir_all(&dir);
+    for (name, source) in files {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, source).unwrap();
+    }
+    let (out, err) = (Capture::default(), Capture::default());
+    let mut lox = Lox::with_output(Box::new(out.clone()), Box::new(err.clone()));
+    if no_fs {
+        lox.interpreter.borrow_mut().disable_fs();
+    }
+    let code = lox.run_file(&dir.join(files[0].0).to_string_lossy().into_owned());
+    std::fs::remove_dir_all(&dir).unwrap();
+    let err = err.text().replace(&format!("{}/", dir.display()), "");
+    (code, out.text(), err)
+}
+
+#[test]
+fn imports_run_each_file_once() {
+    let (code, out, err) = run_files(
+        "lox_import_diamond",
+        &[
+            (
+                "main.lox",
+                "import \"lib/b.lox\";\nimport \"lib/c.lox\";\nprint loads + b() + c();\n",
+            ),
+            (
+                "lib/b.lox",
+                "import \"d.lox\";\nfun b() { return d() + 1; }\n",
+            ),
+            (
+                "lib/c.lox",
+                "import \"d.lox\";\nfun c() { return d() + 2; }\n",
+            ),
+            (
+                "lib/d.lox",
+                "var loads = 0;\nloads = loads + 1;\nfun d() { return 10; }\nprint \"d\";\n",
+            ),
+        ],
+        false,
+    );
+    assert_eq!(
+        (code, out.as_str(), err.as_str()),
+        (ExitCode::Ok, "d\n24\n", "")
+    );
+}
+
+#[test]
+fn import_cycles_are_compile_errors() {
+    let (code, out, err) = run_files(
+        "lox_import_cycle",
+        &[
+            ("a.lox", "import \"b.lox\";\nprint 1;\n"),
+            ("b.lox", "import \"a.lox\";\n"),
+        ],
+        false,
+    );
+    assert_eq!(code, ExitCode::CompileError);
+    assert_eq!(out, "");
+    assert!(
+        err.starts_with(
+            "b.lox:1: Error at '\"a.lox\"': \
+             Circular import detected: a.lox -> b.lox -> a.lox."
+        ),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn import_failures_name_the_file() {
+    let (code, _, err) = run_files(
+        "lox_import_errors",
+        &[
+            ("main.lox", "import \"bad.lox\";\n"),
+            ("bad.lox", "var = 1;\n"),
+        ],
+        false,
+    );
+    assert_eq!(code, ExitCode::CompileError);
+    assert!(
+        err.starts_with("bad.lox:1: Error at '=': Expect variable name."),
+        "{}",
+        err
+    );
+
+    let (code, _, err) = run_files(
+        "lox_import_missing",
+        &[("main.lox", "import \"missing.lox\";\n")],
+        false,
+    );
+    assert_eq!(code, ExitCode::CompileError);
+    assert!(err.contains("Couldn't import 'missing.lox': "), "{}", err);
+
+    let (code, _, err) = run_files(
+        "lox_import_nested",
+        &[("main.lox", "{ import \"main.lox\"; }\n")],
+        false,
+    );
+    assert_eq!(code, ExitCode::CompileError);
+    assert!(err.contains("Can only import at the top level."), "{}", err);
+
+    let (code, _, err) = run_files(
+        "lox_import_no_fs",
+        &[("main.lox", "import \"b.lox\";\n"), ("b.lox", "")],
+        true,
+    );
+    assert_eq!(code, ExitCode::CompileError);
+    assert!(
+        err.contains("Can't import with file access disabled."),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn diagnostics_name_the_file() {
+    let (code, _, err) = run_files(
+        "lox_names_scan",
+        &[("s.lox", "print 1;\nprint @;\n")],
+        false,
+    );
+    assert_eq!(code, ExitCode::CompileError);
+    assert_eq!(
+        err,
+        "s.lox:2: Error: Unexpected character '@'.\n2 | print @;\n  |       ^\n"
+    );
+
+    let (code, _, err) = run_files("lox_names_parse", &[("p.lox", "print (1;\n")], false);
+    assert_eq!(code, ExitCode::CompileError);
+    assert!(
+        err.starts_with("p.lox:1: Error at ';': Expect ')' after expression."),
+        "{}",
+        err
+    );
+
+    // A runtime error in an imported file quotes that file.
+    let (code, _, err) = run_files(
+        "lox_names_runtime",
+        &[
+            ("main.lox", "import \"lib/f.lox\";\nf();\n"),
+            ("lib/f.lox", "fun f() {\n  return nil + 1;\n}\n"),
+        ],
+        false,
+    );
+    assert_eq!(code, ExitCode::RuntimeError);
+    assert!(
+        err.ends_with("\nlib/f.lox:2\n2 |   return nil + 1;\n  |          ^^^^^^^\n"),
+        "{}",
+        err
+    );
+
+    // Warnings are named too.
+    let (code, _, err) = run_files(
+        "lox_names_warning",
+        &[("w.lox", "fun f() {\n  return 1;\n  print 2;\n}\n")],
+        false,
+    );
+    assert_eq!(code, ExitCode::Ok);
+    assert!(err.starts_with("w.lox:3: Warning at '2': "), "{}", err);
+}
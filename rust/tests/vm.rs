@@ -0,0 +1,134 @@
+// Conformance between the engines: every program here must print the same
+// output and diagnostics and exit the same way on the bytecode VM as on the
+// tree-walk interpreter.
+
+use crafting_interpreters::{ExitCode, Lox};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::thread;
+
+#[derive(Clone, Default)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Capture {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+fn run(source: &str, vm: bool) -> (String, String, ExitCode) {
+    let (out, err) = (Capture::default(), Capture::default());
+    let mut lox = Lox::with_output(Box::new(out.clone()), Box::new(err.clone()));
+    lox.vm = vm;
+    let _ = lox.run_source(source);
+    (out.text(), err.text(), lox.exit_code())
+}
+
+const PROGRAMS: &[&str] = &[
+    // Expressions
+    "print 1 + 2 * 3 - 4 / 8; print 7 % 3; print -(2 + 3);",
+    "print \"a\" + \"b\"; print \"ab\" * 3; print \"a\" < \"b\"; print 2 >= 3;",
+    "print 1 == 1; print nil == false; print \"1\" != 1; print [1, 2] == [1, 2];",
+    "var a = [1]; a[0] = a; var b = [1]; b[0] = b; print a == b; print a != [a, 1];",
+    "print 6 & 3; print 6 | 3; print 6 ^ 3; print 1 << 4; print -16 >> 2;",
+    "print nil or \"default\"; print 0 and \"zero\"; print false and boom; print !nil;",
+    "print (1, 2); var x = 1; print \"x is ${x + 1}.\";",
+    // Variables and scopes
+    "var a = 1; { var a = 2; { var a = 3; print a; } print a; } print a;",
+    "var a; print a; a = \"set\"; print a; var b = a = \"both\"; print a + b;",
+    "var i = 0; print i++; print i; print i--; print i; { var j = 5; j++; print j; }",
+    "const limit = 3; var total = 0; for (var i = 0; i < limit; i++) total = total + i; print total;",
+    // Control flow
+    "if (1 < 2) print \"then\"; else print \"else\"; if (nil) print 1; else if (0) print 2;",
+    "var i = 0; while (i < 5) { i = i + 1; if (i == 2) continue; if (i == 4) break; print i; }",
+    "for (var i = 0; i < 3; i++) { var sq = i * i; for (var j = 0; j < 2; j++) { if (j > 0) break; print sq + j; } }",
+    "var n = 0; for (;;) { n++; { var inner = n; if (inner > 2) break; } } print n;",
+    // Functions
+    "fun add(a, b) { return a + b; } print add(1, 2); print add;",
+    "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } print fib(20);",
+    "fun noReturn() { var x = 1; } print noReturn(); fun early(x) { if (x) return \"yes\"; return \"no\"; } print early(true) + early(nil);",
+    "fun outer() { fun inner(x) { return x * 2; } return inner(21); } print outer();",
+    "fun count(n) { var sum = 0; while (n > 0) { var step = n; sum = sum + step; n = n - 1; } return sum; } print count(100);",
+    "fun adder(a) { fun add(b) { return b + 1; } return add; } print adder(1)(2); print (len)([1]);",
+    "var calls = 0; fun tick() { calls = calls + 1; } tick(); tick(); print calls;",
+    // Lists, properties and natives
+    "var xs = [1, \"two\", [3]]; xs[0] = xs[0] + 10; print xs; print xs[2][0]; print len(xs);",
+    "print \"Hello\".upper(); print \"a,b\".split(\",\"); print (3.7).floor(); print str(12) + \"!\";",
+    "print clock; print len; print range(3);",
+    // Runtime errors
+    "print 1;\nprint 1 + \"a\";\nprint 2;",
+    "var x = 1;\nx();",
+    "fun f() {}\nf()();",
+    "fun f(a, b) {}\nf(1);",
+    "print undefined;",
+    "undefined = 1;",
+    "var xs = [1];\nprint xs[5];",
+    "print \"a\".nope;",
+    "print len(1);",
+    "print -\"a\";",
+    "print 1 / 0;",
+    "print \"ab\" * 100000000000000000000;",
+    "fun f(n) { return 1 + f(n + 1); }\nf(0);",
+    "fun deep(n) { if (n == 0) return 1 + nil; return deep(n - 1); }\ndeep(3);",
+    // Compile errors are the resolver's, before either engine runs.
+    "print a +;",
+    "{ var a = a; }",
+    "return 1;",
+];
+
+// The tree-walk interpreter recurses on the host stack, so the programs run
+// on a stack as large as the CLI's, which the default call depth needs.
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+#[test]
+fn engines_agree() {
+    let worker = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(|| {
+            for source in PROGRAMS {
+                assert_eq!(run(source, true), run(source, false), "{}", source);
+            }
+        })
+        .unwrap();
+    if let Err(panic) = worker.join() {
+        std::panic::resume_unwind(panic);
+    }
+}
+
+#[test]
+fn unsupported_features_are_compile_errors() {
+    let cases = [
+        (
+            "fun f() { var a = 1; fun g() { return a; } }",
+            "[line 1] Error at 'a': The VM doesn't support closures over enclosing locals yet.\n",
+        ),
+        (
+            "var i = 0; while (i < 2) { var k = i; fun f() { return k; } i = i + 1; }",
+            "[line 1] Error at 'k': The VM doesn't support closures over enclosing locals yet.\n",
+        ),
+        (
+            "fun f(a = 1) {}",
+            "[line 1] Error at 'a': The VM doesn't support default parameters yet.\n",
+        ),
+        (
+            "throw 1;",
+            "[line 1] Error at 'throw': The VM doesn't support 'throw' yet.\n",
+        ),
+    ];
+    for (source, expected) in cases {
+        let (out, err, code) = run(source, true);
+        assert_eq!(out, "", "{}", source);
+        assert_eq!(err.lines().next(), expected.lines().next(), "{}", source);
+        assert_eq!(code, ExitCode::CompileError, "{}", source);
+    }
+}